@@ -0,0 +1,20 @@
+#include <stdio.h>
+#include <stdlib.h>
+
+static inline __attribute__((always_inline)) int square(int x) {
+  return x * x;
+}
+
+static inline __attribute__((always_inline)) int sum_of_squares(int n) {
+  int sum = 0;
+  for (int i = 0; i < n; i++) {
+    sum += square(i);
+  }
+  return sum;
+}
+
+int main(int argc, char** argv) {
+  int n = argc > 1 ? atoi(argv[1]) : 10;
+  printf("%d\n", sum_of_squares(n));
+  return 0;
+}
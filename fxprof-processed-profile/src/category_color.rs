@@ -18,6 +18,39 @@ pub enum CategoryColor {
     DarkGray,
 }
 
+impl CategoryColor {
+    /// The colors which are used by [`CategoryColor::for_name`]. This excludes
+    /// `Transparent`, as well as `Grey` and `DarkGray`, which are commonly used
+    /// for "Other" / idle categories.
+    pub const PALETTE: [CategoryColor; 10] = [
+        CategoryColor::Blue,
+        CategoryColor::Green,
+        CategoryColor::Orange,
+        CategoryColor::Yellow,
+        CategoryColor::LightBlue,
+        CategoryColor::Purple,
+        CategoryColor::Brown,
+        CategoryColor::LightGreen,
+        CategoryColor::Red,
+        CategoryColor::LightRed,
+    ];
+
+    /// Pick a color from [`CategoryColor::PALETTE`] based on the category name.
+    ///
+    /// The color only depends on the name, so a category gets the same color in
+    /// every profile, regardless of which other categories exist or of the order
+    /// in which categories were added.
+    pub fn for_name(name: &str) -> CategoryColor {
+        // FNV-1a, because std's hashers don't guarantee stable output across releases.
+        let mut hash: u32 = 0x811c9dc5;
+        for byte in name.bytes() {
+            hash ^= u32::from(byte);
+            hash = hash.wrapping_mul(0x01000193);
+        }
+        Self::PALETTE[hash as usize % Self::PALETTE.len()].clone()
+    }
+}
+
 impl Serialize for CategoryColor {
     fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
         match self {
@@ -0,0 +1,23 @@
+//! Helpers for removing the rows of a thread's tables which are no longer
+//! used, see [`Thread::discard_before`](crate::thread::Thread::discard_before).
+
+/// Returns the index which each row gets once the rows which are not `used`
+/// have been removed, or `None` for the removed rows.
+pub fn compacted_indexes(used: &[bool]) -> Vec<Option<usize>> {
+    let mut next_index = 0;
+    used.iter()
+        .map(|used| {
+            if !used {
+                return None;
+            }
+            next_index += 1;
+            Some(next_index - 1)
+        })
+        .collect()
+}
+
+/// Removes the entries of `column` for which `keep` is false.
+pub fn retain_by<T>(column: &mut Vec<T>, keep: &[bool]) {
+    let mut keep = keep.iter();
+    column.retain(|_| *keep.next().unwrap());
+}
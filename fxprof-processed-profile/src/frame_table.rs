@@ -3,6 +3,7 @@ use serde::ser::{Serialize, SerializeMap, SerializeSeq, Serializer};
 use crate::category::{
     Category, CategoryHandle, CategoryPairHandle, SerializableSubcategoryColumn, Subcategory,
 };
+use crate::compaction::{compacted_indexes, retain_by};
 use crate::fast_hash_map::FastHashMap;
 use crate::func_table::{FuncIndex, FuncTable};
 use crate::global_lib_table::{GlobalLibIndex, GlobalLibTable};
@@ -18,6 +19,7 @@ pub struct FrameTable {
     subcategories: Vec<Subcategory>,
     funcs: Vec<FuncIndex>,
     native_symbols: Vec<Option<NativeSymbolIndex>>,
+    libs: Vec<Option<GlobalLibIndex>>,
     internal_frame_to_frame_index: FastHashMap<InternalFrame, usize>,
 }
 
@@ -40,17 +42,19 @@ impl FrameTable {
         let native_symbols = &mut self.native_symbols;
         let categories = &mut self.categories;
         let subcategories = &mut self.subcategories;
+        let libs = &mut self.libs;
         *self
             .internal_frame_to_frame_index
             .entry(frame.clone())
             .or_insert_with(|| {
                 let frame_index = addresses.len();
-                let (address, location_string_index, native_symbol, resource) = match frame.location
+                let (address, location_string_index, native_symbol, resource, lib) = match frame
+                    .location
                 {
                     InternalFrameLocation::UnknownAddress(address) => {
                         let location_string = format!("0x{:x}", address);
                         let s = string_table.index_for_string(&location_string);
-                        (None, s, None, None)
+                        (None, s, None, None, None)
                     }
                     InternalFrameLocation::AddressInLib(address, lib_index) => {
                         let res =
@@ -76,9 +80,11 @@ impl FrameTable {
                                 (None, string_table.index_for_string(&location_string))
                             }
                         };
-                        (Some(address), s, native_symbol, Some(res))
+                        (Some(address), s, native_symbol, Some(res), Some(lib_index))
+                    }
+                    InternalFrameLocation::Label(string_index) => {
+                        (None, string_index, None, None, None)
                     }
-                    InternalFrameLocation::Label(string_index) => (None, string_index, None, None),
                 };
                 let func_index = func_table.index_for_func(location_string_index, resource);
                 let CategoryPairHandle(category, subcategory_index) = frame.category_pair;
@@ -91,10 +97,84 @@ impl FrameTable {
                 subcategories.push(subcategory);
                 funcs.push(func_index);
                 native_symbols.push(native_symbol);
+                libs.push(lib);
                 frame_index
             })
     }
 
+    /// The library which contains the frame's address, if any.
+    pub fn lib_for_frame(&self, frame_index: usize) -> Option<GlobalLibIndex> {
+        self.libs[frame_index]
+    }
+
+    pub fn len(&self) -> usize {
+        self.addresses.len()
+    }
+
+    /// Marks the functions, native symbols and label strings of the `used`
+    /// frames as used.
+    pub fn mark_used(
+        &self,
+        used: &[bool],
+        used_funcs: &mut [bool],
+        used_native_symbols: &mut [bool],
+        used_strings: &mut [bool],
+    ) {
+        for (frame_index, is_used) in used.iter().enumerate() {
+            if *is_used {
+                used_funcs[self.funcs[frame_index].0 as usize] = true;
+                if let Some(native_symbol) = self.native_symbols[frame_index] {
+                    used_native_symbols[native_symbol.0 as usize] = true;
+                }
+            }
+        }
+        for (frame, frame_index) in &self.internal_frame_to_frame_index {
+            if let InternalFrameLocation::Label(string_index) = frame.location {
+                if used[*frame_index] {
+                    used_strings[string_index.index()] = true;
+                }
+            }
+        }
+    }
+
+    /// Removes the frames which are not `used`, and returns the new index of
+    /// each frame. The other arguments are the new indexes from compacting the
+    /// tables which the frames refer to.
+    pub fn compact(
+        &mut self,
+        used: &[bool],
+        funcs: &[Option<usize>],
+        native_symbols: &[Option<usize>],
+        strings: &[Option<usize>],
+    ) -> Vec<Option<usize>> {
+        let frames = compacted_indexes(used);
+        retain_by(&mut self.addresses, used);
+        retain_by(&mut self.categories, used);
+        retain_by(&mut self.subcategories, used);
+        retain_by(&mut self.funcs, used);
+        retain_by(&mut self.native_symbols, used);
+        retain_by(&mut self.libs, used);
+        for func in &mut self.funcs {
+            *func = FuncIndex(funcs[func.0 as usize].unwrap() as u32);
+        }
+        for native_symbol in self.native_symbols.iter_mut().flatten() {
+            *native_symbol =
+                NativeSymbolIndex(native_symbols[native_symbol.0 as usize].unwrap() as u32);
+        }
+        self.internal_frame_to_frame_index =
+            std::mem::take(&mut self.internal_frame_to_frame_index)
+                .into_iter()
+                .filter_map(|(mut frame, frame_index)| {
+                    let frame_index = frames[frame_index]?;
+                    if let InternalFrameLocation::Label(string_index) = &mut frame.location {
+                        *string_index = string_index.compacted(strings);
+                    }
+                    Some((frame, frame_index))
+                })
+                .collect();
+        frames
+    }
+
     pub fn as_serializable<'a>(&'a self, categories: &'a [Category]) -> impl Serialize + 'a {
         SerializableFrameTable {
             table: self,
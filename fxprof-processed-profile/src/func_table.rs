@@ -1,5 +1,6 @@
 use serde::ser::{Serialize, SerializeMap, SerializeSeq, Serializer};
 
+use crate::compaction::{compacted_indexes, retain_by};
 use crate::fast_hash_map::FastHashMap;
 use crate::resource_table::ResourceIndex;
 use crate::serialization_helpers::SerializableSingleValueColumn;
@@ -36,10 +37,43 @@ impl FuncTable {
             });
         FuncIndex(func_index as u32)
     }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    /// Marks the names of the `used` functions as used.
+    pub fn mark_used(&self, used: &[bool], used_strings: &mut [bool]) {
+        for (name, is_used) in self.names.iter().zip(used) {
+            if *is_used {
+                used_strings[name.index()] = true;
+            }
+        }
+    }
+
+    /// Removes the functions which are not `used`, and returns the new index
+    /// of each function. `strings` are the new string indexes.
+    pub fn compact(&mut self, used: &[bool], strings: &[Option<usize>]) -> Vec<Option<usize>> {
+        let funcs = compacted_indexes(used);
+        retain_by(&mut self.names, used);
+        retain_by(&mut self.resources, used);
+        for name in &mut self.names {
+            *name = name.compacted(strings);
+        }
+        self.func_name_and_resource_to_func_index = self
+            .names
+            .iter()
+            .copied()
+            .zip(self.resources.iter().copied())
+            .enumerate()
+            .map(|(func_index, name_and_resource)| (name_and_resource, func_index))
+            .collect();
+        funcs
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialOrd, Ord, PartialEq, Eq, Hash)]
-pub struct FuncIndex(u32);
+pub struct FuncIndex(pub(crate) u32);
 
 impl Serialize for FuncIndex {
     fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
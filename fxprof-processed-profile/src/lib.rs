@@ -34,6 +34,7 @@ pub use debugid;
 
 mod category;
 mod category_color;
+mod compaction;
 mod cpu_delta;
 mod fast_hash_map;
 mod frame;
@@ -44,11 +45,13 @@ mod lib_info;
 mod library_info;
 mod marker_table;
 mod markers;
+mod native_allocation_table;
 mod native_symbols;
 mod process;
 mod profile;
 mod reference_timestamp;
 mod resource_table;
+mod sample_density;
 mod sample_table;
 mod serialization_helpers;
 mod stack_table;
@@ -66,5 +69,6 @@ pub use markers::*;
 pub use process::ThreadHandle;
 pub use profile::{Profile, SamplingInterval, StringHandle};
 pub use reference_timestamp::ReferenceTimestamp;
+pub use sample_density::SampleDensity;
 pub use thread::ProcessHandle;
 pub use timestamp::*;
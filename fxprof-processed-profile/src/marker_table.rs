@@ -1,6 +1,8 @@
 use serde::ser::{Serialize, SerializeMap, Serializer};
 use serde_json::Value;
 
+use crate::compaction::retain_by;
+use crate::fast_hash_map::FastHashMap;
 use crate::serialization_helpers::{
     SerializableOptionalTimestampColumn, SerializableSingleValueColumn,
 };
@@ -39,6 +41,66 @@ impl MarkerTable {
         self.marker_phases.push(phase);
         self.marker_datas.push(data);
     }
+
+    /// Removes the markers which end before `time`. Markers which have only
+    /// started are kept while their end is still to come. Once the matching
+    /// `IntervalEnd` marker with the same name has been added, a start before
+    /// `time` is removed, and the end marker stands for the whole interval.
+    pub fn discard_before(&mut self, time: Timestamp) {
+        let ended = self.ended_interval_starts();
+        let keep: Vec<bool> = (0..self.marker_phases.len())
+            .map(|i| match self.marker_phases[i] {
+                Phase::Instant => self.marker_starts[i] >= Some(time),
+                Phase::Interval | Phase::IntervalEnd => self.marker_ends[i] >= Some(time),
+                Phase::IntervalStart => !ended[i] || self.marker_starts[i] >= Some(time),
+            })
+            .collect();
+        retain_by(&mut self.marker_name_string_indexes, &keep);
+        retain_by(&mut self.marker_starts, &keep);
+        retain_by(&mut self.marker_ends, &keep);
+        retain_by(&mut self.marker_phases, &keep);
+        retain_by(&mut self.marker_datas, &keep);
+    }
+
+    /// For each marker, whether it is an `IntervalStart` marker which was
+    /// followed by its `IntervalEnd` marker. Starts and ends with the same name
+    /// are paired like brackets.
+    fn ended_interval_starts(&self) -> Vec<bool> {
+        let mut ended = vec![false; self.marker_phases.len()];
+        let mut open_starts: FastHashMap<ThreadInternalStringIndex, Vec<usize>> =
+            FastHashMap::default();
+        for (i, (phase, name)) in self
+            .marker_phases
+            .iter()
+            .zip(&self.marker_name_string_indexes)
+            .enumerate()
+        {
+            match phase {
+                Phase::IntervalStart => open_starts.entry(*name).or_default().push(i),
+                Phase::IntervalEnd => {
+                    if let Some(start) = open_starts.get_mut(name).and_then(Vec::pop) {
+                        ended[start] = true;
+                    }
+                }
+                Phase::Instant | Phase::Interval => {}
+            }
+        }
+        ended
+    }
+
+    /// Marks the names of all markers as used.
+    pub fn mark_used_strings(&self, used_strings: &mut [bool]) {
+        for name in &self.marker_name_string_indexes {
+            used_strings[name.index()] = true;
+        }
+    }
+
+    /// Updates the names after the string table was compacted.
+    pub fn compact_strings(&mut self, strings: &[Option<usize>]) {
+        for name in &mut self.marker_name_string_indexes {
+            *name = name.compacted(strings);
+        }
+    }
 }
 
 impl Serialize for MarkerTable {
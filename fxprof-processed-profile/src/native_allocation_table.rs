@@ -0,0 +1,60 @@
+use serde::ser::{Serialize, SerializeMap, Serializer};
+
+use crate::Timestamp;
+
+/// The allocations of a thread, each weighted by its size in bytes. The Firefox
+/// profiler can show this table in the call tree instead of the samples.
+#[derive(Debug, Clone, Default)]
+pub struct NativeAllocationTable {
+    allocation_sizes: Vec<i64>,
+    allocation_timestamps: Vec<Timestamp>,
+    allocation_stack_indexes: Vec<Option<usize>>,
+}
+
+impl NativeAllocationTable {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn add_allocation(&mut self, timestamp: Timestamp, stack_index: Option<usize>, size: i64) {
+        self.allocation_sizes.push(size);
+        self.allocation_timestamps.push(timestamp);
+        self.allocation_stack_indexes.push(stack_index);
+    }
+
+    /// Removes the allocations before `time`, which are the first ones.
+    pub fn discard_before(&mut self, time: Timestamp) {
+        let count = self.allocation_timestamps.partition_point(|t| *t < time);
+        self.allocation_sizes.drain(..count);
+        self.allocation_timestamps.drain(..count);
+        self.allocation_stack_indexes.drain(..count);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.allocation_timestamps.is_empty()
+    }
+
+    pub fn stack_indexes(&self) -> impl Iterator<Item = usize> + '_ {
+        self.allocation_stack_indexes.iter().flatten().copied()
+    }
+
+    /// Updates the stack indexes after the stack table was compacted.
+    pub fn compact_stacks(&mut self, stacks: &[Option<usize>]) {
+        for stack_index in self.allocation_stack_indexes.iter_mut().flatten() {
+            *stack_index = stacks[*stack_index].unwrap();
+        }
+    }
+}
+
+impl Serialize for NativeAllocationTable {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let len = self.allocation_timestamps.len();
+        let mut map = serializer.serialize_map(None)?;
+        map.serialize_entry("length", &len)?;
+        map.serialize_entry("stack", &self.allocation_stack_indexes)?;
+        map.serialize_entry("time", &self.allocation_timestamps)?;
+        map.serialize_entry("weight", &self.allocation_sizes)?;
+        map.serialize_entry("weightType", &"bytes")?;
+        map.end()
+    }
+}
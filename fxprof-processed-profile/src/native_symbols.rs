@@ -1,6 +1,7 @@
 use serde::ser::{Serialize, SerializeMap, Serializer};
 
 use crate::{
+    compaction::{compacted_indexes, retain_by},
     fast_hash_map::FastHashMap,
     global_lib_table::GlobalLibIndex,
     library_info::Symbol,
@@ -48,10 +49,45 @@ impl NativeSymbols {
         let name_string_index = names[symbol_index];
         (NativeSymbolIndex(symbol_index as u32), name_string_index)
     }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    /// Marks the names of the `used` symbols as used.
+    pub fn mark_used(&self, used: &[bool], used_strings: &mut [bool]) {
+        for (name, is_used) in self.names.iter().zip(used) {
+            if *is_used {
+                used_strings[name.index()] = true;
+            }
+        }
+    }
+
+    /// Removes the symbols which are not `used`, and returns the new index of
+    /// each symbol. `strings` are the new string indexes.
+    pub fn compact(&mut self, used: &[bool], strings: &[Option<usize>]) -> Vec<Option<usize>> {
+        let symbols = compacted_indexes(used);
+        retain_by(&mut self.addresses, used);
+        retain_by(&mut self.function_sizes, used);
+        retain_by(&mut self.lib_indexes, used);
+        retain_by(&mut self.names, used);
+        for name in &mut self.names {
+            *name = name.compacted(strings);
+        }
+        self.lib_and_symbol_address_to_symbol_index = self
+            .lib_indexes
+            .iter()
+            .copied()
+            .zip(self.addresses.iter().copied())
+            .enumerate()
+            .map(|(symbol_index, lib_and_address)| (lib_and_address, symbol_index))
+            .collect();
+        symbols
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialOrd, Ord, PartialEq, Eq, Hash)]
-pub struct NativeSymbolIndex(u32);
+pub struct NativeSymbolIndex(pub(crate) u32);
 
 impl Serialize for NativeSymbolIndex {
     fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
@@ -13,6 +13,7 @@ use crate::global_lib_table::GlobalLibTable;
 use crate::library_info::LibraryInfo;
 use crate::process::{Process, ThreadHandle};
 use crate::reference_timestamp::ReferenceTimestamp;
+use crate::sample_density::SampleDensity;
 use crate::string_table::{GlobalStringIndex, GlobalStringTable};
 use crate::thread::{ProcessHandle, Thread};
 use crate::{MarkerSchema, MarkerTiming, ProfilerMarker, Timestamp};
@@ -90,6 +91,14 @@ pub struct StringHandle(GlobalStringIndex);
 pub struct Profile {
     pub(crate) product: String,
     pub(crate) interval: SamplingInterval,
+    pub(crate) throttled_interval: Option<SamplingInterval>,
+    pub(crate) truncated: bool,
+    pub(crate) os_name: Option<String>,
+    pub(crate) platform: Option<String>,
+    pub(crate) cpu_name: Option<String>,
+    /// (physical, logical)
+    pub(crate) cpu_counts: Option<(u32, u32)>,
+    pub(crate) misc: Option<String>,
     pub(crate) libs: GlobalLibTable,
     pub(crate) categories: Vec<Category>, // append-only for stable CategoryHandles
     pub(crate) processes: Vec<Process>,   // append-only for stable ProcessHandles
@@ -113,6 +122,13 @@ impl Profile {
     ) -> Self {
         Profile {
             interval,
+            throttled_interval: None,
+            truncated: false,
+            os_name: None,
+            platform: None,
+            cpu_name: None,
+            cpu_counts: None,
+            misc: None,
             product: product.to_string(),
             threads: Vec::new(),
             libs: GlobalLibTable::new(),
@@ -133,6 +149,24 @@ impl Profile {
         self.interval = interval;
     }
 
+    /// Record that the sampler had to lower its sampling rate at some point,
+    /// because taking a sample took longer than the declared interval.
+    /// `interval` is the longest interval which was used instead.
+    ///
+    /// This is serialized as `meta.throttledInterval`, in milliseconds.
+    pub fn set_throttled_interval(&mut self, interval: SamplingInterval) {
+        self.throttled_interval = Some(interval);
+    }
+
+    /// Record that the recording was stopped early, for example because a
+    /// limit on its duration or its number of samples was reached, so the
+    /// profile doesn't cover the whole lifetime of the profiled processes.
+    ///
+    /// This is serialized as `meta.truncated`.
+    pub fn set_truncated(&mut self, truncated: bool) {
+        self.truncated = truncated;
+    }
+
     /// Change the reference timestamp.
     pub fn set_reference_timestamp(&mut self, reference_timestamp: ReferenceTimestamp) {
         self.reference_timestamp = reference_timestamp;
@@ -143,6 +177,43 @@ impl Profile {
         self.product = product.to_string();
     }
 
+    /// Set the name and version of the operating system on which the profile was
+    /// recorded, e.g. "macOS 14.1".
+    ///
+    /// This is serialized as `meta.oscpu`.
+    pub fn set_os_name(&mut self, os_name: &str) {
+        self.os_name = Some(os_name.to_string());
+    }
+
+    /// Set the platform on which the profile was recorded, e.g. "Macintosh".
+    ///
+    /// This is serialized as `meta.platform`.
+    pub fn set_platform(&mut self, platform: &str) {
+        self.platform = Some(platform.to_string());
+    }
+
+    /// Set the model name of the CPU, e.g. "Apple M1 Pro".
+    ///
+    /// This is serialized as `meta.CPUName`.
+    pub fn set_cpu_name(&mut self, cpu_name: &str) {
+        self.cpu_name = Some(cpu_name.to_string());
+    }
+
+    /// Set the number of physical and logical CPU cores of the machine.
+    ///
+    /// This is serialized as `meta.physicalCPUs` and `meta.logicalCPUs`.
+    pub fn set_cpu_counts(&mut self, physical_cpus: u32, logical_cpus: u32) {
+        self.cpu_counts = Some((physical_cpus, logical_cpus));
+    }
+
+    /// Set a free-form description of how the profile was recorded, for example
+    /// the name and version of the profiler.
+    ///
+    /// This is serialized as `meta.misc`.
+    pub fn set_misc(&mut self, misc: &str) {
+        self.misc = Some(misc.to_string());
+    }
+
     /// Add a category and return its handle.
     ///
     /// Categories are used for stack frames and markers, as part of a "category pair".
@@ -156,6 +227,33 @@ impl Profile {
         handle
     }
 
+    /// Add a category whose color is picked based on its name, or return the handle
+    /// of the existing category with this name.
+    ///
+    /// This is useful for categories which are assigned automatically, for example
+    /// based on library names: the same category name always gets the same color,
+    /// see [`CategoryColor::for_name`], so colors stay consistent across profiles.
+    pub fn add_category_with_stable_color(&mut self, name: &str) -> CategoryHandle {
+        match self.categories.iter().position(|c| c.name == name) {
+            Some(index) => CategoryHandle(index as u16),
+            None => self.add_category(name, CategoryColor::for_name(name)),
+        }
+    }
+
+    /// Returns the name and color of every category, sorted by name.
+    ///
+    /// This can be used by tools which want to pin category colors, for example
+    /// when comparing multiple profiles.
+    pub fn category_colors(&self) -> Vec<(&str, CategoryColor)> {
+        let mut colors: Vec<(&str, CategoryColor)> = self
+            .categories
+            .iter()
+            .map(|category| (category.name.as_str(), category.color.clone()))
+            .collect();
+        colors.sort();
+        colors
+    }
+
     /// Add a subcategory for a category, and return the "category pair" handle.
     pub fn add_subcategory(&mut self, category: CategoryHandle, name: &str) -> CategoryPairHandle {
         let subcategory = self.categories[category.0 as usize].add_subcategory(name.into());
@@ -226,11 +324,86 @@ impl Profile {
         self.threads[thread.0].set_start_time(start_time);
     }
 
+    /// Declare whether the thread's start time is its true start time.
+    ///
+    /// When attaching to a running process, the threads which already existed
+    /// at attach time have an unknown start time, and their start time is only
+    /// the time at which the profiler first saw them. Marking such threads
+    /// as having an unknown start time makes sure that consumers don't interpret
+    /// the attach time as the time at which these threads were created.
+    ///
+    /// Threads have a known start time by default.
+    pub fn set_thread_start_time_known(&mut self, thread: ThreadHandle, start_time_known: bool) {
+        self.threads[thread.0].set_start_time_known(start_time_known);
+    }
+
     /// Set the end time of a thread.
     pub fn set_thread_end_time(&mut self, thread: ThreadHandle, end_time: Timestamp) {
         self.threads[thread.0].set_end_time(end_time);
     }
 
+    /// Set the number of samples on this thread whose stack was truncated
+    /// because it exceeded the stack depth limit of the profiler.
+    ///
+    /// Threads with a non-zero count are listed in the `truncatedStackCounts`
+    /// property of the profile meta, so that the effect of the depth limit stays
+    /// visible in the profile.
+    pub fn set_thread_truncated_stack_count(&mut self, thread: ThreadHandle, count: u64) {
+        self.threads[thread.0].set_truncated_stack_count(count);
+    }
+
+    /// The number of truncated stacks on this thread, as set by
+    /// [`Profile::set_thread_truncated_stack_count`].
+    pub fn thread_truncated_stack_count(&self, thread: ThreadHandle) -> u64 {
+        self.threads[thread.0].truncated_stack_count()
+    }
+
+    /// Compare the number of samples on this thread to the number of samples
+    /// expected from the thread's lifetime and the sampling interval.
+    ///
+    /// This is also emitted as the `sampleDensity` property of each thread, and
+    /// can be used to warn about threads which were sampled too rarely for their
+    /// call trees to be meaningful.
+    pub fn thread_sample_density(&self, thread: ThreadHandle) -> SampleDensity {
+        self.threads[thread.0].sample_density(self.interval)
+    }
+
+    /// Returns the `n` libraries with the most samples, summed over all threads,
+    /// in descending order of their sample count.
+    ///
+    /// Each sample is attributed to the library which contains its leaf frame.
+    /// Samples whose leaf frame is not in any known library, for example because
+    /// it is a label frame or an address outside all libraries, and samples
+    /// without a stack are counted under [`Profile::UNKNOWN_MODULE_NAME`].
+    pub fn top_modules(&self, n: usize) -> Vec<(String, u64)> {
+        let mut lib_counts = FastHashMap::default();
+        for thread in &self.threads {
+            thread.accumulate_leaf_lib_sample_counts(&mut lib_counts);
+        }
+        let mut name_counts: FastHashMap<&str, u64> = FastHashMap::default();
+        for (lib_index, count) in lib_counts {
+            let name = match lib_index.and_then(|lib_index| self.libs.get_lib(lib_index)) {
+                Some(lib) => lib.name.as_str(),
+                None => Self::UNKNOWN_MODULE_NAME,
+            };
+            *name_counts.entry(name).or_default() += count;
+        }
+        let mut modules: Vec<(String, u64)> = name_counts
+            .into_iter()
+            .filter(|(_, count)| *count != 0)
+            .map(|(name, count)| (name.to_string(), count))
+            .collect();
+        modules.sort_by(|(name_a, count_a), (name_b, count_b)| {
+            count_b.cmp(count_a).then_with(|| name_a.cmp(name_b))
+        });
+        modules.truncate(n);
+        modules
+    }
+
+    /// The module name which [`Profile::top_modules`] uses for samples outside
+    /// any known library.
+    pub const UNKNOWN_MODULE_NAME: &'static str = "unknown";
+
     /// Turn the string into in a [`StringHandle`], for use in [`Frame::Label`].
     pub fn intern_string(&mut self, s: &str) -> StringHandle {
         StringHandle(self.string_table.index_for_string(s))
@@ -270,6 +443,44 @@ impl Profile {
         self.threads[thread.0].add_sample_same_stack_zero_cpu(timestamp, weight);
     }
 
+    /// Add an allocation of `size` bytes to the given thread. A negative size
+    /// stands for a deallocation.
+    ///
+    /// Allocations are kept separately from the samples. The Firefox profiler
+    /// can switch the call tree from the samples to the allocations, which
+    /// then shows how many bytes were allocated in each call stack.
+    pub fn add_allocation_sample(
+        &mut self,
+        thread: ThreadHandle,
+        timestamp: Timestamp,
+        frames: impl Iterator<Item = (Frame, CategoryPairHandle)>,
+        size: i64,
+    ) {
+        let stack_index = self.stack_index_for_frames(thread, frames);
+        self.threads[thread.0].add_native_allocation(timestamp, stack_index, size);
+    }
+
+    /// Remove all samples, allocations and markers before `time`, from all
+    /// threads. This keeps the memory use of a long-running recording bounded,
+    /// for example to only keep the most recent seconds until something
+    /// interesting happens, and to save just those.
+    ///
+    /// Interval markers are removed if they end before `time`. Threads which
+    /// started before `time` get `time` as their start time, and are marked as
+    /// having an unknown start time, see [`Profile::set_thread_start_time_known`].
+    ///
+    /// Interval starts are kept until their interval ends. The stacks, frames
+    /// and strings which are no longer used by the remaining samples, allocations
+    /// and markers are removed, so the memory use is bounded by what happens in
+    /// the kept time range, not by the length of the recording. Libraries and
+    /// the strings from [`Profile::intern_string`] are kept, since handles to
+    /// them may still be in use.
+    pub fn discard_before(&mut self, time: Timestamp) {
+        for thread in &mut self.threads {
+            thread.discard_before(time);
+        }
+    }
+
     /// Add a marker to the given thread.
     pub fn add_marker<T: ProfilerMarker>(
         &mut self,
@@ -346,9 +557,34 @@ impl<'a> Serialize for SerializableProfileMeta<'a> {
             }),
         )?;
         map.serialize_entry("interval", &(self.0.interval.as_secs_f64() * 1000.0))?;
+        if let Some(throttled_interval) = self.0.throttled_interval {
+            map.serialize_entry(
+                "throttledInterval",
+                &(throttled_interval.as_secs_f64() * 1000.0),
+            )?;
+        }
+        if self.0.truncated {
+            map.serialize_entry("truncated", &true)?;
+        }
         map.serialize_entry("preprocessedProfileVersion", &44)?;
         map.serialize_entry("processType", &0)?;
         map.serialize_entry("product", &self.0.product)?;
+        if let Some(os_name) = &self.0.os_name {
+            map.serialize_entry("oscpu", os_name)?;
+        }
+        if let Some(platform) = &self.0.platform {
+            map.serialize_entry("platform", platform)?;
+        }
+        if let Some(cpu_name) = &self.0.cpu_name {
+            map.serialize_entry("CPUName", cpu_name)?;
+        }
+        if let Some((physical_cpus, logical_cpus)) = self.0.cpu_counts {
+            map.serialize_entry("physicalCPUs", &physical_cpus)?;
+            map.serialize_entry("logicalCPUs", &logical_cpus)?;
+        }
+        if let Some(misc) = &self.0.misc {
+            map.serialize_entry("misc", misc)?;
+        }
         map.serialize_entry(
             "sampleUnits",
             &json!({
@@ -365,6 +601,23 @@ impl<'a> Serialize for SerializableProfileMeta<'a> {
         map.serialize_entry("doesNotUseFrameImplementation", &true)?;
         map.serialize_entry("sourceCodeIsNotOnSearchfox", &true)?;
 
+        let truncated_stack_counts: Vec<_> = self
+            .0
+            .threads
+            .iter()
+            .filter(|thread| thread.truncated_stack_count() != 0)
+            .map(|thread| {
+                json!({
+                    "pid": self.0.processes[thread.process().0].pid(),
+                    "tid": thread.tid(),
+                    "count": thread.truncated_stack_count(),
+                })
+            })
+            .collect();
+        if !truncated_stack_counts.is_empty() {
+            map.serialize_entry("truncatedStackCounts", &truncated_stack_counts)?;
+        }
+
         let mut marker_schemas: Vec<MarkerSchema> =
             self.0.marker_schemas.values().cloned().collect();
         marker_schemas.sort_by_key(|schema| schema.type_name);
@@ -403,7 +656,12 @@ impl<'a> Serialize for SerializableProfileThreadsProperty<'a> {
                 let categories = &self.0.categories;
                 let thread = &self.0.threads[thread.0];
                 let process = &self.0.processes[thread.process().0];
-                seq.serialize_element(&SerializableProfileThread(process, thread, categories))?;
+                seq.serialize_element(&SerializableProfileThread(
+                    process,
+                    thread,
+                    categories,
+                    self.0.interval,
+                ))?;
             }
         }
 
@@ -411,11 +669,11 @@ impl<'a> Serialize for SerializableProfileThreadsProperty<'a> {
     }
 }
 
-struct SerializableProfileThread<'a>(&'a Process, &'a Thread, &'a [Category]);
+struct SerializableProfileThread<'a>(&'a Process, &'a Thread, &'a [Category], SamplingInterval);
 
 impl<'a> Serialize for SerializableProfileThread<'a> {
     fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
-        let SerializableProfileThread(process, thread, categories) = self;
+        let SerializableProfileThread(process, thread, categories, interval) = self;
         let process_start_time = process.start_time();
         let process_end_time = process.end_time();
         let process_name = process.name();
@@ -427,6 +685,7 @@ impl<'a> Serialize for SerializableProfileThread<'a> {
             process_end_time,
             process_name,
             pid,
+            *interval,
         )
     }
 }
@@ -34,6 +34,21 @@ impl ResourceTable {
             resource
         })
     }
+
+    /// Marks the names of all resources as used. There is one resource per
+    /// library, so resources are never removed.
+    pub fn mark_used_strings(&self, used_strings: &mut [bool]) {
+        for name in &self.resource_names {
+            used_strings[name.index()] = true;
+        }
+    }
+
+    /// Updates the names after the string table was compacted.
+    pub fn compact_strings(&mut self, strings: &[Option<usize>]) {
+        for name in &mut self.resource_names {
+            *name = name.compacted(strings);
+        }
+    }
 }
 
 impl Serialize for ResourceTable {
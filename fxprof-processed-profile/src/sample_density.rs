@@ -0,0 +1,45 @@
+use serde::ser::{Serialize, SerializeMap, Serializer};
+
+/// How densely a thread was sampled, compared to what the sampling interval
+/// would lead one to expect. See [`Profile::thread_sample_density`](crate::Profile::thread_sample_density).
+///
+/// Threads with very few samples, for example because they were rarely seen
+/// during sampling passes, produce statistically meaningless call trees.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SampleDensity {
+    /// The number of samples on the thread, including the samples which were
+    /// merged into a previous sample because they had zero CPU usage.
+    pub sample_count: u64,
+    /// The number of samples expected from the thread's lifetime and the
+    /// profile's sampling interval.
+    pub expected_sample_count: u64,
+}
+
+impl SampleDensity {
+    /// Threads whose coverage is below this value are flagged as undersampled.
+    pub const UNDERSAMPLED_THRESHOLD: f64 = 0.5;
+
+    /// The ratio of actual to expected samples, between 0.0 and 1.0.
+    pub fn coverage(&self) -> f64 {
+        if self.expected_sample_count == 0 {
+            return 1.0;
+        }
+        (self.sample_count as f64 / self.expected_sample_count as f64).min(1.0)
+    }
+
+    /// Whether the coverage is below [`SampleDensity::UNDERSAMPLED_THRESHOLD`].
+    pub fn is_undersampled(&self) -> bool {
+        self.coverage() < Self::UNDERSAMPLED_THRESHOLD
+    }
+}
+
+impl Serialize for SampleDensity {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+        map.serialize_entry("sampleCount", &self.sample_count)?;
+        map.serialize_entry("expectedSampleCount", &self.expected_sample_count)?;
+        map.serialize_entry("coverage", &self.coverage())?;
+        map.serialize_entry("undersampled", &self.is_undersampled())?;
+        map.end()
+    }
+}
@@ -1,3 +1,5 @@
+use std::convert::TryFrom;
+
 use serde::ser::{Serialize, SerializeMap, Serializer};
 
 use crate::cpu_delta::CpuDelta;
@@ -29,10 +31,55 @@ impl SampleTable {
         self.sample_cpu_deltas.push(cpu_delta);
     }
 
+    /// The total weight of all samples.
+    pub fn sample_count(&self) -> u64 {
+        self.sample_weights
+            .iter()
+            .map(|weight| u64::try_from(*weight).unwrap_or(0))
+            .sum()
+    }
+
+    /// The stack index and weight of each sample.
+    pub fn stacks_and_weights(&self) -> impl Iterator<Item = (Option<usize>, i32)> + '_ {
+        self.sample_stack_indexes
+            .iter()
+            .copied()
+            .zip(self.sample_weights.iter().copied())
+    }
+
+    pub fn last_sample_timestamp(&self) -> Option<Timestamp> {
+        self.sample_timestamps.last().copied()
+    }
+
     pub fn modify_last_sample(&mut self, timestamp: Timestamp, weight: i32) {
         *self.sample_weights.last_mut().unwrap() += weight;
         *self.sample_timestamps.last_mut().unwrap() = timestamp;
     }
+
+    /// Removes the samples before `time`. Samples are added in time order, so
+    /// these are the first samples.
+    pub fn discard_before(&mut self, time: Timestamp) {
+        let count = self.sample_timestamps.partition_point(|t| *t < time);
+        self.sample_weights.drain(..count);
+        self.sample_timestamps.drain(..count);
+        self.sample_stack_indexes.drain(..count);
+        self.sample_cpu_deltas.drain(..count);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sample_timestamps.is_empty()
+    }
+
+    pub fn stack_indexes(&self) -> impl Iterator<Item = usize> + '_ {
+        self.sample_stack_indexes.iter().flatten().copied()
+    }
+
+    /// Updates the stack indexes after the stack table was compacted.
+    pub fn compact_stacks(&mut self, stacks: &[Option<usize>]) {
+        for stack_index in self.sample_stack_indexes.iter_mut().flatten() {
+            *stack_index = stacks[*stack_index].unwrap();
+        }
+    }
 }
 
 impl Serialize for SampleTable {
@@ -3,6 +3,7 @@ use serde::ser::{Serialize, SerializeMap, Serializer};
 use crate::category::{
     Category, CategoryHandle, CategoryPairHandle, SerializableSubcategoryColumn, Subcategory,
 };
+use crate::compaction::{compacted_indexes, retain_by};
 use crate::fast_hash_map::FastHashMap;
 
 #[derive(Debug, Clone, Default)]
@@ -47,6 +48,54 @@ impl StackTable {
         }
     }
 
+    pub fn frame_for_stack(&self, stack_index: usize) -> usize {
+        self.stack_frames[stack_index]
+    }
+
+    pub fn len(&self) -> usize {
+        self.stack_prefixes.len()
+    }
+
+    /// Marks the prefixes of the `used` stacks as used too, and the frames of
+    /// all used stacks in `used_frames`.
+    pub fn mark_used(&self, used: &mut [bool], used_frames: &mut [bool]) {
+        // A prefix always has a lower index than the stacks which extend it.
+        for stack in (0..used.len()).rev() {
+            if used[stack] {
+                if let Some(prefix) = self.stack_prefixes[stack] {
+                    used[prefix] = true;
+                }
+                used_frames[self.stack_frames[stack]] = true;
+            }
+        }
+    }
+
+    /// Removes the stacks which are not `used`, and returns the new index of
+    /// each stack. `frames` are the new frame indexes from
+    /// [`FrameTable::compact`](crate::frame_table::FrameTable::compact).
+    pub fn compact(&mut self, used: &[bool], frames: &[Option<usize>]) -> Vec<Option<usize>> {
+        let stacks = compacted_indexes(used);
+        retain_by(&mut self.stack_prefixes, used);
+        retain_by(&mut self.stack_frames, used);
+        retain_by(&mut self.stack_categories, used);
+        retain_by(&mut self.stack_subcategories, used);
+        for prefix in &mut self.stack_prefixes {
+            *prefix = prefix.map(|prefix| stacks[prefix].unwrap());
+        }
+        for frame in &mut self.stack_frames {
+            *frame = frames[*frame].unwrap();
+        }
+        self.index = self
+            .stack_prefixes
+            .iter()
+            .copied()
+            .zip(self.stack_frames.iter().copied())
+            .enumerate()
+            .map(|(stack, prefix_and_frame)| (prefix_and_frame, stack))
+            .collect();
+        stacks
+    }
+
     pub fn serialize_with_categories<'a>(
         &'a self,
         categories: &'a [Category],
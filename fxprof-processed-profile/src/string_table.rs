@@ -2,10 +2,11 @@ use std::ops::Deref;
 
 use serde::{Serialize, Serializer};
 
+use crate::compaction::{compacted_indexes, retain_by};
 use crate::fast_hash_map::FastHashMap;
 
 #[derive(Debug, Clone, Copy, PartialOrd, Ord, PartialEq, Eq, Hash)]
-pub struct StringIndex(u32);
+pub struct StringIndex(pub(crate) u32);
 
 #[derive(Debug, Clone, Default)]
 pub struct StringTable {
@@ -29,6 +30,24 @@ impl StringTable {
     pub fn get_string(&self, index: StringIndex) -> Option<&str> {
         self.strings.get(index.0 as usize).map(Deref::deref)
     }
+
+    pub fn len(&self) -> usize {
+        self.strings.len()
+    }
+
+    /// Removes the strings which are not `used`, and returns the new index of
+    /// each string, see [`compacted_indexes`].
+    pub fn compact(&mut self, used: &[bool]) -> Vec<Option<usize>> {
+        let strings = compacted_indexes(used);
+        retain_by(&mut self.strings, used);
+        self.index = self
+            .strings
+            .iter()
+            .enumerate()
+            .map(|(index, s)| (s.clone(), StringIndex(index as u32)))
+            .collect();
+        strings
+    }
 }
 
 impl Serialize for StringTable {
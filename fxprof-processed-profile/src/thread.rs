@@ -1,20 +1,24 @@
 use std::cmp::Ordering;
+use std::convert::TryFrom;
 
 use serde::ser::{SerializeMap, Serializer};
 
 use crate::category::{Category, CategoryPairHandle};
 use crate::cpu_delta::CpuDelta;
+use crate::fast_hash_map::FastHashMap;
 use crate::frame_table::{FrameTable, InternalFrame};
 use crate::func_table::FuncTable;
-use crate::global_lib_table::GlobalLibTable;
+use crate::global_lib_table::{GlobalLibIndex, GlobalLibTable};
 use crate::marker_table::MarkerTable;
+use crate::native_allocation_table::NativeAllocationTable;
 use crate::native_symbols::NativeSymbols;
 use crate::resource_table::ResourceTable;
+use crate::sample_density::SampleDensity;
 use crate::sample_table::SampleTable;
 use crate::stack_table::StackTable;
 use crate::string_table::{GlobalStringIndex, GlobalStringTable};
 use crate::thread_string_table::{ThreadInternalStringIndex, ThreadStringTable};
-use crate::{MarkerTiming, ProfilerMarker, Timestamp};
+use crate::{MarkerTiming, ProfilerMarker, SamplingInterval, Timestamp};
 
 /// A process. Can be created with [`Profile::add_process`](crate::Profile::add_process).
 #[derive(Debug, Clone, Copy, PartialOrd, Ord, PartialEq, Eq, Hash)]
@@ -26,12 +30,15 @@ pub struct Thread {
     tid: u32,
     name: Option<String>,
     start_time: Timestamp,
+    start_time_known: bool,
     end_time: Option<Timestamp>,
+    truncated_stack_count: u64,
     is_main: bool,
     stack_table: StackTable,
     frame_table: FrameTable,
     func_table: FuncTable,
     samples: SampleTable,
+    native_allocations: NativeAllocationTable,
     markers: MarkerTable,
     resources: ResourceTable,
     native_symbols: NativeSymbols,
@@ -47,12 +54,15 @@ impl Thread {
             tid,
             name: None,
             start_time,
+            start_time_known: true,
             end_time: None,
+            truncated_stack_count: 0,
             is_main,
             stack_table: StackTable::new(),
             frame_table: FrameTable::new(),
             func_table: FuncTable::new(),
             samples: SampleTable::new(),
+            native_allocations: NativeAllocationTable::new(),
             markers: MarkerTable::new(),
             resources: ResourceTable::new(),
             native_symbols: NativeSymbols::new(),
@@ -69,10 +79,26 @@ impl Thread {
         self.start_time = start_time;
     }
 
+    pub fn set_start_time_known(&mut self, start_time_known: bool) {
+        self.start_time_known = start_time_known;
+    }
+
     pub fn set_end_time(&mut self, end_time: Timestamp) {
         self.end_time = Some(end_time);
     }
 
+    pub fn set_truncated_stack_count(&mut self, truncated_stack_count: u64) {
+        self.truncated_stack_count = truncated_stack_count;
+    }
+
+    pub fn truncated_stack_count(&self) -> u64 {
+        self.truncated_stack_count
+    }
+
+    pub fn tid(&self) -> u32 {
+        self.tid
+    }
+
     pub fn process(&self) -> ProcessHandle {
         self.process
     }
@@ -135,6 +161,123 @@ impl Thread {
         }
     }
 
+    pub fn add_native_allocation(
+        &mut self,
+        timestamp: Timestamp,
+        stack_index: Option<usize>,
+        size: i64,
+    ) {
+        self.native_allocations
+            .add_allocation(timestamp, stack_index, size);
+    }
+
+    /// Removes the samples, allocations and markers before `time`, and then the
+    /// stacks, frames, functions, native symbols and strings which only they
+    /// used.
+    pub fn discard_before(&mut self, time: Timestamp) {
+        self.samples.discard_before(time);
+        self.native_allocations.discard_before(time);
+        self.markers.discard_before(time);
+        if self.samples.is_empty() {
+            // There's no sample left to merge the next zero-CPU sample into.
+            self.last_sample_was_zero_cpu = false;
+        }
+        // Threads which ended before `time` keep their lifetime, just without
+        // samples.
+        let start_time = self.end_time.map_or(time, |end_time| end_time.min(time));
+        if self.start_time < start_time {
+            self.start_time = start_time;
+            self.start_time_known = false;
+        }
+        self.compact_tables();
+    }
+
+    /// Removes the rows of the interned tables which are no longer used by any
+    /// sample, allocation or marker. The remaining rows get new indexes, and
+    /// everything which refers to them is updated.
+    fn compact_tables(&mut self) {
+        let mut used_stacks = vec![false; self.stack_table.len()];
+        let used_stack_indexes = self
+            .samples
+            .stack_indexes()
+            .chain(self.native_allocations.stack_indexes())
+            .chain(self.last_sample_stack);
+        for stack_index in used_stack_indexes {
+            used_stacks[stack_index] = true;
+        }
+        let mut used_frames = vec![false; self.frame_table.len()];
+        self.stack_table
+            .mark_used(&mut used_stacks, &mut used_frames);
+        let mut used_funcs = vec![false; self.func_table.len()];
+        let mut used_native_symbols = vec![false; self.native_symbols.len()];
+        let mut used_strings = vec![false; self.string_table.len()];
+        self.frame_table.mark_used(
+            &used_frames,
+            &mut used_funcs,
+            &mut used_native_symbols,
+            &mut used_strings,
+        );
+        self.func_table.mark_used(&used_funcs, &mut used_strings);
+        self.native_symbols
+            .mark_used(&used_native_symbols, &mut used_strings);
+        self.resources.mark_used_strings(&mut used_strings);
+        self.markers.mark_used_strings(&mut used_strings);
+
+        let strings = self.string_table.compact(&used_strings);
+        self.resources.compact_strings(&strings);
+        self.markers.compact_strings(&strings);
+        let native_symbols = self.native_symbols.compact(&used_native_symbols, &strings);
+        let funcs = self.func_table.compact(&used_funcs, &strings);
+        let frames = self
+            .frame_table
+            .compact(&used_frames, &funcs, &native_symbols, &strings);
+        let stacks = self.stack_table.compact(&used_stacks, &frames);
+        self.samples.compact_stacks(&stacks);
+        self.native_allocations.compact_stacks(&stacks);
+        self.last_sample_stack = self
+            .last_sample_stack
+            .map(|stack_index| stacks[stack_index].unwrap());
+    }
+
+    /// Compares the number of samples to the number of samples expected from the
+    /// thread's lifetime. For threads which haven't ended, the lifetime ends at the
+    /// last sample.
+    pub fn sample_density(&self, interval: SamplingInterval) -> SampleDensity {
+        let sample_count = self.samples.sample_count();
+        let end_time = self
+            .end_time
+            .or_else(|| self.samples.last_sample_timestamp());
+        let expected_sample_count = match end_time {
+            Some(end_time) if interval.nanos() != 0 => {
+                let duration = end_time
+                    .nanos_since_reference()
+                    .saturating_sub(self.start_time.nanos_since_reference());
+                duration / interval.nanos()
+            }
+            _ => 0,
+        };
+        SampleDensity {
+            sample_count,
+            expected_sample_count,
+        }
+    }
+
+    /// Adds the weight of each sample to the entry for the library which
+    /// contains the sample's leaf frame, or to the `None` entry if the leaf
+    /// frame is not in any library or the sample has no stack.
+    pub fn accumulate_leaf_lib_sample_counts(
+        &self,
+        counts: &mut FastHashMap<Option<GlobalLibIndex>, u64>,
+    ) {
+        for (stack_index, weight) in self.samples.stacks_and_weights() {
+            let lib = stack_index.and_then(|stack_index| {
+                let frame_index = self.stack_table.frame_for_stack(stack_index);
+                self.frame_table.lib_for_frame(frame_index)
+            });
+            *counts.entry(lib).or_default() += u64::try_from(weight).unwrap_or(0);
+        }
+    }
+
     pub fn add_marker<T: ProfilerMarker>(&mut self, name: &str, marker: T, timing: MarkerTiming) {
         let name_string_index = self.string_table.index_for_string(name);
         self.markers
@@ -154,6 +297,7 @@ impl Thread {
         self.tid.cmp(&other.tid)
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn serialize_with<S: Serializer>(
         &self,
         serializer: S,
@@ -162,6 +306,7 @@ impl Thread {
         process_end_time: Option<Timestamp>,
         process_name: &str,
         pid: u32,
+        interval: SamplingInterval,
     ) -> Result<S::Ok, S::Error> {
         let thread_name = if self.is_main {
             // https://github.com/firefox-devtools/profiler/issues/2508
@@ -180,6 +325,9 @@ impl Thread {
         map.serialize_entry("funcTable", &self.func_table)?;
         map.serialize_entry("markers", &self.markers)?;
         map.serialize_entry("name", &thread_name)?;
+        if !self.native_allocations.is_empty() {
+            map.serialize_entry("nativeAllocations", &self.native_allocations)?;
+        }
         map.serialize_entry("nativeSymbols", &self.native_symbols)?;
         map.serialize_entry("pausedRanges", &[] as &[()])?;
         map.serialize_entry("pid", &pid)?;
@@ -189,11 +337,15 @@ impl Thread {
         map.serialize_entry("processType", &"default")?;
         map.serialize_entry("registerTime", &thread_register_time)?;
         map.serialize_entry("resourceTable", &self.resources)?;
+        map.serialize_entry("sampleDensity", &self.sample_density(interval))?;
         map.serialize_entry("samples", &self.samples)?;
         map.serialize_entry(
             "stackTable",
             &self.stack_table.serialize_with_categories(categories),
         )?;
+        if !self.start_time_known {
+            map.serialize_entry("startTimeKnown", &false)?;
+        }
         map.serialize_entry("stringArray", &self.string_table)?;
         map.serialize_entry("tid", &self.tid)?;
         map.serialize_entry("unregisterTime", &thread_unregister_time)?;
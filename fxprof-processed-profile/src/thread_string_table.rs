@@ -6,6 +6,18 @@ use crate::{fast_hash_map::FastHashMap, string_table::StringTable};
 #[derive(Debug, Clone, Copy, PartialOrd, Ord, PartialEq, Eq, Hash)]
 pub struct ThreadInternalStringIndex(pub StringIndex);
 
+impl ThreadInternalStringIndex {
+    pub fn index(self) -> usize {
+        self.0 .0 as usize
+    }
+
+    /// The index of this string after [`ThreadStringTable::compact`] returned
+    /// `strings`. The string must have been used.
+    pub fn compacted(self, strings: &[Option<usize>]) -> Self {
+        ThreadInternalStringIndex(StringIndex(strings[self.index()].unwrap() as u32))
+    }
+}
+
 impl Serialize for ThreadInternalStringIndex {
     fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
         self.0.serialize(serializer)
@@ -41,6 +53,25 @@ impl ThreadStringTable {
                 ThreadInternalStringIndex(table.index_for_string(s))
             })
     }
+
+    pub fn len(&self) -> usize {
+        self.table.len()
+    }
+
+    /// Removes the strings which are not `used`, and returns the new index of
+    /// each string. Strings which are interned again later get a new index.
+    pub fn compact(&mut self, used: &[bool]) -> Vec<Option<usize>> {
+        let strings = self.table.compact(used);
+        self.global_to_local_string
+            .retain(|_, local_index| match strings[local_index.index()] {
+                Some(index) => {
+                    *local_index = ThreadInternalStringIndex(StringIndex(index as u32));
+                    true
+                }
+                None => false,
+            });
+        strings
+    }
 }
 
 impl Serialize for ThreadStringTable {
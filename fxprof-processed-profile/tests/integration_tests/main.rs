@@ -5,7 +5,8 @@ use serde_json::json;
 use fxprof_processed_profile::{
     CategoryColor, CpuDelta, Frame, LibraryInfo, MarkerDynamicField, MarkerFieldFormat,
     MarkerLocation, MarkerSchema, MarkerSchemaField, MarkerStaticField, MarkerTiming, Profile,
-    ProfilerMarker, ReferenceTimestamp, SamplingInterval, Symbol, SymbolTable, Timestamp,
+    ProfilerMarker, ReferenceTimestamp, SampleDensity, SamplingInterval, Symbol, SymbolTable,
+    Timestamp,
 };
 
 use std::sync::Arc;
@@ -792,6 +793,12 @@ fn it_works() {
                         1
                       ]
                     },
+                    "sampleDensity": {
+                      "sampleCount": 4,
+                      "expectedSampleCount": 3,
+                      "coverage": 1.0,
+                      "undersampled": false
+                    },
                     "samples": {
                       "length": 4,
                       "stack": [
@@ -928,3 +935,515 @@ fn it_works() {
         )
     )
 }
+
+#[test]
+fn stable_category_colors() {
+    let mut profile_a = Profile::new(
+        "test",
+        ReferenceTimestamp::from_millis_since_unix_epoch(0.0),
+        SamplingInterval::from_millis(1),
+    );
+    let mut profile_b = Profile::new(
+        "test",
+        ReferenceTimestamp::from_millis_since_unix_epoch(0.0),
+        SamplingInterval::from_millis(1),
+    );
+    let libxul_a = profile_a.add_category_with_stable_color("libxul.so");
+    profile_a.add_category_with_stable_color("libc.so.6");
+    profile_b.add_category_with_stable_color("libc.so.6");
+    profile_b.add_category_with_stable_color("libxul.so");
+
+    assert_eq!(
+        profile_a.add_category_with_stable_color("libxul.so"),
+        libxul_a
+    );
+    assert_eq!(profile_a.category_colors(), profile_b.category_colors());
+    assert_eq!(
+        profile_a.category_colors(),
+        vec![
+            ("Other", CategoryColor::Grey),
+            ("libc.so.6", CategoryColor::for_name("libc.so.6")),
+            ("libxul.so", CategoryColor::for_name("libxul.so")),
+        ]
+    );
+}
+
+#[test]
+fn truncated_stack_counts() {
+    let mut profile = Profile::new(
+        "test",
+        ReferenceTimestamp::from_millis_since_unix_epoch(0.0),
+        SamplingInterval::from_millis(1),
+    );
+    let process = profile.add_process("test", 123, Timestamp::from_millis_since_reference(0.0));
+    let main_thread = profile.add_thread(
+        process,
+        12345,
+        Timestamp::from_millis_since_reference(0.0),
+        true,
+    );
+    let other_thread = profile.add_thread(
+        process,
+        12346,
+        Timestamp::from_millis_since_reference(0.0),
+        false,
+    );
+    profile.set_thread_truncated_stack_count(other_thread, 3);
+
+    assert_eq!(profile.thread_truncated_stack_count(main_thread), 0);
+    assert_eq!(profile.thread_truncated_stack_count(other_thread), 3);
+    let json = serde_json::to_value(&profile).unwrap();
+    assert_eq!(
+        json["meta"]["truncatedStackCounts"],
+        serde_json::json!([{ "pid": 123, "tid": 12346, "count": 3 }])
+    );
+}
+
+#[test]
+fn thread_sample_density() {
+    let mut profile = Profile::new(
+        "test",
+        ReferenceTimestamp::from_millis_since_unix_epoch(0.0),
+        SamplingInterval::from_millis(1),
+    );
+    let process = profile.add_process("test", 123, Timestamp::from_millis_since_reference(0.0));
+    let thread = profile.add_thread(
+        process,
+        12345,
+        Timestamp::from_millis_since_reference(0.0),
+        true,
+    );
+    for time in [0.0, 1.0, 10.0] {
+        profile.add_sample(
+            thread,
+            Timestamp::from_millis_since_reference(time),
+            vec![].into_iter(),
+            CpuDelta::ZERO,
+            1,
+        );
+    }
+    profile.set_thread_end_time(thread, Timestamp::from_millis_since_reference(20.0));
+
+    let density = profile.thread_sample_density(thread);
+    assert_eq!(
+        density,
+        SampleDensity {
+            sample_count: 3,
+            expected_sample_count: 20,
+        }
+    );
+    assert_eq!(density.coverage(), 0.15);
+    assert!(density.is_undersampled());
+    let json = serde_json::to_value(&profile).unwrap();
+    assert_eq!(
+        json["threads"][0]["sampleDensity"],
+        serde_json::json!({
+            "sampleCount": 3,
+            "expectedSampleCount": 20,
+            "coverage": 0.15,
+            "undersampled": true,
+        })
+    );
+}
+
+#[test]
+fn top_modules() {
+    let mut profile = Profile::new(
+        "test",
+        ReferenceTimestamp::from_millis_since_unix_epoch(0.0),
+        SamplingInterval::from_millis(1),
+    );
+    let process = profile.add_process("test", 123, Timestamp::from_millis_since_reference(0.0));
+    let thread = profile.add_thread(
+        process,
+        12345,
+        Timestamp::from_millis_since_reference(0.0),
+        true,
+    );
+    for (name, base) in [("libc.so.6", 0x1000), ("libxul.so", 0x10000)] {
+        profile.add_lib(
+            process,
+            LibraryInfo {
+                name: name.to_string(),
+                debug_name: name.to_string(),
+                path: format!("/usr/lib/{}", name),
+                code_id: None,
+                debug_path: format!("/usr/lib/{}", name),
+                debug_id: DebugId::nil(),
+                arch: None,
+                base_avma: base,
+                avma_range: base..(base + 0x1000),
+                symbol_table: None,
+            },
+        );
+    }
+    let category = profile.add_category("Regular", CategoryColor::Blue).into();
+    let label = profile.intern_string("label");
+    let samples: Vec<(Vec<Frame>, i32)> = vec![
+        // The leaf frame decides the module, not the root frame.
+        (
+            vec![
+                Frame::InstructionPointer(0x10010),
+                Frame::InstructionPointer(0x1010),
+            ],
+            3,
+        ),
+        (vec![Frame::InstructionPointer(0x10020)], 1),
+        (vec![Frame::ReturnAddress(0x10030)], 1),
+        (vec![Frame::InstructionPointer(0x50000)], 1),
+        (
+            vec![Frame::InstructionPointer(0x1010), Frame::Label(label)],
+            1,
+        ),
+        (vec![], 1),
+    ];
+    for (i, (frames, weight)) in samples.into_iter().enumerate() {
+        profile.add_sample(
+            thread,
+            Timestamp::from_millis_since_reference(i as f64),
+            frames.into_iter().map(|frame| (frame, category)),
+            CpuDelta::ZERO,
+            weight,
+        );
+    }
+
+    assert_eq!(
+        profile.top_modules(10),
+        vec![
+            ("libc.so.6".to_string(), 3),
+            (Profile::UNKNOWN_MODULE_NAME.to_string(), 3),
+            ("libxul.so".to_string(), 2),
+        ]
+    );
+    assert_eq!(profile.top_modules(1), vec![("libc.so.6".to_string(), 3)]);
+    assert_eq!(profile.top_modules(0), vec![]);
+}
+
+#[test]
+fn lib_unloaded_and_replaced_at_same_base() {
+    let mut profile = Profile::new(
+        "test",
+        ReferenceTimestamp::from_millis_since_unix_epoch(0.0),
+        SamplingInterval::from_millis(1),
+    );
+    let process = profile.add_process("test", 123, Timestamp::from_millis_since_reference(0.0));
+    let thread = profile.add_thread(
+        process,
+        12345,
+        Timestamp::from_millis_since_reference(0.0),
+        true,
+    );
+    let category = profile.add_category("Regular", CategoryColor::Blue).into();
+    let lib_info = |name: &str| LibraryInfo {
+        name: name.to_string(),
+        debug_name: name.to_string(),
+        path: format!("/usr/lib/{}", name),
+        code_id: None,
+        debug_path: format!("/usr/lib/{}", name),
+        debug_id: DebugId::nil(),
+        arch: None,
+        base_avma: 0x1000,
+        avma_range: 0x1000..0x2000,
+        symbol_table: None,
+    };
+    let add_sample = |profile: &mut Profile, time: f64, weight: i32| {
+        profile.add_sample(
+            thread,
+            Timestamp::from_millis_since_reference(time),
+            vec![(Frame::InstructionPointer(0x1010), category)].into_iter(),
+            CpuDelta::ZERO,
+            weight,
+        );
+    };
+
+    // The same address belongs to a different library before and after the
+    // reload. Samples resolve against the libraries mapped at their time.
+    profile.add_lib(process, lib_info("liba.so"));
+    add_sample(&mut profile, 0.0, 2);
+    profile.unload_lib(process, 0x1000);
+    add_sample(&mut profile, 1.0, 4);
+    profile.add_lib(process, lib_info("libb.so"));
+    add_sample(&mut profile, 2.0, 1);
+
+    assert_eq!(
+        profile.top_modules(10),
+        vec![
+            (Profile::UNKNOWN_MODULE_NAME.to_string(), 4),
+            ("liba.so".to_string(), 2),
+            ("libb.so".to_string(), 1),
+        ]
+    );
+    let json = serde_json::to_value(&profile).unwrap();
+    let libs: Vec<_> = json["libs"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|lib| lib["name"].as_str().unwrap())
+        .collect();
+    assert_eq!(libs, vec!["liba.so", "libb.so"]);
+}
+
+#[test]
+fn throttled_interval() {
+    let mut profile = Profile::new(
+        "test",
+        ReferenceTimestamp::from_millis_since_unix_epoch(0.0),
+        SamplingInterval::from_millis(1),
+    );
+    let json = serde_json::to_value(&profile).unwrap();
+    assert!(json["meta"].get("throttledInterval").is_none());
+
+    profile.set_throttled_interval(SamplingInterval::from_millis(4));
+    let json = serde_json::to_value(&profile).unwrap();
+    assert_eq!(json["meta"]["interval"], 1.0);
+    assert_eq!(json["meta"]["throttledInterval"], 4.0);
+}
+
+#[test]
+fn truncated() {
+    let mut profile = Profile::new(
+        "test",
+        ReferenceTimestamp::from_millis_since_unix_epoch(0.0),
+        SamplingInterval::from_millis(1),
+    );
+    let json = serde_json::to_value(&profile).unwrap();
+    assert!(json["meta"].get("truncated").is_none());
+
+    profile.set_truncated(true);
+    let json = serde_json::to_value(&profile).unwrap();
+    assert_eq!(json["meta"]["truncated"], true);
+}
+
+#[test]
+fn system_info() {
+    let mut profile = Profile::new(
+        "test",
+        ReferenceTimestamp::from_millis_since_unix_epoch(0.0),
+        SamplingInterval::from_millis(1),
+    );
+    let json = serde_json::to_value(&profile).unwrap();
+    for key in [
+        "oscpu",
+        "platform",
+        "CPUName",
+        "physicalCPUs",
+        "logicalCPUs",
+        "misc",
+    ] {
+        assert!(json["meta"].get(key).is_none());
+    }
+
+    profile.set_os_name("macOS 14.1");
+    profile.set_platform("Macintosh");
+    profile.set_cpu_name("Apple M1 Pro");
+    profile.set_cpu_counts(8, 10);
+    profile.set_misc("samply 0.11.0");
+    let json = serde_json::to_value(&profile).unwrap();
+    assert_eq!(json["meta"]["oscpu"], "macOS 14.1");
+    assert_eq!(json["meta"]["platform"], "Macintosh");
+    assert_eq!(json["meta"]["CPUName"], "Apple M1 Pro");
+    assert_eq!(json["meta"]["physicalCPUs"], 8);
+    assert_eq!(json["meta"]["logicalCPUs"], 10);
+    assert_eq!(json["meta"]["misc"], "samply 0.11.0");
+}
+
+#[test]
+fn allocation_samples() {
+    let mut profile = Profile::new(
+        "test",
+        ReferenceTimestamp::from_millis_since_unix_epoch(0.0),
+        SamplingInterval::from_millis(1),
+    );
+    let process = profile.add_process("test", 123, Timestamp::from_millis_since_reference(0.0));
+    let thread = profile.add_thread(
+        process,
+        12345,
+        Timestamp::from_millis_since_reference(0.0),
+        true,
+    );
+    let json = serde_json::to_value(&profile).unwrap();
+    assert!(json["threads"][0].get("nativeAllocations").is_none());
+
+    let category = profile.add_category("Regular", CategoryColor::Blue);
+    let malloc = profile.intern_string("malloc");
+    profile.add_allocation_sample(
+        thread,
+        Timestamp::from_millis_since_reference(1.0),
+        vec![(Frame::Label(malloc), category.into())].into_iter(),
+        4096,
+    );
+    profile.add_allocation_sample(
+        thread,
+        Timestamp::from_millis_since_reference(2.0),
+        vec![].into_iter(),
+        -4096,
+    );
+    let json = serde_json::to_value(&profile).unwrap();
+    assert_json_eq!(
+        json["threads"][0]["nativeAllocations"],
+        json!({
+            "length": 2,
+            "stack": [0, null],
+            "time": [1.0, 2.0],
+            "weight": [4096, -4096],
+            "weightType": "bytes"
+        })
+    );
+    assert!(json["threads"][0]["samples"]["stack"]
+        .as_array()
+        .unwrap()
+        .is_empty());
+}
+
+#[test]
+fn discard_before() {
+    let mut profile = Profile::new(
+        "test",
+        ReferenceTimestamp::from_millis_since_unix_epoch(0.0),
+        SamplingInterval::from_millis(1),
+    );
+    let process = profile.add_process("test", 123, Timestamp::from_millis_since_reference(0.0));
+    let thread = profile.add_thread(
+        process,
+        12345,
+        Timestamp::from_millis_since_reference(0.0),
+        true,
+    );
+    let category = profile.add_category("Regular", CategoryColor::Blue).into();
+    let work = profile.intern_string("work");
+    let wait = profile.intern_string("wait");
+    for (time, label) in [(0.0, work), (1.0, wait), (2.0, work)] {
+        profile.add_sample(
+            thread,
+            Timestamp::from_millis_since_reference(time),
+            vec![(Frame::Label(label), category)].into_iter(),
+            CpuDelta::from_millis(1.0),
+            1,
+        );
+    }
+    profile.add_sample_same_stack_zero_cpu(thread, Timestamp::from_millis_since_reference(3.0), 1);
+    let marker = |text: &str| TextMarker(text.to_string());
+    let ms = Timestamp::from_millis_since_reference;
+    profile.add_marker(
+        thread,
+        "Early",
+        marker("early"),
+        MarkerTiming::Instant(ms(0.5)),
+    );
+    profile.add_marker(
+        thread,
+        "Overlapping",
+        marker("overlapping"),
+        MarkerTiming::Interval(ms(0.5), ms(2.5)),
+    );
+    profile.add_marker(
+        thread,
+        "Open",
+        marker("open"),
+        MarkerTiming::IntervalStart(ms(0.5)),
+    );
+
+    profile.discard_before(ms(2.0));
+
+    // The "wait" stack is no longer used, so only the "work" stack is left.
+    let json = serde_json::to_value(&profile).unwrap();
+    let thread_json = &json["threads"][0];
+    assert_eq!(thread_json["samples"]["time"], json!([2.0, 3.0]));
+    assert_eq!(thread_json["samples"]["stack"], json!([0, 0]));
+    assert_eq!(thread_json["samples"]["weight"], json!([1, 1]));
+    assert_eq!(thread_json["stackTable"]["length"], 1);
+    assert_eq!(thread_json["frameTable"]["length"], 1);
+    assert_eq!(thread_json["funcTable"]["name"], json!([0]));
+    assert_eq!(
+        thread_json["stringArray"],
+        json!(["work", "Overlapping", "Open"])
+    );
+    assert_eq!(thread_json["markers"]["name"], json!([1, 2]));
+    assert_eq!(thread_json["markers"]["startTime"], json!([0.5, 0.5]));
+    assert_eq!(thread_json["markers"]["phase"], json!([1, 2]));
+    assert_eq!(thread_json["registerTime"], 2.0);
+    assert_eq!(thread_json["startTimeKnown"], false);
+
+    // Zero-CPU samples after the discarded range still merge with the last
+    // remaining sample, and samples can be added after everything was
+    // discarded.
+    profile.add_sample_same_stack_zero_cpu(thread, ms(4.0), 1);
+    profile.discard_before(ms(10.0));
+    profile.add_sample_same_stack_zero_cpu(thread, ms(11.0), 1);
+    let json = serde_json::to_value(&profile).unwrap();
+    assert_eq!(json["threads"][0]["samples"]["time"], json!([11.0]));
+    assert_eq!(json["threads"][0]["samples"]["stack"], json!([0]));
+}
+
+#[test]
+fn discard_before_keeps_memory_bounded() {
+    let mut profile = Profile::new(
+        "test",
+        ReferenceTimestamp::from_millis_since_unix_epoch(0.0),
+        SamplingInterval::from_millis(1),
+    );
+    let process = profile.add_process("test", 123, Timestamp::from_millis_since_reference(0.0));
+    let thread = profile.add_thread(
+        process,
+        12345,
+        Timestamp::from_millis_since_reference(0.0),
+        true,
+    );
+    let category = profile.add_category("Regular", CategoryColor::Blue).into();
+    let ms = Timestamp::from_millis_since_reference;
+
+    // Every millisecond has a new stack, and every 10 milliseconds have a
+    // paired interval marker. Only the last 100 milliseconds are kept.
+    let table_lengths = |profile: &Profile| {
+        let json = serde_json::to_value(profile).unwrap();
+        let thread_json = &json["threads"][0];
+        [
+            thread_json["samples"]["length"].as_u64().unwrap(),
+            thread_json["stackTable"]["length"].as_u64().unwrap(),
+            thread_json["frameTable"]["length"].as_u64().unwrap(),
+            thread_json["funcTable"]["length"].as_u64().unwrap(),
+            thread_json["markers"]["length"].as_u64().unwrap(),
+            thread_json["stringArray"].as_array().unwrap().len() as u64,
+        ]
+    };
+    let mut lengths_after_each_round = Vec::new();
+    for round in 0..5 {
+        for i in 0..1000 {
+            let time = (round * 1000 + i) as f64;
+            let address = 0x1000 + (round * 1000 + i) as u64;
+            profile.add_sample(
+                thread,
+                ms(time),
+                vec![
+                    (Frame::InstructionPointer(0x10), category),
+                    (Frame::InstructionPointer(address), category),
+                ]
+                .into_iter(),
+                CpuDelta::from_millis(1.0),
+                1,
+            );
+            if i % 10 == 0 {
+                profile.add_marker(
+                    thread,
+                    "Work",
+                    TextMarker("work".to_string()),
+                    MarkerTiming::IntervalStart(ms(time)),
+                );
+                profile.add_marker(
+                    thread,
+                    "Work",
+                    TextMarker("work".to_string()),
+                    MarkerTiming::IntervalEnd(ms(time + 5.0)),
+                );
+            }
+            if i % 100 == 99 {
+                profile.discard_before(ms(time - 99.0));
+            }
+        }
+        lengths_after_each_round.push(table_lengths(&profile));
+    }
+    assert_eq!(lengths_after_each_round[0], [100, 101, 101, 101, 20, 102]);
+    assert!(lengths_after_each_round
+        .iter()
+        .all(|lengths| *lengths == lengths_after_each_round[0]));
+}
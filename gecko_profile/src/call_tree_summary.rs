@@ -0,0 +1,178 @@
+//! Per-function self and total times, as a text-friendly summary of the call
+//! tree, e.g. for performance checks in CI.
+
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::fmt;
+
+use crate::{FrameNameMapper, ProfileBuilder, ThreadBuilder};
+
+/// The result of [`ProfileBuilder::self_and_total_times`].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SelfAndTotalTimes {
+    /// The total weight of all samples, including the samples without a stack.
+    /// This is what the fractions are relative to.
+    pub sample_weight: u64,
+    /// One entry per function, sorted by descending self weight, then by
+    /// descending total weight, then by name.
+    pub functions: Vec<FunctionTimes>,
+}
+
+/// An entry in [`SelfAndTotalTimes::functions`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionTimes {
+    /// The function name, resolved like the frame names in the Gecko profile JSON.
+    pub name: String,
+    /// The weight of the samples in which this function is the leaf frame.
+    pub self_weight: u64,
+    /// The weight of the samples in which this function is on the stack, counted
+    /// once per sample even if the function appears multiple times in the stack.
+    pub total_weight: u64,
+    /// `self_weight` as a fraction of [`SelfAndTotalTimes::sample_weight`],
+    /// between 0.0 and 1.0.
+    pub self_fraction: f64,
+    /// `total_weight` as a fraction of [`SelfAndTotalTimes::sample_weight`],
+    /// between 0.0 and 1.0.
+    pub total_fraction: f64,
+}
+
+/// One line per function, e.g. `work: 42.0% self, 78.0% total`.
+impl fmt::Display for SelfAndTotalTimes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for function in &self.functions {
+            writeln!(
+                f,
+                "{}: {:.1}% self, {:.1}% total",
+                function.name,
+                function.self_fraction * 100.0,
+                function.total_fraction * 100.0
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl ProfileBuilder {
+    /// Aggregates the samples of all threads, including those of subprocesses,
+    /// into the self and total weight of every function.
+    ///
+    /// Frames are grouped by their name, which is resolved like in the Gecko
+    /// profile JSON: address frames in libraries with
+    /// [supplied symbols](ProfileBuilder::add_jit_lib) get the symbol's name, and
+    /// the [frame name mapper](ProfileBuilder::set_frame_name_mapper) is applied.
+    /// So all addresses inside a function, as well as label frames for inlined
+    /// calls which carry the function's name, count as that function. Addresses
+    /// without a symbol are listed separately, under their hex address.
+    pub fn self_and_total_times(&self) -> SelfAndTotalTimes {
+        let mut weights = HashMap::new();
+        let mut sample_weight = 0;
+        add_process(&mut weights, &mut sample_weight, self, None);
+
+        let mut functions: Vec<FunctionTimes> = weights
+            .into_iter()
+            .map(|(name, (self_weight, total_weight))| FunctionTimes {
+                name,
+                self_weight,
+                total_weight,
+                self_fraction: fraction(self_weight, sample_weight),
+                total_fraction: fraction(total_weight, sample_weight),
+            })
+            .collect();
+        functions.sort_by(|a, b| {
+            b.self_weight
+                .cmp(&a.self_weight)
+                .then(b.total_weight.cmp(&a.total_weight))
+                .then_with(|| a.name.cmp(&b.name))
+        });
+        SelfAndTotalTimes {
+            sample_weight,
+            functions,
+        }
+    }
+}
+
+fn fraction(weight: u64, sample_weight: u64) -> f64 {
+    if sample_weight == 0 {
+        return 0.0;
+    }
+    weight as f64 / sample_weight as f64
+}
+
+fn add_process(
+    weights: &mut HashMap<String, (u64, u64)>,
+    sample_weight: &mut u64,
+    process: &ProfileBuilder,
+    inherited_mapper: Option<&FrameNameMapper>,
+) {
+    let frame_name_mapper = process.frame_name_mapper.as_ref().or(inherited_mapper);
+    for thread in process.threads.values() {
+        add_thread(weights, sample_weight, process, thread, frame_name_mapper);
+    }
+    for subprocess in &process.subprocesses {
+        add_process(weights, sample_weight, subprocess, frame_name_mapper);
+    }
+}
+
+fn add_thread(
+    weights: &mut HashMap<String, (u64, u64)>,
+    sample_weight: &mut u64,
+    process: &ProfileBuilder,
+    thread: &ThreadBuilder,
+    frame_name_mapper: Option<&FrameNameMapper>,
+) {
+    let frame_table = &thread.frame_table;
+    let strings = &thread.string_table.strings;
+
+    // Frames with the same name share a function index.
+    let mut function_indexes: HashMap<Cow<str>, usize> = HashMap::new();
+    let frame_functions: Vec<usize> = (0..frame_table.frames.len())
+        .map(|frame_index| {
+            let name = &strings[frame_table.frames[frame_index].0 as usize];
+            let lib_symbol_name = frame_table.addresses[frame_index]
+                .and_then(|address| process.libs.iter().find_map(|lib| lib.symbol_name(address)));
+            let is_address_name =
+                frame_table.addresses[frame_index].is_some() && lib_symbol_name.is_none();
+            let name = lib_symbol_name.unwrap_or(name);
+            let name = match frame_name_mapper {
+                Some(mapper) if !is_address_name => mapper.map(name),
+                _ => Cow::Borrowed(name),
+            };
+            let function_count = function_indexes.len();
+            *function_indexes.entry(name).or_insert(function_count)
+        })
+        .collect();
+
+    let mut function_weights = vec![(0, 0); function_indexes.len()];
+    let mut functions_in_stack = HashSet::new();
+    for sample in &thread.samples.0 {
+        let weight = u64::try_from(sample.weight).unwrap_or(0);
+        *sample_weight += weight;
+
+        let mut stack_index = sample.stack_index;
+        let mut is_leaf = true;
+        while let Some(index) = stack_index {
+            let (prefix, frame_index) = thread.stack_table.stacks[index];
+            let function = frame_functions[frame_index];
+            if is_leaf {
+                function_weights[function].0 += weight;
+                is_leaf = false;
+            }
+            if functions_in_stack.insert(function) {
+                function_weights[function].1 += weight;
+            }
+            stack_index = prefix;
+        }
+        functions_in_stack.clear();
+    }
+
+    for (name, function) in function_indexes {
+        let (self_weight, total_weight) = function_weights[function];
+        if total_weight == 0 {
+            continue;
+        }
+        let entry = weights.entry(name.into_owned()).or_insert((0, 0));
+        entry.0 += self_weight;
+        entry.1 += total_weight;
+    }
+}
@@ -0,0 +1,101 @@
+use serde_json::{json, Value};
+use std::path::Path;
+
+/// The category of a frame. The Firefox profiler colors the stack chart and the
+/// activity graph by category, and the call tree shows it for every function.
+///
+/// Address frames get the category of the library they belong to, see
+/// [`ProfileBuilder::add_lib`](crate::ProfileBuilder::add_lib). Any frame's
+/// category can be set with
+/// [`ThreadBuilder::set_frame_category`](crate::ThreadBuilder::set_frame_category).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FrameCategory {
+    /// The default category, for frames which aren't classified otherwise.
+    Regular,
+    Other,
+    /// Native code of the profiled application.
+    Native,
+    /// Native code in system libraries.
+    System,
+    JavaScript,
+    /// Garbage collection.
+    GC,
+}
+
+impl FrameCategory {
+    /// All categories, in the order of `meta.categories`.
+    const ALL: [FrameCategory; 6] = [
+        FrameCategory::Regular,
+        FrameCategory::Other,
+        FrameCategory::Native,
+        FrameCategory::System,
+        FrameCategory::JavaScript,
+        FrameCategory::GC,
+    ];
+
+    /// The default category of the code in the library at `path`: `System` for
+    /// libraries in the operating system's library directories, `Native` for
+    /// everything else.
+    pub fn for_lib_path(path: &Path) -> Self {
+        const SYSTEM_DIRS: &[&str] = &[
+            "/System",
+            "/usr/lib",
+            "/usr/lib64",
+            "/lib",
+            "/lib64",
+            "/usr/libexec",
+        ];
+        let is_windows_system_lib = path
+            .to_string_lossy()
+            .to_ascii_lowercase()
+            .contains(":\\windows\\");
+        if is_windows_system_lib || SYSTEM_DIRS.iter().any(|dir| path.starts_with(dir)) {
+            FrameCategory::System
+        } else {
+            FrameCategory::Native
+        }
+    }
+
+    /// The index of this category in `meta.categories`.
+    pub(crate) fn index(self) -> usize {
+        Self::ALL.iter().position(|c| *c == self).unwrap()
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            FrameCategory::Regular => "Regular",
+            FrameCategory::Other => "Other",
+            FrameCategory::Native => "Native",
+            FrameCategory::System => "System",
+            FrameCategory::JavaScript => "JavaScript",
+            FrameCategory::GC => "GC / CC",
+        }
+    }
+
+    fn color(self) -> &'static str {
+        match self {
+            FrameCategory::Regular => "blue",
+            FrameCategory::Other => "grey",
+            FrameCategory::Native => "lightblue",
+            FrameCategory::System => "purple",
+            FrameCategory::JavaScript => "yellow",
+            FrameCategory::GC => "orange",
+        }
+    }
+
+    /// The `meta.categories` list of the profile.
+    pub(crate) fn serializable_list() -> Value {
+        Value::Array(
+            Self::ALL
+                .iter()
+                .map(|category| {
+                    json!({
+                        "name": category.name(),
+                        "color": category.color(),
+                        "subcategories": ["Other"],
+                    })
+                })
+                .collect(),
+        )
+    }
+}
@@ -0,0 +1,168 @@
+//! Conversion into Chrome's [trace event format].
+//!
+//! [trace event format]: https://docs.google.com/document/d/1CvAClvFfyA5R-PhYUmn5OOQtYMH4h6I0nSsKchNAySU
+
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::time::Instant;
+
+use crate::{to_profile_timestamp, FrameNameMapper, ProfileBuilder, ThreadBuilder};
+
+impl ProfileBuilder {
+    /// Converts the profile, including its subprocesses, into Chrome's trace
+    /// event JSON format, which can be loaded by `chrome://tracing`, Perfetto
+    /// and other trace event consumers.
+    ///
+    /// Every process and thread gets a `process_name` / `thread_name` metadata
+    /// event (`"ph": "M"`), and every sample becomes a sample event
+    /// (`"ph": "P"`) whose `sf` refers to its leaf node in the `stackFrames`
+    /// map. Stack frame nodes are chained to their caller through `parent`, and
+    /// are shared between all threads. Timestamps are in microseconds since the
+    /// start of the profile. The category of an address frame is the name of
+    /// its library, and the category of a label frame is the process name.
+    pub fn to_chrome_trace(&self) -> Value {
+        let mut builder = ChromeTraceBuilder::default();
+        builder.add_process(self, None, self.start_time);
+        json!({
+            "traceEvents": builder.events,
+            "stackFrames": builder.stack_frames,
+        })
+    }
+}
+
+#[derive(Default)]
+struct ChromeTraceBuilder {
+    events: Vec<Value>,
+    stack_frames: Map<String, Value>,
+    /// (parent id, name, category) -> id
+    stack_frame_ids: HashMap<(Option<usize>, String, String), usize>,
+}
+
+impl ChromeTraceBuilder {
+    fn stack_frame(&mut self, parent: Option<usize>, name: String, category: String) -> usize {
+        let stack_frames = &mut self.stack_frames;
+        *self
+            .stack_frame_ids
+            .entry((parent, name, category))
+            .or_insert_with_key(|(parent, name, category)| {
+                let id = stack_frames.len() + 1;
+                let mut stack_frame = json!({ "name": name, "category": category });
+                if let Some(parent) = parent {
+                    stack_frame["parent"] = parent.to_string().into();
+                }
+                stack_frames.insert(id.to_string(), stack_frame);
+                id
+            })
+    }
+
+    fn add_process(
+        &mut self,
+        process: &ProfileBuilder,
+        inherited_mapper: Option<&FrameNameMapper>,
+        profile_start: Instant,
+    ) {
+        let frame_name_mapper = process.frame_name_mapper.as_ref().or(inherited_mapper);
+
+        self.events.push(json!({
+            "ph": "M",
+            "name": "process_name",
+            "pid": process.pid,
+            "args": { "name": process.command_name },
+        }));
+
+        // (start, end, name)
+        let lib_ranges: Vec<(u64, u64, String)> = process
+            .libs
+            .iter()
+            .map(|lib| {
+                let name = match lib.path.file_name() {
+                    Some(name) => name.to_string_lossy().into_owned(),
+                    None => lib.path.to_string_lossy().into_owned(),
+                };
+                (lib.start_address, lib.end_address, name)
+            })
+            .collect();
+
+        let mut threads: Vec<_> = process.threads.values().collect();
+        threads.sort_by_key(|thread| thread.index);
+        for thread in threads {
+            self.add_thread(
+                thread,
+                &process.command_name,
+                &lib_ranges,
+                frame_name_mapper,
+                profile_start,
+            );
+        }
+
+        for subprocess in &process.subprocesses {
+            self.add_process(subprocess, frame_name_mapper, profile_start);
+        }
+    }
+
+    fn add_thread(
+        &mut self,
+        thread: &ThreadBuilder,
+        process_name: &str,
+        lib_ranges: &[(u64, u64, String)],
+        frame_name_mapper: Option<&FrameNameMapper>,
+        profile_start: Instant,
+    ) {
+        let thread_name = match &thread.name {
+            Some(name) => name.clone(),
+            None => format!("Thread <{}>", thread.index),
+        };
+        self.events.push(json!({
+            "ph": "M",
+            "name": "thread_name",
+            "pid": thread.pid,
+            "tid": thread.index,
+            "args": { "name": thread_name },
+        }));
+
+        let frame_table = &thread.frame_table;
+        let strings = &thread.string_table.strings;
+
+        // The stack table is ordered so that a stack's prefix comes before the
+        // stack itself, so the parent's id is always known.
+        let mut stack_frame_ids: Vec<usize> = Vec::with_capacity(thread.stack_table.stacks.len());
+        for (prefix, frame_index) in &thread.stack_table.stacks {
+            let name = &strings[frame_table.frames[*frame_index].0 as usize];
+            let (name, category) = match frame_table.addresses[*frame_index] {
+                Some(address) => {
+                    let lib_name = lib_ranges
+                        .iter()
+                        .find(|(start, end, _)| *start <= address && address < *end)
+                        .map_or(process_name, |(_, _, name)| name);
+                    (name.clone(), lib_name.to_owned())
+                }
+                None => {
+                    let name = match frame_name_mapper {
+                        Some(mapper) => mapper.map(name).into_owned(),
+                        None => name.clone(),
+                    };
+                    (name, process_name.to_owned())
+                }
+            };
+            let parent = prefix.map(|prefix| stack_frame_ids[prefix]);
+            stack_frame_ids.push(self.stack_frame(parent, name, category));
+        }
+
+        for sample in &thread.samples.0 {
+            // Sample events need a stack; samples without one carry no information.
+            let stack_index = match sample.stack_index {
+                Some(stack_index) => stack_index,
+                None => continue,
+            };
+            let ts = to_profile_timestamp(sample.timestamp, profile_start) * 1000.0;
+            self.events.push(json!({
+                "ph": "P",
+                "name": "sample",
+                "pid": thread.pid,
+                "tid": thread.index,
+                "ts": ts,
+                "sf": stack_frame_ids[stack_index].to_string(),
+            }));
+        }
+    }
+}
@@ -0,0 +1,119 @@
+use serde::ser::{Serialize, SerializeMap, SerializeSeq, Serializer};
+use serde_json::json;
+use std::time::Instant;
+
+/// A counter track, returned from [`ProfileBuilder::add_counter`](crate::ProfileBuilder::add_counter).
+#[derive(Debug, Clone, Copy, PartialOrd, Ord, PartialEq, Eq, Hash)]
+pub struct CounterHandle(pub(crate) usize);
+
+#[derive(Debug, Clone)]
+pub(crate) struct Counter {
+    name: String,
+    category: String,
+    samples: Vec<(Instant, f64)>,
+}
+
+impl Counter {
+    pub fn new(name: &str, category: &str) -> Self {
+        Counter {
+            name: name.to_owned(),
+            category: category.to_owned(),
+            samples: Vec::new(),
+        }
+    }
+
+    pub fn add_sample(&mut self, timestamp: Instant, value: f64) {
+        self.samples.push((timestamp, value));
+    }
+
+    pub fn has_same_name(&self, other: &Counter) -> bool {
+        self.name == other.name && self.category == other.category
+    }
+
+    /// Appends the samples of `other`, which must be on the same timeline.
+    pub fn merge(&mut self, other: Counter) {
+        self.samples.extend(other.samples);
+    }
+
+    pub fn earliest_time(&self) -> Option<Instant> {
+        self.samples.iter().map(|(timestamp, _)| *timestamp).min()
+    }
+
+    pub fn map_times(&mut self, f: &impl Fn(Instant) -> Instant) {
+        for (timestamp, _) in &mut self.samples {
+            *timestamp = f(*timestamp);
+        }
+    }
+
+    pub fn to_serializable(&self, process_start: Instant) -> SerializableCounter<'_> {
+        SerializableCounter {
+            counter: self,
+            process_start,
+        }
+    }
+}
+
+pub(crate) struct SerializableCounter<'a> {
+    counter: &'a Counter,
+    process_start: Instant,
+}
+
+impl<'a> Serialize for SerializableCounter<'a> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+        map.serialize_entry("name", &self.counter.name)?;
+        map.serialize_entry("category", &self.counter.category)?;
+        map.serialize_entry("description", &self.counter.name)?;
+        map.serialize_entry("sample_groups", &[SerializableCounterSampleGroup(self)])?;
+        map.end()
+    }
+}
+
+struct SerializableCounterSampleGroup<'a, 'b>(&'b SerializableCounter<'a>);
+
+impl<'a, 'b> Serialize for SerializableCounterSampleGroup<'a, 'b> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+        map.serialize_entry("id", &0)?;
+        map.serialize_entry("samples", &SerializableCounterSampleTable(self.0))?;
+        map.end()
+    }
+}
+
+struct SerializableCounterSampleTable<'a, 'b>(&'b SerializableCounter<'a>);
+
+impl<'a, 'b> Serialize for SerializableCounterSampleTable<'a, 'b> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let schema = json!({
+            "time": 0,
+            "number": 1,
+            "count": 2,
+        });
+        let mut map = serializer.serialize_map(None)?;
+        map.serialize_entry("schema", &schema)?;
+        map.serialize_entry("data", &SerializableCounterSamples(self.0))?;
+        map.end()
+    }
+}
+
+struct SerializableCounterSamples<'a, 'b>(&'b SerializableCounter<'a>);
+
+impl<'a, 'b> Serialize for SerializableCounterSamples<'a, 'b> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        // Samples can be recorded out of order, but the format needs them sorted
+        // by time, because each sample's count is the change since the previous
+        // sample. The sort is stable, so samples with the same timestamp keep
+        // the order in which they were recorded.
+        let mut samples = self.0.counter.samples.clone();
+        samples.sort_by_key(|(timestamp, _)| *timestamp);
+
+        let mut seq = serializer.serialize_seq(Some(samples.len()))?;
+        let mut previous_value = 0.0;
+        for (timestamp, value) in samples {
+            let time = super::to_profile_timestamp(timestamp, self.0.process_start);
+            seq.serialize_element(&(time, 0, value - previous_value))?;
+            previous_value = value;
+        }
+        seq.end()
+    }
+}
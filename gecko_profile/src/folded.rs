@@ -0,0 +1,111 @@
+//! Conversion into the collapsed stack format of [flamegraph.pl] and [inferno].
+//!
+//! [flamegraph.pl]: https://github.com/brendangregg/FlameGraph
+//! [inferno]: https://github.com/jonhoo/inferno
+
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+
+use crate::{FrameNameMapper, ProfileBuilder, ThreadBuilder};
+
+impl ProfileBuilder {
+    /// Converts the profile, including its subprocesses, into the "folded"
+    /// text format which `flamegraph.pl` and `inferno-flamegraph` read.
+    ///
+    /// Every line is one distinct stack followed by the total
+    /// [weight](ThreadBuilder::add_sample_with_weight) of the samples with
+    /// that stack, e.g. `Main (pid 123, tid 12345);main;work 42`. The frames are
+    /// separated by `;` and go from the root to the leaf, with the thread as
+    /// the outermost frame, so that the threads stay apart in the flame graph.
+    /// Frame names are resolved like in the Gecko profile JSON: address frames
+    /// in libraries with [supplied symbols](ProfileBuilder::add_jit_lib) get
+    /// the symbol's name, and the
+    /// [frame name mapper](ProfileBuilder::set_frame_name_mapper) is applied.
+    /// Samples without a stack are left out, and the lines are sorted.
+    pub fn to_folded(&self) -> String {
+        let mut stack_counts = BTreeMap::new();
+        add_process(&mut stack_counts, self, None);
+        stack_counts
+            .into_iter()
+            .map(|(stack, count)| format!("{} {}\n", stack, count))
+            .collect()
+    }
+}
+
+fn add_process(
+    stack_counts: &mut BTreeMap<String, u64>,
+    process: &ProfileBuilder,
+    inherited_mapper: Option<&FrameNameMapper>,
+) {
+    let frame_name_mapper = process.frame_name_mapper.as_ref().or(inherited_mapper);
+
+    let mut threads: Vec<_> = process.threads.values().collect();
+    threads.sort_by_key(|thread| thread.index);
+    for thread in threads {
+        add_thread(stack_counts, process, thread, frame_name_mapper);
+    }
+
+    for subprocess in &process.subprocesses {
+        add_process(stack_counts, subprocess, frame_name_mapper);
+    }
+}
+
+fn add_thread(
+    stack_counts: &mut BTreeMap<String, u64>,
+    process: &ProfileBuilder,
+    thread: &ThreadBuilder,
+    frame_name_mapper: Option<&FrameNameMapper>,
+) {
+    let frame_table = &thread.frame_table;
+    let strings = &thread.string_table.strings;
+
+    let frame_names: Vec<String> = (0..frame_table.frames.len())
+        .map(|frame_index| {
+            let name = &strings[frame_table.frames[frame_index].0 as usize];
+            let lib_symbol_name = frame_table.addresses[frame_index]
+                .and_then(|address| process.libs.iter().find_map(|lib| lib.symbol_name(address)));
+            let is_address_name =
+                frame_table.addresses[frame_index].is_some() && lib_symbol_name.is_none();
+            let name = lib_symbol_name.unwrap_or(name);
+            let name = match frame_name_mapper {
+                Some(mapper) if !is_address_name => mapper.map(name),
+                _ => Cow::Borrowed(name),
+            };
+            escape_frame_name(&name)
+        })
+        .collect();
+
+    let thread_name = match &thread.name {
+        Some(name) => name.clone(),
+        None => format!("Thread <{}>", thread.index),
+    };
+    let thread_frame = escape_frame_name(&format!(
+        "{} (pid {}, tid {})",
+        thread_name, thread.pid, thread.index
+    ));
+
+    for sample in &thread.samples.0 {
+        let mut stack = Vec::new();
+        let mut stack_index = sample.stack_index;
+        while let Some(index) = stack_index {
+            let (prefix, frame_index) = thread.stack_table.stacks[index];
+            stack.push(frame_names[frame_index].as_str());
+            stack_index = prefix;
+        }
+        if stack.is_empty() {
+            continue;
+        }
+        stack.push(&thread_frame);
+        // The folded format wants the root frame first.
+        stack.reverse();
+        *stack_counts.entry(stack.join(";")).or_insert(0) +=
+            u64::try_from(sample.weight).unwrap_or(0);
+    }
+}
+
+/// `;` separates frames and the line ends with the count, so replace `;` like
+/// the stackcollapse scripts do, and line breaks with spaces.
+fn escape_frame_name(name: &str) -> String {
+    name.replace(';', ":").replace(['\n', '\r'], " ")
+}
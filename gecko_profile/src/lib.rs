@@ -1,17 +1,37 @@
 pub use debugid;
 
 use debugid::{CodeId, DebugId};
+use flate2::write::GzEncoder;
+use flate2::Compression;
 use serde_json::{json, Value};
+use std::borrow::Cow;
 use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::ops::Range;
 use std::path::{Path, PathBuf};
 
 use std::cmp::Ordering;
 use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+mod call_tree_summary;
+mod category;
+mod chrome_trace;
+mod counters;
+mod folded;
 mod markers;
+mod pprof;
+mod speedscope;
 
+pub use call_tree_summary::{FunctionTimes, SelfAndTotalTimes};
+pub use category::FrameCategory;
+pub use counters::CounterHandle;
 pub use markers::*;
 
+use counters::Counter;
+
 #[derive(Debug)]
 pub struct ProfileBuilder {
     pid: u32,
@@ -22,12 +42,40 @@ pub struct ProfileBuilder {
     start_time_system: SystemTime,
     end_time: Option<Instant>,
     command_name: String,
+    product: Option<String>,
+    os_name: Option<String>,
+    platform: Option<String>,
+    cpu_name: Option<String>,
+    /// (physical, logical)
+    cpu_counts: Option<(u32, u32)>,
+    misc: Option<String>,
     subprocesses: Vec<ProfileBuilder>,
+    frame_name_mapper: Option<FrameNameMapper>,
+    counters: Vec<Counter>,
+    /// Set with [`ProfileBuilder::set_main_thread`].
+    main_thread_tid: Option<u32>,
 }
 
 #[derive(Debug, Clone, Copy, PartialOrd, Ord, PartialEq, Eq)]
 pub struct StringIndex(u32);
 
+/// A function which rewrites frame names, see [`ProfileBuilder::set_frame_name_mapper`].
+pub struct FrameNameMapper(Box<FrameNameMapperFn>);
+
+type FrameNameMapperFn = dyn Fn(&str) -> Cow<'_, str> + Send + Sync;
+
+impl FrameNameMapper {
+    fn map<'s>(&self, name: &'s str) -> Cow<'s, str> {
+        (self.0)(name)
+    }
+}
+
+impl std::fmt::Debug for FrameNameMapper {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("FrameNameMapper")
+    }
+}
+
 #[derive(Debug, Clone, PartialOrd, Ord, PartialEq, Eq)]
 pub enum Frame {
     /// An instruction pointer address / return address
@@ -53,7 +101,16 @@ impl ProfileBuilder {
             start_time_system,
             end_time: None,
             command_name: command_name.to_owned(),
+            product: None,
+            os_name: None,
+            platform: None,
+            cpu_name: None,
+            cpu_counts: None,
+            misc: None,
             subprocesses: Vec::new(),
+            frame_name_mapper: None,
+            counters: Vec::new(),
+            main_thread_tid: None,
         }
     }
 
@@ -65,10 +122,79 @@ impl ProfileBuilder {
         self.end_time = Some(end_time);
     }
 
+    /// Move the start time to the earliest sample, marker or counter sample in
+    /// the profile, so that the exported profile begins at t=0. This makes
+    /// profiles easier to compare, because their timelines line up regardless
+    /// of how long it took until the first sample was taken.
+    ///
+    /// The start time's `SystemTime` is moved along with it, so the absolute
+    /// time of each event stays the same. Subprocesses are normalized to their
+    /// own earliest event. Profiles without any events are left unchanged.
+    pub fn normalize_start(&mut self) {
+        if let Some(earliest) = self.earliest_time() {
+            self.start_time_system = match earliest.checked_duration_since(self.start_time) {
+                Some(delay) => self.start_time_system + delay,
+                None => self.start_time_system - (self.start_time - earliest),
+            };
+            self.start_time = earliest;
+        }
+        for process in &mut self.subprocesses {
+            process.normalize_start();
+        }
+    }
+
+    /// The time of the earliest event in this profile, including subprocesses.
+    fn earliest_time(&self) -> Option<Instant> {
+        let threads = self
+            .threads
+            .values()
+            .filter_map(ThreadBuilder::earliest_time);
+        let counters = self.counters.iter().filter_map(Counter::earliest_time);
+        let processes = self.subprocesses.iter().filter_map(Self::earliest_time);
+        threads.chain(counters).chain(processes).min()
+    }
+
     pub fn set_interval(&mut self, interval: Duration) {
         self.interval = interval;
     }
 
+    /// Set the product name, which is shown at the top of the profiler. It
+    /// defaults to the command name.
+    pub fn set_product(&mut self, product: &str) {
+        self.product = Some(product.to_owned());
+    }
+
+    /// Set the name and version of the operating system, e.g. "macOS 14.1".
+    /// Serialized as `meta.oscpu`.
+    pub fn set_os_name(&mut self, os_name: &str) {
+        self.os_name = Some(os_name.to_owned());
+    }
+
+    /// Set the platform, e.g. "Macintosh". Serialized as `meta.platform`.
+    pub fn set_platform(&mut self, platform: &str) {
+        self.platform = Some(platform.to_owned());
+    }
+
+    /// Set the model name of the CPU. Serialized as `meta.CPUName`.
+    pub fn set_cpu_name(&mut self, cpu_name: &str) {
+        self.cpu_name = Some(cpu_name.to_owned());
+    }
+
+    /// Set the number of physical and logical CPU cores. Serialized as
+    /// `meta.physicalCPUs` and `meta.logicalCPUs`.
+    pub fn set_cpu_counts(&mut self, physical_cpus: u32, logical_cpus: u32) {
+        self.cpu_counts = Some((physical_cpus, logical_cpus));
+    }
+
+    /// Set a free-form description of how the profile was recorded, for example
+    /// the name and version of the profiler. Serialized as `meta.misc`.
+    pub fn set_misc(&mut self, misc: &str) {
+        self.misc = Some(misc.to_owned());
+    }
+
+    /// Address frames in this library get the category
+    /// [`FrameCategory::for_lib_path`] returns for `path`, unless it's changed
+    /// with [`ProfileBuilder::set_lib_category`].
     #[allow(clippy::too_many_arguments)]
     pub fn add_lib(
         &mut self,
@@ -89,17 +215,195 @@ impl ProfileBuilder {
             base_address,
             start_address: address_range.start,
             end_address: address_range.end,
+            category: FrameCategory::for_lib_path(path),
+            symbols: Vec::new(),
+            debug_status: None,
+        })
+    }
+
+    /// Add a code region which has no file on disk, for example code that was
+    /// generated by a JIT. Address frames in `address_range` are named with
+    /// `symbols` when the profile is serialized, so no symbolication is needed
+    /// for them. The symbol ranges are relative to `address_range.start`.
+    ///
+    /// The library shows up as a module called `name`, without a debug ID or
+    /// architecture. Its frames get the category [`FrameCategory::Other`],
+    /// which can be changed with [`ProfileBuilder::set_lib_category`]. Its
+    /// debug status is [`LibDebugStatus::Found`], because the symbols are supplied.
+    pub fn add_jit_lib(
+        &mut self,
+        name: &str,
+        address_range: Range<u64>,
+        mut symbols: Vec<(Range<u32>, String)>,
+    ) {
+        symbols.sort_by_key(|(range, _)| range.start);
+        self.libs.push(Lib {
+            path: PathBuf::from(name),
+            debug_path: PathBuf::from(name),
+            arch: None,
+            debug_id: DebugId::nil(),
+            code_id: None,
+            base_address: address_range.start,
+            start_address: address_range.start,
+            end_address: address_range.end,
+            category: FrameCategory::Other,
+            symbols,
+            debug_status: Some(LibDebugStatus::Found),
         })
     }
 
-    pub fn add_thread(&mut self, thread_builder: ThreadBuilder) {
+    /// Set the category of the address frames in the libraries with the given path.
+    pub fn set_lib_category(&mut self, path: &Path, category: FrameCategory) {
+        for lib in self.libs.iter_mut().filter(|lib| lib.path == path) {
+            lib.category = category;
+        }
+    }
+
+    /// Record whether debug information was found for the libraries with the
+    /// given path, e.g. by the symbolication of the profile. Serialized as the
+    /// library's `debugStatus`, and summarized by
+    /// [`ProfileBuilder::debug_status_report`]. Unknown by default.
+    pub fn set_lib_debug_status(&mut self, path: &Path, debug_status: LibDebugStatus) {
+        for lib in self.libs.iter_mut().filter(|lib| lib.path == path) {
+            lib.debug_status = Some(debug_status);
+        }
+    }
+
+    /// Set a function which rewrites the name of every frame when the profile is
+    /// serialized. This can be used to apply project-specific display conventions,
+    /// for example to strip template arguments or to collapse anonymous namespaces.
+    ///
+    /// The mapper is also used for subprocesses which don't have their own mapper.
+    /// Frames whose name is unchanged by the mapper keep their original string.
+    /// Address frames without a symbol keep their `0x...` name and are not mapped.
+    pub fn set_frame_name_mapper(
+        &mut self,
+        mapper: impl Fn(&str) -> Cow<'_, str> + Send + Sync + 'static,
+    ) {
+        self.frame_name_mapper = Some(FrameNameMapper(Box::new(mapper)));
+    }
+
+    pub fn add_thread(&mut self, mut thread_builder: ThreadBuilder) {
+        if let Some(main_thread_tid) = self.main_thread_tid {
+            thread_builder.is_main = thread_builder.index == main_thread_tid;
+        }
         self.threads.insert(thread_builder.index, thread_builder);
     }
 
+    /// Make the thread with the tid `tid` the main thread of this process, and
+    /// all other threads non-main threads. This overrides the `is_main` flag
+    /// which the threads were created with, also for threads which are added
+    /// later.
+    ///
+    /// The main thread comes first in the serialized profile, so that the
+    /// Firefox profiler selects it when the profile is opened.
+    pub fn set_main_thread(&mut self, tid: u32) {
+        self.main_thread_tid = Some(tid);
+        for thread in self.threads.values_mut() {
+            thread.is_main = thread.index == tid;
+        }
+    }
+
     pub fn add_subprocess(&mut self, profile_builder: ProfileBuilder) {
         self.subprocesses.push(profile_builder);
     }
 
+    /// Merges `other`, which was recorded separately, into this profile.
+    /// `time_offset` is the time between the start of this profile and the start
+    /// of `other`; all of `other`'s timestamps are shifted accordingly.
+    ///
+    /// Threads with the same tid are merged into one thread, and so are counters
+    /// with the same name and category and subprocesses with the same pid.
+    /// Libraries which this profile already has are not added again. The
+    /// interval, metadata and frame name mapper of this profile are kept.
+    pub fn merge(&mut self, mut other: ProfileBuilder, time_offset: Duration) {
+        let other_start = other.start_time;
+        let new_start = self.start_time + time_offset;
+        other.map_times(&|t| match t.checked_duration_since(other_start) {
+            Some(since_start) => new_start + since_start,
+            // Times which would end up before the earliest representable
+            // instant are clamped to the new start.
+            None => new_start.checked_sub(other_start - t).unwrap_or(new_start),
+        });
+        self.merge_shifted(other);
+    }
+
+    /// Merges `other`, whose timestamps have already been shifted into this
+    /// profile's timeline.
+    fn merge_shifted(&mut self, other: ProfileBuilder) {
+        self.end_time = match (self.end_time, other.end_time) {
+            (Some(a), Some(b)) => Some(a.max(b)),
+            (a, b) => a.or(b),
+        };
+        for lib in other.libs {
+            if !self.libs.iter().any(|l| l.is_same_mapping(&lib)) {
+                self.libs.push(lib);
+            }
+        }
+        for (tid, thread) in other.threads {
+            match self.threads.get_mut(&tid) {
+                Some(existing) => existing.merge(thread),
+                None => {
+                    self.threads.insert(tid, thread);
+                }
+            }
+        }
+        if let Some(main_thread_tid) = self.main_thread_tid {
+            self.set_main_thread(main_thread_tid);
+        }
+        for counter in other.counters {
+            match self.counters.iter_mut().find(|c| c.has_same_name(&counter)) {
+                Some(existing) => existing.merge(counter),
+                None => self.counters.push(counter),
+            }
+        }
+        for process in other.subprocesses {
+            match self.subprocesses.iter_mut().find(|p| p.pid == process.pid) {
+                Some(existing) => existing.merge_shifted(process),
+                None => self.subprocesses.push(process),
+            }
+        }
+    }
+
+    /// Rewrites every timestamp in this profile, including its subprocesses.
+    fn map_times(&mut self, f: &impl Fn(Instant) -> Instant) {
+        self.start_time = f(self.start_time);
+        self.end_time = self.end_time.map(f);
+        for thread in self.threads.values_mut() {
+            thread.map_times(f);
+        }
+        for counter in &mut self.counters {
+            counter.map_times(f);
+        }
+        for process in &mut self.subprocesses {
+            process.map_times(f);
+        }
+    }
+
+    /// Add a counter track to this process, for example for its memory usage.
+    /// The Firefox profiler shows it as a graph below the process's main thread.
+    /// `category` is the name of the counter's category, e.g. `"Memory"`.
+    pub fn add_counter(&mut self, name: &str, category: &str) -> CounterHandle {
+        let handle = CounterHandle(self.counters.len());
+        self.counters.push(Counter::new(name, category));
+        handle
+    }
+
+    /// Record the value of a counter at `timestamp`. `value` is the absolute
+    /// value, e.g. the number of bytes in use, not the change since the
+    /// previous sample.
+    ///
+    /// Samples don't need to be recorded in order; they are sorted by time
+    /// when the profile is serialized.
+    pub fn record_counter_sample(
+        &mut self,
+        counter: CounterHandle,
+        timestamp: Instant,
+        value: f64,
+    ) {
+        self.counters[counter.0].add_sample(timestamp, value);
+    }
+
     fn collect_marker_schemas(&self) -> HashMap<&'static str, MarkerSchema> {
         let mut marker_schemas = HashMap::new();
         for thread in self.threads.values() {
@@ -111,12 +415,298 @@ impl ProfileBuilder {
         marker_schemas
     }
 
+    /// Returns a report of the sampled code addresses which don't fall within
+    /// any of the libraries that were added with [`ProfileBuilder::add_lib`].
+    ///
+    /// Such addresses cannot be symbolicated. A high unmapped fraction usually
+    /// means that the process was running JIT code, that a library mapping
+    /// (for example from the dyld shared cache) is missing, or that the unwinder
+    /// produced garbage return addresses.
+    ///
+    /// Subprocesses are included in the report; their addresses are checked
+    /// against their own libraries.
+    pub fn unmapped_address_report(&self) -> UnmappedReport {
+        let mut total_address_count = 0;
+        let mut unmapped_counts = HashMap::new();
+        self.collect_unmapped_addresses(&mut total_address_count, &mut unmapped_counts);
+
+        let mut unmapped_addresses: Vec<(u64, u64)> = unmapped_counts.into_iter().collect();
+        unmapped_addresses.sort_by(|(a_addr, a_count), (b_addr, b_count)| {
+            b_count.cmp(a_count).then(a_addr.cmp(b_addr))
+        });
+        UnmappedReport {
+            total_address_count,
+            unmapped_addresses,
+        }
+    }
+
+    /// Counts the libraries of this process and of its subprocesses by their
+    /// [debug status](ProfileBuilder::set_lib_debug_status), so that it's clear
+    /// which libraries will show up as hex addresses. A library which is loaded
+    /// by several processes is counted once per process.
+    pub fn debug_status_report(&self) -> DebugStatusReport {
+        let mut report = DebugStatusReport::default();
+        self.collect_debug_status(&mut report);
+        report
+    }
+
+    fn collect_debug_status(&self, report: &mut DebugStatusReport) {
+        for lib in &self.libs {
+            match lib.debug_status {
+                Some(LibDebugStatus::Found) => report.found_count += 1,
+                Some(LibDebugStatus::Partial) => report.partial_count += 1,
+                Some(LibDebugStatus::Missing) => report.missing_libs.push(lib.path.clone()),
+                None => report.unknown_count += 1,
+            }
+        }
+        for subprocess in &self.subprocesses {
+            subprocess.collect_debug_status(report);
+        }
+    }
+
+    fn collect_unmapped_addresses(
+        &self,
+        total_address_count: &mut u64,
+        unmapped_counts: &mut HashMap<u64, u64>,
+    ) {
+        let mut lib_ranges: Vec<(u64, u64)> = self
+            .libs
+            .iter()
+            .map(|lib| (lib.start_address, lib.end_address))
+            .collect();
+        lib_ranges.sort_unstable();
+
+        for thread in self.threads.values() {
+            thread.for_each_sampled_address(|address| {
+                *total_address_count += 1;
+                if !is_address_in_ranges(address, &lib_ranges) {
+                    *unmapped_counts.entry(address).or_insert(0) += 1;
+                }
+            });
+        }
+        for process in &self.subprocesses {
+            process.collect_unmapped_addresses(total_address_count, unmapped_counts);
+        }
+    }
+
+    /// Aggregates the source locations of the leaf frames of all samples, as set
+    /// with [`ThreadBuilder::set_frame_source_location`].
+    ///
+    /// Samples whose leaf frame has no source location, and samples with an empty
+    /// stack, are counted in a single entry whose location is `None`. The result
+    /// includes subprocesses and is sorted by descending sample count.
+    pub fn line_hotspots(&self) -> Vec<LineHotspot> {
+        let mut counts = HashMap::new();
+        self.collect_line_hotspots(&mut counts);
+
+        let mut hotspots: Vec<LineHotspot> = counts
+            .into_iter()
+            .map(|(location, sample_count)| LineHotspot {
+                location,
+                sample_count,
+            })
+            .collect();
+        hotspots.sort_by(|a, b| {
+            b.sample_count
+                .cmp(&a.sample_count)
+                .then_with(|| a.location.cmp(&b.location))
+        });
+        hotspots
+    }
+
+    fn collect_line_hotspots(&self, counts: &mut HashMap<Option<(String, u32)>, u64>) {
+        for thread in self.threads.values() {
+            thread.for_each_sample_leaf_location(|location| {
+                let location = location.map(|(file, line)| (file.to_owned(), line));
+                *counts.entry(location).or_insert(0) += 1;
+            });
+        }
+        for process in &self.subprocesses {
+            process.collect_line_hotspots(counts);
+        }
+    }
+
+    /// Returns sample counts for the whole profile, including subprocesses.
+    pub fn stats(&self) -> ProfileStats {
+        let mut stats = ProfileStats::default();
+        self.collect_stats(&mut stats);
+        stats
+    }
+
+    fn collect_stats(&self, stats: &mut ProfileStats) {
+        for thread in self.threads.values() {
+            stats.sample_count += thread.samples.0.len() as u64;
+            stats.empty_stack_sample_count += thread.empty_stack_sample_count;
+        }
+        for process in &self.subprocesses {
+            process.collect_stats(stats);
+        }
+    }
+
     pub fn to_serializable(&self) -> SerializableProfile {
-        SerializableProfile(self)
+        SerializableProfile(self, self.frame_name_mapper.as_ref())
+    }
+
+    /// Writes the profile as Gecko profile JSON to `writer`. The JSON is
+    /// streamed into the writer; it is never built up in memory as a whole.
+    pub fn to_json_writer(&self, writer: impl Write) -> std::io::Result<()> {
+        serde_json::to_writer(writer, &self.to_serializable())?;
+        Ok(())
+    }
+
+    /// Saves the profile as Gecko profile JSON to the file at `path`. If the
+    /// path ends in `.gz`, the JSON is gzip-compressed, which the Firefox
+    /// profiler can load directly.
+    pub fn save_to_file(&self, path: &Path) -> std::io::Result<()> {
+        let writer = BufWriter::new(File::create(path)?);
+        if path.extension() == Some("gz".as_ref()) {
+            let mut encoder = GzEncoder::new(writer, Compression::default());
+            self.to_json_writer(&mut encoder)?;
+            encoder.finish()?.flush()
+        } else {
+            let mut writer = writer;
+            self.to_json_writer(&mut writer)?;
+            writer.flush()
+        }
+    }
+}
+
+/// Checks whether `address` is inside one of the `(start, end)` ranges.
+/// The ranges need to be sorted by start address.
+fn is_address_in_ranges(address: u64, sorted_ranges: &[(u64, u64)]) -> bool {
+    let index = match sorted_ranges.binary_search_by_key(&address, |&(start, _)| start) {
+        Ok(_) => return true,
+        Err(0) => return false,
+        Err(insertion_index) => insertion_index - 1,
+    };
+    // Ranges may overlap, so check all ranges which start before the address.
+    sorted_ranges[..=index]
+        .iter()
+        .rev()
+        .any(|&(_, end)| address < end)
+}
+
+/// The result of [`ProfileBuilder::unmapped_address_report`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UnmappedReport {
+    /// The number of address frames that were checked, counted once per sample
+    /// that they appear in.
+    pub total_address_count: u64,
+    /// The addresses which are not covered by any library, together with the
+    /// number of samples they appear in. Sorted by descending count.
+    pub unmapped_addresses: Vec<(u64, u64)>,
+}
+
+impl UnmappedReport {
+    /// The number of unmapped address frames, counted once per sample.
+    pub fn unmapped_count(&self) -> u64 {
+        self.unmapped_addresses.iter().map(|(_, count)| count).sum()
+    }
+
+    /// The fraction of address frames which are unmapped, between 0.0 and 1.0.
+    pub fn unmapped_fraction(&self) -> f64 {
+        if self.total_address_count == 0 {
+            return 0.0;
+        }
+        self.unmapped_count() as f64 / self.total_address_count as f64
+    }
+}
+
+/// Whether debug information was found for a library, see
+/// [`ProfileBuilder::set_lib_debug_status`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LibDebugStatus {
+    /// Full symbol information was found, e.g. a PDB file or a dSYM bundle with
+    /// a matching debug ID.
+    Found,
+    /// Only some symbol information was found, e.g. the export table or the
+    /// symbol table of the binary itself, without inline frames or line numbers.
+    Partial,
+    /// No symbol information was found. The library's frames stay hex addresses.
+    Missing,
+}
+
+impl LibDebugStatus {
+    /// The value of `debugStatus` in the serialized library.
+    fn as_str(self) -> &'static str {
+        match self {
+            LibDebugStatus::Found => "found",
+            LibDebugStatus::Partial => "partial",
+            LibDebugStatus::Missing => "missing",
+        }
+    }
+}
+
+/// The result of [`ProfileBuilder::debug_status_report`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DebugStatusReport {
+    /// The number of libraries with [`LibDebugStatus::Found`].
+    pub found_count: usize,
+    /// The number of libraries with [`LibDebugStatus::Partial`].
+    pub partial_count: usize,
+    /// The paths of the libraries with [`LibDebugStatus::Missing`].
+    pub missing_libs: Vec<PathBuf>,
+    /// The number of libraries whose debug status wasn't set.
+    pub unknown_count: usize,
+}
+
+impl DebugStatusReport {
+    /// The number of libraries for which at least some symbols were found.
+    pub fn symbolicated_count(&self) -> usize {
+        self.found_count + self.partial_count
+    }
+
+    /// The number of libraries, whatever their status.
+    pub fn lib_count(&self) -> usize {
+        self.symbolicated_count() + self.missing_libs.len() + self.unknown_count
+    }
+}
+
+/// E.g. `3 of 5 libraries symbolicated (1 partially), no symbols for: /usr/lib/libfoo.so`.
+impl fmt::Display for DebugStatusReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} of {} libraries symbolicated",
+            self.symbolicated_count(),
+            self.lib_count()
+        )?;
+        if self.partial_count > 0 {
+            write!(f, " ({} partially)", self.partial_count)?;
+        }
+        if !self.missing_libs.is_empty() {
+            let missing_libs: Vec<_> = self
+                .missing_libs
+                .iter()
+                .map(|path| path.to_string_lossy())
+                .collect();
+            write!(f, ", no symbols for: {}", missing_libs.join(", "))?;
+        }
+        Ok(())
     }
 }
 
-pub struct SerializableProfile<'a>(&'a ProfileBuilder);
+/// The result of [`ProfileBuilder::stats`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ProfileStats {
+    /// The total number of samples.
+    pub sample_count: u64,
+    /// The number of samples which were added without any frames. A high number
+    /// usually means that stack walking is failing.
+    pub empty_stack_sample_count: u64,
+}
+
+/// An entry in the result of [`ProfileBuilder::line_hotspots`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LineHotspot {
+    /// The file and line of the leaf frame, or `None` for samples whose leaf
+    /// frame has no known source location.
+    pub location: Option<(String, u32)>,
+    /// The number of samples with this leaf location.
+    pub sample_count: u64,
+}
+
+pub struct SerializableProfile<'a>(&'a ProfileBuilder, Option<&'a FrameNameMapper>);
 
 use serde::ser::{Serialize, SerializeMap, SerializeSeq, Serializer};
 
@@ -139,27 +729,16 @@ impl<'a> Serialize for SerializableProfile<'a> {
             self.0.collect_marker_schemas().into_values().collect();
         marker_schemas.sort_by_key(|schema| schema.type_name);
 
-        let meta = json!({
+        let mut meta = json!({
             "version": 24,
             "startTime": start_time_ms_since_unix_epoch,
             "shutdownTime": end_time_ms_since_start,
             "pausedRanges": [],
-            "product": self.0.command_name,
+            "product": self.0.product.as_ref().unwrap_or(&self.0.command_name),
             "interval": self.0.interval.as_secs_f64() * 1000.0,
             "pid": self.0.pid,
             "processType": 0,
-            "categories": [
-                {
-                    "name": "Regular",
-                    "color": "blue",
-                    "subcategories": ["Other"],
-                },
-                {
-                    "name": "Other",
-                    "color": "grey",
-                    "subcategories": ["Other"],
-                }
-            ],
+            "categories": FrameCategory::serializable_list(),
             "sampleUnits": {
                 "time": "ms",
                 "eventDelay": "ms",
@@ -167,9 +746,28 @@ impl<'a> Serialize for SerializableProfile<'a> {
             },
             "markerSchema": marker_schemas
         });
+        if let Some(os_name) = &self.0.os_name {
+            meta["oscpu"] = os_name.as_str().into();
+        }
+        if let Some(platform) = &self.0.platform {
+            meta["platform"] = platform.as_str().into();
+        }
+        if let Some(cpu_name) = &self.0.cpu_name {
+            meta["CPUName"] = cpu_name.as_str().into();
+        }
+        if let Some((physical_cpus, logical_cpus)) = self.0.cpu_counts {
+            meta["physicalCPUs"] = physical_cpus.into();
+            meta["logicalCPUs"] = logical_cpus.into();
+        }
+        if let Some(misc) = &self.0.misc {
+            meta["misc"] = misc.as_str().into();
+        }
 
         let mut sorted_threads: Vec<_> = self.0.threads.values().collect();
         sorted_threads.sort_by(|a, b| {
+            if a.is_main != b.is_main {
+                return b.is_main.cmp(&a.is_main);
+            }
             if let Some(ordering) = a.get_start_time().partial_cmp(&b.get_start_time()) {
                 if ordering != Ordering::Equal {
                     return ordering;
@@ -185,7 +783,15 @@ impl<'a> Serialize for SerializableProfile<'a> {
         let command_name = self.0.command_name.clone();
         let threads: Vec<_> = sorted_threads
             .into_iter()
-            .map(|thread| thread.to_serializable(&command_name, self.0.start_time))
+            .map(|thread| {
+                thread.to_serializable(
+                    &command_name,
+                    &self.0.libs,
+                    self.0.start_time,
+                    self.0.end_time,
+                    self.1,
+                )
+            })
             .collect();
 
         let mut libs: Vec<_> = self.0.libs.iter().collect();
@@ -203,7 +809,7 @@ impl<'a> Serialize for SerializableProfile<'a> {
 
         let subprocesses: Vec<_> = sorted_subprocesses
             .iter()
-            .map(|p| p.to_serializable())
+            .map(|p| SerializableProfile(p, p.frame_name_mapper.as_ref().or(self.1)))
             .collect();
 
         let mut map = serializer.serialize_map(None)?;
@@ -211,6 +817,15 @@ impl<'a> Serialize for SerializableProfile<'a> {
         map.serialize_entry("libs", &libs)?;
         map.serialize_entry("threads", &threads)?;
         map.serialize_entry("processes", &subprocesses)?;
+        if !self.0.counters.is_empty() {
+            let counters: Vec<_> = self
+                .0
+                .counters
+                .iter()
+                .map(|counter| counter.to_serializable(self.0.start_time))
+                .collect();
+            map.serialize_entry("counters", &counters)?;
+        }
         map.end()
     }
 }
@@ -234,9 +849,14 @@ pub struct ThreadBuilder {
     markers: MarkerTable,
     marker_schemas: HashMap<&'static str, MarkerSchema>,
     string_table: StringTable,
+    empty_stack_sample_count: u64,
 }
 
 impl ThreadBuilder {
+    /// The label of the frame which [`ThreadBuilder::add_sample`] uses for
+    /// samples without any frames.
+    pub const NO_STACK_LABEL: &'static str = "(no stack)";
+
     pub fn new(
         pid: u32,
         thread_index: u32,
@@ -258,6 +878,7 @@ impl ThreadBuilder {
             markers: MarkerTable::new(),
             marker_schemas: HashMap::new(),
             string_table: StringTable::new(),
+            empty_stack_sample_count: 0,
         }
     }
 
@@ -285,17 +906,62 @@ impl ThreadBuilder {
         self.string_table.index_for_string(s)
     }
 
+    /// Set the source file and line of a frame. This is used by
+    /// [`ProfileBuilder::line_hotspots`].
+    pub fn set_frame_source_location(&mut self, frame: Frame, file: &str, line: u32) {
+        let frame_index = self.frame_index_for_frame(frame);
+        let file = self.string_table.index_for_string(file);
+        self.frame_table.source_locations[frame_index] = Some((file, line));
+    }
+
+    /// Set the category of a frame. Without this, label frames have the
+    /// category [`FrameCategory::Regular`], and address frames have the
+    /// category of their library.
+    pub fn set_frame_category(&mut self, frame: Frame, category: FrameCategory) {
+        let frame_index = self.frame_index_for_frame(frame);
+        self.frame_table.categories[frame_index] = Some(category);
+    }
+
+    /// Adds a sample with the given stack, with the root frame first.
+    ///
+    /// If `frames` is empty, for example because the thread had only just
+    /// started or because unwinding failed right away, the sample gets a stack
+    /// consisting of a single [`ThreadBuilder::NO_STACK_LABEL`] frame, so that its
+    /// time is still accounted for. Such samples are counted in
+    /// [`ProfileStats::empty_stack_sample_count`].
     pub fn add_sample(
         &mut self,
         timestamp: Instant,
         frames: impl Iterator<Item = Frame>,
         cpu_delta: Duration,
     ) -> Option<usize> {
-        let stack_index = self.stack_index_for_frames(frames);
+        self.add_sample_with_weight(timestamp, frames, cpu_delta, 1)
+    }
+
+    /// Like [`ThreadBuilder::add_sample`], for a sample which stands for `weight`
+    /// sampling intervals, e.g. because the sampler fell behind or lowered its
+    /// sampling rate. The Firefox profiler's call tree adds up the weights
+    /// instead of counting samples.
+    pub fn add_sample_with_weight(
+        &mut self,
+        timestamp: Instant,
+        frames: impl Iterator<Item = Frame>,
+        cpu_delta: Duration,
+        weight: i32,
+    ) -> Option<usize> {
+        let mut frames = frames.peekable();
+        let stack_index = if frames.peek().is_none() {
+            self.empty_stack_sample_count += 1;
+            let label = self.string_table.index_for_string(Self::NO_STACK_LABEL);
+            self.stack_index_for_frames(std::iter::once(Frame::Label(label)))
+        } else {
+            self.stack_index_for_frames(frames)
+        };
         self.samples.0.push(Sample {
             timestamp,
             stack_index,
             cpu_delta_us: cpu_delta.as_micros() as u64,
+            weight,
         });
         stack_index
     }
@@ -305,11 +971,24 @@ impl ThreadBuilder {
         timestamp: Instant,
         previous_stack: Option<usize>,
         cpu_delta: Duration,
+    ) {
+        self.add_sample_same_stack_with_weight(timestamp, previous_stack, cpu_delta, 1);
+    }
+
+    /// Like [`ThreadBuilder::add_sample_same_stack`], with a weight as in
+    /// [`ThreadBuilder::add_sample_with_weight`].
+    pub fn add_sample_same_stack_with_weight(
+        &mut self,
+        timestamp: Instant,
+        previous_stack: Option<usize>,
+        cpu_delta: Duration,
+        weight: i32,
     ) {
         self.samples.0.push(Sample {
             timestamp,
             stack_index: previous_stack,
             cpu_delta_us: cpu_delta.as_micros() as u64,
+            weight,
         });
     }
 
@@ -330,6 +1009,132 @@ impl ThreadBuilder {
         self.end_time = Some(end_time);
     }
 
+    /// Appends the samples and markers of `other`, which must be on the same
+    /// timeline as this thread. `other`'s strings, frames and stacks are
+    /// interned into this thread's tables.
+    fn merge(&mut self, other: ThreadBuilder) {
+        let strings: Vec<StringIndex> = other
+            .string_table
+            .strings
+            .iter()
+            .map(|s| self.string_table.index_for_string(s))
+            .collect();
+        let map_string = |index: StringIndex| strings[index.0 as usize];
+
+        let mut frames = Vec::with_capacity(other.frame_table.frames.len());
+        for (i, address) in other.frame_table.addresses.iter().enumerate() {
+            let frame = match address {
+                Some(address) => Frame::Address(*address),
+                None => Frame::Label(map_string(other.frame_table.frames[i])),
+            };
+            let frame_index = self.frame_index_for_frame(frame);
+            if let Some((file, line)) = other.frame_table.source_locations[i] {
+                self.frame_table.source_locations[frame_index] = Some((map_string(file), line));
+            }
+            if let Some(category) = other.frame_table.categories[i] {
+                self.frame_table.categories[frame_index].get_or_insert(category);
+            }
+            frames.push(frame_index);
+        }
+
+        // A stack's prefix always comes before the stack itself, so the prefix
+        // has been mapped by the time we get to the stack.
+        let mut stacks: Vec<usize> = Vec::with_capacity(other.stack_table.stacks.len());
+        for (prefix, frame_index) in &other.stack_table.stacks {
+            let prefix = prefix.map(|prefix| stacks[prefix]);
+            stacks.push(
+                self.stack_table
+                    .index_for_stack(prefix, frames[*frame_index]),
+            );
+        }
+
+        self.samples
+            .0
+            .extend(other.samples.0.into_iter().map(|sample| Sample {
+                stack_index: sample.stack_index.map(|index| stacks[index]),
+                ..sample
+            }));
+        self.samples.0.sort_by_key(|sample| sample.timestamp);
+        self.markers
+            .0
+            .extend(other.markers.0.into_iter().map(|marker| Marker {
+                name_string_index: map_string(marker.name_string_index),
+                ..marker
+            }));
+        self.marker_schemas.extend(other.marker_schemas);
+        self.empty_stack_sample_count += other.empty_stack_sample_count;
+
+        self.start_time = self.start_time.min(other.start_time);
+        // A thread which is still alive in either profile is still alive in the
+        // merged profile.
+        self.end_time = match (self.end_time, other.end_time) {
+            (Some(a), Some(b)) => Some(a.max(b)),
+            _ => None,
+        };
+        if self.name.is_none() {
+            self.name = other.name;
+        }
+        self.is_main |= other.is_main;
+        self.is_libdispatch_thread |= other.is_libdispatch_thread;
+    }
+
+    /// The time of the earliest sample or marker of this thread.
+    fn earliest_time(&self) -> Option<Instant> {
+        let samples = self.samples.0.iter().map(|sample| sample.timestamp);
+        let markers = self.markers.0.iter().map(|marker| match marker.timing {
+            MarkerTiming::Instant(s)
+            | MarkerTiming::Interval(s, _)
+            | MarkerTiming::IntervalStart(s) => s,
+            MarkerTiming::IntervalEnd(e) => e,
+        });
+        samples.chain(markers).min()
+    }
+
+    /// Rewrites every timestamp of this thread's samples and markers.
+    fn map_times(&mut self, f: &impl Fn(Instant) -> Instant) {
+        self.start_time = f(self.start_time);
+        self.end_time = self.end_time.map(f);
+        for sample in &mut self.samples.0 {
+            sample.timestamp = f(sample.timestamp);
+        }
+        for marker in &mut self.markers.0 {
+            marker.timing = match marker.timing {
+                MarkerTiming::Instant(s) => MarkerTiming::Instant(f(s)),
+                MarkerTiming::Interval(s, e) => MarkerTiming::Interval(f(s), f(e)),
+                MarkerTiming::IntervalStart(s) => MarkerTiming::IntervalStart(f(s)),
+                MarkerTiming::IntervalEnd(e) => MarkerTiming::IntervalEnd(f(e)),
+            };
+        }
+    }
+
+    /// Calls `f` for every address frame of every sample, walking each sample's stack
+    /// from the leaf to the root.
+    fn for_each_sampled_address(&self, mut f: impl FnMut(u64)) {
+        for sample in &self.samples.0 {
+            let mut stack_index = sample.stack_index;
+            while let Some(index) = stack_index {
+                let (prefix, frame_index) = self.stack_table.stacks[index];
+                if let Some(address) = self.frame_table.addresses[frame_index] {
+                    f(address);
+                }
+                stack_index = prefix;
+            }
+        }
+    }
+
+    /// Calls `f` once for every sample, with the source file and line of the
+    /// sample's leaf frame, if known.
+    fn for_each_sample_leaf_location(&self, mut f: impl FnMut(Option<(&str, u32)>)) {
+        for sample in &self.samples.0 {
+            let location = sample.stack_index.and_then(|index| {
+                let (_prefix, frame_index) = self.stack_table.stacks[index];
+                let (file, line) = self.frame_table.source_locations[frame_index]?;
+                Some((self.string_table.get_string(file)?, line))
+            });
+            f(location);
+        }
+    }
+
     fn stack_index_for_frames(&mut self, frames: impl Iterator<Item = Frame>) -> Option<usize> {
         let frame_indexes: Vec<_> = frames
             .map(|frame| self.frame_index_for_frame(frame))
@@ -345,12 +1150,18 @@ impl ThreadBuilder {
     fn to_serializable<'a, 'n>(
         &'a self,
         process_name: &'n str,
+        libs: &'a [Lib],
         process_start: Instant,
+        process_end: Option<Instant>,
+        frame_name_mapper: Option<&'a FrameNameMapper>,
     ) -> SerializableProfileThread<'a, 'n> {
         SerializableProfileThread {
             thread: self,
             process_name,
+            libs,
             process_start,
+            process_end,
+            frame_name_mapper,
         }
     }
 }
@@ -358,7 +1169,10 @@ impl ThreadBuilder {
 pub struct SerializableProfileThread<'a, 'n> {
     thread: &'a ThreadBuilder,
     process_name: &'n str,
+    libs: &'a [Lib],
     process_start: Instant,
+    process_end: Option<Instant>,
+    frame_name_mapper: Option<&'a FrameNameMapper>,
 }
 
 impl<'a, 'n> Serialize for SerializableProfileThread<'a, 'n> {
@@ -378,6 +1192,11 @@ impl<'a, 'n> Serialize for SerializableProfileThread<'a, 'n> {
             .thread
             .end_time
             .map(|end_time| to_profile_timestamp(end_time, self.process_start));
+        // Thread times are relative to the process start, so the process
+        // starts at 0.
+        let process_shutdown_time = self
+            .process_end
+            .map(|end_time| to_profile_timestamp(end_time, self.process_start));
 
         let mut map = serializer.serialize_map(None)?;
         map.serialize_entry("name", &name)?;
@@ -385,9 +1204,65 @@ impl<'a, 'n> Serialize for SerializableProfileThread<'a, 'n> {
         map.serialize_entry("pid", &self.thread.pid)?;
         map.serialize_entry("processType", &"default")?;
         map.serialize_entry("processName", &self.process_name)?;
+        map.serialize_entry("processStartupTime", &0.0)?;
+        map.serialize_entry("processShutdownTime", &process_shutdown_time)?;
         map.serialize_entry("registerTime", &register_time)?;
         map.serialize_entry("unregisterTime", &unregister_time)?;
-        map.serialize_entry("frameTable", &self.thread.frame_table)?;
+        let frame_table = &self.thread.frame_table;
+
+        // Name address frames in libraries with supplied symbols, and apply the
+        // frame name mapper, if present. Changed names are appended to the string
+        // table, so that other users of the original strings are unaffected.
+        let has_lib_symbols = self.libs.iter().any(|lib| !lib.symbols.is_empty());
+        let mut frame_locations = Cow::Borrowed(&frame_table.frames[..]);
+        let mut mapped_strings: Vec<String> = Vec::new();
+        if has_lib_symbols || self.frame_name_mapper.is_some() {
+            let strings = &self.thread.string_table.strings;
+            let mut mapped_string_indexes: HashMap<String, StringIndex> = HashMap::new();
+            let locations = frame_locations.to_mut();
+            for (location, address) in locations.iter_mut().zip(&frame_table.addresses) {
+                let name = &strings[location.0 as usize];
+                let lib_symbol_name = address
+                    .and_then(|address| self.libs.iter().find_map(|lib| lib.symbol_name(address)));
+                let unmapped_name = lib_symbol_name.unwrap_or(name);
+                // Address frames without a symbol are named after their address,
+                // which is not a function name and is not passed to the mapper.
+                let is_address_name = address.is_some() && lib_symbol_name.is_none();
+                let mapped_name = match self.frame_name_mapper {
+                    Some(mapper) if !is_address_name => mapper.map(unmapped_name),
+                    _ => Cow::Borrowed(unmapped_name),
+                };
+                if mapped_name != name.as_str() {
+                    let next_index = StringIndex((strings.len() + mapped_strings.len()) as u32);
+                    *location = *mapped_string_indexes
+                        .entry(mapped_name.into_owned())
+                        .or_insert_with_key(|mapped_name| {
+                            mapped_strings.push(mapped_name.clone());
+                            next_index
+                        });
+                }
+            }
+        }
+
+        let frame_categories: Vec<FrameCategory> = frame_table
+            .categories
+            .iter()
+            .zip(&frame_table.addresses)
+            .map(|(category, address)| match (category, address) {
+                (Some(category), _) => *category,
+                (None, Some(address)) => self
+                    .libs
+                    .iter()
+                    .find(|lib| lib.contains(*address))
+                    .map_or(FrameCategory::Regular, |lib| lib.category),
+                (None, None) => FrameCategory::Regular,
+            })
+            .collect();
+
+        map.serialize_entry(
+            "frameTable",
+            &SerializableFrameTable(&frame_locations, &frame_categories),
+        )?;
         map.serialize_entry("stackTable", &self.thread.stack_table)?;
         map.serialize_entry(
             "samples",
@@ -397,7 +1272,10 @@ impl<'a, 'n> Serialize for SerializableProfileThread<'a, 'n> {
             "markers",
             &self.thread.markers.to_serializable(self.process_start),
         )?;
-        map.serialize_entry("stringTable", &self.thread.string_table.to_serializable())?;
+        map.serialize_entry(
+            "stringTable",
+            &SerializableStringTable(self.thread.string_table.to_serializable(), &mapped_strings),
+        )?;
         map.end()
     }
 }
@@ -412,6 +1290,39 @@ struct Lib {
     base_address: u64,
     start_address: u64,
     end_address: u64,
+    category: FrameCategory,
+    /// Symbols which were supplied with the library, for libraries added with
+    /// [`ProfileBuilder::add_jit_lib`]. Sorted by start address, relative to
+    /// `start_address`.
+    symbols: Vec<(Range<u32>, String)>,
+    debug_status: Option<LibDebugStatus>,
+}
+
+impl Lib {
+    fn contains(&self, address: u64) -> bool {
+        self.start_address <= address && address < self.end_address
+    }
+
+    /// Whether `other` is the same library, mapped at the same address.
+    fn is_same_mapping(&self, other: &Lib) -> bool {
+        self.path == other.path
+            && self.debug_id == other.debug_id
+            && self.start_address == other.start_address
+            && self.end_address == other.end_address
+    }
+
+    /// The name of the supplied symbol which contains `address`, if any.
+    fn symbol_name(&self, address: u64) -> Option<&str> {
+        if !self.contains(address) {
+            return None;
+        }
+        let relative_address = u32::try_from(address - self.start_address).ok()?;
+        let index = self
+            .symbols
+            .partition_point(|(range, _)| range.start <= relative_address);
+        let (range, name) = &self.symbols[index.checked_sub(1)?];
+        range.contains(&relative_address).then_some(name.as_str())
+    }
 }
 
 impl Serialize for Lib {
@@ -431,6 +1342,9 @@ impl Serialize for Lib {
         map.serialize_entry("start", &self.start_address)?;
         map.serialize_entry("end", &self.end_address)?;
         map.serialize_entry("arch", &self.arch)?;
+        if let Some(debug_status) = self.debug_status {
+            map.serialize_entry("debugStatus", debug_status.as_str())?;
+        }
         map.end()
     }
 }
@@ -455,20 +1369,19 @@ impl StackTable {
     pub fn index_for_frames(&mut self, frame_indexes: &[usize]) -> Option<usize> {
         let mut prefix = None;
         for &frame_index in frame_indexes {
-            match self.index.get(&(prefix, frame_index)) {
-                Some(stack_index) => {
-                    prefix = Some(*stack_index);
-                }
-                None => {
-                    let stack_index = self.stacks.len();
-                    self.stacks.push((prefix, frame_index));
-                    self.index.insert((prefix, frame_index), stack_index);
-                    prefix = Some(stack_index);
-                }
-            }
+            prefix = Some(self.index_for_stack(prefix, frame_index));
         }
         prefix
     }
+
+    pub fn index_for_stack(&mut self, prefix: Option<usize>, frame_index: usize) -> usize {
+        let stacks = &mut self.stacks;
+        *self.index.entry((prefix, frame_index)).or_insert_with(|| {
+            let stack_index = stacks.len();
+            stacks.push((prefix, frame_index));
+            stack_index
+        })
+    }
 }
 
 impl Serialize for StackTable {
@@ -514,6 +1427,15 @@ struct FrameTable {
     // [string_index]
     frames: Vec<StringIndex>,
 
+    // [address], None for label frames
+    addresses: Vec<Option<u64>>,
+
+    // [(file string_index, line)], None if unknown
+    source_locations: Vec<Option<(StringIndex, u32)>>,
+
+    // [category], None if not set explicitly
+    categories: Vec<Option<FrameCategory>>,
+
     // address -> frame index
     index: BTreeMap<Frame, usize>,
 }
@@ -522,28 +1444,43 @@ impl FrameTable {
     pub fn new() -> FrameTable {
         FrameTable {
             frames: Vec::new(),
+            addresses: Vec::new(),
+            source_locations: Vec::new(),
+            categories: Vec::new(),
             index: BTreeMap::new(),
         }
     }
 
     pub fn index_for_frame(&mut self, string_table: &mut StringTable, frame: Frame) -> usize {
         let frames = &mut self.frames;
+        let addresses = &mut self.addresses;
+        let source_locations = &mut self.source_locations;
+        let categories = &mut self.categories;
         *self.index.entry(frame.clone()).or_insert_with(|| {
             let frame_index = frames.len();
-            let location_string_index = match frame {
+            let (location_string_index, address) = match frame {
                 Frame::Address(address) => {
                     let location_string = format!("0x{:x}", address);
-                    string_table.index_for_string(&location_string)
+                    (
+                        string_table.index_for_string(&location_string),
+                        Some(address),
+                    )
                 }
-                Frame::Label(string_index) => string_index,
+                Frame::Label(string_index) => (string_index, None),
             };
             frames.push(location_string_index);
+            addresses.push(address);
+            source_locations.push(None);
+            categories.push(None);
             frame_index
         })
     }
 }
 
-impl Serialize for FrameTable {
+/// The frame table, given as the location string and the category of each frame.
+struct SerializableFrameTable<'a>(&'a [StringIndex], &'a [FrameCategory]);
+
+impl<'a> Serialize for SerializableFrameTable<'a> {
     fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
         let schema = json!({
             "location": 0,
@@ -558,24 +1495,24 @@ impl Serialize for FrameTable {
         });
         let mut map = serializer.serialize_map(None)?;
         map.serialize_entry("schema", &schema)?;
-        map.serialize_entry("data", &SerializableFrameTableData(self))?;
+        map.serialize_entry("data", &SerializableFrameTableData(self.0, self.1))?;
         map.end()
     }
 }
 
-struct SerializableFrameTableData<'a>(&'a FrameTable);
+struct SerializableFrameTableData<'a>(&'a [StringIndex], &'a [FrameCategory]);
 
 impl<'a> Serialize for SerializableFrameTableData<'a> {
     fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
-        let mut seq = serializer.serialize_seq(Some(self.0.frames.len()))?;
-        for location in &self.0.frames {
-            seq.serialize_element(&SerializableFrameTableDataValue(*location))?;
+        let mut seq = serializer.serialize_seq(Some(self.0.len()))?;
+        for (location, category) in self.0.iter().zip(self.1) {
+            seq.serialize_element(&SerializableFrameTableDataValue(*location, *category))?;
         }
         seq.end()
     }
 }
 
-struct SerializableFrameTableDataValue(StringIndex);
+struct SerializableFrameTableDataValue(StringIndex, FrameCategory);
 
 impl Serialize for SerializableFrameTableDataValue {
     fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
@@ -587,7 +1524,7 @@ impl Serialize for SerializableFrameTableDataValue {
         seq.serialize_element(&())?; // optimizations
         seq.serialize_element(&())?; // line
         seq.serialize_element(&())?; // column
-        seq.serialize_element(&0)?; // category
+        seq.serialize_element(&self.1.index())?; // category
         seq.serialize_element(&0)?; // subcategory
         seq.end()
     }
@@ -616,10 +1553,12 @@ impl<'a> Serialize for SerializableSampleTable<'a> {
             "stack": 0,
             "time": 1,
             "eventDelay": 2,
-            "threadCPUDelta": 3
+            "threadCPUDelta": 3,
+            "weight": 4
         });
         let mut map = serializer.serialize_map(None)?;
         map.serialize_entry("schema", &schema)?;
+        map.serialize_entry("weightType", &"samples")?;
         map.serialize_entry(
             "data",
             &SerializableSampleTableData {
@@ -643,6 +1582,7 @@ impl<'a> Serialize for SerializableSampleTableData<'a> {
                 stack_index: sample.stack_index,
                 timestamp: to_profile_timestamp(sample.timestamp, self.process_start),
                 cpu_delta_us: sample.cpu_delta_us,
+                weight: sample.weight,
             })?;
         }
         seq.end()
@@ -653,15 +1593,17 @@ struct SerializableSampleTableDataValue {
     stack_index: Option<usize>,
     timestamp: f64,
     cpu_delta_us: u64,
+    weight: i32,
 }
 
 impl Serialize for SerializableSampleTableDataValue {
     fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
-        let mut seq = serializer.serialize_seq(Some(4))?;
+        let mut seq = serializer.serialize_seq(Some(5))?;
         seq.serialize_element(&self.stack_index)?;
         seq.serialize_element(&self.timestamp)?;
         seq.serialize_element(&0.0)?;
         seq.serialize_element(&self.cpu_delta_us)?;
+        seq.serialize_element(&self.weight)?;
         seq.end()
     }
 }
@@ -671,6 +1613,8 @@ struct Sample {
     timestamp: Instant,
     stack_index: Option<usize>,
     cpu_delta_us: u64,
+    /// The number of sampling intervals this sample stands for.
+    weight: i32,
 }
 
 #[derive(Debug)]
@@ -825,21 +1769,42 @@ impl StringTable {
         }
     }
 
+    pub fn get_string(&self, index: StringIndex) -> Option<&str> {
+        self.strings.get(index.0 as usize).map(String::as_str)
+    }
+
     fn to_serializable(&self) -> &[String] {
         &self.strings
     }
 }
 
+/// The string table, followed by any extra strings that were created during serialization.
+struct SerializableStringTable<'a>(&'a [String], &'a [String]);
+
+impl<'a> Serialize for SerializableStringTable<'a> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(Some(self.0.len() + self.1.len()))?;
+        for s in self.0.iter().chain(self.1) {
+            seq.serialize_element(s)?;
+        }
+        seq.end()
+    }
+}
+
 #[cfg(test)]
 mod test {
     use assert_json_diff::assert_json_eq;
-    use serde_json::json;
+    use serde_json::{json, Value};
+    use std::borrow::Cow;
     use std::time::{Duration, Instant, SystemTime};
 
     use crate::{
-        MarkerDynamicField, MarkerFieldFormat, MarkerLocation, MarkerSchema, MarkerSchemaField,
-        MarkerStaticField, MarkerTiming, ProfileBuilder, ProfilerMarker, TextMarker, ThreadBuilder,
+        DebugStatusReport, Frame, FrameCategory, LibDebugStatus, LineHotspot, MarkerDynamicField,
+        MarkerFieldFormat, MarkerLocation, MarkerSchema, MarkerSchemaField, MarkerStaticField,
+        MarkerTiming, ProfileBuilder, ProfileStats, ProfilerMarker, TextMarker, ThreadBuilder,
     };
+    use debugid::DebugId;
+    use std::path::{Path, PathBuf};
 
     #[test]
     fn it_works() {
@@ -954,7 +1919,11 @@ mod test {
                     "meta": {
                       "categories": [
                         { "color": "blue", "name": "Regular", "subcategories": ["Other"] },
-                        { "color": "grey", "name": "Other", "subcategories": ["Other"] }
+                        { "color": "grey", "name": "Other", "subcategories": ["Other"] },
+                        { "color": "lightblue", "name": "Native", "subcategories": ["Other"] },
+                        { "color": "purple", "name": "System", "subcategories": ["Other"] },
+                        { "color": "yellow", "name": "JavaScript", "subcategories": ["Other"] },
+                        { "color": "orange", "name": "GC / CC", "subcategories": ["Other"] }
                       ],
                       "interval": 1.0,
                       "markerSchema": [
@@ -998,7 +1967,7 @@ mod test {
                     "threads": [
                       {
                         "frameTable": {
-                          "data": [],
+                          "data": [[0, false, 0, null, null, null, null, 0, 0]],
                           "schema": {
                             "category": 7,
                             "column": 6,
@@ -1013,9 +1982,9 @@ mod test {
                         },
                         "markers": {
                           "data": [
-                            [0, 0.0, 0.0, 0, 0, { "name": "Hello world!", "type": "Text" }],
+                            [1, 0.0, 0.0, 0, 0, { "name": "Hello world!", "type": "Text" }],
                             [
-                              1,
+                              2,
                               0.0,
                               2.0,
                               1,
@@ -1041,24 +2010,28 @@ mod test {
                         "name": "GeckoMain",
                         "pid": 123,
                         "processName": "test",
+                        "processShutdownTime": null,
+                        "processStartupTime": 0.0,
                         "processType": "default",
                         "registerTime": 0.0,
                         "samples": {
                           "data": [
-                            [null, 0.0, 0.0, 0],
-                            [null, 1.0, 0.0, 0],
-                            [null, 2.0, 0.0, 0],
-                            [null, 3.0, 0.0, 0]
+                            [0, 0.0, 0.0, 0, 1],
+                            [0, 1.0, 0.0, 0, 1],
+                            [0, 2.0, 0.0, 0, 1],
+                            [0, 3.0, 0.0, 0, 1]
                           ],
                           "schema": {
                             "eventDelay": 2,
                             "stack": 0,
                             "threadCPUDelta": 3,
-                            "time": 1
-                          }
+                            "time": 1,
+                            "weight": 4
+                          },
+                          "weightType": "samples"
                         },
-                        "stackTable": { "data": [], "schema": { "frame": 1, "prefix": 0 } },
-                        "stringTable": ["Experimental", "CustomName"],
+                        "stackTable": { "data": [[null, 0]], "schema": { "frame": 1, "prefix": 0 } },
+                        "stringTable": ["(no stack)", "Experimental", "CustomName"],
                         "tid": 12345,
                         "unregisterTime": null
                       }
@@ -1067,4 +2040,1283 @@ mod test {
             )
         )
     }
+
+    #[test]
+    fn sample_weights() {
+        let start_time = Instant::now();
+        let mut thread = ThreadBuilder::new(123, 12345, start_time, true, false);
+        thread.set_name("Main");
+        let main = Frame::Label(thread.intern_string("main"));
+        let stack = thread.add_sample(start_time, vec![main.clone()].into_iter(), Duration::ZERO);
+        thread.add_sample_with_weight(
+            start_time + Duration::from_millis(5),
+            vec![main].into_iter(),
+            Duration::ZERO,
+            4,
+        );
+        thread.add_sample_same_stack_with_weight(
+            start_time + Duration::from_millis(7),
+            stack,
+            Duration::ZERO,
+            2,
+        );
+        let mut profile = ProfileBuilder::new(
+            start_time,
+            SystemTime::now(),
+            "test",
+            123,
+            Duration::from_millis(1),
+        );
+        profile.add_thread(thread);
+
+        let json = serde_json::to_value(profile.to_serializable()).unwrap();
+        let samples = &json["threads"][0]["samples"];
+        assert_eq!(samples["weightType"], "samples");
+        let weight_column = samples["schema"]["weight"].as_u64().unwrap() as usize;
+        let weights: Vec<_> = samples["data"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|sample| sample[weight_column].as_i64().unwrap())
+            .collect();
+        assert_eq!(weights, vec![1, 4, 2]);
+
+        assert_eq!(profile.to_folded(), "Main (pid 123, tid 12345);main 7\n");
+    }
+
+    #[test]
+    fn empty_stack_samples() {
+        let start_time = Instant::now();
+        let mut thread = ThreadBuilder::new(123, 12345, start_time, true, false);
+        let main = Frame::Label(thread.intern_string("main"));
+        thread.add_sample(start_time, vec![main].into_iter(), Duration::ZERO);
+        let empty_stack = thread.add_sample(
+            start_time + Duration::from_millis(1),
+            vec![].into_iter(),
+            Duration::ZERO,
+        );
+        assert!(empty_stack.is_some());
+        let mut profile = ProfileBuilder::new(
+            start_time,
+            SystemTime::now(),
+            "test",
+            123,
+            Duration::from_millis(1),
+        );
+        profile.add_thread(thread);
+
+        let mut subprocess = ProfileBuilder::new(
+            start_time,
+            SystemTime::now(),
+            "child",
+            124,
+            Duration::from_millis(1),
+        );
+        let mut child_thread = ThreadBuilder::new(124, 12346, start_time, true, false);
+        child_thread.add_sample(start_time, vec![].into_iter(), Duration::ZERO);
+        subprocess.add_thread(child_thread);
+        profile.add_subprocess(subprocess);
+
+        assert_eq!(
+            profile.stats(),
+            ProfileStats {
+                sample_count: 3,
+                empty_stack_sample_count: 2,
+            }
+        );
+
+        let json = serde_json::to_value(profile.to_serializable()).unwrap();
+        let thread = &json["threads"][0];
+        let string_table = thread["stringTable"].as_array().unwrap();
+        let stack_index = thread["samples"]["data"][1][0].as_u64().unwrap() as usize;
+        let frame_index = thread["stackTable"]["data"][stack_index][1]
+            .as_u64()
+            .unwrap() as usize;
+        let location = thread["frameTable"]["data"][frame_index][0]
+            .as_u64()
+            .unwrap() as usize;
+        assert_eq!(string_table[location], ThreadBuilder::NO_STACK_LABEL);
+    }
+
+    #[test]
+    fn unmapped_address_report() {
+        let start_time = Instant::now();
+        let mut thread = ThreadBuilder::new(123, 12345, start_time, true, false);
+        let label = Frame::Label(thread.intern_string("Root"));
+        thread.add_sample(
+            start_time,
+            vec![
+                label.clone(),
+                Frame::Address(0x1010),
+                Frame::Address(0x5000),
+            ]
+            .into_iter(),
+            Duration::ZERO,
+        );
+        thread.add_sample(
+            start_time + Duration::from_millis(1),
+            vec![label, Frame::Address(0x1010), Frame::Address(0x5000)].into_iter(),
+            Duration::ZERO,
+        );
+        thread.add_sample(
+            start_time + Duration::from_millis(2),
+            vec![Frame::Address(0x1020), Frame::Address(0x7000)].into_iter(),
+            Duration::ZERO,
+        );
+        let mut profile = ProfileBuilder::new(
+            start_time,
+            SystemTime::now(),
+            "test",
+            123,
+            Duration::from_millis(1),
+        );
+        profile.add_lib(
+            Path::new("/usr/lib/libtest.so"),
+            None,
+            Path::new("/usr/lib/libtest.so"),
+            DebugId::nil(),
+            None,
+            0x1000,
+            0x1000..0x2000,
+        );
+        profile.add_thread(thread);
+
+        let report = profile.unmapped_address_report();
+        assert_eq!(report.total_address_count, 6);
+        assert_eq!(report.unmapped_addresses, vec![(0x5000, 2), (0x7000, 1)]);
+        assert_eq!(report.unmapped_count(), 3);
+        assert_eq!(report.unmapped_fraction(), 0.5);
+    }
+
+    #[test]
+    fn lib_debug_status() {
+        let start_time = Instant::now();
+        let new_profile = |name: &str, pid: u32| {
+            ProfileBuilder::new(
+                start_time,
+                SystemTime::now(),
+                name,
+                pid,
+                Duration::from_millis(1),
+            )
+        };
+        let add_lib = |profile: &mut ProfileBuilder, path: &str, start: u64| {
+            profile.add_lib(
+                Path::new(path),
+                None,
+                Path::new(path),
+                DebugId::nil(),
+                None,
+                start,
+                start..start + 0x1000,
+            );
+        };
+        let mut profile = new_profile("test", 123);
+        add_lib(&mut profile, "/app/test", 0x1000);
+        add_lib(&mut profile, "/usr/lib/libc.so", 0x2000);
+        add_lib(&mut profile, "/usr/lib/libstripped.so", 0x3000);
+        add_lib(&mut profile, "/usr/lib/libunknown.so", 0x4000);
+        profile.add_jit_lib("JIT", 0x7000..0x8000, Vec::new());
+        profile.set_lib_debug_status(Path::new("/app/test"), LibDebugStatus::Found);
+        profile.set_lib_debug_status(Path::new("/usr/lib/libc.so"), LibDebugStatus::Partial);
+        let mut subprocess = new_profile("child", 124);
+        add_lib(&mut subprocess, "/usr/lib/libstripped.so", 0x3000);
+        subprocess.set_lib_debug_status(
+            Path::new("/usr/lib/libstripped.so"),
+            LibDebugStatus::Missing,
+        );
+        profile.add_subprocess(subprocess);
+
+        let report = profile.debug_status_report();
+        assert_eq!(
+            report,
+            DebugStatusReport {
+                found_count: 2,
+                partial_count: 1,
+                missing_libs: vec![PathBuf::from("/usr/lib/libstripped.so")],
+                unknown_count: 2,
+            }
+        );
+        assert_eq!(
+            report.to_string(),
+            "3 of 6 libraries symbolicated (1 partially), no symbols for: /usr/lib/libstripped.so"
+        );
+
+        let json = serde_json::to_value(profile.to_serializable()).unwrap();
+        let debug_statuses: Vec<Value> = json["libs"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|lib| lib["debugStatus"].clone())
+            .collect();
+        assert_eq!(
+            debug_statuses,
+            vec![
+                json!("found"),
+                json!("partial"),
+                Value::Null,
+                Value::Null,
+                json!("found"),
+            ]
+        );
+        assert_eq!(json["processes"][0]["libs"][0]["debugStatus"], "missing");
+    }
+
+    #[test]
+    fn frame_name_mapper() {
+        let start_time = Instant::now();
+        let mut thread = ThreadBuilder::new(123, 12345, start_time, true, false);
+        let frames = vec![
+            Frame::Label(thread.intern_string("main")),
+            Frame::Label(thread.intern_string("foo::h0123456789abcdef")),
+            Frame::Label(thread.intern_string("bar::h0123456789abcdef")),
+        ];
+        thread.add_sample(start_time, frames.into_iter(), Duration::ZERO);
+        let mut profile = ProfileBuilder::new(
+            start_time,
+            SystemTime::now(),
+            "test",
+            123,
+            Duration::from_millis(1),
+        );
+        profile.add_thread(thread);
+        profile.set_frame_name_mapper(|name| match name.strip_suffix("::h0123456789abcdef") {
+            Some(stripped) => Cow::Owned(format!("{stripped} (mapped)")),
+            None => Cow::Borrowed(name),
+        });
+
+        let json = serde_json::to_value(profile.to_serializable()).unwrap();
+        let thread = &json["threads"][0];
+        let string_table = thread["stringTable"].as_array().unwrap();
+        let frame_names: Vec<&str> = thread["frameTable"]["data"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|frame| {
+                string_table[frame[0].as_u64().unwrap() as usize]
+                    .as_str()
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(frame_names, vec!["main", "foo (mapped)", "bar (mapped)"]);
+        assert_eq!(string_table[1], "foo::h0123456789abcdef");
+    }
+
+    #[test]
+    fn frame_name_mapper_skips_address_frames() {
+        let start_time = Instant::now();
+        let mut thread = ThreadBuilder::new(123, 12345, start_time, true, false);
+        let frames = vec![
+            Frame::Label(thread.intern_string("main")),
+            Frame::Address(0xabc),
+        ];
+        thread.add_sample(start_time, frames.into_iter(), Duration::ZERO);
+        let mut profile = ProfileBuilder::new(
+            start_time,
+            SystemTime::now(),
+            "test",
+            123,
+            Duration::from_millis(1),
+        );
+        profile.add_thread(thread);
+        profile.set_frame_name_mapper(|name| Cow::Owned(name.to_uppercase()));
+
+        let json = serde_json::to_value(profile.to_serializable()).unwrap();
+        let thread = &json["threads"][0];
+        let string_table = thread["stringTable"].as_array().unwrap();
+        let frame_names: Vec<&str> = thread["frameTable"]["data"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|frame| {
+                string_table[frame[0].as_u64().unwrap() as usize]
+                    .as_str()
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(frame_names, vec!["MAIN", "0xabc"]);
+    }
+
+    #[test]
+    fn line_hotspots() {
+        let start_time = Instant::now();
+        let mut thread = ThreadBuilder::new(123, 12345, start_time, true, false);
+        thread.set_frame_source_location(Frame::Address(0x1010), "main.rs", 10);
+        thread.set_frame_source_location(Frame::Address(0x1020), "main.rs", 20);
+        thread.set_frame_source_location(Frame::Address(0x1030), "lib.rs", 5);
+        let stacks = vec![
+            vec![Frame::Address(0x1010), Frame::Address(0x1020)],
+            vec![Frame::Address(0x1010), Frame::Address(0x1020)],
+            vec![Frame::Address(0x1020), Frame::Address(0x1030)],
+            vec![Frame::Address(0x1020), Frame::Address(0x2000)],
+            vec![],
+        ];
+        for (i, stack) in stacks.into_iter().enumerate() {
+            thread.add_sample(
+                start_time + Duration::from_millis(i as u64),
+                stack.into_iter(),
+                Duration::ZERO,
+            );
+        }
+        let mut profile = ProfileBuilder::new(
+            start_time,
+            SystemTime::now(),
+            "test",
+            123,
+            Duration::from_millis(1),
+        );
+        profile.add_thread(thread);
+
+        assert_eq!(
+            profile.line_hotspots(),
+            vec![
+                LineHotspot {
+                    location: None,
+                    sample_count: 2,
+                },
+                LineHotspot {
+                    location: Some(("main.rs".to_string(), 20)),
+                    sample_count: 2,
+                },
+                LineHotspot {
+                    location: Some(("lib.rs".to_string(), 5)),
+                    sample_count: 1,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn save_to_file() {
+        use std::io::Read;
+
+        let start_time = Instant::now();
+        let mut thread = ThreadBuilder::new(123, 12345, start_time, true, false);
+        let main = Frame::Label(thread.intern_string("main"));
+        thread.add_sample(start_time, vec![main].into_iter(), Duration::ZERO);
+        // A whole number of milliseconds, so that the start time survives the
+        // round trip through the JSON text exactly.
+        let mut profile = ProfileBuilder::new(
+            start_time,
+            SystemTime::UNIX_EPOCH + Duration::from_millis(1_600_000_000_000),
+            "test",
+            123,
+            Duration::from_millis(1),
+        );
+        profile.add_thread(thread);
+        let expected = serde_json::to_value(profile.to_serializable()).unwrap();
+
+        let mut json_bytes = Vec::new();
+        profile.to_json_writer(&mut json_bytes).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&json_bytes).unwrap();
+        assert_json_eq!(json, expected);
+
+        let dir = std::env::temp_dir().join(format!("gecko_profile-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let plain_path = dir.join("profile.json");
+        profile.save_to_file(&plain_path).unwrap();
+        let json: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&plain_path).unwrap()).unwrap();
+        assert_json_eq!(json, expected);
+
+        let gz_path = dir.join("profile.json.gz");
+        profile.save_to_file(&gz_path).unwrap();
+        let compressed = std::fs::read(&gz_path).unwrap();
+        assert_eq!(&compressed[..2], &[0x1f, 0x8b]);
+        let mut decompressed = Vec::new();
+        flate2::read::GzDecoder::new(&compressed[..])
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&decompressed).unwrap();
+        assert_json_eq!(json, expected);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn counters() {
+        let start_time = Instant::now();
+        let mut profile = ProfileBuilder::new(
+            start_time,
+            SystemTime::now(),
+            "test",
+            123,
+            Duration::from_millis(1),
+        );
+        let json = serde_json::to_value(profile.to_serializable()).unwrap();
+        assert!(json.get("counters").is_none());
+
+        let memory = profile.add_counter("malloc", "Memory");
+        // Recorded out of order on purpose.
+        profile.record_counter_sample(memory, start_time + Duration::from_millis(2), 1500.0);
+        profile.record_counter_sample(memory, start_time, 1000.0);
+        profile.record_counter_sample(memory, start_time + Duration::from_millis(3), 500.0);
+
+        let json = serde_json::to_value(profile.to_serializable()).unwrap();
+        assert_json_eq!(
+            json["counters"],
+            json!([
+                {
+                    "name": "malloc",
+                    "category": "Memory",
+                    "description": "malloc",
+                    "sample_groups": [
+                        {
+                            "id": 0,
+                            "samples": {
+                                "schema": { "time": 0, "number": 1, "count": 2 },
+                                "data": [
+                                    [0.0, 0, 1000.0],
+                                    [2.0, 0, 500.0],
+                                    [3.0, 0, -1000.0]
+                                ]
+                            }
+                        }
+                    ]
+                }
+            ])
+        );
+    }
+
+    #[test]
+    fn stacks_and_frames_are_deduplicated() {
+        let start_time = Instant::now();
+        let mut thread = ThreadBuilder::new(123, 12345, start_time, true, false);
+        let root = Frame::Label(thread.intern_string("root"));
+        let stack = vec![
+            root,
+            Frame::Address(0x1000),
+            Frame::Address(0x1010),
+            Frame::Address(0x1020),
+            Frame::Address(0x1030),
+        ];
+        for i in 0..1000 {
+            thread.add_sample(
+                start_time + Duration::from_millis(i),
+                stack.clone().into_iter(),
+                Duration::ZERO,
+            );
+        }
+        // A stack which shares all but its leaf frame with the first one only
+        // adds one stack and one frame.
+        let mut other_stack = stack.clone();
+        other_stack[4] = Frame::Address(0x1040);
+        thread.add_sample(
+            start_time + Duration::from_millis(1000),
+            other_stack.into_iter(),
+            Duration::ZERO,
+        );
+        let mut profile = ProfileBuilder::new(
+            start_time,
+            SystemTime::now(),
+            "test",
+            123,
+            Duration::from_millis(1),
+        );
+        profile.add_thread(thread);
+
+        let json = serde_json::to_value(profile.to_serializable()).unwrap();
+        let thread = &json["threads"][0];
+        assert_eq!(thread["samples"]["data"].as_array().unwrap().len(), 1001);
+        assert_eq!(thread["stackTable"]["data"].as_array().unwrap().len(), 6);
+        assert_eq!(thread["frameTable"]["data"].as_array().unwrap().len(), 6);
+        assert_eq!(
+            thread["stringTable"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .filter(|s| *s == "root")
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn frame_categories() {
+        let start_time = Instant::now();
+        let mut thread = ThreadBuilder::new(123, 12345, start_time, true, false);
+        let main = Frame::Label(thread.intern_string("main"));
+        let gc = Frame::Label(thread.intern_string("gc"));
+        thread.set_frame_category(gc.clone(), FrameCategory::GC);
+        thread.add_sample(
+            start_time,
+            vec![
+                main,
+                Frame::Address(0x1010),
+                Frame::Address(0x5010),
+                Frame::Address(0x9000),
+                gc,
+            ]
+            .into_iter(),
+            Duration::ZERO,
+        );
+        let mut profile = ProfileBuilder::new(
+            start_time,
+            SystemTime::now(),
+            "test",
+            123,
+            Duration::from_millis(1),
+        );
+        profile.add_lib(
+            Path::new("/home/user/app"),
+            None,
+            Path::new("/home/user/app"),
+            DebugId::nil(),
+            None,
+            0x1000,
+            0x1000..0x2000,
+        );
+        profile.add_lib(
+            Path::new("/usr/lib/libc.so.6"),
+            None,
+            Path::new("/usr/lib/libc.so.6"),
+            DebugId::nil(),
+            None,
+            0x5000,
+            0x5000..0x6000,
+        );
+        profile.add_thread(thread);
+
+        let category_names = |profile: &ProfileBuilder| -> Vec<String> {
+            let json = serde_json::to_value(profile.to_serializable()).unwrap();
+            let categories = json["meta"]["categories"].as_array().unwrap().clone();
+            json["threads"][0]["frameTable"]["data"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|frame| {
+                    let category = frame[7].as_u64().unwrap() as usize;
+                    categories[category]["name"].as_str().unwrap().to_owned()
+                })
+                .collect()
+        };
+        assert_eq!(
+            category_names(&profile),
+            vec!["GC / CC", "Regular", "Native", "System", "Regular"]
+        );
+
+        profile.set_lib_category(Path::new("/home/user/app"), FrameCategory::JavaScript);
+        assert_eq!(category_names(&profile)[2], "JavaScript");
+    }
+
+    #[test]
+    fn jit_lib_symbols() {
+        let start_time = Instant::now();
+        let mut thread = ThreadBuilder::new(123, 12345, start_time, true, false);
+        let main = Frame::Label(thread.intern_string("main"));
+        thread.add_sample(
+            start_time,
+            vec![
+                main,
+                Frame::Address(0x1010),
+                Frame::Address(0x7000_0010),
+                Frame::Address(0x7000_0050),
+                Frame::Address(0x7000_0100),
+            ]
+            .into_iter(),
+            Duration::ZERO,
+        );
+        let mut profile = ProfileBuilder::new(
+            start_time,
+            SystemTime::now(),
+            "test",
+            123,
+            Duration::from_millis(1),
+        );
+        profile.add_jit_lib(
+            "JIT",
+            0x7000_0000..0x7001_0000,
+            vec![
+                (0x40..0x80, "bar".to_string()),
+                (0x0..0x40, "foo".to_string()),
+            ],
+        );
+        profile.add_thread(thread);
+
+        let frame_names = |profile: &ProfileBuilder| -> Vec<(String, String)> {
+            let json = serde_json::to_value(profile.to_serializable()).unwrap();
+            let categories = &json["meta"]["categories"];
+            let thread = &json["threads"][0];
+            thread["frameTable"]["data"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|frame| {
+                    let location = frame[0].as_u64().unwrap() as usize;
+                    let category = frame[7].as_u64().unwrap() as usize;
+                    (
+                        thread["stringTable"][location].as_str().unwrap().to_owned(),
+                        categories[category]["name"].as_str().unwrap().to_owned(),
+                    )
+                })
+                .collect()
+        };
+        let names = |names: &[(&str, &str)]| -> Vec<(String, String)> {
+            names
+                .iter()
+                .map(|(name, category)| (name.to_string(), category.to_string()))
+                .collect()
+        };
+        // Addresses outside of the supplied symbols keep their address string.
+        assert_eq!(
+            frame_names(&profile),
+            names(&[
+                ("main", "Regular"),
+                ("0x1010", "Regular"),
+                ("foo", "Other"),
+                ("bar", "Other"),
+                ("0x70000100", "Other"),
+            ])
+        );
+
+        let json = serde_json::to_value(profile.to_serializable()).unwrap();
+        assert_eq!(json["libs"][0]["name"], "JIT");
+        assert_eq!(json["libs"][0]["arch"], Value::Null);
+
+        profile.set_frame_name_mapper(|name| Cow::Owned(name.to_uppercase()));
+        assert_eq!(frame_names(&profile)[3].0, "BAR");
+    }
+
+    #[test]
+    fn system_info() {
+        let mut profile = ProfileBuilder::new(
+            Instant::now(),
+            SystemTime::now(),
+            "test",
+            123,
+            Duration::from_millis(1),
+        );
+        let json = serde_json::to_value(profile.to_serializable()).unwrap();
+        assert_eq!(json["meta"]["product"], "test");
+        for key in [
+            "oscpu",
+            "platform",
+            "CPUName",
+            "physicalCPUs",
+            "logicalCPUs",
+            "misc",
+        ] {
+            assert!(json["meta"].get(key).is_none());
+        }
+
+        profile.set_product("My App");
+        profile.set_os_name("macOS 14.1");
+        profile.set_platform("Macintosh");
+        profile.set_cpu_name("Apple M1 Pro");
+        profile.set_cpu_counts(8, 10);
+        profile.set_misc("samply 0.11.0");
+        let json = serde_json::to_value(profile.to_serializable()).unwrap();
+        assert_eq!(json["meta"]["product"], "My App");
+        assert_eq!(json["meta"]["oscpu"], "macOS 14.1");
+        assert_eq!(json["meta"]["platform"], "Macintosh");
+        assert_eq!(json["meta"]["CPUName"], "Apple M1 Pro");
+        assert_eq!(json["meta"]["physicalCPUs"], 8);
+        assert_eq!(json["meta"]["logicalCPUs"], 10);
+        assert_eq!(json["meta"]["misc"], "samply 0.11.0");
+    }
+
+    #[test]
+    fn process_and_thread_lifetimes() {
+        let start_time = Instant::now();
+        let mut main_thread = ThreadBuilder::new(123, 1, start_time, true, false);
+        main_thread.notify_dead(start_time + Duration::from_millis(30));
+        let mut short_lived =
+            ThreadBuilder::new(123, 2, start_time + Duration::from_millis(10), false, false);
+        short_lived.notify_dead(start_time + Duration::from_millis(20));
+        let still_running =
+            ThreadBuilder::new(123, 3, start_time + Duration::from_millis(15), false, false);
+        let mut profile = ProfileBuilder::new(
+            start_time,
+            SystemTime::now(),
+            "test",
+            123,
+            Duration::from_millis(1),
+        );
+        profile.add_thread(main_thread);
+        profile.add_thread(short_lived);
+        profile.add_thread(still_running);
+
+        let lifetimes = |profile: &ProfileBuilder| -> Vec<Value> {
+            let json = serde_json::to_value(profile.to_serializable()).unwrap();
+            json["threads"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|thread| {
+                    json!([
+                        thread["registerTime"],
+                        thread["unregisterTime"],
+                        thread["processStartupTime"],
+                        thread["processShutdownTime"],
+                    ])
+                })
+                .collect()
+        };
+        assert_eq!(
+            lifetimes(&profile),
+            vec![
+                json!([0.0, 30.0, 0.0, null]),
+                json!([10.0, 20.0, 0.0, null]),
+                json!([15.0, null, 0.0, null]),
+            ]
+        );
+
+        profile.set_end_time(start_time + Duration::from_millis(30));
+        assert_eq!(
+            lifetimes(&profile),
+            vec![
+                json!([0.0, 30.0, 0.0, 30.0]),
+                json!([10.0, 20.0, 0.0, 30.0]),
+                json!([15.0, null, 0.0, 30.0]),
+            ]
+        );
+    }
+
+    #[test]
+    fn to_pprof() {
+        // A minimal protobuf decoder, which returns (field number, varint value, bytes).
+        fn varint(buf: &mut &[u8]) -> u64 {
+            let mut value = 0;
+            let mut shift = 0;
+            loop {
+                let byte = buf[0];
+                *buf = &buf[1..];
+                value |= u64::from(byte & 0x7f) << shift;
+                if byte < 0x80 {
+                    return value;
+                }
+                shift += 7;
+            }
+        }
+        fn decode(mut buf: &[u8]) -> Vec<(u64, u64, &[u8])> {
+            let mut fields = Vec::new();
+            while !buf.is_empty() {
+                let key = varint(&mut buf);
+                match key & 7 {
+                    0 => fields.push((key >> 3, varint(&mut buf), &[][..])),
+                    2 => {
+                        let len = varint(&mut buf) as usize;
+                        fields.push((key >> 3, 0, &buf[..len]));
+                        buf = &buf[len..];
+                    }
+                    wire_type => panic!("unexpected wire type {}", wire_type),
+                }
+            }
+            fields
+        }
+        fn packed(mut buf: &[u8]) -> Vec<u64> {
+            let mut values = Vec::new();
+            while !buf.is_empty() {
+                values.push(varint(&mut buf));
+            }
+            values
+        }
+
+        let start_time = Instant::now();
+        let mut thread = ThreadBuilder::new(123, 12345, start_time, true, false);
+        thread.set_name("Main");
+        let root = Frame::Label(thread.intern_string("root"));
+        thread.add_sample(
+            start_time + Duration::from_millis(1),
+            vec![root, Frame::Address(0x101234)].into_iter(),
+            Duration::from_micros(500),
+        );
+        let mut profile = ProfileBuilder::new(
+            start_time,
+            SystemTime::now(),
+            "test",
+            123,
+            Duration::from_millis(1),
+        );
+        profile.add_lib(
+            Path::new("/usr/lib/libtest.so"),
+            None,
+            Path::new("/usr/lib/libtest.so"),
+            DebugId::nil(),
+            None,
+            0x100000,
+            0x101000..0x102000,
+        );
+        profile.set_frame_name_mapper(|name| Cow::Owned(name.to_uppercase()));
+        profile.add_thread(thread);
+
+        let pprof = profile.to_pprof();
+        let fields = decode(&pprof);
+        let strings: Vec<&str> = fields
+            .iter()
+            .filter(|(field, _, _)| *field == 6)
+            .map(|(_, _, bytes)| std::str::from_utf8(bytes).unwrap())
+            .collect();
+        assert_eq!(strings[0], "");
+        let messages = |number| -> Vec<Vec<(u64, u64, &[u8])>> {
+            fields
+                .iter()
+                .filter(|(field, _, _)| *field == number)
+                .map(|(_, _, bytes)| decode(bytes))
+                .collect()
+        };
+        let string_field = |message: &[(u64, u64, &[u8])], number| {
+            let index = message
+                .iter()
+                .find(|(field, _, _)| *field == number)
+                .map_or(0, |(_, value, _)| *value);
+            strings[index as usize]
+        };
+
+        let sample_types: Vec<_> = messages(1)
+            .iter()
+            .map(|vt| (string_field(vt, 1), string_field(vt, 2)))
+            .collect();
+        assert_eq!(
+            sample_types,
+            vec![("samples", "count"), ("cpu", "nanoseconds")]
+        );
+
+        let samples = messages(2);
+        assert_eq!(samples.len(), 1);
+        let location_ids = packed(samples[0][0].2);
+        assert_eq!(packed(samples[0][1].2), vec![1, 500_000]);
+        let labels: Vec<_> = samples[0][2..].iter().map(|(_, _, l)| decode(l)).collect();
+        assert_eq!(string_field(&labels[0], 1), "thread");
+        assert_eq!(string_field(&labels[0], 2), "Main");
+        assert_eq!(string_field(&labels[1], 1), "tid");
+        assert_eq!(labels[1][1].1, 12345);
+
+        let mappings = messages(3);
+        assert_eq!(mappings.len(), 1);
+        assert_eq!(string_field(&mappings[0], 5), "/usr/lib/libtest.so");
+
+        // The leaf frame comes first.
+        let locations = messages(4);
+        assert_eq!(location_ids.len(), 2);
+        let location = |id| {
+            locations
+                .iter()
+                .find(|location| location[0] == (1, id, &[][..]))
+                .unwrap()
+        };
+        let leaf = location(location_ids[0]);
+        assert_eq!(leaf[1].1, 1, "mapping id");
+        assert_eq!(leaf[2].1, 0x101234, "address");
+        assert_eq!(leaf.len(), 3, "no line for unsymbolicated addresses");
+        let root_location = location(location_ids[1]);
+        let line = decode(root_location[1].2);
+        let functions = messages(5);
+        let function = functions
+            .iter()
+            .find(|function| function[0] == (1, line[0].1, &[][..]))
+            .unwrap();
+        assert_eq!(string_field(function, 2), "ROOT");
+    }
+
+    #[test]
+    fn to_speedscope() {
+        let start_time = Instant::now();
+        let mut thread = ThreadBuilder::new(123, 12345, start_time, true, false);
+        thread.set_name("Main");
+        let root = Frame::Label(thread.intern_string("root"));
+        let child = Frame::Label(thread.intern_string("child"));
+        thread.add_sample(
+            start_time + Duration::from_millis(1),
+            vec![root.clone(), child].into_iter(),
+            Duration::ZERO,
+        );
+        thread.add_sample(
+            start_time + Duration::from_millis(3),
+            vec![root, Frame::Address(0x1234)].into_iter(),
+            Duration::ZERO,
+        );
+        thread.set_frame_source_location(Frame::Address(0x1234), "main.rs", 7);
+        let mut profile = ProfileBuilder::new(
+            start_time,
+            SystemTime::now(),
+            "test",
+            123,
+            Duration::from_millis(1),
+        );
+        profile.set_frame_name_mapper(|name| Cow::Owned(name.to_uppercase()));
+        profile.add_thread(thread);
+
+        let speedscope = profile.to_speedscope();
+        assert_json_eq!(
+            speedscope,
+            json!({
+                "$schema": "https://www.speedscope.app/file-format-schema.json",
+                "name": "test",
+                "exporter": concat!("gecko_profile@", env!("CARGO_PKG_VERSION")),
+                "activeProfileIndex": 0,
+                "shared": {
+                    "frames": [
+                        { "name": "ROOT" },
+                        { "name": "CHILD" },
+                        { "name": "0x1234", "file": "main.rs", "line": 7 },
+                    ],
+                },
+                "profiles": [
+                    {
+                        "type": "sampled",
+                        "name": "Main (pid 123, tid 12345)",
+                        "unit": "milliseconds",
+                        "startValue": 1.0,
+                        "endValue": 4.0,
+                        "samples": [[0, 1], [0, 2]],
+                        "weights": [2.0, 1.0],
+                    },
+                ],
+            })
+        );
+    }
+
+    #[test]
+    fn to_folded() {
+        let start_time = Instant::now();
+        let mut thread = ThreadBuilder::new(123, 12345, start_time, true, false);
+        thread.set_name("Main");
+        let root = Frame::Label(thread.intern_string("root"));
+        let child = Frame::Label(thread.intern_string("child;1"));
+        for (ms, frames) in [
+            (1, vec![root.clone(), child.clone()]),
+            (2, vec![root.clone(), Frame::Address(0x7000_0010)]),
+            (3, vec![root.clone(), child]),
+        ] {
+            thread.add_sample(
+                start_time + Duration::from_millis(ms),
+                frames.into_iter(),
+                Duration::ZERO,
+            );
+        }
+        let mut other_thread = ThreadBuilder::new(123, 12346, start_time, false, false);
+        let other_root = Frame::Label(other_thread.intern_string("root"));
+        other_thread.add_sample(
+            start_time + Duration::from_millis(1),
+            vec![other_root, Frame::Address(0x1234)].into_iter(),
+            Duration::ZERO,
+        );
+        let mut profile = ProfileBuilder::new(
+            start_time,
+            SystemTime::now(),
+            "test",
+            123,
+            Duration::from_millis(1),
+        );
+        profile.add_jit_lib(
+            "JIT",
+            0x7000_0000..0x7001_0000,
+            vec![(0x0..0x40, "jitted".to_string())],
+        );
+        profile.set_frame_name_mapper(|name| Cow::Owned(name.to_uppercase()));
+        profile.add_thread(thread);
+        profile.add_thread(other_thread);
+
+        assert_eq!(
+            profile.to_folded(),
+            "Main (pid 123, tid 12345);ROOT;CHILD:1 2\n\
+             Main (pid 123, tid 12345);ROOT;JITTED 1\n\
+             Thread <12346> (pid 123, tid 12346);ROOT;0x1234 1\n"
+        );
+    }
+
+    #[test]
+    fn self_and_total_times() {
+        let start_time = Instant::now();
+        let mut thread = ThreadBuilder::new(123, 12345, start_time, true, false);
+        let main = Frame::Label(thread.intern_string("main"));
+        let helper = Frame::Label(thread.intern_string("helper"));
+        // An inlined call, which has the same name as the jitted function.
+        let inlined_work = Frame::Label(thread.intern_string("work"));
+        for (ms, frames, weight) in [
+            (1, vec![main.clone(), Frame::Address(0x7000_0010)], 1),
+            (
+                2,
+                vec![main.clone(), Frame::Address(0x7000_0020), helper],
+                1,
+            ),
+            (3, vec![main, inlined_work, Frame::Address(0x7000_0010)], 2),
+        ] {
+            thread.add_sample_with_weight(
+                start_time + Duration::from_millis(ms),
+                frames.into_iter(),
+                Duration::ZERO,
+                weight,
+            );
+        }
+        let mut profile = ProfileBuilder::new(
+            start_time,
+            SystemTime::now(),
+            "test",
+            123,
+            Duration::from_millis(1),
+        );
+        profile.add_jit_lib(
+            "JIT",
+            0x7000_0000..0x7001_0000,
+            vec![(0x0..0x40, "work".to_string())],
+        );
+        profile.add_thread(thread);
+
+        let times = profile.self_and_total_times();
+        assert_eq!(times.sample_weight, 4);
+        let weights: Vec<_> = times
+            .functions
+            .iter()
+            .map(|f| (f.name.as_str(), f.self_weight, f.total_weight))
+            .collect();
+        assert_eq!(
+            weights,
+            vec![("work", 3, 4), ("helper", 1, 1), ("main", 0, 4)]
+        );
+        assert_eq!(
+            times.to_string(),
+            "work: 75.0% self, 100.0% total\n\
+             helper: 25.0% self, 25.0% total\n\
+             main: 0.0% self, 100.0% total\n"
+        );
+    }
+
+    #[test]
+    fn to_chrome_trace() {
+        let start_time = Instant::now();
+        let mut thread = ThreadBuilder::new(123, 12345, start_time, true, false);
+        thread.set_name("Main");
+        let root = Frame::Label(thread.intern_string("root"));
+        thread.add_sample(
+            start_time + Duration::from_millis(1),
+            vec![root.clone(), Frame::Address(0x101234)].into_iter(),
+            Duration::ZERO,
+        );
+        thread.add_sample(
+            start_time + Duration::from_micros(2500),
+            vec![root, Frame::Address(0x5678)].into_iter(),
+            Duration::ZERO,
+        );
+        let mut profile = ProfileBuilder::new(
+            start_time,
+            SystemTime::now(),
+            "test",
+            123,
+            Duration::from_millis(1),
+        );
+        profile.add_lib(
+            Path::new("/usr/lib/libtest.so"),
+            None,
+            Path::new("/usr/lib/libtest.so"),
+            DebugId::nil(),
+            None,
+            0x100000,
+            0x101000..0x102000,
+        );
+        profile.set_frame_name_mapper(|name| Cow::Owned(name.to_uppercase()));
+        profile.add_thread(thread);
+
+        assert_json_eq!(
+            profile.to_chrome_trace(),
+            json!({
+                "traceEvents": [
+                    {
+                        "ph": "M",
+                        "name": "process_name",
+                        "pid": 123,
+                        "args": { "name": "test" },
+                    },
+                    {
+                        "ph": "M",
+                        "name": "thread_name",
+                        "pid": 123,
+                        "tid": 12345,
+                        "args": { "name": "Main" },
+                    },
+                    {
+                        "ph": "P",
+                        "name": "sample",
+                        "pid": 123,
+                        "tid": 12345,
+                        "ts": 1000.0,
+                        "sf": "2",
+                    },
+                    {
+                        "ph": "P",
+                        "name": "sample",
+                        "pid": 123,
+                        "tid": 12345,
+                        "ts": 2500.0,
+                        "sf": "3",
+                    },
+                ],
+                "stackFrames": {
+                    "1": { "name": "ROOT", "category": "test" },
+                    "2": { "name": "0x101234", "category": "libtest.so", "parent": "1" },
+                    "3": { "name": "0x5678", "category": "test", "parent": "1" },
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn merge() {
+        let interval = Duration::from_millis(1);
+        let make_profile = |start_time: Instant, root: &str, leaf: &str, extra_tid: u32| {
+            let mut profile =
+                ProfileBuilder::new(start_time, SystemTime::now(), "test", 123, interval);
+            let mut thread = ThreadBuilder::new(123, 12345, start_time, true, false);
+            thread.set_name("Main");
+            let frames = vec![thread.intern_string(root), thread.intern_string(leaf)];
+            thread.add_sample(
+                start_time + Duration::from_millis(1),
+                frames.into_iter().map(Frame::Label),
+                Duration::ZERO,
+            );
+            thread.add_marker(
+                "Request",
+                TextMarker(leaf.to_string()),
+                MarkerTiming::Instant(start_time + Duration::from_millis(1)),
+            );
+            profile.add_thread(thread);
+            let mut thread = ThreadBuilder::new(123, extra_tid, start_time, false, false);
+            thread.add_sample(
+                start_time + Duration::from_millis(2),
+                std::iter::once(Frame::Address(0x1234)),
+                Duration::ZERO,
+            );
+            profile.add_thread(thread);
+            profile
+        };
+
+        let start_time = Instant::now();
+        let mut profile = make_profile(start_time, "main", "first", 12346);
+        // The second profile has a different start time, and interns its
+        // strings in a different order.
+        let other_start_time = start_time + Duration::from_secs(60);
+        let other = make_profile(other_start_time, "second", "main", 12347);
+        profile.merge(other, Duration::from_millis(10));
+
+        assert_eq!(
+            profile.to_folded(),
+            "Main (pid 123, tid 12345);main;first 1\n\
+             Main (pid 123, tid 12345);second;main 1\n\
+             Thread <12346> (pid 123, tid 12346);0x1234 1\n\
+             Thread <12347> (pid 123, tid 12347);0x1234 1\n"
+        );
+        assert_eq!(profile.stats().sample_count, 4);
+
+        let json = serde_json::to_value(profile.to_serializable()).unwrap();
+        let main_thread = json["threads"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|thread| thread["tid"] == 12345)
+            .unwrap();
+        let sample_times: Vec<&Value> = main_thread["samples"]["data"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|sample| &sample[1])
+            .collect();
+        assert_eq!(sample_times, [1.0, 11.0]);
+        let marker_times: Vec<&Value> = main_thread["markers"]["data"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|marker| &marker[1])
+            .collect();
+        assert_eq!(marker_times, [1.0, 11.0]);
+    }
+
+    #[test]
+    fn merge_sample_before_start() {
+        let interval = Duration::from_millis(1);
+        let start_time = Instant::now();
+        let mut profile = ProfileBuilder::new(start_time, SystemTime::now(), "test", 123, interval);
+
+        // `other` has a sample from before its start time, e.g. from a thread
+        // which was already running when the recording started.
+        let other_start_time = start_time + Duration::from_secs(60);
+        let mut other =
+            ProfileBuilder::new(other_start_time, SystemTime::now(), "test", 123, interval);
+        let sample_time = other_start_time - Duration::from_millis(5);
+        let mut thread = ThreadBuilder::new(123, 12345, sample_time, true, false);
+        let main = Frame::Label(thread.intern_string("main"));
+        thread.add_sample(sample_time, std::iter::once(main), Duration::ZERO);
+        other.add_thread(thread);
+        profile.merge(other, Duration::from_millis(10));
+
+        let json = serde_json::to_value(profile.to_serializable()).unwrap();
+        let thread = &json["threads"][0];
+        assert_eq!(thread["registerTime"], 5.0);
+        assert_eq!(thread["samples"]["data"][0][1], 5.0);
+    }
+
+    #[test]
+    fn normalize_start() {
+        let start_time = Instant::now();
+        let start_time_system = SystemTime::UNIX_EPOCH + Duration::from_millis(1636162232627);
+        let mut profile = ProfileBuilder::new(
+            start_time,
+            start_time_system,
+            "test",
+            123,
+            Duration::from_millis(1),
+        );
+        let mut thread = ThreadBuilder::new(123, 12345, start_time, true, false);
+        thread.add_sample(
+            start_time + Duration::from_millis(7),
+            vec![].into_iter(),
+            Duration::ZERO,
+        );
+        thread.add_marker(
+            "Experimental",
+            TextMarker("Hello world!".to_string()),
+            MarkerTiming::Instant(start_time + Duration::from_millis(5)),
+        );
+        profile.add_thread(thread);
+        profile.normalize_start();
+
+        let json = serde_json::to_value(profile.to_serializable()).unwrap();
+        assert_eq!(json["meta"]["startTime"], 1636162232632.0);
+        let thread = &json["threads"][0];
+        assert_eq!(thread["samples"]["data"][0][1], 2.0);
+        assert_eq!(thread["markers"]["data"][0][1], 0.0);
+    }
+
+    #[test]
+    fn set_main_thread() {
+        let start_time = Instant::now();
+        let mut profile = ProfileBuilder::new(
+            start_time,
+            SystemTime::now(),
+            "test",
+            123,
+            Duration::from_millis(1),
+        );
+        // The thread which started first was created as the main thread, but
+        // it's a helper thread.
+        let mut helper = ThreadBuilder::new(123, 12345, start_time, true, false);
+        helper.set_name("Helper");
+        profile.add_thread(helper);
+        profile.set_main_thread(12346);
+        let mut main = ThreadBuilder::new(
+            123,
+            12346,
+            start_time + Duration::from_millis(1),
+            false,
+            false,
+        );
+        main.set_name("Main");
+        profile.add_thread(main);
+
+        let json = serde_json::to_value(profile.to_serializable()).unwrap();
+        let threads: Vec<(&Value, &Value)> = json["threads"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|thread| (&thread["tid"], &thread["name"]))
+            .collect();
+        assert_eq!(
+            threads,
+            [
+                (&json!(12346), &json!("GeckoMain")),
+                (&json!(12345), &json!("Helper"))
+            ]
+        );
+    }
 }
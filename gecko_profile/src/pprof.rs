@@ -0,0 +1,297 @@
+//! Conversion into the [pprof] `perftools.profiles.Profile` protobuf format.
+//!
+//! The protobuf message is encoded by hand; the format only needs varints and
+//! length-delimited fields, which doesn't warrant a protobuf dependency.
+//!
+//! [pprof]: https://github.com/google/pprof/blob/main/proto/profile.proto
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::time::UNIX_EPOCH;
+
+use crate::{FrameNameMapper, ProfileBuilder, ThreadBuilder};
+
+impl ProfileBuilder {
+    /// Converts the profile, including its subprocesses, into a pprof
+    /// `perftools.profiles.Profile` message, encoded as protobuf. The result can
+    /// be gzip-compressed and loaded by `go tool pprof` and other pprof consumers.
+    ///
+    /// Every sample has two values: `samples`/`count`, the sample's
+    /// [weight](crate::ThreadBuilder::add_sample_with_weight), and
+    /// `cpu`/`nanoseconds`, the sample's CPU delta. Samples are labeled with
+    /// their `pid`, `tid` and `thread` name. Each library becomes a mapping, and
+    /// address frames become locations in that mapping, so that pprof can
+    /// symbolicate them. Label frames become functions with the frame's name,
+    /// after applying the [frame name mapper](ProfileBuilder::set_frame_name_mapper).
+    pub fn to_pprof(&self) -> Vec<u8> {
+        let mut builder = PprofBuilder::default();
+        builder.add_process(self, None);
+
+        let mut profile = Vec::new();
+        let sample_type_count = builder.value_type("samples", "count");
+        let sample_type_cpu = builder.value_type("cpu", "nanoseconds");
+        encode_message_field(&mut profile, 1, &sample_type_count);
+        encode_message_field(&mut profile, 1, &sample_type_cpu);
+        for sample in &builder.samples {
+            encode_message_field(&mut profile, 2, sample);
+        }
+        for mapping in &builder.mappings {
+            encode_message_field(&mut profile, 3, mapping);
+        }
+        for location in &builder.locations {
+            encode_message_field(&mut profile, 4, location);
+        }
+        for function in &builder.functions {
+            encode_message_field(&mut profile, 5, function);
+        }
+        let time_nanos = self
+            .start_time_system
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_nanos() as u64);
+        encode_uint64_field(&mut profile, 9, time_nanos);
+        if let Some(end_time) = self.end_time {
+            let duration_nanos = end_time.saturating_duration_since(self.start_time);
+            encode_uint64_field(&mut profile, 10, duration_nanos.as_nanos() as u64);
+        }
+        let period_type = builder.value_type("cpu", "nanoseconds");
+        encode_message_field(&mut profile, 11, &period_type);
+        encode_uint64_field(&mut profile, 12, self.interval.as_nanos() as u64);
+        // The string table has to come last, because the fields above add to it.
+        for string in &builder.strings {
+            encode_bytes_field(&mut profile, 6, string.as_bytes());
+        }
+        profile
+    }
+}
+
+/// Collects the encoded sub-messages of the profile. IDs are 1-based, because
+/// pprof reserves 0 for "none".
+struct PprofBuilder {
+    strings: Vec<String>,
+    string_indexes: HashMap<String, u64>,
+    mappings: Vec<Vec<u8>>,
+    locations: Vec<Vec<u8>>,
+    functions: Vec<Vec<u8>>,
+    function_ids: HashMap<(u64, u64), u64>,
+    samples: Vec<Vec<u8>>,
+}
+
+impl Default for PprofBuilder {
+    fn default() -> Self {
+        PprofBuilder {
+            // The first string has to be the empty string.
+            strings: vec![String::new()],
+            string_indexes: HashMap::from([(String::new(), 0)]),
+            mappings: Vec::new(),
+            locations: Vec::new(),
+            functions: Vec::new(),
+            function_ids: HashMap::new(),
+            samples: Vec::new(),
+        }
+    }
+}
+
+impl PprofBuilder {
+    fn string(&mut self, s: &str) -> u64 {
+        if let Some(index) = self.string_indexes.get(s) {
+            return *index;
+        }
+        let index = self.strings.len() as u64;
+        self.strings.push(s.to_owned());
+        self.string_indexes.insert(s.to_owned(), index);
+        index
+    }
+
+    fn value_type(&mut self, value_type: &str, unit: &str) -> Vec<u8> {
+        let mut message = Vec::new();
+        encode_uint64_field(&mut message, 1, self.string(value_type));
+        encode_uint64_field(&mut message, 2, self.string(unit));
+        message
+    }
+
+    fn function(&mut self, name: &str, file: &str) -> u64 {
+        let name = self.string(name);
+        let file = self.string(file);
+        let next_id = self.functions.len() as u64 + 1;
+        let id = *self.function_ids.entry((name, file)).or_insert(next_id);
+        if id == next_id {
+            let mut message = Vec::new();
+            encode_uint64_field(&mut message, 1, id);
+            encode_uint64_field(&mut message, 2, name);
+            encode_uint64_field(&mut message, 3, name);
+            encode_uint64_field(&mut message, 4, file);
+            self.functions.push(message);
+        }
+        id
+    }
+
+    fn add_process(
+        &mut self,
+        process: &ProfileBuilder,
+        inherited_mapper: Option<&FrameNameMapper>,
+    ) {
+        let frame_name_mapper = process.frame_name_mapper.as_ref().or(inherited_mapper);
+
+        // (start, end, mapping id)
+        let mut lib_ranges = Vec::new();
+        for lib in &process.libs {
+            let id = self.mappings.len() as u64 + 1;
+            let filename = self.string(&lib.path.to_string_lossy());
+            let build_id = match &lib.code_id {
+                Some(code_id) => self.string(code_id.as_ref()),
+                None => self.string(&lib.debug_id.breakpad().to_string()),
+            };
+            let mut message = Vec::new();
+            encode_uint64_field(&mut message, 1, id);
+            encode_uint64_field(&mut message, 2, lib.start_address);
+            encode_uint64_field(&mut message, 3, lib.end_address);
+            encode_uint64_field(
+                &mut message,
+                4,
+                lib.start_address.saturating_sub(lib.base_address),
+            );
+            encode_uint64_field(&mut message, 5, filename);
+            encode_uint64_field(&mut message, 6, build_id);
+            self.mappings.push(message);
+            lib_ranges.push((lib.start_address, lib.end_address, id));
+        }
+
+        let mut threads: Vec<_> = process.threads.values().collect();
+        threads.sort_by_key(|thread| thread.index);
+        for thread in threads {
+            self.add_thread(thread, &lib_ranges, frame_name_mapper);
+        }
+
+        for subprocess in &process.subprocesses {
+            self.add_process(subprocess, frame_name_mapper);
+        }
+    }
+
+    fn add_thread(
+        &mut self,
+        thread: &ThreadBuilder,
+        lib_ranges: &[(u64, u64, u64)],
+        frame_name_mapper: Option<&FrameNameMapper>,
+    ) {
+        let frame_table = &thread.frame_table;
+        let strings = &thread.string_table.strings;
+
+        // Every frame of the thread becomes a location.
+        let mut location_ids = Vec::with_capacity(frame_table.frames.len());
+        for (frame_index, name_index) in frame_table.frames.iter().enumerate() {
+            let id = self.locations.len() as u64 + 1;
+            let name = &strings[name_index.0 as usize];
+            let source_location = frame_table.source_locations[frame_index]
+                .map(|(file, line)| (strings[file.0 as usize].as_str(), line));
+            let mut message = Vec::new();
+            encode_uint64_field(&mut message, 1, id);
+            let line = match frame_table.addresses[frame_index] {
+                Some(address) => {
+                    let mapping_id = lib_ranges
+                        .iter()
+                        .find(|(start, end, _)| *start <= address && address < *end)
+                        .map_or(0, |(_, _, id)| *id);
+                    encode_uint64_field(&mut message, 2, mapping_id);
+                    encode_uint64_field(&mut message, 3, address);
+                    // Without a source location, leave the address for pprof to symbolicate.
+                    source_location.map(|(file, line)| (self.function(name, file), line))
+                }
+                None => {
+                    let name = match frame_name_mapper {
+                        Some(mapper) => mapper.map(name),
+                        None => name.into(),
+                    };
+                    let (file, line) = source_location.unwrap_or(("", 0));
+                    Some((self.function(&name, file), line))
+                }
+            };
+            if let Some((function_id, line)) = line {
+                let mut line_message = Vec::new();
+                encode_uint64_field(&mut line_message, 1, function_id);
+                encode_uint64_field(&mut line_message, 2, line.into());
+                encode_message_field(&mut message, 4, &line_message);
+            }
+            self.locations.push(message);
+            location_ids.push(id);
+        }
+
+        let thread_name = match &thread.name {
+            Some(name) => name.clone(),
+            None => format!("Thread <{}>", thread.index),
+        };
+        let labels = [
+            self.label_str("thread", &thread_name),
+            self.label_num("tid", thread.index.into()),
+            self.label_num("pid", thread.pid.into()),
+        ];
+        for sample in &thread.samples.0 {
+            // pprof wants the leaf frame first.
+            let mut sample_location_ids = Vec::new();
+            let mut stack_index = sample.stack_index;
+            while let Some(index) = stack_index {
+                let (prefix, frame_index) = thread.stack_table.stacks[index];
+                sample_location_ids.push(location_ids[frame_index]);
+                stack_index = prefix;
+            }
+            let mut message = Vec::new();
+            encode_packed_field(&mut message, 1, &sample_location_ids);
+            let count = u64::try_from(sample.weight).unwrap_or(0);
+            encode_packed_field(&mut message, 2, &[count, sample.cpu_delta_us * 1000]);
+            for label in &labels {
+                encode_message_field(&mut message, 3, label);
+            }
+            self.samples.push(message);
+        }
+    }
+
+    fn label_str(&mut self, key: &str, value: &str) -> Vec<u8> {
+        let mut message = Vec::new();
+        encode_uint64_field(&mut message, 1, self.string(key));
+        encode_uint64_field(&mut message, 2, self.string(value));
+        message
+    }
+
+    fn label_num(&mut self, key: &str, value: u64) -> Vec<u8> {
+        let mut message = Vec::new();
+        encode_uint64_field(&mut message, 1, self.string(key));
+        encode_uint64_field(&mut message, 3, value);
+        message
+    }
+}
+
+const WIRE_TYPE_VARINT: u64 = 0;
+const WIRE_TYPE_LENGTH_DELIMITED: u64 = 2;
+
+fn encode_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+/// Encodes a varint field. Zero values are omitted, as in proto3.
+fn encode_uint64_field(buf: &mut Vec<u8>, field: u64, value: u64) {
+    if value != 0 {
+        encode_varint(buf, field << 3 | WIRE_TYPE_VARINT);
+        encode_varint(buf, value);
+    }
+}
+
+fn encode_bytes_field(buf: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+    encode_varint(buf, field << 3 | WIRE_TYPE_LENGTH_DELIMITED);
+    encode_varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+fn encode_message_field(buf: &mut Vec<u8>, field: u64, message: &[u8]) {
+    encode_bytes_field(buf, field, message);
+}
+
+fn encode_packed_field(buf: &mut Vec<u8>, field: u64, values: &[u64]) {
+    let mut packed = Vec::new();
+    for value in values {
+        encode_varint(&mut packed, *value);
+    }
+    encode_bytes_field(buf, field, &packed);
+}
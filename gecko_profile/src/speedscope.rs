@@ -0,0 +1,154 @@
+//! Conversion into the [speedscope] file format.
+//!
+//! [speedscope]: https://github.com/jlfwong/speedscope/wiki/Importing-from-custom-sources
+
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::time::Instant;
+
+use crate::{to_profile_timestamp, FrameNameMapper, ProfileBuilder, ThreadBuilder};
+
+impl ProfileBuilder {
+    /// Converts the profile, including its subprocesses, into speedscope's JSON
+    /// file format, for example for a quick look at the profile in
+    /// <https://www.speedscope.app/> or in the speedscope command line tool.
+    ///
+    /// Every thread becomes a `sampled` profile. A sample's weight is the time
+    /// until the thread's next sample, in milliseconds; the last sample of a
+    /// thread is weighted with the sampling interval. Frames are shared between
+    /// all profiles, with one frame per distinct name and source location.
+    /// Frame names are the ones the Gecko profile JSON would contain, i.e.
+    /// addresses are not symbolicated, and the
+    /// [frame name mapper](ProfileBuilder::set_frame_name_mapper) is applied
+    /// to labels.
+    pub fn to_speedscope(&self) -> Value {
+        let mut builder = SpeedscopeBuilder::default();
+        let interval_ms = self.interval.as_secs_f64() * 1000.0;
+        builder.add_process(self, None, self.start_time, interval_ms);
+        json!({
+            "$schema": "https://www.speedscope.app/file-format-schema.json",
+            "name": self.command_name,
+            "exporter": concat!("gecko_profile@", env!("CARGO_PKG_VERSION")),
+            "activeProfileIndex": 0,
+            "shared": {
+                "frames": builder.frames,
+            },
+            "profiles": builder.profiles,
+        })
+    }
+}
+
+#[derive(Default)]
+struct SpeedscopeBuilder {
+    frames: Vec<Value>,
+    frame_indexes: HashMap<(String, Option<(String, u32)>), usize>,
+    profiles: Vec<Value>,
+}
+
+impl SpeedscopeBuilder {
+    fn frame(&mut self, name: String, source_location: Option<(String, u32)>) -> usize {
+        let frames = &mut self.frames;
+        *self
+            .frame_indexes
+            .entry((name, source_location))
+            .or_insert_with_key(|(name, source_location)| {
+                let frame = match source_location {
+                    Some((file, line)) => json!({ "name": name, "file": file, "line": line }),
+                    None => json!({ "name": name }),
+                };
+                frames.push(frame);
+                frames.len() - 1
+            })
+    }
+
+    /// Timestamps of all processes are relative to the root process's start time,
+    /// so that the profiles line up in speedscope.
+    fn add_process(
+        &mut self,
+        process: &ProfileBuilder,
+        inherited_mapper: Option<&FrameNameMapper>,
+        profile_start: Instant,
+        interval_ms: f64,
+    ) {
+        let frame_name_mapper = process.frame_name_mapper.as_ref().or(inherited_mapper);
+
+        let mut threads: Vec<_> = process.threads.values().collect();
+        threads.sort_by_key(|thread| thread.index);
+        for thread in threads {
+            self.add_thread(thread, frame_name_mapper, profile_start, interval_ms);
+        }
+
+        for subprocess in &process.subprocesses {
+            self.add_process(subprocess, frame_name_mapper, profile_start, interval_ms);
+        }
+    }
+
+    fn add_thread(
+        &mut self,
+        thread: &ThreadBuilder,
+        frame_name_mapper: Option<&FrameNameMapper>,
+        profile_start: Instant,
+        interval_ms: f64,
+    ) {
+        let frame_table = &thread.frame_table;
+        let strings = &thread.string_table.strings;
+
+        let frame_indexes: Vec<usize> = (0..frame_table.frames.len())
+            .map(|frame_index| {
+                let name = &strings[frame_table.frames[frame_index].0 as usize];
+                let name = match (frame_table.addresses[frame_index], frame_name_mapper) {
+                    (None, Some(mapper)) => mapper.map(name).into_owned(),
+                    _ => name.clone(),
+                };
+                let source_location = frame_table.source_locations[frame_index]
+                    .map(|(file, line)| (strings[file.0 as usize].clone(), line));
+                self.frame(name, source_location)
+            })
+            .collect();
+
+        let samples = &thread.samples.0;
+        let times: Vec<f64> = samples
+            .iter()
+            .map(|sample| to_profile_timestamp(sample.timestamp, profile_start))
+            .collect();
+        let stacks: Vec<Vec<usize>> = samples
+            .iter()
+            .map(|sample| {
+                let mut stack = Vec::new();
+                let mut stack_index = sample.stack_index;
+                while let Some(index) = stack_index {
+                    let (prefix, frame_index) = thread.stack_table.stacks[index];
+                    stack.push(frame_indexes[frame_index]);
+                    stack_index = prefix;
+                }
+                // speedscope wants the root frame first.
+                stack.reverse();
+                stack
+            })
+            .collect();
+        let weights: Vec<f64> = times
+            .iter()
+            .enumerate()
+            .map(|(i, time)| match times.get(i + 1) {
+                Some(next_time) => next_time - time,
+                None => interval_ms,
+            })
+            .collect();
+        let start_value = times.first().copied().unwrap_or(0.0);
+        let end_value = times.last().map_or(start_value, |time| time + interval_ms);
+
+        let thread_name = match &thread.name {
+            Some(name) => name.clone(),
+            None => format!("Thread <{}>", thread.index),
+        };
+        self.profiles.push(json!({
+            "type": "sampled",
+            "name": format!("{} (pid {}, tid {})", thread_name, thread.pid, thread.index),
+            "unit": "milliseconds",
+            "startValue": start_value,
+            "endValue": end_value,
+            "samples": stacks,
+            "weights": weights,
+        }));
+    }
+}
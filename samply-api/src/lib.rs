@@ -162,12 +162,28 @@ pub(crate) fn to_debug_id(breakpad_id: &str) -> Result<DebugId, samply_symbols::
 #[derive(Clone, Copy)]
 pub struct Api<'a, 'h: 'a, H: FileAndPathHelper<'h>> {
     symbol_manager: &'a SymbolManager<'h, H>,
+    symbolication_concurrency: usize,
 }
 
 impl<'a, 'h: 'a, H: FileAndPathHelper<'h>> Api<'a, 'h, H> {
     /// Create a [`Api`] instance which uses the provided [`SymbolManager`].
     pub fn new(symbol_manager: &'a SymbolManager<'h, H>) -> Self {
-        Self { symbol_manager }
+        Self {
+            symbol_manager,
+            symbolication_concurrency: 1,
+        }
+    }
+
+    /// The maximum number of libraries whose symbols are loaded and looked up
+    /// concurrently by `/symbolicate/v5` requests. Defaults to 1, i.e. the
+    /// libraries are processed one after the other.
+    ///
+    /// With a higher value, the loading of symbol files for different libraries,
+    /// e.g. downloads from symbol servers, can overlap. The value is capped at the
+    /// number of available CPU cores.
+    pub fn with_symbolication_concurrency(mut self, concurrency: usize) -> Self {
+        self.symbolication_concurrency = concurrency;
+        self
     }
 
     /// This is the main API of this crate.
@@ -187,7 +203,8 @@ impl<'a, 'h: 'a, H: FileAndPathHelper<'h>> Api<'a, 'h, H> {
     ///    symbol information for that address.
     pub async fn query_api(self, request_url: &str, request_json_data: &str) -> String {
         if request_url == "/symbolicate/v5" {
-            let symbolicate_api = SymbolicateApi::new(self.symbol_manager);
+            let symbolicate_api = SymbolicateApi::new(self.symbol_manager)
+                .with_concurrency(self.symbolication_concurrency);
             symbolicate_api.query_api_json(request_json_data).await
         } else if request_url == "/source/v1" {
             let source_api = SourceApi::new(self.symbol_manager);
@@ -1,5 +1,6 @@
 use crate::to_debug_id;
 use crate::{api_file_path::to_api_file_path, error::Error};
+use futures_util::stream::{self, StreamExt};
 use samply_symbols::{FileAndPathHelper, FramesLookupResult, LibraryInfo, SymbolManager};
 use std::collections::HashMap;
 
@@ -13,12 +14,24 @@ use serde_json::json;
 
 pub struct SymbolicateApi<'a, 'h: 'a, H: FileAndPathHelper<'h>> {
     symbol_manager: &'a SymbolManager<'h, H>,
+    concurrency: usize,
 }
 
 impl<'a, 'h: 'a, H: FileAndPathHelper<'h>> SymbolicateApi<'a, 'h, H> {
     /// Create a [`SymbolicateApi`] instance which uses the provided [`SymbolManager`].
     pub fn new(symbol_manager: &'a SymbolManager<'h, H>) -> Self {
-        Self { symbol_manager }
+        Self {
+            symbol_manager,
+            concurrency: 1,
+        }
+    }
+
+    /// Process up to `concurrency` libraries at the same time. The value is
+    /// capped at the number of available CPU cores, to avoid oversubscription.
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        let max_concurrency = std::thread::available_parallelism().map_or(1, |n| n.get());
+        self.concurrency = concurrency.clamp(1, max_concurrency);
+        self
     }
 
     pub async fn query_api_json(&self, request_json: &str) -> String {
@@ -49,14 +62,18 @@ impl<'a, 'h: 'a, H: FileAndPathHelper<'h>> SymbolicateApi<'a, 'h, H> {
         &self,
         requested_addresses: HashMap<Lib, Vec<u32>>,
     ) -> HashMap<Lib, Result<LookedUpAddresses, samply_symbols::Error>> {
-        let mut symbolicated_addresses = HashMap::new();
-        for (lib, addresses) in requested_addresses.into_iter() {
-            let address_results = self
-                .symbolicate_requested_addresses_for_lib(&lib, addresses)
-                .await;
-            symbolicated_addresses.insert(lib, address_results);
-        }
-        symbolicated_addresses
+        // Libraries are independent of each other, so up to `concurrency` of them
+        // can be in flight at the same time.
+        stream::iter(requested_addresses)
+            .map(|(lib, addresses)| async move {
+                let address_results = self
+                    .symbolicate_requested_addresses_for_lib(&lib, addresses)
+                    .await;
+                (lib, address_results)
+            })
+            .buffer_unordered(self.concurrency)
+            .collect()
+            .await
     }
 
     async fn symbolicate_requested_addresses_for_lib(
@@ -13,9 +13,18 @@ use std::path::PathBuf;
 use std::pin::Pin;
 
 pub async fn query_api(request_url: &str, request_json: &str, symbol_directory: PathBuf) -> String {
+    query_api_with_concurrency(request_url, request_json, symbol_directory, 1).await
+}
+
+pub async fn query_api_with_concurrency(
+    request_url: &str,
+    request_json: &str,
+    symbol_directory: PathBuf,
+    symbolication_concurrency: usize,
+) -> String {
     let helper = Helper { symbol_directory };
     let symbol_manager = SymbolManager::with_helper(&helper);
-    let api = Api::new(&symbol_manager);
+    let api = Api::new(&symbol_manager).with_symbolication_concurrency(symbolication_concurrency);
     api.query_api(request_url, request_json).await
 }
 struct Helper {
@@ -295,6 +304,33 @@ fn win64_ci_v5_snapshot() {
     );
 }
 
+#[test]
+fn win64_ci_v5_concurrent() {
+    let request_json = r#"{
+            "memoryMap": [
+              ["firefox.pdb", "AA152DEB2D9B76084C4C44205044422E1"],
+              ["mozglue.pdb", "63C609072D3499F64C4C44205044422E1"],
+              ["unknown.pdb", "00000000000000000000000000000000A"]
+            ],
+            "stacks": [[[0, 204776], [1, 244290], [2, 1234], [0, 129423], [1, 237799]]]
+          }"#;
+    let serial = futures::executor::block_on(query_api_with_concurrency(
+        "/symbolicate/v5",
+        request_json,
+        fixtures_dir().join("win64-ci"),
+        1,
+    ));
+    let concurrent = futures::executor::block_on(query_api_with_concurrency(
+        "/symbolicate/v5",
+        request_json,
+        fixtures_dir().join("win64-ci"),
+        3,
+    ));
+    let serial: serde_json::Value = serde_json::from_str(&serial).unwrap();
+    let concurrent: serde_json::Value = serde_json::from_str(&concurrent).unwrap();
+    assert_json_eq!(concurrent, serial);
+}
+
 #[test]
 fn android32_v5_local() {
     compare_snapshot(
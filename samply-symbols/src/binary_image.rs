@@ -1,7 +1,10 @@
+use std::convert::TryFrom;
+use std::ops::Range;
+
 use debugid::DebugId;
 use object::{
     read::pe::{ImageNtHeaders, ImageOptionalHeader, PeFile, PeFile32, PeFile64},
-    Endianness, FileKind, Object, ReadRef,
+    Endianness, FileKind, Object, ObjectSection, ReadRef, SectionFlags, SectionKind,
 };
 
 use crate::{
@@ -92,6 +95,44 @@ impl<F: FileContents + 'static> BinaryImage<F> {
             .make_object(self.file_kind)
             .expect("We already parsed this before, why is it not parsing now?")
     }
+
+    /// Returns the ranges of relative addresses which are covered by executable
+    /// sections, sorted by start address.
+    ///
+    /// For PE binaries, this checks the `IMAGE_SCN_MEM_EXECUTE` section characteristic,
+    /// so data sections and non-executable import thunk sections are excluded. For
+    /// other formats, text sections are considered executable.
+    ///
+    /// Unwinders can use this, together with [`is_executable_address`], to reject
+    /// candidate return addresses which can't point to code, for example bogus
+    /// frames produced by frame pointer unwinding.
+    pub fn executable_ranges(&self) -> Vec<Range<u32>> {
+        let object = self.make_object();
+        let base = object.relative_address_base();
+        let mut ranges: Vec<Range<u32>> = object
+            .sections()
+            .filter(|section| match section.flags() {
+                SectionFlags::Coff { characteristics } => {
+                    characteristics & object::pe::IMAGE_SCN_MEM_EXECUTE != 0
+                }
+                _ => section.kind() == SectionKind::Text,
+            })
+            .filter_map(|section| {
+                let start = u32::try_from(section.address().checked_sub(base)?).ok()?;
+                let end = start.checked_add(u32::try_from(section.size()).ok()?)?;
+                Some(start..end)
+            })
+            .collect();
+        ranges.sort_by_key(|range| range.start);
+        ranges
+    }
+}
+
+/// Checks whether `address` is inside one of the `ranges`, which need to be
+/// sorted by start address, as returned by [`BinaryImage::executable_ranges`].
+pub fn is_executable_address(ranges: &[Range<u32>], address: u32) -> bool {
+    let index = ranges.partition_point(|range| range.start <= address);
+    index > 0 && address < ranges[index - 1].end
 }
 
 pub enum BinaryImageInner<F: FileContents + 'static> {
@@ -204,3 +245,20 @@ fn pe_info<'a, Pe: ImageNtHeaders, R: ReadRef<'a>>(pe: &PeFile<'a, Pe, R>) -> Pe
         pdb_name,
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_is_executable_address() {
+        let ranges = [0x1000..0x2000, 0x3000..0x3100];
+        assert!(!is_executable_address(&ranges, 0x500));
+        assert!(is_executable_address(&ranges, 0x1000));
+        assert!(is_executable_address(&ranges, 0x1fff));
+        assert!(!is_executable_address(&ranges, 0x2000));
+        assert!(is_executable_address(&ranges, 0x3050));
+        assert!(!is_executable_address(&ranges, 0x3100));
+        assert!(!is_executable_address(&[], 0x1000));
+    }
+}
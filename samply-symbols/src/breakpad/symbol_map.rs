@@ -258,6 +258,7 @@ impl<'a, T: FileContents> SymbolMapTrait for BreakpadSymbolMapInner<'a, T> {
                             next_symbol_address.checked_sub(symbol_address)
                         }),
                         name: info.name.to_string(),
+                        full_name: None,
                     },
                     frames: FramesLookupResult::Unavailable,
                 })
@@ -279,8 +280,10 @@ impl<'a, T: FileContents> SymbolMapTrait for BreakpadSymbolMapInner<'a, T> {
                         .map(ToString::to_string);
                     frames.push(FrameDebugInfo {
                         function: name,
+                        full_function: None,
                         file_path: file.map(SourceFilePath::from_breakpad_path),
                         line_number: Some(inlinee.call_line),
+                        is_inlined: depth > 0,
                     });
                     let inline_origin = inline_origins
                         .get_str(inlinee.origin_id)
@@ -298,8 +301,10 @@ impl<'a, T: FileContents> SymbolMapTrait for BreakpadSymbolMapInner<'a, T> {
                 };
                 frames.push(FrameDebugInfo {
                     function: name,
+                    full_function: None,
                     file_path: file.map(SourceFilePath::from_breakpad_path),
                     line_number,
+                    is_inlined: depth > 0,
                 });
                 frames.reverse();
 
@@ -308,6 +313,7 @@ impl<'a, T: FileContents> SymbolMapTrait for BreakpadSymbolMapInner<'a, T> {
                         address: symbol_address,
                         size: Some(info.size),
                         name: info.name.to_string(),
+                        full_name: None,
                     },
                     frames: FramesLookupResult::Available(frames),
                 })
@@ -1,23 +1,34 @@
 use std::marker::PhantomData;
+use std::sync::Arc;
 
 use crate::path_mapper::PathMapper;
 use crate::shared::FrameDebugInfo;
 use crate::{demangle, Error, SourceFilePath};
 use addr2line::fallible_iterator;
 use addr2line::gimli;
+use addr2line::{LookupContinuation, LookupResult, SplitDwarfLoad};
 use elsa::sync::FrozenVec;
 use fallible_iterator::FallibleIterator;
-use gimli::{EndianSlice, Reader, RunTimeEndian, SectionId};
+use gimli::{DwarfPackage, EndianSlice, Reader, RunTimeEndian, SectionId};
 use object::read::ReadRef;
 use object::{CompressedFileRange, CompressionFormat};
 
 pub fn get_frames<R: Reader>(
     address: u64,
     context: Option<&addr2line::Context<R>>,
+    dwarf_package: Option<&DwarfPackage<R>>,
     path_mapper: &mut PathMapper<()>,
 ) -> Option<Vec<FrameDebugInfo>> {
-    let frame_iter = context?.find_frames(address).ok()?;
-    let frames: Vec<_> = frame_iter
+    let mut lookup_result = context?.find_frames(address);
+    let frame_iter = loop {
+        match lookup_result {
+            LookupResult::Output(frame_iter) => break frame_iter.ok()?,
+            LookupResult::Load { load, continuation } => {
+                lookup_result = continuation.resume(load_split_dwarf(load, dwarf_package));
+            }
+        }
+    };
+    let mut frames: Vec<_> = frame_iter
         .map(|f| Ok(convert_stack_frame(f, &mut *path_mapper)))
         .collect()
         .ok()?;
@@ -25,10 +36,26 @@ pub fn get_frames<R: Reader>(
     if frames.is_empty() {
         None
     } else {
+        let inline_frame_count = frames.len() - 1;
+        for frame in &mut frames[..inline_frame_count] {
+            frame.is_inlined = true;
+        }
         Some(frames)
     }
 }
 
+/// Finds the split DWARF unit for a skeleton unit in the DWARF package (.dwp).
+/// Without the split unit, only the skeleton unit's line information is
+/// available, and there are no inline frames.
+/// Split units in individual .dwo files are not supported; `load.path` is ignored.
+fn load_split_dwarf<R: Reader>(
+    load: SplitDwarfLoad<R>,
+    dwarf_package: Option<&DwarfPackage<R>>,
+) -> Option<Arc<gimli::Dwarf<R>>> {
+    let dwarf = dwarf_package?.find_cu(load.dwo_id, &load.parent).ok()??;
+    Some(Arc::new(dwarf))
+}
+
 pub fn convert_stack_frame<R: gimli::Reader>(
     frame: addr2line::Frame<R>,
     path_mapper: &mut PathMapper<()>,
@@ -50,8 +77,10 @@ pub fn convert_stack_frame<R: gimli::Reader>(
 
     FrameDebugInfo {
         function,
+        full_function: None,
         file_path,
         line_number: frame.location.and_then(|l| l.line),
+        is_inlined: false,
     }
 }
 
@@ -68,7 +97,7 @@ pub enum SingleSectionData<'data, T: ReadRef<'data>> {
 pub fn try_get_section_data<'data, 'file, O, T>(
     data: T,
     file: &'file O,
-    section_id: SectionId,
+    section_name: &str,
 ) -> Option<SingleSectionData<'data, T>>
 where
     'data: 'file,
@@ -76,7 +105,6 @@ where
     T: ReadRef<'data>,
 {
     use object::ObjectSection;
-    let section_name = section_id.name();
     let (section, used_manual_zdebug_path) =
         if let Some(section) = file.section_by_name(section_name) {
             (section, false)
@@ -164,7 +192,7 @@ impl Addr2lineContextData {
         &'ctxdata self,
         data: R,
         obj: &'file O,
-        section_id: SectionId,
+        section_name: &str,
         endian: RunTimeEndian,
     ) -> EndianSlice<'ctxdata, RunTimeEndian>
     where
@@ -174,7 +202,7 @@ impl Addr2lineContextData {
         O: object::Object<'data, 'file>,
         R: ReadRef<'data>,
     {
-        let slice: &[u8] = match try_get_section_data(data, obj, section_id) {
+        let slice: &[u8] = match try_get_section_data(data, obj, section_name) {
             Some(SingleSectionData::Owned(section_data)) => {
                 self.uncompressed_section_data.push_get(section_data)
             }
@@ -200,20 +228,57 @@ impl Addr2lineContextData {
         O: object::Object<'data, 'file>,
         R: ReadRef<'data>,
     {
-        let e = if obj.is_little_endian() {
-            gimli::RunTimeEndian::Little
-        } else {
-            gimli::RunTimeEndian::Big
-        };
-        let mut dwarf = gimli::Dwarf::load(|s| Ok(self.sect(data, obj, s, e)))
+        let e = endian(obj);
+        let mut dwarf = gimli::Dwarf::load(|s| Ok(self.sect(data, obj, s.name(), e)))
             .map_err(Error::Addr2lineContextCreationError)?;
         if let (Some(sup_obj), Some(sup_data)) = (sup_obj, sup_data) {
             dwarf
-                .load_sup(|s| Ok(self.sect(sup_data, sup_obj, s, e)))
+                .load_sup(|s| Ok(self.sect(sup_data, sup_obj, s.name(), e)))
                 .map_err(Error::Addr2lineContextCreationError)?;
         }
         let context =
             addr2line::Context::from_dwarf(dwarf).map_err(Error::Addr2lineContextCreationError)?;
         Ok(context)
     }
+
+    /// Loads the split DWARF units from a DWARF package file (.dwp), for
+    /// binaries which were compiled with -gsplit-dwarf.
+    pub fn make_dwarf_package<'data, 'ctxdata, 'file, O, R>(
+        &'ctxdata self,
+        data: R,
+        obj: &'file O,
+    ) -> Result<DwarfPackage<EndianSlice<'ctxdata, RunTimeEndian>>, gimli::Error>
+    where
+        'data: 'file,
+        'data: 'ctxdata,
+        'ctxdata: 'file,
+        O: object::Object<'data, 'file>,
+        R: ReadRef<'data>,
+    {
+        let e = endian(obj);
+        let empty = EndianSlice::new(&[][..], e);
+        DwarfPackage::load(
+            |s| match s.dwo_name() {
+                // Type units aren't needed for address lookups. Also, GNU dwp writes
+                // an index with zero slots if there are no type units, which gimli
+                // rejects as invalid.
+                _ if s == SectionId::DebugTuIndex => Ok(empty),
+                Some(section_name) => Ok(self.sect(data, obj, section_name, e)),
+                None => Ok(empty),
+            },
+            empty,
+        )
+    }
+}
+
+fn endian<'data, 'file, O>(obj: &'file O) -> RunTimeEndian
+where
+    'data: 'file,
+    O: object::Object<'data, 'file>,
+{
+    if obj.is_little_endian() {
+        RunTimeEndian::Little
+    } else {
+        RunTimeEndian::Big
+    }
 }
@@ -33,7 +33,13 @@ where
     if let Some(supplementary_file) =
         try_to_load_supplementary_file(&file_location, &elf_file, helper).await
     {
-        let owner = ElfSymbolMapData::new(file_contents, Some(supplementary_file), file_kind, None);
+        let owner = ElfSymbolMapData::new(
+            file_contents,
+            Some(supplementary_file),
+            None,
+            file_kind,
+            None,
+        );
         let symbol_map = GenericSymbolMap::new(owner)?;
         return Ok(SymbolMap::new(file_location, Box::new(symbol_map)));
     }
@@ -45,7 +51,11 @@ where
         return Ok(symbol_map);
     }
 
-    let owner = ElfSymbolMapData::new(file_contents, None, file_kind, None);
+    // If the binary was compiled with split DWARF, most of the debug info is in a
+    // DWARF package file next to it.
+    let dwp_file = try_to_load_dwp_file(&file_location, helper).await;
+
+    let owner = ElfSymbolMapData::new(file_contents, None, dwp_file, file_kind, None);
     let symbol_map = GenericSymbolMap::new(owner)?;
     Ok(SymbolMap::new(file_location, Box::new(symbol_map)))
 }
@@ -109,7 +119,7 @@ where
         return Err(Error::DebugLinkCrcMismatch(actual_crc, expected_crc));
     }
 
-    let owner = ElfSymbolMapData::new(file_contents, None, file_kind, Some(debug_id));
+    let owner = ElfSymbolMapData::new(file_contents, None, None, file_kind, Some(debug_id));
     let symbol_map = GenericSymbolMap::new(owner)?;
     Ok(SymbolMap::new(
         original_file_location.clone(),
@@ -254,6 +264,22 @@ where
     None
 }
 
+async fn try_to_load_dwp_file<'h, H, F>(
+    original_file_location: &H::FL,
+    helper: &'h H,
+) -> Option<FileContentsWrapper<F>>
+where
+    H: FileAndPathHelper<'h, F = F>,
+    F: FileContents + 'static,
+{
+    let dwp_location = original_file_location.location_for_dwp()?;
+    let file_contents = FileContentsWrapper::new(helper.load_file(dwp_location).await.ok()?);
+    let dwp_file = File::parse(&file_contents).ok()?;
+    // Only use files which actually are DWARF packages.
+    dwp_file.section_by_name(".debug_cu_index")?;
+    Some(file_contents)
+}
+
 fn try_get_symbol_map_from_mini_debug_info<'data, R: ReadRef<'data>, FL: FileLocation>(
     elf_file: &File<'data, R>,
     file_kind: FileKind,
@@ -265,7 +291,7 @@ fn try_get_symbol_map_from_mini_debug_info<'data, R: ReadRef<'data>, FL: FileLoc
     let mut objdata = Vec::new();
     lzma_rs::xz_decompress(&mut cursor, &mut objdata).ok()?;
     let file_contents = FileContentsWrapper::new(objdata);
-    let owner = ElfSymbolMapData::new(file_contents, None, file_kind, None);
+    let owner = ElfSymbolMapData::new(file_contents, None, None, file_kind, None);
     let symbol_map = GenericSymbolMap::new(owner).ok()?;
     Some(SymbolMap::new(
         debug_file_location.clone(),
@@ -279,6 +305,7 @@ where
 {
     file_data: FileContentsWrapper<T>,
     supplementary_file_data: Option<FileContentsWrapper<T>>,
+    dwp_file_data: Option<FileContentsWrapper<T>>,
     file_kind: FileKind,
     override_debug_id: Option<DebugId>,
 }
@@ -287,12 +314,14 @@ impl<T: FileContents> ElfSymbolMapData<T> {
     pub fn new(
         file_data: FileContentsWrapper<T>,
         supplementary_file_data: Option<FileContentsWrapper<T>>,
+        dwp_file_data: Option<FileContentsWrapper<T>>,
         file_kind: FileKind,
         override_debug_id: Option<DebugId>,
     ) -> Self {
         Self {
             file_data,
             supplementary_file_data,
+            dwp_file_data,
             file_kind,
             override_debug_id,
         }
@@ -310,6 +339,13 @@ impl<T: FileContents + 'static> SymbolMapDataOuterTrait for ElfSymbolMapData<T>
             ),
             None => None,
         };
+        let dwp_object = match self.dwp_file_data.as_ref() {
+            Some(dwp_file_data) => Some(
+                File::parse(dwp_file_data)
+                    .map_err(|e| Error::ObjectParseError(self.file_kind, e))?,
+            ),
+            None => None,
+        };
         let debug_id = if let Some(debug_id) = self.override_debug_id {
             debug_id
         } else {
@@ -319,9 +355,11 @@ impl<T: FileContents + 'static> SymbolMapDataOuterTrait for ElfSymbolMapData<T>
         let object = ObjectSymbolMapDataMid::new(
             object,
             supplementary_object,
+            dwp_object,
             ElfFunctionAddressesComputer,
             &self.file_data,
             self.supplementary_file_data.as_ref(),
+            self.dwp_file_data.as_ref(),
             None,
             debug_id,
         );
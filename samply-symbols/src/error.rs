@@ -160,6 +160,9 @@ pub enum Error {
 
     #[error("Could not create addr2line Context: {0}")]
     Addr2lineContextCreationError(#[source] gimli::Error),
+
+    #[error("Could not extract the compressed PDB file from the cab archive: {0}")]
+    CabExtractionError(#[source] std::io::Error),
 }
 
 fn format_errors(errors: &[Error]) -> String {
@@ -270,6 +273,7 @@ impl Error {
             Error::SrcSrvParseError(_) => "SrcSrvParseError",
             Error::SrcSrvEvalError(_) => "SrcSrvEvalError",
             Error::Addr2lineContextCreationError(_) => "Addr2lineContextCreationError",
+            Error::CabExtractionError(_) => "CabExtractionError",
         }
     }
 }
@@ -49,7 +49,7 @@ impl<'a> ExternalFileMemberContext<'a> {
     ) -> Option<Vec<FrameDebugInfo>> {
         let symbol_address = self.symbol_addresses.get(symbol_name)?;
         let address = symbol_address + offset_from_symbol as u64;
-        get_frames(address, self.context.as_ref(), path_mapper)
+        get_frames(address, self.context.as_ref(), None, path_mapper)
     }
 }
 
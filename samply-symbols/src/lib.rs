@@ -239,13 +239,15 @@ mod error;
 mod external_file;
 mod macho;
 mod mapped_path;
+mod multi_symbol_map;
 mod path_mapper;
 mod shared;
+mod simplify_name;
 mod symbol_map;
 mod symbol_map_object;
 mod windows;
 
-pub use crate::binary_image::BinaryImage;
+pub use crate::binary_image::{is_executable_address, BinaryImage};
 pub use crate::cache::{FileByteSource, FileContentsWithChunkedCaching};
 pub use crate::compact_symbol_table::CompactSymbolTable;
 pub use crate::debugid_util::{debug_id_for_object, DebugIdExt};
@@ -253,6 +255,7 @@ pub use crate::error::Error;
 pub use crate::external_file::{load_external_file, ExternalFileSymbolMap};
 pub use crate::macho::FatArchiveMember;
 pub use crate::mapped_path::MappedPath;
+pub use crate::multi_symbol_map::MultiSymbolMap;
 pub use crate::shared::{
     relative_address_base, AddressInfo, CandidatePathInfo, CodeId, ElfBuildId,
     ExternalFileAddressInFileRef, ExternalFileAddressRef, ExternalFileRef, FileAndPathHelper,
@@ -260,7 +263,10 @@ pub use crate::shared::{
     FileLocation, FrameDebugInfo, FramesLookupResult, LibraryInfo, MultiArchDisambiguator,
     OptionallySendFuture, PeCodeId, SourceFilePath, SymbolInfo,
 };
-pub use crate::symbol_map::SymbolMap;
+pub use crate::simplify_name::simplify_function_name;
+pub use crate::symbol_map::{
+    FunctionNameMatch, PathRewriteRule, SymbolMap, UnnamedInlineFramePolicy,
+};
 
 pub struct SymbolManager<'h, H: FileAndPathHelper<'h>> {
     helper: &'h H,
@@ -623,6 +629,8 @@ where
             }
         } else if windows::is_pdb_file(&file_contents) {
             windows::get_symbol_map_for_pdb(file_contents, file_location)
+        } else if windows::is_cab_file(&file_contents) {
+            windows::get_symbol_map_for_cab_compressed_pdb(file_contents, file_location)
         } else if breakpad::is_breakpad_file(&file_contents) {
             breakpad::get_symbol_map_for_breakpad_sym(file_contents, file_location)
         } else {
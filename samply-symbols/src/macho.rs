@@ -282,9 +282,11 @@ impl<T: FileContents + 'static> SymbolMapDataOuterTrait for DyldCacheFileData<T>
         let object = ObjectSymbolMapDataMid::new(
             object,
             None,
+            None,
             function_addresses_computer,
             &self.root_file_data,
             None,
+            None,
             arch,
             debug_id,
         );
@@ -337,9 +339,11 @@ impl<T: FileContents + 'static> SymbolMapDataOuterTrait for MachSymbolMapData<T>
         let object = ObjectSymbolMapDataMid::new(
             macho_file,
             None,
+            None,
             function_addresses_computer,
             &self.file_data,
             None,
+            None,
             arch,
             debug_id,
         );
@@ -383,9 +387,11 @@ impl<T: FileContents + 'static> SymbolMapDataOuterTrait for MachOFatArchiveMembe
         let object = ObjectSymbolMapDataMid::new(
             macho_file,
             None,
+            None,
             function_addresses_computer,
             range_data,
             None,
+            None,
             arch,
             debug_id,
         );
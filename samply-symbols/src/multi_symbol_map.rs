@@ -0,0 +1,95 @@
+use std::borrow::Cow;
+
+use crate::shared::AddressInfo;
+use crate::{FileLocation, SymbolMap};
+
+/// Combines the symbol maps of several debug files which together describe a
+/// single binary, for example when the debug info of a binary is split across
+/// one PDB file per static library.
+///
+/// Lookups are dispatched to the symbol map whose symbols cover the address. If
+/// more than one symbol map covers an address, the one that comes first in the
+/// list passed to [`MultiSymbolMap::new`] wins, so that every address is only
+/// attributed once.
+pub struct MultiSymbolMap<FL: FileLocation> {
+    symbol_maps: Vec<SymbolMap<FL>>,
+}
+
+impl<FL: FileLocation> MultiSymbolMap<FL> {
+    /// Create a `MultiSymbolMap` from the given symbol maps, in order of priority.
+    pub fn new(symbol_maps: Vec<SymbolMap<FL>>) -> Self {
+        Self { symbol_maps }
+    }
+
+    pub fn symbol_maps(&self) -> &[SymbolMap<FL>] {
+        &self.symbol_maps
+    }
+
+    /// The number of symbols returned by [`MultiSymbolMap::iter_symbols`].
+    pub fn symbol_count(&self) -> usize {
+        self.iter_symbols().count()
+    }
+
+    /// Iterate over the symbols of all symbol maps, ordered by address. If
+    /// several symbol maps have symbols at the same address, only the symbols
+    /// from the first of them are returned.
+    pub fn iter_symbols(&self) -> Box<dyn Iterator<Item = (u32, Cow<'_, str>)> + '_> {
+        let mut symbols: Vec<(u32, usize, Cow<'_, str>)> = self
+            .symbol_maps
+            .iter()
+            .enumerate()
+            .flat_map(|(map_index, symbol_map)| {
+                symbol_map
+                    .iter_symbols()
+                    .map(move |(address, name)| (address, map_index, name))
+            })
+            .collect();
+        symbols.sort_by_key(|(address, map_index, _)| (*address, *map_index));
+        // Keep the symbols from the first symbol map which has the address.
+        let mut first_map_for_address = None;
+        symbols.retain(|(address, map_index, _)| match first_map_for_address {
+            Some((a, m)) if a == *address => m == *map_index,
+            _ => {
+                first_map_for_address = Some((*address, *map_index));
+                true
+            }
+        });
+        Box::new(
+            symbols
+                .into_iter()
+                .map(|(address, _, name)| (address, name)),
+        )
+    }
+
+    /// Look up symbol information by "relative address".
+    ///
+    /// The result comes from the first symbol map whose containing symbol
+    /// covers the address. If no symbol map has a symbol of known size which
+    /// covers it, the symbol map with the closest preceding symbol is used.
+    pub fn lookup(&self, address: u32) -> Option<AddressInfo> {
+        let mut closest: Option<AddressInfo> = None;
+        for symbol_map in &self.symbol_maps {
+            let info = match symbol_map.lookup(address) {
+                Some(info) => info,
+                None => continue,
+            };
+            let symbol = &info.symbol;
+            if let Some(size) = symbol.size {
+                if u64::from(address) < u64::from(symbol.address) + u64::from(size) {
+                    return Some(info);
+                }
+            }
+            match &closest {
+                Some(closest_info) if closest_info.symbol.address >= symbol.address => {}
+                _ => closest = Some(info),
+            }
+        }
+        closest
+    }
+
+    /// Look up symbol information for the return address of a caller frame.
+    /// See [`SymbolMap::lookup_return_address`].
+    pub fn lookup_return_address(&self, return_address: u32) -> Option<AddressInfo> {
+        self.lookup(return_address.checked_sub(1)?)
+    }
+}
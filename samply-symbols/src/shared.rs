@@ -401,10 +401,18 @@ pub trait FileContents: Send + Sync {
 pub struct FrameDebugInfo {
     /// The function name for this frame, if known.
     pub function: Option<String>,
+    /// The full function name, if `function` has been shortened by name simplification.
+    /// See [`SymbolMap::set_simplify_names`](crate::SymbolMap::set_simplify_names).
+    pub full_function: Option<String>,
     /// The [`SourceFilePath`] for this frame, if known.
     pub file_path: Option<SourceFilePath>,
     /// The line number for this frame, if known.
     pub line_number: Option<u32>,
+    /// Whether this frame was inlined into its caller, i.e. whether it's one of
+    /// the inline frames rather than the outermost, non-inlined function.
+    /// Frames are ordered from inside to outside, so this is true for all
+    /// frames except the last one.
+    pub is_inlined: bool,
 }
 
 /// A trait which abstracts away the token that's passed to the [`FileAndPathHelper::load_file`]
@@ -429,6 +437,17 @@ pub trait FileLocation: Clone + Display {
     /// a corresponding PDB file, based on an absolute PDB path found in the binary.
     fn location_for_pdb_from_binary(&self, pdb_path_in_binary: &str) -> Option<Self>;
 
+    /// Called on the location of an ELF binary in order to create a location for
+    /// its DWARF package file, which contains the split DWARF units of binaries
+    /// that were compiled with `-gsplit-dwarf`. By convention, the DWARF package
+    /// is next to the binary and has the binary's file name plus `.dwp`.
+    ///
+    /// The default implementation returns `None`, so split DWARF units aren't
+    /// found.
+    fn location_for_dwp(&self) -> Option<Self> {
+        None
+    }
+
     /// Called on the location of a debug file in order to create a location for
     /// a source file. `source_file_path` is the path to the source file as written
     /// down in the debug file. This is usually an absolute path.
@@ -613,6 +632,9 @@ pub struct SymbolInfo {
     pub size: Option<u32>,
     /// The function name, demangled.
     pub name: String,
+    /// The full function name, if `name` has been shortened by name simplification.
+    /// See [`SymbolMap::set_simplify_names`](crate::SymbolMap::set_simplify_names).
+    pub full_name: Option<String>,
 }
 
 /// The lookup result for an address.
@@ -0,0 +1,163 @@
+use std::borrow::Cow;
+
+/// Generic argument lists which are longer than this are replaced with `<...>`.
+const MAX_GENERIC_ARGS_LEN: usize = 40;
+
+/// Makes a demangled function name more readable, mostly for Rust functions.
+///
+///  - Closure markers are shortened: `{{closure}}` becomes `{closure}`.
+///  - Generic argument lists longer than 40 bytes are replaced with `<...>`.
+///    Qualified paths such as `<T as Trait>` are kept.
+///  - Hash suffixes are removed: both legacy `::h0123456789abcdef` suffixes and
+///    `[0123abcd]` crate disambiguators.
+///
+/// Returns a borrowed string if the name did not need to be changed.
+pub fn simplify_function_name(name: &str) -> Cow<'_, str> {
+    if !name.contains(['<', '{', '[']) && !name.contains("::h") {
+        return Cow::Borrowed(name);
+    }
+
+    let mut result = String::with_capacity(name.len());
+    let mut rest = name;
+    while let Some(c) = rest.chars().next() {
+        if let Some(after) = rest.strip_prefix("{{closure}}") {
+            result.push_str("{closure}");
+            rest = after;
+        } else if let Some(len) = hash_suffix_len(rest) {
+            rest = &rest[len..];
+        } else if let Some(len) = crate_disambiguator_len(rest, &result) {
+            rest = &rest[len..];
+        } else if c == '<' && is_generic_args_start(&result) {
+            match matching_angle_bracket(rest) {
+                Some(end) if end - 1 > MAX_GENERIC_ARGS_LEN => {
+                    result.push_str("<...>");
+                    rest = &rest[end + 1..];
+                }
+                Some(end) => {
+                    // Short enough. Keep the list, but still simplify its contents.
+                    result.push('<');
+                    result.push_str(&simplify_function_name(&rest[1..end]));
+                    result.push('>');
+                    rest = &rest[end + 1..];
+                }
+                None => {
+                    result.push_str(rest);
+                    rest = "";
+                }
+            }
+        } else {
+            result.push(c);
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+
+    if result == name {
+        Cow::Borrowed(name)
+    } else {
+        Cow::Owned(result)
+    }
+}
+
+/// Returns the length of a `::h0123456789abcdef` hash suffix at the start of `s`.
+/// The hash needs to be followed by the end of a path segment.
+fn hash_suffix_len(s: &str) -> Option<usize> {
+    let hash = s.strip_prefix("::h")?;
+    let hex_len = hash.bytes().take_while(u8::is_ascii_hexdigit).count();
+    if hex_len != 16 {
+        return None;
+    }
+    match hash[hex_len..].chars().next() {
+        None | Some('>' | ',' | ' ' | ')' | ':') => Some(3 + hex_len),
+        Some(_) => None,
+    }
+}
+
+/// Returns the length of a `[0123abcd]` crate disambiguator at the start of `s`,
+/// if it directly follows an identifier in the already-processed `preceding` text.
+fn crate_disambiguator_len(s: &str, preceding: &str) -> Option<usize> {
+    if !preceding.ends_with(is_ident_char) {
+        return None;
+    }
+    let inner = s.strip_prefix('[')?;
+    let hex_len = inner.bytes().take_while(u8::is_ascii_hexdigit).count();
+    if hex_len == 0 || !inner[hex_len..].starts_with(']') {
+        return None;
+    }
+    Some(hex_len + 2)
+}
+
+/// Whether a `<` after `preceding` opens a list of generic arguments, as opposed
+/// to a qualified path (`<T as Trait>::method`) or a C++ comparison operator.
+fn is_generic_args_start(preceding: &str) -> bool {
+    preceding.ends_with(is_ident_char) && !preceding.ends_with("operator")
+}
+
+fn is_ident_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// `s` starts with `<`. Returns the index of the matching `>`.
+fn matching_angle_bracket(s: &str) -> Option<usize> {
+    let mut depth = 0usize;
+    for (i, b) in s.bytes().enumerate() {
+        match b {
+            b'<' => depth += 1,
+            b'>' if i > 0 && s.as_bytes()[i - 1] == b'-' => {
+                // Part of a "->" in a function signature.
+            }
+            b'>' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_simplify_function_name() {
+        assert_eq!(
+            simplify_function_name("core::fmt::write"),
+            Cow::Borrowed("core::fmt::write")
+        );
+        assert_eq!(
+            simplify_function_name("std::rt::lang_start::{{closure}}::{{closure}}"),
+            "std::rt::lang_start::{closure}::{closure}"
+        );
+        assert_eq!(
+            simplify_function_name("core::fmt::write::h0123456789abcdef"),
+            "core::fmt::write"
+        );
+        assert_eq!(
+            simplify_function_name("std[5f5c2d1ba9b2a0b3]::rt::lang_start_internal"),
+            "std::rt::lang_start_internal"
+        );
+        assert_eq!(
+            simplify_function_name("alloc::vec::Vec<u8>::push"),
+            "alloc::vec::Vec<u8>::push"
+        );
+        assert_eq!(
+            simplify_function_name(
+                "hashbrown::map::HashMap<alloc::string::String, alloc::vec::Vec<u32>, std::collections::hash::map::RandomState>::insert"
+            ),
+            "hashbrown::map::HashMap<...>::insert"
+        );
+        assert_eq!(
+            simplify_function_name(
+                "<alloc::vec::Vec<T,A> as core::ops::drop::Drop>::drop::h0123456789abcdef"
+            ),
+            "<alloc::vec::Vec<T,A> as core::ops::drop::Drop>::drop"
+        );
+        assert_eq!(
+            simplify_function_name("operator<<(std::ostream&, int)"),
+            Cow::Borrowed("operator<<(std::ostream&, int)")
+        );
+    }
+}
@@ -1,13 +1,89 @@
 use std::borrow::Cow;
+use std::collections::HashMap;
+use std::ops::Range;
 
 use debugid::DebugId;
 use yoke::{Yoke, Yokeable};
 
-use crate::{shared::AddressInfo, Error, FileLocation};
+use crate::shared::{AddressInfo, FrameDebugInfo, FramesLookupResult, SourceFilePath};
+use crate::simplify_name::simplify_function_name;
+use crate::{Error, FileLocation};
 
 pub struct SymbolMap<FL: FileLocation> {
     debug_file_location: FL,
     pub(crate) inner: Box<dyn SymbolMapTrait>,
+    simplify_names: bool,
+    unnamed_inline_frames: UnnamedInlineFramePolicy,
+    max_inline_depth: Option<usize>,
+    path_rewrite_rules: Vec<PathRewriteRule>,
+    address_overrides: HashMap<u32, AddressInfo>,
+}
+
+/// What [`SymbolMap::lookup`] should do with inline frames whose function name
+/// could not be determined.
+///
+/// This happens for example in PDB files when the inlinee's type information
+/// is missing, so that no name can be formatted for the inline site.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UnnamedInlineFramePolicy {
+    /// Use [`UnnamedInlineFramePolicy::PLACEHOLDER_NAME`] as the function name.
+    Placeholder,
+    /// Remove the frame. The line information of the frame is lost.
+    Skip,
+    /// Keep the frame without a function name.
+    #[default]
+    Keep,
+}
+
+impl UnnamedInlineFramePolicy {
+    /// The function name used by [`UnnamedInlineFramePolicy::Placeholder`].
+    pub const PLACEHOLDER_NAME: &'static str = "<unknown inlined function>";
+}
+
+/// Replaces a prefix of the raw source file paths which [`SymbolMap::lookup`]
+/// returns, e.g. to keep the user's home directory out of shared profiles.
+/// See [`SymbolMap::set_path_rewrite_rules`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PathRewriteRule {
+    /// The prefix to replace, e.g. `"/Users/alice/"`. It is compared literally,
+    /// including the case and the path separators.
+    pub prefix: String,
+    /// The replacement for the prefix, e.g. `"<home>/"`. Use an empty string to
+    /// strip the prefix.
+    pub replacement: String,
+}
+
+impl PathRewriteRule {
+    pub fn new(prefix: impl Into<String>, replacement: impl Into<String>) -> Self {
+        Self {
+            prefix: prefix.into(),
+            replacement: replacement.into(),
+        }
+    }
+
+    /// Returns the rewritten path, or `None` if `path` doesn't start with the prefix.
+    pub fn apply(&self, path: &str) -> Option<String> {
+        let rest = path.strip_prefix(self.prefix.as_str())?;
+        Some(format!("{}{}", self.replacement, rest))
+    }
+}
+
+/// How [`SymbolMap::find_function_ranges`] compares symbol names.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FunctionNameMatch {
+    /// The symbol name must be equal to the given name.
+    Exact,
+    /// The symbol name must contain the given name.
+    Contains,
+}
+
+impl FunctionNameMatch {
+    fn matches(self, symbol_name: &str, name: &str) -> bool {
+        match self {
+            FunctionNameMatch::Exact => symbol_name == name,
+            FunctionNameMatch::Contains => symbol_name.contains(name),
+        }
+    }
 }
 
 impl<FL: FileLocation> SymbolMap<FL> {
@@ -15,9 +91,77 @@ impl<FL: FileLocation> SymbolMap<FL> {
         Self {
             debug_file_location,
             inner,
+            simplify_names: false,
+            unnamed_inline_frames: UnnamedInlineFramePolicy::default(),
+            max_inline_depth: None,
+            path_rewrite_rules: Vec::new(),
+            address_overrides: HashMap::new(),
         }
     }
 
+    /// Choose how [`SymbolMap::lookup`] reports inline frames without a function
+    /// name. Defaults to [`UnnamedInlineFramePolicy::Keep`].
+    pub fn set_unnamed_inline_frame_policy(&mut self, policy: UnnamedInlineFramePolicy) {
+        self.unnamed_inline_frames = policy;
+    }
+
+    /// Limit the number of inline frames which [`SymbolMap::lookup`] returns per
+    /// address. Deeply inlined code, for example long iterator chains, can
+    /// otherwise produce dozens of frames for a single address. No limit by
+    /// default.
+    ///
+    /// The innermost `max_inline_depth` inline frames are kept. If frames were
+    /// removed, they are replaced by a single frame without file and line
+    /// information, whose function name says how many frames were omitted,
+    /// e.g. `<3 inlined frames omitted>`. The outer function is always kept.
+    /// The limit is applied after the [`UnnamedInlineFramePolicy`].
+    pub fn set_max_inline_depth(&mut self, max_inline_depth: Option<usize>) {
+        self.max_inline_depth = max_inline_depth;
+    }
+
+    /// Rewrite the raw source file paths of all frames which [`SymbolMap::lookup`]
+    /// returns, both of the function and of the inline frames. For each path,
+    /// the first rule whose prefix matches is applied. No rules by default.
+    ///
+    /// This keeps absolute build paths, e.g. `/Users/alice/secret-project/`,
+    /// out of symbolicated profiles which are shared with others. Mapped paths,
+    /// see [`SourceFilePath::mapped_path`], don't contain the local part of the
+    /// path and are left alone.
+    pub fn set_path_rewrite_rules(&mut self, rules: Vec<PathRewriteRule>) {
+        self.path_rewrite_rules = rules;
+    }
+
+    /// Supply authoritative lookup results for specific relative addresses, for
+    /// example symbols which were provided by a runtime or a JIT. These replace
+    /// any previously set overrides.
+    ///
+    /// [`SymbolMap::lookup`] resolves an address in the following order:
+    ///
+    ///  1. An override for this exact address.
+    ///  2. The symbol information in the file. For PDB files, procedure symbols
+    ///     take precedence over public symbols. For other files, the symbol
+    ///     table takes precedence over the export table of exe / dll files.
+    ///
+    /// Override results are returned as-is; the name simplification and the
+    /// unnamed inline frame policy are not applied to them.
+    /// [`SymbolMap::lookup_return_address`] consults the override for
+    /// `return_address - 1`.
+    pub fn set_address_overrides(&mut self, overrides: HashMap<u32, AddressInfo>) {
+        self.address_overrides = overrides;
+    }
+
+    /// Whether function names returned by [`SymbolMap::lookup`] should be simplified,
+    /// for better readability. Off by default.
+    ///
+    /// This mostly affects Rust functions: closure markers are shortened, long
+    /// generic argument lists are replaced with `<...>`, and hash suffixes are
+    /// removed. If a name is changed, the full name is available in
+    /// [`SymbolInfo::full_name`](crate::SymbolInfo::full_name) and
+    /// [`FrameDebugInfo::full_function`](crate::FrameDebugInfo::full_function).
+    pub fn set_simplify_names(&mut self, simplify_names: bool) {
+        self.simplify_names = simplify_names;
+    }
+
     pub fn debug_file_location(&self) -> &FL {
         &self.debug_file_location
     }
@@ -34,8 +178,146 @@ impl<FL: FileLocation> SymbolMap<FL> {
         self.inner.iter_symbols()
     }
 
+    /// Find the relative address ranges of all functions whose symbol name
+    /// matches `name`. This is the reverse of [`SymbolMap::lookup`]. Overloaded
+    /// functions, or functions which exist more than once, produce more than one
+    /// range. The ranges are sorted by address.
+    ///
+    /// Names are compared with the names which [`SymbolMap::iter_symbols`]
+    /// returns, i.e. demangled names for most formats. If the size of a symbol
+    /// is unknown, its range ends at the next symbol.
+    pub fn find_function_ranges(
+        &self,
+        name: &str,
+        match_mode: FunctionNameMatch,
+    ) -> Vec<Range<u32>> {
+        let mut symbol_addresses: Vec<u32> = Vec::new();
+        let mut matching_addresses: Vec<u32> = Vec::new();
+        for (address, symbol_name) in self.inner.iter_symbols() {
+            symbol_addresses.push(address);
+            if match_mode.matches(&symbol_name, name) {
+                matching_addresses.push(address);
+            }
+        }
+        symbol_addresses.sort_unstable();
+        symbol_addresses.dedup();
+        matching_addresses.sort_unstable();
+        matching_addresses.dedup();
+
+        matching_addresses
+            .into_iter()
+            .filter_map(|address| {
+                let size = self.inner.lookup(address).and_then(|info| info.symbol.size);
+                let end = match size {
+                    Some(size) => address.checked_add(size)?,
+                    None => {
+                        let next_index = symbol_addresses.partition_point(|a| *a <= address);
+                        *symbol_addresses.get(next_index)?
+                    }
+                };
+                Some(address..end)
+            })
+            .collect()
+    }
+
     pub fn lookup(&self, address: u32) -> Option<AddressInfo> {
-        self.inner.lookup(address)
+        if let Some(info) = self.address_overrides.get(&address) {
+            return Some(info.clone());
+        }
+        let mut info = self.inner.lookup(address)?;
+        self.apply_options(&mut info);
+        Some(info)
+    }
+
+    /// Look up the symbol information for a caller frame, i.e. a non-leaf frame
+    /// whose address was obtained from the stack during unwinding.
+    ///
+    /// Such an address is a return address: it points to the instruction *after*
+    /// the call instruction. That instruction can belong to a different source
+    /// line, a different inline frame, or even a different function if the call
+    /// was the last instruction of a function which doesn't return. This method
+    /// looks up `return_address - 1` instead, which is inside the call instruction.
+    ///
+    /// Use [`SymbolMap::lookup`] for the leaf frame, whose address is the address
+    /// of the instruction that was executing.
+    pub fn lookup_return_address(&self, return_address: u32) -> Option<AddressInfo> {
+        self.lookup(return_address.checked_sub(1)?)
+    }
+
+    fn apply_options(&self, info: &mut AddressInfo) {
+        if let FramesLookupResult::Available(frames) = &mut info.frames {
+            // Frames are ordered from inside to outside; all but the last are inline frames.
+            let inline_frame_count = frames.len().saturating_sub(1);
+            match self.unnamed_inline_frames {
+                UnnamedInlineFramePolicy::Placeholder => {
+                    for frame in &mut frames[..inline_frame_count] {
+                        if frame.function.is_none() {
+                            frame.function =
+                                Some(UnnamedInlineFramePolicy::PLACEHOLDER_NAME.to_string());
+                        }
+                    }
+                }
+                UnnamedInlineFramePolicy::Skip => {
+                    let mut index = 0;
+                    frames.retain(|frame| {
+                        index += 1;
+                        index > inline_frame_count || frame.function.is_some()
+                    });
+                }
+                UnnamedInlineFramePolicy::Keep => {}
+            }
+            let inline_frame_count = frames.len().saturating_sub(1);
+            if let Some(max_inline_depth) = self.max_inline_depth {
+                if inline_frame_count > max_inline_depth {
+                    let omitted_count = inline_frame_count - max_inline_depth;
+                    let omitted_frames = max_inline_depth..inline_frame_count;
+                    frames.splice(
+                        omitted_frames,
+                        std::iter::once(FrameDebugInfo {
+                            function: Some(format!("<{omitted_count} inlined frames omitted>")),
+                            full_function: None,
+                            file_path: None,
+                            line_number: None,
+                            is_inlined: true,
+                        }),
+                    );
+                }
+            }
+        }
+        if !self.path_rewrite_rules.is_empty() {
+            if let FramesLookupResult::Available(frames) = &mut info.frames {
+                for file_path in frames
+                    .iter_mut()
+                    .filter_map(|frame| frame.file_path.as_mut())
+                {
+                    let rewritten_path = self
+                        .path_rewrite_rules
+                        .iter()
+                        .find_map(|rule| rule.apply(file_path.raw_path()));
+                    if let Some(rewritten_path) = rewritten_path {
+                        let mapped_path = file_path.mapped_path().cloned();
+                        *file_path = SourceFilePath::new(rewritten_path, mapped_path);
+                    }
+                }
+            }
+        }
+        if self.simplify_names {
+            if let Cow::Owned(name) = simplify_function_name(&info.symbol.name) {
+                info.symbol.full_name = Some(std::mem::replace(&mut info.symbol.name, name));
+            }
+            if let FramesLookupResult::Available(frames) = &mut info.frames {
+                for frame in frames {
+                    let simplified_name = match frame.function.as_deref() {
+                        Some(name) => match simplify_function_name(name) {
+                            Cow::Owned(simplified_name) => simplified_name,
+                            Cow::Borrowed(_) => continue,
+                        },
+                        None => continue,
+                    };
+                    frame.full_function = frame.function.replace(simplified_name);
+                }
+            }
+        }
     }
 }
 
@@ -30,9 +30,11 @@ pub trait FunctionAddressesComputer<'data> {
 pub struct ObjectSymbolMapDataMid<'data, R: ReadRef<'data>, FAC: FunctionAddressesComputer<'data>> {
     object: File<'data, R>,
     supplementary_object: Option<File<'data, R>>,
+    dwarf_package_object: Option<File<'data, R>>,
     function_addresses_computer: FAC,
     file_data: R,
     supplementary_file_data: Option<R>,
+    dwarf_package_file_data: Option<R>,
     addr2line_context_data: Addr2lineContextData,
     arch: Option<&'static str>,
     debug_id: DebugId,
@@ -41,21 +43,26 @@ pub struct ObjectSymbolMapDataMid<'data, R: ReadRef<'data>, FAC: FunctionAddress
 impl<'data, R: ReadRef<'data>, FAC: FunctionAddressesComputer<'data>>
     ObjectSymbolMapDataMid<'data, R, FAC>
 {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         object: File<'data, R>,
         supplementary_object: Option<File<'data, R>>,
+        dwarf_package_object: Option<File<'data, R>>,
         function_addresses_computer: FAC,
         file_data: R,
         supplementary_file_data: Option<R>,
+        dwarf_package_file_data: Option<R>,
         arch: Option<&'static str>,
         debug_id: DebugId,
     ) -> Self {
         Self {
             object,
             supplementary_object,
+            dwarf_package_object,
             function_addresses_computer,
             file_data,
             supplementary_file_data,
+            dwarf_package_file_data,
             addr2line_context_data: Addr2lineContextData::new(),
             arch,
             debug_id,
@@ -74,8 +81,10 @@ impl<'data, R: ReadRef<'data>, FAC: FunctionAddressesComputer<'data>> SymbolMapD
         let symbol_map = ObjectSymbolMapInner::new(
             &self.object,
             self.supplementary_object.as_ref(),
+            self.dwarf_package_object.as_ref(),
             self.file_data,
             self.supplementary_file_data,
+            self.dwarf_package_file_data,
             self.debug_id,
             function_starts.as_deref(),
             function_ends.as_deref(),
@@ -135,6 +144,7 @@ where
     path_mapper: Mutex<PathMapper<()>>,
     object_map: ObjectMap<'data>,
     context: Option<addr2line::Context<gimli::EndianSlice<'file, gimli::RunTimeEndian>>>,
+    dwarf_package: Option<gimli::DwarfPackage<gimli::EndianSlice<'file, gimli::RunTimeEndian>>>,
     image_base_address: u64,
 }
 
@@ -156,8 +166,10 @@ where
     pub fn new<O, R>(
         object_file: &'file O,
         sup_object_file: Option<&'file O>,
+        dwp_object_file: Option<&'file O>,
         data: R,
         sup_data: Option<R>,
+        dwp_data: Option<R>,
         debug_id: DebugId,
         function_start_addresses: Option<&[u32]>,
         function_end_addresses: Option<&[u32]>,
@@ -324,6 +336,12 @@ where
         let context = addr2line_context_data
             .make_context(data, object_file, sup_data, sup_object_file)
             .ok();
+        let dwarf_package = match (dwp_object_file, dwp_data) {
+            (Some(dwp_object_file), Some(dwp_data)) => addr2line_context_data
+                .make_dwarf_package(dwp_data, dwp_object_file)
+                .ok(),
+            _ => None,
+        };
 
         let path_mapper = Mutex::new(PathMapper::new());
 
@@ -333,6 +351,7 @@ where
             path_mapper,
             object_map: object_file.object_map(),
             context,
+            dwarf_package,
             arch,
             image_base_address: base_address,
         }
@@ -386,7 +405,12 @@ where
             let mut path_mapper = self.path_mapper.lock().unwrap();
 
             let vmaddr = self.image_base_address + u64::from(address);
-            let frames = match get_frames(vmaddr, self.context.as_ref(), &mut path_mapper) {
+            let frames = match get_frames(
+                vmaddr,
+                self.context.as_ref(),
+                self.dwarf_package.as_ref(),
+                &mut path_mapper,
+            ) {
                 Some(frames) => FramesLookupResult::Available(frames),
                 None => {
                     if let Some(entry) = self.object_map.get(vmaddr) {
@@ -431,6 +455,7 @@ where
                     address: *start_addr,
                     size: Some(function_size),
                     name,
+                    full_name: None,
                 },
                 frames,
             })
@@ -20,6 +20,7 @@ use pdb::PDB;
 use pdb_addr2line::pdb;
 use std::borrow::Cow;
 use std::collections::HashMap;
+use std::io::{Cursor, Read};
 use std::ops::Deref;
 use std::sync::Mutex;
 
@@ -52,7 +53,12 @@ pub async fn load_symbol_map_for_pdb_corresponding_to_binary<
         .load_file(pdb_location)
         .await
         .map_err(|e| Error::HelperErrorDuringOpenFile(pdb_path_str.to_string(), e))?;
-    let symbol_map = get_symbol_map_for_pdb(FileContentsWrapper::new(pdb_file), file_location)?;
+    let pdb_file = FileContentsWrapper::new(pdb_file);
+    let symbol_map = if is_cab_file(&pdb_file) {
+        get_symbol_map_for_cab_compressed_pdb(pdb_file, file_location)?
+    } else {
+        get_symbol_map_for_pdb(pdb_file, file_location)?
+    };
     if symbol_map.debug_id() != binary_debug_id {
         return Err(Error::UnmatchedDebugId(
             binary_debug_id,
@@ -102,10 +108,12 @@ impl<T: FileContents + 'static> SymbolMapDataOuterTrait for PeSymbolMapData<T> {
         let object = ObjectSymbolMapDataMid::new(
             object,
             None,
+            None,
             PeFunctionAddressesComputer,
             &self.file_data,
             None,
             None,
+            None,
             debug_id,
         );
 
@@ -142,6 +150,56 @@ pub fn is_pdb_file<F: FileContents>(file: &FileContentsWrapper<F>) -> bool {
     PDB::open(file).is_ok()
 }
 
+/// Compressed PDB files, like the `.pd_` files served by some symbol servers,
+/// are cab archives which contain the actual PDB file.
+pub fn is_cab_file<F: FileContents>(file: &FileContentsWrapper<F>) -> bool {
+    matches!(file.read_bytes_at(0, 4), Ok(b"MSCF"))
+}
+
+/// Decompresses the PDB file contained in the cab archive into memory and
+/// creates a symbol map for it.
+pub fn get_symbol_map_for_cab_compressed_pdb<F, FL>(
+    file_contents: FileContentsWrapper<F>,
+    debug_file_location: FL,
+) -> Result<SymbolMap<FL>, Error>
+where
+    F: FileContents + 'static,
+    FL: FileLocation,
+{
+    let pdb_data = extract_first_file_from_cab(&file_contents, &debug_file_location)?;
+    get_symbol_map_for_pdb(FileContentsWrapper::new(pdb_data), debug_file_location)
+}
+
+fn extract_first_file_from_cab<F: FileContents>(
+    file_contents: &FileContentsWrapper<F>,
+    file_location: &impl FileLocation,
+) -> Result<Vec<u8>, Error> {
+    let cab_data = file_contents
+        .read_bytes_at(0, file_contents.len())
+        .map_err(|e| Error::HelperErrorDuringFileReading(file_location.to_string(), e))?;
+    let mut cabinet =
+        cab::Cabinet::new(Cursor::new(cab_data)).map_err(Error::CabExtractionError)?;
+    let file_name = cabinet
+        .folder_entries()
+        .flat_map(|folder| folder.file_entries())
+        .map(|file| file.name().to_string())
+        .next()
+        .ok_or_else(|| {
+            Error::CabExtractionError(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "the cab archive is empty",
+            ))
+        })?;
+    let mut reader = cabinet
+        .read_file(&file_name)
+        .map_err(Error::CabExtractionError)?;
+    let mut pdb_data = Vec::new();
+    reader
+        .read_to_end(&mut pdb_data)
+        .map_err(Error::CabExtractionError)?;
+    Ok(pdb_data)
+}
+
 struct PdbObject<'data, FC: FileContents + 'static> {
     context_data: pdb_addr2line::ContextPdbData<'data, 'data, &'data FileContentsWrapper<FC>>,
     debug_id: DebugId,
@@ -245,6 +303,7 @@ impl<'object> SymbolMapTrait for PdbSymbolMapInner<'object> {
             address: symbol_address,
             size: function_size,
             name: symbol_name,
+            full_name: None,
         };
         let frames = if has_debug_info(&function_frames) {
             let mut path_mapper = self.path_mapper.lock().unwrap();
@@ -252,13 +311,17 @@ impl<'object> SymbolMapTrait for PdbSymbolMapInner<'object> {
                 let mapped_path = path_mapper.map_path(&path);
                 SourceFilePath::new(path.into_owned(), mapped_path)
             };
+            let inline_frame_count = function_frames.frames.len().saturating_sub(1);
             let frames: Vec<_> = function_frames
                 .frames
                 .into_iter()
-                .map(|frame| FrameDebugInfo {
+                .enumerate()
+                .map(|(index, frame)| FrameDebugInfo {
                     function: frame.function,
+                    full_function: None,
                     file_path: frame.file.map(&mut map_path),
                     line_number: frame.line,
+                    is_inlined: index < inline_frame_count,
                 })
                 .collect();
             FramesLookupResult::Available(frames)
@@ -1,9 +1,11 @@
 use samply_symbols::debugid::DebugId;
 use samply_symbols::{
-    self, CandidatePathInfo, CompactSymbolTable, Error, FileAndPathHelper, FileAndPathHelperResult,
-    FileLocation, LibraryInfo, MultiArchDisambiguator, OptionallySendFuture, SymbolManager,
-    SymbolMap,
+    self, AddressInfo, CandidatePathInfo, CompactSymbolTable, Error, FileAndPathHelper,
+    FileAndPathHelperResult, FileLocation, FramesLookupResult, FunctionNameMatch, LibraryInfo,
+    MultiArchDisambiguator, MultiSymbolMap, OptionallySendFuture, PathRewriteRule, SymbolInfo,
+    SymbolManager, SymbolMap,
 };
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::{BufWriter, Read, Write};
 use std::path::{Path, PathBuf};
@@ -112,6 +114,12 @@ impl FileLocation for FileLocationType {
         Some(Self(pdb_path_in_binary.into()))
     }
 
+    fn location_for_dwp(&self) -> Option<Self> {
+        let mut filename = self.0.file_name()?.to_owned();
+        filename.push(".dwp");
+        Some(Self(self.0.with_file_name(filename)))
+    }
+
     fn location_for_source_file(&self, source_file_path: &str) -> Option<Self> {
         Some(Self(source_file_path.into()))
     }
@@ -251,6 +259,31 @@ fn successful_pdb() {
         );
 }
 
+#[test]
+fn cab_compressed_pdb() {
+    let pdb_path = fixtures_dir().join("win64-ci").join("WriteArgument.pdb");
+    let mut cab_builder = cab::CabinetBuilder::new();
+    cab_builder
+        .add_folder(cab::CompressionType::MsZip)
+        .add_file("WriteArgument.pdb");
+    let mut cab_writer = cab_builder.build(std::io::Cursor::new(Vec::new())).unwrap();
+    while let Some(mut writer) = cab_writer.next_file().unwrap() {
+        std::io::copy(&mut File::open(&pdb_path).unwrap(), &mut writer).unwrap();
+    }
+    let cab_data = cab_writer.finish().unwrap().into_inner();
+
+    let temp_dir = tempfile::tempdir().unwrap();
+    let cab_path = temp_dir.path().join("WriteArgument.pd_");
+    std::fs::write(&cab_path, cab_data).unwrap();
+
+    let compressed = futures::executor::block_on(crate::get_table(&cab_path, None)).unwrap();
+    let uncompressed = futures::executor::block_on(crate::get_table(&pdb_path, None)).unwrap();
+    assert!(!uncompressed.addr.is_empty());
+    assert_eq!(compressed.addr, uncompressed.addr);
+    assert_eq!(compressed.index, uncompressed.index);
+    assert_eq!(compressed.buffer, uncompressed.buffer);
+}
+
 #[test]
 fn successful_pdb2() {
     let result = futures::executor::block_on(crate::get_table(
@@ -451,6 +484,114 @@ fn example_linux() {
     assert_eq!(&symbol_map.lookup(0x1156).unwrap().symbol.name, "main");
     assert_eq!(symbol_map.lookup(0x1158), None, "Gap between main and f");
     assert_eq!(&symbol_map.lookup(0x1160).unwrap().symbol.name, "f");
+
+    // A return address right after the end of main still belongs to main.
+    assert_eq!(
+        &symbol_map
+            .lookup_return_address(0x1157)
+            .unwrap()
+            .symbol
+            .name,
+        "main"
+    );
+    assert_eq!(
+        symbol_map.lookup_return_address(0x1158),
+        symbol_map.lookup(0x1157)
+    );
+    assert_eq!(symbol_map.lookup_return_address(0), None);
+}
+
+#[test]
+fn executable_ranges_of_pe_binary() {
+    let helper = Helper {
+        symbol_directory: fixtures_dir().join("win64-ci"),
+    };
+    let symbol_manager = SymbolManager::with_helper(&helper);
+    let binary = futures::executor::block_on(symbol_manager.load_binary_at_location(
+        FileLocationType(fixtures_dir().join("win64-ci").join("mozglue.dll")),
+        None,
+        None,
+        None,
+    ))
+    .unwrap();
+
+    // Only .text is executable. .rdata and .data follow it.
+    let ranges = binary.executable_ranges();
+    assert_eq!(ranges.len(), 1);
+    assert_eq!(ranges[0], 0x1000..0x78e11);
+    assert!(samply_symbols::is_executable_address(&ranges, 0x1000));
+    assert!(samply_symbols::is_executable_address(&ranges, 0x78e10));
+    assert!(!samply_symbols::is_executable_address(&ranges, 0x79000));
+    assert!(!samply_symbols::is_executable_address(&ranges, 0x8b000));
+}
+
+#[test]
+fn split_dwarf_with_dwp() {
+    let helper = Helper {
+        symbol_directory: fixtures_dir().join("other").join("split-dwarf"),
+    };
+    let symbol_manager = SymbolManager::with_helper(&helper);
+    let symbol_map = futures::executor::block_on(
+        symbol_manager.load_symbol_map_from_location(
+            FileLocationType(
+                fixtures_dir()
+                    .join("other")
+                    .join("split-dwarf")
+                    .join("example"),
+            ),
+            None,
+        ),
+    )
+    .unwrap();
+    let info = symbol_map.lookup(0x107a).unwrap();
+    assert_eq!(&info.symbol.name, "main");
+
+    // The skeleton unit in the binary only has line numbers. The inline frames
+    // and function names come from the split unit in example.dwp.
+    let frames = match info.frames {
+        FramesLookupResult::Available(frames) => frames,
+        _ => panic!("Frames should be available"),
+    };
+    let functions: Vec<_> = frames
+        .iter()
+        .map(|frame| frame.function.as_deref().unwrap())
+        .collect();
+    assert_eq!(functions, vec!["square", "sum_of_squares", "main"]);
+    let lines: Vec<_> = frames.iter().map(|frame| frame.line_number).collect();
+    assert_eq!(lines, vec![Some(5), Some(11), Some(18)]);
+    assert_eq!(
+        frames[0].file_path.as_ref().unwrap().raw_path(),
+        "./example.c"
+    );
+}
+
+#[test]
+fn address_overrides() {
+    let helper = Helper {
+        symbol_directory: fixtures_dir().join("other"),
+    };
+    let symbol_manager = SymbolManager::with_helper(&helper);
+    let mut symbol_map = futures::executor::block_on(symbol_manager.load_symbol_map_from_location(
+        FileLocationType(fixtures_dir().join("other").join("example-linux")),
+        None,
+    ))
+    .unwrap();
+    let jit_info = AddressInfo {
+        symbol: SymbolInfo {
+            address: 0x1158,
+            size: Some(8),
+            name: "jit_function".to_string(),
+            full_name: None,
+        },
+        frames: FramesLookupResult::Unavailable,
+    };
+    symbol_map.set_address_overrides(HashMap::from([(0x1158, jit_info.clone())]));
+    assert_eq!(symbol_map.lookup(0x1158), Some(jit_info.clone()));
+    assert_eq!(&symbol_map.lookup(0x1156).unwrap().symbol.name, "main");
+    assert_eq!(symbol_map.lookup_return_address(0x1159), Some(jit_info));
+
+    symbol_map.set_address_overrides(HashMap::new());
+    assert_eq!(symbol_map.lookup(0x1158), None);
 }
 
 #[test]
@@ -506,3 +647,206 @@ fn compare_snapshot() {
 
     assert_eq!(output, expected);
 }
+
+#[test]
+fn max_inline_depth() {
+    let helper = Helper {
+        symbol_directory: fixtures_dir().join("win64-local"),
+    };
+    let symbol_manager = SymbolManager::with_helper(&helper);
+    let mut symbol_map = futures::executor::block_on(symbol_manager.load_symbol_map_from_location(
+        FileLocationType(fixtures_dir().join("win64-local").join("mozglue.pdb")),
+        None,
+    ))
+    .unwrap();
+    let function_names = |info: AddressInfo| -> Vec<String> {
+        match info.frames {
+            FramesLookupResult::Available(frames) => frames
+                .into_iter()
+                .map(|frame| frame.function.unwrap())
+                .collect(),
+            _ => panic!("expected frames"),
+        }
+    };
+    // 17 inline frames in ActivePS::AddLiveProfiledThread.
+    let all_frames = function_names(symbol_map.lookup(0x468c6).unwrap());
+    assert_eq!(all_frames.len(), 18);
+
+    symbol_map.set_max_inline_depth(Some(2));
+    let frames = function_names(symbol_map.lookup(0x468c6).unwrap());
+    assert_eq!(
+        frames,
+        vec![
+            all_frames[0].clone(),
+            all_frames[1].clone(),
+            "<15 inlined frames omitted>".to_string(),
+            all_frames[17].clone(),
+        ]
+    );
+
+    symbol_map.set_max_inline_depth(Some(0));
+    let frames = function_names(symbol_map.lookup(0x468c6).unwrap());
+    assert_eq!(
+        frames,
+        vec![
+            "<17 inlined frames omitted>".to_string(),
+            all_frames[17].clone()
+        ]
+    );
+
+    symbol_map.set_max_inline_depth(Some(17));
+    assert_eq!(
+        function_names(symbol_map.lookup(0x468c6).unwrap()),
+        all_frames
+    );
+}
+
+#[test]
+fn inline_frames_are_marked() {
+    let helper = Helper {
+        symbol_directory: fixtures_dir().join("win64-local"),
+    };
+    let symbol_manager = SymbolManager::with_helper(&helper);
+    let mut symbol_map = futures::executor::block_on(symbol_manager.load_symbol_map_from_location(
+        FileLocationType(fixtures_dir().join("win64-local").join("mozglue.pdb")),
+        None,
+    ))
+    .unwrap();
+    let inlined_flags = |info: AddressInfo| -> Vec<bool> {
+        match info.frames {
+            FramesLookupResult::Available(frames) => {
+                frames.into_iter().map(|frame| frame.is_inlined).collect()
+            }
+            _ => panic!("expected frames"),
+        }
+    };
+    // 17 inline frames in ActivePS::AddLiveProfiledThread, then the function itself.
+    let mut expected = vec![true; 17];
+    expected.push(false);
+    assert_eq!(inlined_flags(symbol_map.lookup(0x468c6).unwrap()), expected);
+
+    // The placeholder for omitted frames stands for inline frames, too.
+    symbol_map.set_max_inline_depth(Some(1));
+    assert_eq!(
+        inlined_flags(symbol_map.lookup(0x468c6).unwrap()),
+        vec![true, true, false]
+    );
+}
+
+#[test]
+fn find_function_ranges() {
+    let helper = Helper {
+        symbol_directory: fixtures_dir().join("win64-local"),
+    };
+    let symbol_manager = SymbolManager::with_helper(&helper);
+    let symbol_map = futures::executor::block_on(symbol_manager.load_symbol_map_from_location(
+        FileLocationType(fixtures_dir().join("win64-local").join("mozglue.pdb")),
+        None,
+    ))
+    .unwrap();
+    let name = "mozilla::baseprofiler::profiler_get_profile(double, bool, bool)";
+    let ranges = symbol_map.find_function_ranges(name, FunctionNameMatch::Exact);
+    assert_eq!(ranges.len(), 1);
+    assert_eq!(ranges[0].start, 0x34670);
+    assert!(ranges[0].end > ranges[0].start);
+    assert_eq!(
+        &symbol_map.lookup(ranges[0].end - 1).unwrap().symbol.name,
+        name
+    );
+
+    let ranges =
+        symbol_map.find_function_ranges("profiler_get_profile", FunctionNameMatch::Contains);
+    assert_eq!(ranges, vec![0x34670..ranges[0].end]);
+    let ranges = symbol_map.find_function_ranges("baseprofiler::", FunctionNameMatch::Contains);
+    assert!(ranges.len() > 1);
+    assert!(ranges.iter().any(|range| range.start == 0x34670));
+    assert!(ranges.windows(2).all(|w| w[0].start < w[1].start));
+    assert!(symbol_map
+        .find_function_ranges("profiler_get_profile", FunctionNameMatch::Exact)
+        .is_empty());
+}
+
+#[test]
+fn multi_symbol_map() {
+    let helper = Helper {
+        symbol_directory: fixtures_dir().join("win64-local"),
+    };
+    let symbol_manager = SymbolManager::with_helper(&helper);
+    let load = |file_name: &str| {
+        futures::executor::block_on(symbol_manager.load_symbol_map_from_location(
+            FileLocationType(fixtures_dir().join("win64-local").join(file_name)),
+            None,
+        ))
+        .unwrap()
+    };
+
+    // Both PDBs have symbols at 0x1200; the first symbol map wins.
+    let multi_symbol_map = MultiSymbolMap::new(vec![load("firefox.pdb"), load("mozglue.pdb")]);
+    assert_eq!(
+        multi_symbol_map.lookup(0x1200).unwrap().symbol.name,
+        "wmain(int, wchar_t**)"
+    );
+    // firefox.pdb ends before 0x60000, so mozglue.pdb is used.
+    assert_eq!(
+        multi_symbol_map.lookup(0x60000).unwrap().symbol.name,
+        "LZ4_decompress_safe_forceExtDict(char const*, char*, int, int, void const*, unsigned long long)"
+    );
+
+    // Symbols which are present in more than one symbol map are only counted once.
+    let single_symbol_map = load("mozglue.pdb");
+    let multi_symbol_map = MultiSymbolMap::new(vec![load("mozglue.pdb"), load("mozglue.pdb")]);
+    assert_eq!(
+        multi_symbol_map.symbol_count(),
+        single_symbol_map.iter_symbols().count()
+    );
+    assert_eq!(
+        multi_symbol_map.lookup(0x34680),
+        single_symbol_map.lookup(0x34680)
+    );
+}
+
+#[test]
+fn path_rewrite_rules() {
+    let helper = Helper {
+        symbol_directory: fixtures_dir().join("win64-local"),
+    };
+    let symbol_manager = SymbolManager::with_helper(&helper);
+    let mut symbol_map = futures::executor::block_on(symbol_manager.load_symbol_map_from_location(
+        FileLocationType(fixtures_dir().join("win64-local").join("mozglue.pdb")),
+        None,
+    ))
+    .unwrap();
+    let file_paths = |info: AddressInfo| -> Vec<String> {
+        match info.frames {
+            FramesLookupResult::Available(frames) => frames
+                .into_iter()
+                .map(|frame| frame.file_path.unwrap().into_raw_path())
+                .collect(),
+            _ => panic!("expected frames"),
+        }
+    };
+    let original_paths = file_paths(symbol_map.lookup(0x468c6).unwrap());
+    assert_eq!(
+        original_paths.last().unwrap(),
+        r"c:\mozilla-source\mozilla-central\mozglue\baseprofiler\core\platform.cpp"
+    );
+
+    symbol_map.set_path_rewrite_rules(vec![
+        PathRewriteRule::new(r"c:\mozilla-source\mozilla-central\", ""),
+        PathRewriteRule::new(r"c:\mozilla-source\", "<src>/"),
+    ]);
+    let paths = file_paths(symbol_map.lookup(0x468c6).unwrap());
+    assert_eq!(paths.len(), original_paths.len());
+    // The rules apply to the inline frames as well as to the function itself,
+    // and the first matching rule wins.
+    assert_eq!(
+        paths.last().unwrap(),
+        r"mozglue\baseprofiler\core\platform.cpp"
+    );
+    assert!(paths.contains(&r"<src>/obj-m-opt\dist\include\mozilla\RefPtr.h".to_string()));
+    assert!(paths
+        .iter()
+        .all(|path| !path.starts_with(r"c:\mozilla-source\")));
+    // Paths which don't match any rule are unchanged.
+    assert_eq!(paths[0], original_paths[0]);
+}
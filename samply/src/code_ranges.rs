@@ -0,0 +1,119 @@
+use wholesym::samply_symbols::is_executable_address;
+use wholesym::{MultiArchDisambiguator, SymbolManager};
+
+use std::convert::TryFrom;
+use std::ops::Range;
+use std::path::Path;
+
+/// The address ranges of the code in the loaded libraries of a process.
+/// Unwinders only accept return addresses in these ranges, so that values which
+/// point into data or outside of any library don't become frames.
+#[derive(Debug, Clone, Default)]
+pub struct CodeRanges {
+    /// Sorted by start address, non-overlapping.
+    libs: Vec<LibCodeRanges>,
+}
+
+#[derive(Debug, Clone)]
+struct LibCodeRanges {
+    avma_range: Range<u64>,
+    /// The executable sections, relative to the start of `avma_range`, see
+    /// [`executable_ranges_for_lib`]. `None` if they're unknown, in which case
+    /// the whole library counts as code.
+    executable_ranges: Option<Vec<Range<u32>>>,
+}
+
+impl CodeRanges {
+    pub fn add(&mut self, avma_range: Range<u64>, executable_ranges: Option<Vec<Range<u32>>>) {
+        let index = self
+            .libs
+            .partition_point(|lib| lib.avma_range.start < avma_range.start);
+        self.libs.insert(
+            index,
+            LibCodeRanges {
+                avma_range,
+                executable_ranges,
+            },
+        );
+    }
+
+    /// Remove the library which starts at `start`, after it was unloaded.
+    pub fn remove(&mut self, start: u64) {
+        self.libs.retain(|lib| lib.avma_range.start != start);
+    }
+
+    pub fn contains(&self, address: u64) -> bool {
+        let index = self
+            .libs
+            .partition_point(|lib| lib.avma_range.start <= address);
+        let lib = match index.checked_sub(1) {
+            Some(index) => &self.libs[index],
+            None => return false,
+        };
+        if address >= lib.avma_range.end {
+            return false;
+        }
+        match &lib.executable_ranges {
+            Some(ranges) => u32::try_from(address - lib.avma_range.start)
+                .is_ok_and(|relative_address| is_executable_address(ranges, relative_address)),
+            None => true,
+        }
+    }
+}
+
+/// Reads the ranges of the executable sections of the library at `path`,
+/// relative to the library's base address. On macOS, libraries in the dyld
+/// shared cache are found in the cache. Returns `None` if the library can't be
+/// read.
+#[tokio::main(flavor = "current_thread")]
+pub async fn executable_ranges_for_lib(
+    path: &Path,
+    disambiguator: Option<MultiArchDisambiguator>,
+) -> Option<Vec<Range<u32>>> {
+    SymbolManager::executable_ranges_for_binary_at_path(path, disambiguator)
+        .await
+        .ok()
+}
+
+#[test]
+fn test_code_ranges() {
+    let mut code_ranges = CodeRanges::default();
+    code_ranges.add(0x5000..0x6000, None);
+    code_ranges.add(0x1000..0x2000, None);
+    assert!(!code_ranges.contains(0xfff));
+    assert!(code_ranges.contains(0x1000));
+    assert!(code_ranges.contains(0x1fff));
+    assert!(!code_ranges.contains(0x2000));
+    assert!(code_ranges.contains(0x5800));
+    code_ranges.remove(0x5000);
+    assert!(!code_ranges.contains(0x5800));
+    assert!(code_ranges.contains(0x1800));
+}
+
+#[test]
+fn test_code_ranges_with_executable_sections() {
+    let mut code_ranges = CodeRanges::default();
+    code_ranges.add(0x10000..0x20000, Some(vec![0x1000..0x8000, 0xa000..0xb000]));
+    // The headers and the data sections of the library aren't code.
+    assert!(!code_ranges.contains(0x10500));
+    assert!(code_ranges.contains(0x11000));
+    assert!(code_ranges.contains(0x17fff));
+    assert!(!code_ranges.contains(0x18000));
+    assert!(code_ranges.contains(0x1a000));
+    assert!(!code_ranges.contains(0x20000));
+}
+
+#[test]
+fn test_executable_ranges_for_lib() {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("../fixtures/win64-ci")
+        .join("mozglue.dll");
+    // Only .text is executable.
+    let ranges = executable_ranges_for_lib(&path, None).unwrap();
+    assert_eq!(ranges.len(), 1);
+    assert_eq!(ranges[0], 0x1000..0x78e11);
+    assert_eq!(
+        executable_ranges_for_lib(Path::new("/nonexistent.dll"), None),
+        None
+    );
+}
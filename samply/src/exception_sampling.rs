@@ -0,0 +1,41 @@
+// Only the macOS sampler can capture stacks on exceptions so far.
+#![cfg_attr(not(target_os = "macos"), allow(dead_code))]
+
+/// An exception which makes the recorded thread stop until the exception has
+/// been handled, so that its stack can be captured at that point.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ExceptionKind {
+    /// Invalid memory accesses, which become SIGSEGV or SIGBUS.
+    BadAccess,
+    /// Illegal instructions, which become SIGILL.
+    BadInstruction,
+    /// Arithmetic errors such as integer division by zero, which become SIGFPE.
+    Arithmetic,
+    /// Breakpoint and trap instructions, which become SIGTRAP.
+    Breakpoint,
+    /// Exceeded resource limits, e.g. of CPU usage, wakeups or memory.
+    Resource,
+    /// Violations of guarded resources, e.g. closing a guarded file descriptor.
+    Guard,
+}
+
+/// Captures a stack every time a recorded thread raises one of the selected
+/// exceptions, in addition to or instead of the timer samples.
+#[derive(Debug, Clone, Default)]
+pub struct ExceptionSampling {
+    pub kinds: Vec<ExceptionKind>,
+    /// Don't take timer samples, only the stacks of the exceptions.
+    pub exceptions_only: bool,
+}
+
+impl ExceptionSampling {
+    /// Whether no exceptions are captured.
+    pub fn is_empty(&self) -> bool {
+        self.kinds.is_empty()
+    }
+
+    /// Whether threads are sampled on every tick of the sampling timer.
+    pub fn timer_samples(&self) -> bool {
+        self.is_empty() || !self.exceptions_only
+    }
+}
@@ -2,19 +2,19 @@ use linux_perf_data::linux_perf_event_reader::EventRecord;
 
 use std::collections::HashMap;
 use std::ffi::OsString;
-use std::fs::File;
-use std::io::BufWriter;
 use std::path::Path;
 use std::process::Command;
 use std::process::ExitStatus;
 use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 
 use super::perf_event::EventSource;
 use super::perf_group::PerfGroup;
 use crate::linux_shared::{ConvertRegs, Converter, EventInterpretation};
+use crate::profile_output::{save_profile, ProfileOutput};
+use crate::recording_options::RecordingOptions;
 use crate::server::{start_server_main, ServerProps};
 
 #[cfg(target_arch = "x86_64")]
@@ -24,13 +24,14 @@ pub type ConvertRegsNative = crate::linux_shared::ConvertRegsX86_64;
 pub type ConvertRegsNative = crate::linux_shared::ConvertRegsAarch64;
 
 pub fn start_recording(
-    output_file: &Path,
+    output: &ProfileOutput,
     command_name: OsString,
     command_args: &[OsString],
-    time_limit: Option<Duration>,
-    interval: Duration,
+    options: &RecordingOptions,
     server_props: Option<ServerProps>,
 ) -> Result<ExitStatus, ()> {
+    options.warn_unsupported("Linux");
+
     // Ignore SIGINT while the subcommand is running. The signal still reaches the process
     // under observation while we continue to record it. (ctrl+c will send the SIGINT signal
     // to all processes in the foreground process group).
@@ -42,6 +43,7 @@ pub fn start_recording(
     )
     .expect("cannot register signal handler");
 
+    let launch_time = SystemTime::now();
     let mut root_child = Command::new(&command_name)
         .args(command_args)
         .spawn()
@@ -49,8 +51,10 @@ pub fn start_recording(
 
     let pid = root_child.id();
 
-    let output_file_copy = output_file.to_owned();
+    let output_file = output.path_for_process(pid, launch_time);
+    let output_file_copy = output_file.clone();
     let command_name_copy = command_name.to_string_lossy().to_string();
+    let (time_limit, interval) = (options.time_limit, options.interval);
     let observer_thread = thread::spawn(move || {
         let product = command_name_copy;
         // start profiling pid
@@ -67,7 +71,7 @@ pub fn start_recording(
         .expect("couldn't join observer thread");
 
     if let Some(server_props) = server_props {
-        start_server_main(output_file, server_props);
+        start_server_main(&output_file, server_props);
     }
 
     Ok(exit_status)
@@ -242,9 +246,8 @@ fn run_profiler(
 
     let profile = converter.finish();
 
-    let output_file = File::create(output_filename).unwrap();
-    let writer = BufWriter::new(output_file);
-    serde_json::to_writer(writer, &profile).expect("Couldn't write JSON");
+    save_profile(&profile, output_filename).expect("Couldn't write JSON");
+    eprintln!("Saved profile to {}", output_filename.display());
 }
 
 pub fn read_string_lossy<P: AsRef<Path>>(path: P) -> std::io::Result<String> {
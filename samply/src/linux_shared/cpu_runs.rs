@@ -0,0 +1,112 @@
+use fxprof_processed_profile::{
+    MarkerDynamicField, MarkerFieldFormat, MarkerLocation, MarkerSchema, MarkerSchemaField,
+    ProfilerMarker,
+};
+
+/// Tracks which CPU a thread was running on, based on the CPU of its samples,
+/// so that the profile can show "CPU" markers for each stretch of samples on
+/// the same CPU.
+#[derive(Debug, Clone, Default)]
+pub struct ThreadCpuRuns {
+    current: Option<CpuRun>,
+}
+
+/// Consecutive samples of a thread which were taken on the same CPU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CpuRun {
+    pub cpu: u32,
+    /// The timestamp of the first sample on this CPU.
+    pub start: u64,
+    /// The timestamp of the last sample on this CPU.
+    pub end: u64,
+}
+
+impl ThreadCpuRuns {
+    /// Call this for every sample of the thread. Returns the previous run if the
+    /// thread is now on a different CPU.
+    pub fn handle_sample(&mut self, cpu: u32, timestamp: u64) -> Option<CpuRun> {
+        match &mut self.current {
+            Some(run) if run.cpu == cpu => {
+                run.end = timestamp;
+                None
+            }
+            current => current.replace(CpuRun {
+                cpu,
+                start: timestamp,
+                end: timestamp,
+            }),
+        }
+    }
+
+    /// Returns the run which is still ongoing, if any, once there are no more
+    /// samples.
+    pub fn finish(&mut self) -> Option<CpuRun> {
+        self.current.take()
+    }
+}
+
+/// The CPU on which a thread was running during the marker's time span.
+#[derive(Debug, Clone)]
+pub struct CpuMarker(pub u32);
+
+impl ProfilerMarker for CpuMarker {
+    const MARKER_TYPE_NAME: &'static str = "CPU";
+
+    fn schema() -> MarkerSchema {
+        MarkerSchema {
+            type_name: Self::MARKER_TYPE_NAME,
+            locations: vec![MarkerLocation::MarkerChart, MarkerLocation::MarkerTable],
+            chart_label: Some("CPU {marker.data.cpu}"),
+            tooltip_label: Some("Running on CPU {marker.data.cpu}"),
+            table_label: Some("CPU {marker.data.cpu}"),
+            fields: vec![MarkerSchemaField::Dynamic(MarkerDynamicField {
+                key: "cpu",
+                label: "CPU",
+                format: MarkerFieldFormat::Integer,
+                searchable: Some(true),
+            })],
+        }
+    }
+
+    fn json_marker_data(&self) -> serde_json::Value {
+        serde_json::json!({ "type": Self::MARKER_TYPE_NAME, "cpu": self.0 })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{CpuRun, ThreadCpuRuns};
+
+    #[test]
+    fn it_works() {
+        let mut runs = ThreadCpuRuns::default();
+        assert_eq!(runs.handle_sample(2, 10), None);
+        assert_eq!(runs.handle_sample(2, 20), None);
+        assert_eq!(runs.handle_sample(2, 30), None);
+        assert_eq!(
+            runs.handle_sample(5, 40),
+            Some(CpuRun {
+                cpu: 2,
+                start: 10,
+                end: 30
+            })
+        );
+        assert_eq!(
+            runs.handle_sample(2, 50),
+            Some(CpuRun {
+                cpu: 5,
+                start: 40,
+                end: 40
+            })
+        );
+        assert_eq!(
+            runs.finish(),
+            Some(CpuRun {
+                cpu: 2,
+                start: 50,
+                end: 50
+            })
+        );
+        assert_eq!(runs.finish(), None);
+    }
+}
@@ -1,15 +1,18 @@
 mod context_switch;
+mod cpu_runs;
 mod kernel_symbols;
+mod perf_map;
 
 use byteorder::LittleEndian;
 use context_switch::{ContextSwitchHandler, OffCpuSampleGroup, ThreadContextSwitchData};
+use cpu_runs::{CpuMarker, CpuRun, ThreadCpuRuns};
 use debugid::{CodeId, DebugId};
 use framehop::aarch64::UnwindRegsAarch64;
 use framehop::x86_64::UnwindRegsX86_64;
 use framehop::{FrameAddress, Module, ModuleSvmaInfo, ModuleUnwindData, TextByteData, Unwinder};
 use fxprof_processed_profile::{
-    CategoryColor, CategoryPairHandle, CpuDelta, Frame, LibraryInfo, ProcessHandle, Profile,
-    ReferenceTimestamp, SamplingInterval, ThreadHandle, Timestamp,
+    CategoryColor, CategoryPairHandle, CpuDelta, Frame, LibraryInfo, MarkerTiming, ProcessHandle,
+    Profile, ReferenceTimestamp, SamplingInterval, ThreadHandle, Timestamp,
 };
 use linux_perf_data::linux_perf_event_reader;
 use linux_perf_data::{AttributeDescription, DsoInfo, DsoKey};
@@ -32,10 +35,12 @@ use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::fmt::Debug;
 use std::path::PathBuf;
+use std::sync::Arc;
 use std::time::SystemTime;
 use std::{ops::Range, path::Path};
 
 use self::kernel_symbols::KernelSymbols;
+use self::perf_map::PerfMap;
 
 pub trait ConvertRegs {
     type UnwindRegs;
@@ -217,7 +222,17 @@ where
         }
     }
 
-    pub fn finish(self) -> Profile {
+    pub fn finish(mut self) -> Profile {
+        for thread in self.threads.0.values_mut() {
+            if let Some(run) = thread.cpu_runs.finish() {
+                add_cpu_marker(
+                    run,
+                    thread.profile_thread,
+                    &self.timestamp_converter,
+                    &mut self.profile,
+                );
+            }
+        }
         self.profile
     }
 
@@ -287,6 +302,17 @@ where
         self.profile
             .add_sample(thread_handle, profile_timestamp, frames, cpu_delta, 1);
         thread.last_sample_timestamp = Some(timestamp);
+
+        if let Some(cpu) = e.cpu {
+            if let Some(run) = thread.cpu_runs.handle_sample(cpu, timestamp) {
+                add_cpu_marker(
+                    run,
+                    thread_handle,
+                    &self.timestamp_converter,
+                    &mut self.profile,
+                );
+            }
+        }
     }
 
     pub fn handle_sched_switch<C: ConvertRegs<UnwindRegs = U::UnwindRegs>>(
@@ -411,6 +437,10 @@ where
         }
 
         let mut path = e.path.as_slice();
+        if is_anonymous_mapping(&path) && e.pid != -1 {
+            self.add_jit_lib(e.pid, e.address, e.length);
+            return;
+        }
         let dso_key = match DsoKey::detect(&path, e.cpu_mode) {
             Some(dso_key) => dso_key,
             None => return,
@@ -456,6 +486,10 @@ where
         }
 
         let path = e.path.as_slice();
+        if is_anonymous_mapping(&path) && e.pid != -1 {
+            self.add_jit_lib(e.pid, e.address, e.length);
+            return;
+        }
         let build_id = match &e.file_id {
             Mmap2FileId::BuildId(build_id) => Some(&build_id[..]),
             Mmap2FileId::InodeAndVersion(_) => {
@@ -483,6 +517,43 @@ where
         }
     }
 
+    /// Anonymous executable mappings usually contain JIT code. If the process
+    /// has a perf map file which lists functions in the mapping, add the mapping
+    /// as a library whose symbol table has these functions, so that the frames
+    /// get their names. Otherwise the mapping is ignored, as before.
+    fn add_jit_lib(&mut self, pid: i32, start_avma: u64, size: u64) {
+        let perf_map = match PerfMap::read_for_pid(pid, self.extra_binary_artifact_dir.as_deref()) {
+            Some(perf_map) => perf_map,
+            None => return,
+        };
+        let avma_range = start_avma..start_avma + size;
+        let symbol_table = match perf_map.symbol_table_for_range(avma_range.clone(), start_avma) {
+            Some(symbol_table) => symbol_table,
+            None => return,
+        };
+        let path = perf_map.path.to_string_lossy().to_string();
+        let name = perf_map
+            .path
+            .file_name()
+            .map_or(path.clone(), |f| f.to_string_lossy().to_string());
+        let lib = LibraryInfo {
+            base_avma: start_avma,
+            avma_range,
+            debug_id: DebugId::nil(),
+            code_id: None,
+            path: path.clone(),
+            debug_path: path,
+            debug_name: name.clone(),
+            name,
+            arch: None,
+            symbol_table: Some(Arc::new(symbol_table)),
+        };
+        let process = self
+            .processes
+            .get_by_pid(pid, &mut self.profile, &self.kernel_modules);
+        self.profile.add_lib(process.profile_process, lib);
+    }
+
     pub fn handle_context_switch(&mut self, e: ContextSwitchRecord, common: CommonData) {
         let pid = common.pid.expect("Can't handle samples without pids");
         let tid = common.tid.expect("Can't handle samples without tids");
@@ -557,6 +628,14 @@ where
             .threads
             .get_by_tid(e.tid, process_handle, is_main, &mut self.profile);
         let thread_handle = thread.profile_thread;
+        if let Some(run) = thread.cpu_runs.finish() {
+            add_cpu_marker(
+                run,
+                thread_handle,
+                &self.timestamp_converter,
+                &mut self.profile,
+            );
+        }
         self.profile.set_thread_end_time(thread_handle, end_time);
         self.threads.0.remove(&e.tid);
         if is_main {
@@ -692,6 +771,24 @@ impl TimestampConverter {
     }
 }
 
+/// Adds a "CPU" marker which spans the samples of `run`.
+fn add_cpu_marker(
+    run: CpuRun,
+    thread_handle: ThreadHandle,
+    timestamp_converter: &TimestampConverter,
+    profile: &mut Profile,
+) {
+    profile.add_marker(
+        thread_handle,
+        "CPU",
+        CpuMarker(run.cpu),
+        MarkerTiming::Interval(
+            timestamp_converter.convert_time(run.start),
+            timestamp_converter.convert_time(run.end),
+        ),
+    );
+}
+
 fn process_off_cpu_sample_group(
     off_cpu_sample: OffCpuSampleGroup,
     thread_handle: ThreadHandle,
@@ -829,6 +926,7 @@ impl Threads {
                 context_switch_data: Default::default(),
                 last_sample_timestamp: None,
                 off_cpu_stack: Vec::new(),
+                cpu_runs: ThreadCpuRuns::default(),
             }
         })
     }
@@ -839,6 +937,8 @@ struct Thread {
     context_switch_data: ThreadContextSwitchData,
     last_sample_timestamp: Option<u64>,
     off_cpu_stack: Vec<(Frame, CategoryPairHandle)>,
+    /// The CPUs which the thread's samples were taken on.
+    cpu_runs: ThreadCpuRuns,
 }
 
 struct Process<U> {
@@ -1344,6 +1444,10 @@ where
     })
 }
 
+fn is_anonymous_mapping(path: &[u8]) -> bool {
+    path == b"//anon" || path.starts_with(b"[anon:")
+}
+
 fn kernel_module_build_id(
     path: &Path,
     extra_binary_artifact_dir: Option<&Path>,
@@ -0,0 +1,112 @@
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use fxprof_processed_profile::{Symbol, SymbolTable};
+
+/// The symbols from a perf map file, `/tmp/perf-<pid>.map`, in which JITs such
+/// as V8 or the JVM list the functions they generate. Each line has the format
+/// `<hex start address> <hex size> <name>`.
+#[derive(Debug, Clone)]
+pub struct PerfMap {
+    pub path: PathBuf,
+    /// (start address, size, name), in file order
+    entries: Vec<(u64, u64, String)>,
+}
+
+impl PerfMap {
+    /// Reads the perf map file of the process with `pid`. When converting a
+    /// perf.data file, the perf map can be placed next to it, in
+    /// `extra_binary_artifact_dir`; otherwise it's read from `/tmp`.
+    pub fn read_for_pid(pid: i32, extra_binary_artifact_dir: Option<&Path>) -> Option<Self> {
+        let file_name = format!("perf-{}.map", pid);
+        let candidates = extra_binary_artifact_dir
+            .map(|dir| dir.join(&file_name))
+            .into_iter()
+            .chain(std::iter::once(Path::new("/tmp").join(&file_name)));
+        for path in candidates {
+            if let Ok(data) = std::fs::read(&path) {
+                return Some(Self::parse(path, &data));
+            }
+        }
+        None
+    }
+
+    pub fn parse(path: PathBuf, data: &[u8]) -> Self {
+        let entries = String::from_utf8_lossy(data)
+            .lines()
+            .filter_map(parse_line)
+            .collect();
+        PerfMap { path, entries }
+    }
+
+    /// Returns the symbols in `avma_range`, with addresses relative to
+    /// `base_avma`, or `None` if there are none.
+    pub fn symbol_table_for_range(
+        &self,
+        avma_range: Range<u64>,
+        base_avma: u64,
+    ) -> Option<SymbolTable> {
+        // If the JIT reused an address, the most recent entry describes the
+        // current code, so later entries replace earlier ones.
+        let mut symbols = BTreeMap::new();
+        for (start, size, name) in &self.entries {
+            if !avma_range.contains(start) {
+                continue;
+            }
+            let relative_address = start.checked_sub(base_avma);
+            if let Some(address) = relative_address.and_then(|a| u32::try_from(a).ok()) {
+                let symbol = Symbol {
+                    address,
+                    size: u32::try_from(*size).ok(),
+                    name: name.clone(),
+                };
+                symbols.insert(address, symbol);
+            }
+        }
+        if symbols.is_empty() {
+            return None;
+        }
+        Some(SymbolTable::new(symbols.into_values().collect()))
+    }
+}
+
+fn parse_line(line: &str) -> Option<(u64, u64, String)> {
+    let mut parts = line.trim_end().splitn(3, ' ');
+    let start = parse_hex(parts.next()?)?;
+    let size = parse_hex(parts.next()?)?;
+    let name = parts.next()?;
+    Some((start, size, name.to_owned()))
+}
+
+fn parse_hex(s: &str) -> Option<u64> {
+    let s = s.strip_prefix("0x").unwrap_or(s);
+    u64::from_str_radix(s, 16).ok()
+}
+
+#[test]
+fn test_perf_map() {
+    let data = b"7f0000001000 40 LazyCompile:~foo file.js:1\n\
+                 0x7f0000001040 0x20 bar\n\
+                 not a valid line\n\
+                 7f0000001000 10 LazyCompile:*foo file.js:1\n\
+                 7f0000100000 10 elsewhere\n";
+    let perf_map = PerfMap::parse(PathBuf::from("/tmp/perf-123.map"), data);
+    let symbol_table = perf_map
+        .symbol_table_for_range(0x7f0000001000..0x7f0000002000, 0x7f0000001000)
+        .unwrap();
+    // The most recent entry for an address wins.
+    assert_eq!(
+        symbol_table.lookup(0x8).map(|s| s.name.as_str()),
+        Some("LazyCompile:*foo file.js:1")
+    );
+    assert_eq!(
+        symbol_table.lookup(0x48).map(|s| s.name.as_str()),
+        Some("bar")
+    );
+    assert_eq!(symbol_table.lookup(0x60), None);
+    assert!(perf_map
+        .symbol_table_for_range(0x7f0000200000..0x7f0000300000, 0x7f0000200000)
+        .is_none());
+}
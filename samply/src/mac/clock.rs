@@ -0,0 +1,61 @@
+use std::time::{Instant, SystemTime};
+
+/// The source of the current time for the sampler. Sample timestamps, sample
+/// weights and the time limit are all based on it, so that tests can control
+/// time with a [`MockClock`] instead of sleeping. Recordings use [`RealClock`].
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+
+    /// The wall-clock time, which is recorded as the profile's start time.
+    fn system_time(&self) -> SystemTime;
+}
+
+/// The operating system's clock.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RealClock;
+
+impl Clock for RealClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn system_time(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// A clock which only moves when it's told to. Clones share the same time, so
+/// a test can keep one clone and hand another one to the sampler.
+#[cfg(test)]
+#[derive(Debug, Clone)]
+pub struct MockClock {
+    start_instant: Instant,
+    start_system_time: SystemTime,
+    elapsed: std::sync::Arc<std::sync::Mutex<std::time::Duration>>,
+}
+
+#[cfg(test)]
+impl MockClock {
+    pub fn new() -> Self {
+        Self {
+            start_instant: Instant::now(),
+            start_system_time: SystemTime::now(),
+            elapsed: Default::default(),
+        }
+    }
+
+    pub fn advance(&self, duration: std::time::Duration) {
+        *self.elapsed.lock().unwrap() += duration;
+    }
+}
+
+#[cfg(test)]
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.start_instant + *self.elapsed.lock().unwrap()
+    }
+
+    fn system_time(&self) -> SystemTime {
+        self.start_system_time + *self.elapsed.lock().unwrap()
+    }
+}
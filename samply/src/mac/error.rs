@@ -1,12 +1,10 @@
 use thiserror::Error;
 
 use super::kernel_error::KernelError;
+use super::mach_ipc::MachError;
 
 #[derive(Debug, Clone, Error)]
 pub enum SamplingError {
-    #[error("Fatal error encountered during sampling: {0}, {1}")]
-    Fatal(&'static str, KernelError),
-
     #[error("Ignorable error encountered during sampling: {0}, {1}")]
     Ignorable(&'static str, KernelError),
 
@@ -18,4 +16,97 @@ pub enum SamplingError {
 
     #[error("Could not obtain root task.")]
     CouldNotObtainRootTask,
+
+    #[error(transparent)]
+    CouldNotAttach(#[from] AttachError),
+
+    #[error("Could not receive a task port on mach service {0}: {1:?}")]
+    CouldNotReceiveTaskPort(String, MachError),
+}
+
+/// Why the task port of a running process couldn't be obtained.
+#[derive(Debug, Clone, Error)]
+pub enum AttachError {
+    #[error("Not allowed to access process {0}. Recording a running process requires root privileges, so try again with sudo. Processes which are protected by System Integrity Protection (SIP), such as system apps, can only be recorded with SIP disabled, which should only be done on test machines. Other signed executables need to be re-signed with the com.apple.security.get-task-allow entitlement, using codesign.")]
+    PermissionDenied(u32),
+
+    #[error("There is no process with pid {0}.")]
+    NoSuchProcess(u32),
+
+    #[error("Could not get the task port of process {0}: {1}")]
+    TaskForPidFailed(u32, KernelError),
+
+    #[error("Process {0} is samply itself. Recording it would suspend the sampler thread and hang samply.")]
+    OwnProcess(u32),
+
+    #[error("Process 0 is the kernel (kernel_task), which can't be recorded.")]
+    KernelTask,
+}
+
+impl AttachError {
+    /// Rejects the pids which must never be attached to: our own process,
+    /// whose threads include the sampler thread, and the kernel.
+    pub fn check_pid(pid: u32) -> Result<(), Self> {
+        if pid == 0 {
+            Err(AttachError::KernelTask)
+        } else if pid == std::process::id() {
+            Err(AttachError::OwnProcess(pid))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Classifies an error from `task_for_pid` for `pid`. The kernel reports a
+    /// missing process and a denied request with the same error, so this checks
+    /// whether the process exists.
+    pub fn from_task_for_pid_error(pid: u32, err: KernelError) -> Self {
+        match err {
+            KernelError::Failure | KernelError::ProtectionFailure => {
+                let exists = unsafe { libc::kill(pid as i32, 0) } == 0
+                    || std::io::Error::last_os_error().raw_os_error() != Some(libc::ESRCH);
+                if exists {
+                    AttachError::PermissionDenied(pid)
+                } else {
+                    AttachError::NoSuchProcess(pid)
+                }
+            }
+            err => AttachError::TaskForPidFailed(pid, err),
+        }
+    }
+}
+
+#[test]
+fn test_attach_error_classification() {
+    let pid = std::process::id();
+    assert!(matches!(
+        AttachError::from_task_for_pid_error(pid, KernelError::Failure),
+        AttachError::PermissionDenied(p) if p == pid
+    ));
+    assert!(matches!(
+        AttachError::from_task_for_pid_error(pid, KernelError::ProtectionFailure),
+        AttachError::PermissionDenied(p) if p == pid
+    ));
+    assert!(matches!(
+        AttachError::from_task_for_pid_error(pid, KernelError::InvalidArgument),
+        AttachError::TaskForPidFailed(p, KernelError::InvalidArgument) if p == pid
+    ));
+    // Larger than any pid that macOS hands out.
+    assert!(matches!(
+        AttachError::from_task_for_pid_error(99_999_999, KernelError::Failure),
+        AttachError::NoSuchProcess(99_999_999)
+    ));
+}
+
+#[test]
+fn test_attach_check_pid() {
+    let own_pid = std::process::id();
+    assert!(matches!(
+        AttachError::check_pid(own_pid),
+        Err(AttachError::OwnProcess(p)) if p == own_pid
+    ));
+    assert!(matches!(
+        AttachError::check_pid(0),
+        Err(AttachError::KernelTask)
+    ));
+    assert!(AttachError::check_pid(1).is_ok());
 }
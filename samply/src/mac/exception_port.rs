@@ -0,0 +1,569 @@
+//! Receives the EXC_CRASH exception of a profiled task, so that we can grab one
+//! last stack from the crashing thread before the process goes away.
+//! Optionally, other exceptions are received as well, so that a stack can be
+//! captured every time one of them is raised, see [`ExceptionKind`].
+//!
+//! Setup: We allocate a port with a receive right, give it a send right, and
+//! register it as the task's EXC_CRASH handler with `task_swap_exception_ports`.
+//! Exceptions for which the task already has a task-level handler, e.g. a crash
+//! reporter like Crashpad, or a runtime which handles its own EXC_BAD_ACCESS
+//! faults, are left alone: we only register for the exceptions which would
+//! otherwise go straight to the host-level handler.
+//! The kernel raises EXC_CRASH when the process is about to be terminated by a
+//! fatal signal, i.e. after any signal handlers in the process have had their
+//! chance. The crashing thread then blocks until the exception message has been
+//! answered, and its memory and thread state can still be inspected in the
+//! meantime. Non-fatal exceptions, such as EXC_BAD_ACCESS faults which are
+//! handled by the process itself, are not affected, unless they were selected.
+//! Those arrive before the process's own signal handlers run, and the raising
+//! thread blocks in the same way.
+//!
+//! Cleanup: Every received exception message must be replied to, otherwise the
+//! crashing thread never continues and the process never terminates. We reply
+//! with `KERN_FAILURE`, so that the kernel continues with the host-level handler
+//! (e.g. the system crash reporter, or the conversion into a signal for the
+//! process). Since there was no task-level handler for these exceptions, this is
+//! as if we had never been there. The thread and task port rights that come with
+//! the message must be deallocated. Both happen when the [`PendingException`] is
+//! dropped. When the [`ExceptionPort`] is dropped, our registration is removed
+//! again for the exceptions which are still sent to our port, and the port is
+//! destroyed. Handlers which the process installed in the meantime are kept.
+
+use mach::exception_types::{
+    exception_behavior_t, exception_mask_t, exception_type_t, mach_exception_data_type_t,
+    EXCEPTION_DEFAULT, EXC_ARITHMETIC, EXC_BAD_ACCESS, EXC_BAD_INSTRUCTION, EXC_BREAKPOINT,
+    EXC_CRASH, EXC_EMULATION, EXC_GUARD, EXC_MASK_ARITHMETIC, EXC_MASK_BAD_ACCESS,
+    EXC_MASK_BAD_INSTRUCTION, EXC_MASK_BREAKPOINT, EXC_MASK_CRASH, EXC_MASK_GUARD,
+    EXC_MASK_RESOURCE, EXC_RESOURCE, EXC_SOFTWARE, MACH_EXCEPTION_CODES,
+};
+use mach::kern_return::{kern_return_t, KERN_FAILURE};
+use mach::mach_port::{mach_port_allocate, mach_port_deallocate, mach_port_insert_right};
+use mach::mach_types::{task_t, thread_act_t};
+use mach::message::{
+    mach_msg, mach_msg_body_t, mach_msg_destroy, mach_msg_header_t, mach_msg_port_descriptor_t,
+    mach_msg_trailer_t, mach_msg_type_number_t, MACH_MSGH_BITS, MACH_MSGH_BITS_REMOTE_MASK,
+    MACH_MSG_SUCCESS, MACH_MSG_TYPE_MAKE_SEND, MACH_RCV_MSG, MACH_RCV_TIMEOUT, MACH_SEND_MSG,
+};
+use mach::port::MACH_PORT_RIGHT_RECEIVE;
+use mach::port::{mach_port_name_t, mach_port_right_t, mach_port_t, MACH_PORT_NULL};
+use mach::thread_status::{thread_state_flavor_t, THREAD_STATE_NONE};
+use mach::traps::mach_task_self;
+
+use std::mem;
+
+use super::kernel_error::{IntoResult, KernelError};
+use crate::exception_sampling::ExceptionKind;
+
+/// The number of exception types, from `mach/exception_types.h`.
+const EXC_TYPES_COUNT: usize = 14;
+
+/// The MIG message id of `mach_exception_raise`, from `mach_exc.defs`.
+const MACH_EXCEPTION_RAISE_ID: i32 = 2405;
+
+/// MIG replies use the request id plus 100.
+const MIG_REPLY_ID_OFFSET: i32 = 100;
+
+extern "C" {
+    fn task_swap_exception_ports(
+        task: task_t,
+        exception_mask: exception_mask_t,
+        new_port: mach_port_t,
+        behavior: exception_behavior_t,
+        new_flavor: thread_state_flavor_t,
+        masks: *mut exception_mask_t,
+        masks_count: *mut mach_msg_type_number_t,
+        old_handlers: *mut mach_port_t,
+        old_behaviors: *mut exception_behavior_t,
+        old_flavors: *mut thread_state_flavor_t,
+    ) -> kern_return_t;
+
+    fn task_get_exception_ports(
+        task: task_t,
+        exception_mask: exception_mask_t,
+        masks: *mut exception_mask_t,
+        masks_count: *mut mach_msg_type_number_t,
+        old_handlers: *mut mach_port_t,
+        old_behaviors: *mut exception_behavior_t,
+        old_flavors: *mut thread_state_flavor_t,
+    ) -> kern_return_t;
+
+    fn task_set_exception_ports(
+        task: task_t,
+        exception_mask: exception_mask_t,
+        new_port: mach_port_t,
+        behavior: exception_behavior_t,
+        new_flavor: thread_state_flavor_t,
+    ) -> kern_return_t;
+
+    fn mach_port_mod_refs(
+        task: mach_port_t,
+        name: mach_port_name_t,
+        right: mach_port_right_t,
+        delta: i32,
+    ) -> kern_return_t;
+}
+
+/// The request message of `mach_exception_raise`, as laid out by MIG.
+#[repr(C, packed(4))]
+#[derive(Clone, Copy)]
+struct ExceptionRaiseRequest {
+    header: mach_msg_header_t,
+    body: mach_msg_body_t,
+    thread: mach_msg_port_descriptor_t,
+    task: mach_msg_port_descriptor_t,
+    ndr: [u8; 8],
+    exception: exception_type_t,
+    code_count: mach_msg_type_number_t,
+    code: [mach_exception_data_type_t; 2],
+}
+
+/// Receive buffer for [`ExceptionRaiseRequest`], with room for the trailer which
+/// the kernel appends.
+#[repr(C)]
+struct ExceptionRaiseRequestBuffer {
+    request: ExceptionRaiseRequest,
+    trailer: mach_msg_trailer_t,
+}
+
+/// The reply message of `mach_exception_raise`.
+#[repr(C, packed(4))]
+struct ExceptionRaiseReply {
+    header: mach_msg_header_t,
+    ndr: [u8; 8],
+    ret_code: kern_return_t,
+}
+
+/// A task-level exception handler, as returned by `task_get_exception_ports`
+/// and `task_swap_exception_ports`.
+#[derive(Debug, Clone, Copy)]
+struct Handler {
+    mask: exception_mask_t,
+    port: mach_port_t,
+    behavior: exception_behavior_t,
+    flavor: thread_state_flavor_t,
+}
+
+/// A port which receives the EXC_CRASH exception of a task, and the exceptions
+/// which were selected for stack captures. See the module documentation for
+/// the setup and cleanup requirements.
+pub struct ExceptionPort {
+    task: task_t,
+    port: mach_port_t,
+    /// The exceptions which we registered our port for.
+    installed_mask: exception_mask_t,
+}
+
+impl ExceptionPort {
+    /// Registers a new port as the handler of EXC_CRASH and of the exceptions in
+    /// `sample_kinds` of `task`, except for the exceptions which the task
+    /// already handles itself. See [`ExceptionPort::handles`].
+    pub fn install(task: task_t, sample_kinds: &[ExceptionKind]) -> Result<Self, KernelError> {
+        let mut port = MACH_PORT_NULL;
+        unsafe { mach_port_allocate(mach_task_self(), MACH_PORT_RIGHT_RECEIVE, &mut port) }
+            .into_result()?;
+        let mut exception_port = ExceptionPort {
+            task,
+            port,
+            installed_mask: 0,
+        };
+        unsafe { mach_port_insert_right(mach_task_self(), port, port, MACH_MSG_TYPE_MAKE_SEND) }
+            .into_result()?;
+
+        let requested_mask = EXC_MASK_CRASH | exception_mask(sample_kinds);
+        let current_handlers = get_exception_handlers(task, requested_mask)?;
+        let free_mask = mask_handled_by(&current_handlers, MACH_PORT_NULL);
+        if free_mask == 0 {
+            return Ok(exception_port);
+        }
+
+        let mut masks = [0; EXC_TYPES_COUNT];
+        let mut masks_count = EXC_TYPES_COUNT as mach_msg_type_number_t;
+        let mut old_handlers = [MACH_PORT_NULL; EXC_TYPES_COUNT];
+        let mut old_behaviors = [0; EXC_TYPES_COUNT];
+        let mut old_flavors = [0; EXC_TYPES_COUNT];
+        unsafe {
+            task_swap_exception_ports(
+                task,
+                free_mask,
+                port,
+                (EXCEPTION_DEFAULT | MACH_EXCEPTION_CODES) as exception_behavior_t,
+                THREAD_STATE_NONE,
+                masks.as_mut_ptr(),
+                &mut masks_count,
+                old_handlers.as_mut_ptr(),
+                old_behaviors.as_mut_ptr(),
+                old_flavors.as_mut_ptr(),
+            )
+        }
+        .into_result()?;
+        exception_port.installed_mask = free_mask;
+
+        // If the process installed a handler between the two calls, give the
+        // exceptions back to it right away.
+        for i in 0..masks_count as usize {
+            let handler = Handler {
+                mask: masks[i],
+                port: old_handlers[i],
+                behavior: old_behaviors[i],
+                flavor: old_flavors[i],
+            };
+            if handler.port == MACH_PORT_NULL {
+                continue;
+            }
+            unsafe {
+                task_set_exception_ports(
+                    task,
+                    handler.mask,
+                    handler.port,
+                    handler.behavior,
+                    handler.flavor,
+                );
+                mach_port_deallocate(mach_task_self(), handler.port);
+            }
+            exception_port.installed_mask &= !handler.mask;
+        }
+        Ok(exception_port)
+    }
+
+    /// Whether exceptions of this kind are received. This is false for the
+    /// kinds which the task handles with its own task-level handler.
+    pub fn handles(&self, kind: ExceptionKind) -> bool {
+        self.installed_mask & exception_mask(&[kind]) != 0
+    }
+
+    /// Returns the next exception message, if one is waiting. Does not block.
+    pub fn try_receive(&self) -> Option<PendingException> {
+        let mut buffer: ExceptionRaiseRequestBuffer = unsafe { mem::zeroed() };
+        let result = unsafe {
+            mach_msg(
+                &mut buffer.request.header,
+                MACH_RCV_MSG | MACH_RCV_TIMEOUT,
+                0,
+                mem::size_of::<ExceptionRaiseRequestBuffer>() as u32,
+                self.port,
+                0,
+                MACH_PORT_NULL,
+            )
+        };
+        if result != MACH_MSG_SUCCESS {
+            return None;
+        }
+        let request = buffer.request;
+        if request.header.msgh_id != MACH_EXCEPTION_RAISE_ID {
+            // We only asked for mach_exception_raise messages.
+            unsafe { mach_msg_destroy(&mut buffer.request.header) };
+            return None;
+        }
+        Some(PendingException { request })
+    }
+}
+
+impl Drop for ExceptionPort {
+    fn drop(&mut self) {
+        // Only unregister for the exceptions which still go to our port. If the
+        // process registered its own handler for some of them after we did, it
+        // must stay in place. This fails if the task is already gone, which is
+        // fine.
+        if self.installed_mask != 0 {
+            if let Ok(handlers) = get_exception_handlers(self.task, self.installed_mask) {
+                let still_ours = mask_handled_by(&handlers, self.port);
+                if still_ours != 0 {
+                    unsafe {
+                        task_set_exception_ports(
+                            self.task,
+                            still_ours,
+                            MACH_PORT_NULL,
+                            EXCEPTION_DEFAULT as exception_behavior_t,
+                            THREAD_STATE_NONE,
+                        )
+                    };
+                }
+            }
+        }
+        // Destroying the receive right also discards any messages which are still
+        // queued. The kernel treats those as unhandled.
+        unsafe {
+            mach_port_deallocate(mach_task_self(), self.port);
+            mach_port_mod_refs(mach_task_self(), self.port, MACH_PORT_RIGHT_RECEIVE, -1);
+        }
+    }
+}
+
+/// An exception message which has not been replied to yet. The faulting thread
+/// stays blocked until this is dropped.
+pub struct PendingException {
+    request: ExceptionRaiseRequest,
+}
+
+impl PendingException {
+    /// The thread which raised the exception.
+    pub fn thread_act(&self) -> thread_act_t {
+        self.request.thread.name
+    }
+
+    /// Whether the process is about to be terminated, as opposed to one of the
+    /// exceptions which were selected for stack captures.
+    pub fn is_crash(&self) -> bool {
+        self.request.exception == EXC_CRASH as exception_type_t
+    }
+
+    pub fn crash_info(&self) -> CrashInfo {
+        let exception = self.request.exception;
+        let code = self.request.code;
+        if exception == EXC_CRASH as exception_type_t {
+            // For EXC_CRASH, the first code packs the original exception type,
+            // the signal number, and the original exception code.
+            CrashInfo {
+                exception: ((code[0] >> 20) & 0xf) as exception_type_t,
+                signal: ((code[0] >> 24) & 0xff) as i32,
+                code: code[0] & 0xfffff,
+                subcode: code[1],
+            }
+        } else {
+            CrashInfo {
+                exception,
+                signal: 0,
+                code: code[0],
+                subcode: code[1],
+            }
+        }
+    }
+}
+
+impl Drop for PendingException {
+    fn drop(&mut self) {
+        let request_header = self.request.header;
+        let mut reply = ExceptionRaiseReply {
+            header: mach_msg_header_t {
+                msgh_bits: MACH_MSGH_BITS(request_header.msgh_bits & MACH_MSGH_BITS_REMOTE_MASK, 0),
+                msgh_size: mem::size_of::<ExceptionRaiseReply>() as u32,
+                msgh_remote_port: request_header.msgh_remote_port,
+                msgh_local_port: MACH_PORT_NULL,
+                msgh_voucher_port: MACH_PORT_NULL,
+                msgh_id: request_header.msgh_id + MIG_REPLY_ID_OFFSET,
+            },
+            ndr: self.request.ndr,
+            // Let the kernel continue with the next exception handler, so that
+            // the crash proceeds as if we hadn't intercepted it.
+            ret_code: KERN_FAILURE,
+        };
+        unsafe {
+            mach_msg(
+                &mut reply.header,
+                MACH_SEND_MSG,
+                mem::size_of::<ExceptionRaiseReply>() as u32,
+                0,
+                MACH_PORT_NULL,
+                0,
+                MACH_PORT_NULL,
+            );
+            mach_port_deallocate(mach_task_self(), self.request.thread.name);
+            mach_port_deallocate(mach_task_self(), self.request.task.name);
+        }
+    }
+}
+
+/// Returns the task-level handlers of the exceptions in `mask`. Exceptions
+/// without a handler have a null port.
+///
+/// The send rights which come with the handlers are deallocated, so the ports
+/// can only be compared by name, e.g. to a port which we hold a right to.
+fn get_exception_handlers(
+    task: task_t,
+    mask: exception_mask_t,
+) -> Result<Vec<Handler>, KernelError> {
+    let mut masks = [0; EXC_TYPES_COUNT];
+    let mut masks_count = EXC_TYPES_COUNT as mach_msg_type_number_t;
+    let mut ports = [MACH_PORT_NULL; EXC_TYPES_COUNT];
+    let mut behaviors = [0; EXC_TYPES_COUNT];
+    let mut flavors = [0; EXC_TYPES_COUNT];
+    unsafe {
+        task_get_exception_ports(
+            task,
+            mask,
+            masks.as_mut_ptr(),
+            &mut masks_count,
+            ports.as_mut_ptr(),
+            behaviors.as_mut_ptr(),
+            flavors.as_mut_ptr(),
+        )
+    }
+    .into_result()?;
+    let handlers: Vec<Handler> = (0..masks_count as usize)
+        .map(|i| Handler {
+            mask: masks[i],
+            port: ports[i],
+            behavior: behaviors[i],
+            flavor: flavors[i],
+        })
+        .collect();
+    for handler in &handlers {
+        if handler.port != MACH_PORT_NULL {
+            unsafe { mach_port_deallocate(mach_task_self(), handler.port) };
+        }
+    }
+    Ok(handlers)
+}
+
+/// The exceptions which `handlers` send to `port`.
+fn mask_handled_by(handlers: &[Handler], port: mach_port_t) -> exception_mask_t {
+    handlers
+        .iter()
+        .filter(|handler| handler.port == port)
+        .fold(0, |mask, handler| mask | handler.mask)
+}
+
+/// Returns the mask for `task_swap_exception_ports` which selects the exceptions
+/// of `kinds`.
+fn exception_mask(kinds: &[ExceptionKind]) -> exception_mask_t {
+    kinds
+        .iter()
+        .map(|kind| match kind {
+            ExceptionKind::BadAccess => EXC_MASK_BAD_ACCESS,
+            ExceptionKind::BadInstruction => EXC_MASK_BAD_INSTRUCTION,
+            ExceptionKind::Arithmetic => EXC_MASK_ARITHMETIC,
+            ExceptionKind::Breakpoint => EXC_MASK_BREAKPOINT,
+            ExceptionKind::Resource => EXC_MASK_RESOURCE,
+            ExceptionKind::Guard => EXC_MASK_GUARD,
+        })
+        .fold(0, |mask, kind_mask| mask | kind_mask)
+}
+
+/// Describes what caused a crash, or which exception a stack was captured for.
+/// The signal is only known for crashes.
+#[derive(Debug, Clone, Copy)]
+pub struct CrashInfo {
+    /// The exception type, e.g. `EXC_BAD_ACCESS`. This is `0` if the crash was
+    /// caused by a signal which wasn't preceded by a Mach exception, e.g. by
+    /// `abort()`.
+    pub exception: exception_type_t,
+    /// The signal which terminated the process, or `0` if unknown.
+    pub signal: i32,
+    /// The exception code, e.g. `KERN_INVALID_ADDRESS` for `EXC_BAD_ACCESS`.
+    pub code: i64,
+    /// The exception subcode, e.g. the faulting address for `EXC_BAD_ACCESS`.
+    pub subcode: i64,
+}
+
+impl CrashInfo {
+    pub fn exception_name(&self) -> &'static str {
+        match self.exception as u32 {
+            0 => "none",
+            EXC_BAD_ACCESS => "EXC_BAD_ACCESS",
+            EXC_BAD_INSTRUCTION => "EXC_BAD_INSTRUCTION",
+            EXC_ARITHMETIC => "EXC_ARITHMETIC",
+            EXC_EMULATION => "EXC_EMULATION",
+            EXC_SOFTWARE => "EXC_SOFTWARE",
+            EXC_BREAKPOINT => "EXC_BREAKPOINT",
+            EXC_CRASH => "EXC_CRASH",
+            EXC_RESOURCE => "EXC_RESOURCE",
+            EXC_GUARD => "EXC_GUARD",
+            _ => "unknown",
+        }
+    }
+
+    pub fn signal_name(&self) -> &'static str {
+        match self.signal {
+            libc::SIGABRT => "SIGABRT",
+            libc::SIGBUS => "SIGBUS",
+            libc::SIGFPE => "SIGFPE",
+            libc::SIGILL => "SIGILL",
+            libc::SIGKILL => "SIGKILL",
+            libc::SIGSEGV => "SIGSEGV",
+            libc::SIGSYS => "SIGSYS",
+            libc::SIGTRAP => "SIGTRAP",
+            _ => "unknown",
+        }
+    }
+}
+
+#[test]
+fn test_exception_mask() {
+    assert_eq!(exception_mask(&[]), 0);
+    assert_eq!(
+        exception_mask(&[ExceptionKind::Resource, ExceptionKind::Breakpoint]),
+        EXC_MASK_RESOURCE | EXC_MASK_BREAKPOINT
+    );
+    // The crash mask is always added by ExceptionPort::install.
+    assert_eq!(exception_mask(&[ExceptionKind::Guard]) & EXC_MASK_CRASH, 0);
+}
+
+#[test]
+fn test_mask_handled_by() {
+    let handler = |mask, port| Handler {
+        mask,
+        port,
+        behavior: EXCEPTION_DEFAULT as exception_behavior_t,
+        flavor: THREAD_STATE_NONE,
+    };
+    let crash_reporter = 0x1103;
+    let samply = 0x2203;
+    let handlers = [
+        handler(EXC_MASK_CRASH | EXC_MASK_BAD_ACCESS, crash_reporter),
+        handler(EXC_MASK_GUARD, MACH_PORT_NULL),
+        handler(EXC_MASK_BREAKPOINT, samply),
+        handler(EXC_MASK_RESOURCE, MACH_PORT_NULL),
+    ];
+    assert_eq!(
+        mask_handled_by(&handlers, MACH_PORT_NULL),
+        EXC_MASK_GUARD | EXC_MASK_RESOURCE
+    );
+    assert_eq!(mask_handled_by(&handlers, samply), EXC_MASK_BREAKPOINT);
+    assert_eq!(
+        mask_handled_by(&handlers, crash_reporter),
+        EXC_MASK_CRASH | EXC_MASK_BAD_ACCESS
+    );
+    assert_eq!(mask_handled_by(&[], MACH_PORT_NULL), 0);
+}
+
+#[test]
+fn test_existing_handlers_are_kept() {
+    // Our own task stands in for a process with its own exception handlers.
+    let task = unsafe { mach_task_self() };
+    let app_port = || {
+        let mut port = MACH_PORT_NULL;
+        unsafe {
+            mach_port_allocate(mach_task_self(), MACH_PORT_RIGHT_RECEIVE, &mut port);
+            mach_port_insert_right(mach_task_self(), port, port, MACH_MSG_TYPE_MAKE_SEND);
+        }
+        port
+    };
+    let set_handler = |mask, port| unsafe {
+        task_set_exception_ports(
+            task,
+            mask,
+            port,
+            (EXCEPTION_DEFAULT | MACH_EXCEPTION_CODES) as exception_behavior_t,
+            THREAD_STATE_NONE,
+        )
+    };
+    let handler_port = |mask| get_exception_handlers(task, mask).unwrap()[0].port;
+
+    // A handler which was there before we attached is not replaced.
+    let app_arithmetic_port = app_port();
+    set_handler(EXC_MASK_ARITHMETIC, app_arithmetic_port);
+    let exception_port = ExceptionPort::install(
+        task,
+        &[ExceptionKind::Arithmetic, ExceptionKind::Breakpoint],
+    )
+    .unwrap();
+    assert!(!exception_port.handles(ExceptionKind::Arithmetic));
+    assert!(exception_port.handles(ExceptionKind::Breakpoint));
+    assert_eq!(handler_port(EXC_MASK_ARITHMETIC), app_arithmetic_port);
+    assert_eq!(handler_port(EXC_MASK_BREAKPOINT), exception_port.port);
+
+    // A handler which was installed while we were attached stays in place.
+    let app_breakpoint_port = app_port();
+    set_handler(EXC_MASK_BREAKPOINT, app_breakpoint_port);
+    drop(exception_port);
+    assert_eq!(handler_port(EXC_MASK_BREAKPOINT), app_breakpoint_port);
+    assert_eq!(handler_port(EXC_MASK_ARITHMETIC), app_arithmetic_port);
+
+    set_handler(EXC_MASK_ARITHMETIC | EXC_MASK_BREAKPOINT, MACH_PORT_NULL);
+    for port in [app_arithmetic_port, app_breakpoint_port] {
+        unsafe {
+            mach_port_deallocate(mach_task_self(), port);
+            mach_port_mod_refs(mach_task_self(), port, MACH_PORT_RIGHT_RECEIVE, -1);
+        }
+    }
+}
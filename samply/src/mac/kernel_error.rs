@@ -29,6 +29,15 @@ impl IntoResult for kern_return_t {
 
 pub type Result<T> = std::result::Result<T, KernelError>;
 
+/// Calls `f`, and calls it once more if it fails with a
+/// [transient](KernelError::is_transient) error.
+pub fn retry_if_transient<T>(mut f: impl FnMut() -> Result<T>) -> Result<T> {
+    match f() {
+        Err(err) if err.is_transient() => f(),
+        result => result,
+    }
+}
+
 #[derive(Error, Debug, Clone, PartialEq, Eq)]
 pub enum KernelError {
     #[error("Specified address is not currently valid.")]
@@ -303,6 +312,22 @@ pub enum KernelError {
     Unknown(kern_return_t),
 }
 
+impl KernelError {
+    /// Whether the call which failed with this error might succeed if it is
+    /// made again, e.g. because it was interrupted. Errors which mean that the
+    /// target thread or task is gone are not transient.
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            KernelError::Aborted
+                | KernelError::ResourceShortage
+                | KernelError::OperationTimedOut
+                | KernelError::MachSendInterrupted
+                | KernelError::MachRcvInterrupted
+        )
+    }
+}
+
 impl From<kern_return_t> for KernelError {
     fn from(err: kern_return_t) -> KernelError {
         match err {
@@ -398,3 +423,36 @@ impl From<kern_return_t> for KernelError {
         }
     }
 }
+
+#[test]
+fn test_retry_if_transient() {
+    let mut calls = 0;
+    let result = retry_if_transient(|| {
+        calls += 1;
+        if calls == 1 {
+            KERN_ABORTED.into_result()
+        } else {
+            KERN_SUCCESS.into_result()
+        }
+    });
+    assert_eq!(result, Ok(()));
+    assert_eq!(calls, 2);
+
+    // Permanent errors are returned right away.
+    let mut calls = 0;
+    let result = retry_if_transient(|| {
+        calls += 1;
+        MACH_SEND_INVALID_DEST.into_result()
+    });
+    assert_eq!(result, Err(KernelError::MachSendInvalidDest));
+    assert_eq!(calls, 1);
+
+    // Transient errors are only retried once.
+    let mut calls = 0;
+    let result = retry_if_transient(|| {
+        calls += 1;
+        KERN_ABORTED.into_result()
+    });
+    assert_eq!(result, Err(KernelError::Aborted));
+    assert_eq!(calls, 2);
+}
@@ -240,6 +240,36 @@ impl OsIpcReceiver {
         }
     }
 
+    /// Like `register_bootstrap_name`, but with a name chosen by the caller.
+    /// Fails if the name is already registered.
+    fn register_bootstrap_name_as(&self, name: &str) -> Result<(), MachError> {
+        let port = self.port.get();
+        debug_assert!(port != MACH_PORT_NULL);
+        unsafe {
+            let mut bootstrap_port = 0;
+            let os_result = mach_sys::task_get_special_port(
+                mach_task_self(),
+                TASK_BOOTSTRAP_PORT,
+                &mut bootstrap_port,
+            );
+            if os_result != KERN_SUCCESS {
+                return Err(KernelError::from(os_result).into());
+            }
+
+            let (right, acquired_right) =
+                mach_port_extract_right(port, MACH_MSG_TYPE_MAKE_SEND as u32)?;
+            debug_assert!(acquired_right == MACH_MSG_TYPE_PORT_SEND as u32);
+
+            let c_name = CString::new(name).unwrap();
+            let os_result = bootstrap_register2(bootstrap_port, c_name.as_ptr(), right, 0);
+            if os_result == BOOTSTRAP_SUCCESS {
+                Ok(())
+            } else {
+                Err(MachError::from(os_result))
+            }
+        }
+    }
+
     fn unregister_global_name(name: String) -> Result<(), MachError> {
         unsafe {
             let mut bootstrap_port = 0;
@@ -639,6 +669,17 @@ impl OsIpcMultiShotServer {
         ))
     }
 
+    /// Create a server which is registered under `name`, so that another
+    /// process which knows the name can send messages to it.
+    pub fn with_name(name: &str) -> Result<OsIpcMultiShotServer, MachError> {
+        let receiver = OsIpcReceiver::new()?;
+        receiver.register_bootstrap_name_as(name)?;
+        Ok(OsIpcMultiShotServer {
+            receiver,
+            name: name.to_owned(),
+        })
+    }
+
     #[allow(clippy::type_complexity)]
     pub fn accept(
         &mut self,
@@ -0,0 +1,274 @@
+//! Reads the allocation log of a task which was launched with the
+//! `MallocStackLogging=1` environment variable.
+//!
+//! With this variable set, libmalloc records every allocation and deallocation
+//! of the process in a log file, together with a "uniqued" stack identifier
+//! for the call stack of the allocating call. Other processes with a send right
+//! to the task port can read this log with the (private) `__mach_stack_logging_*`
+//! functions in libsystem_malloc, which is how `malloc_history` works.
+//!
+//! The log only ever grows, and the enumeration function always starts at its
+//! beginning. [`MallocStackReader`] remembers how many records it has already
+//! seen and only reports the new ones. Since every read goes through the whole
+//! log, reads should be spaced out.
+//!
+//! The records don't say which thread made the allocation.
+
+use mach::kern_return::kern_return_t;
+use mach::mach_types::task_t;
+use mach::vm_types::{mach_vm_address_t, vm_address_t};
+
+use std::collections::HashMap;
+use std::os::raw::c_void;
+
+use super::kernel_error::{IntoResult, KernelError};
+
+/// From `stack_logging.h` in libmalloc.
+const STACK_LOGGING_TYPE_ALLOC: u32 = 2;
+const STACK_LOGGING_TYPE_VM_ALLOCATE: u32 = 16;
+
+/// The number of frames we ask for per stack. Deeper stacks are cut off at
+/// the root end.
+const MAX_ALLOCATION_STACK_FRAMES: u32 = 512;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+#[allow(non_camel_case_types)]
+struct mach_stack_logging_record_t {
+    type_flags: u32,
+    stack_identifier: u64,
+    argument: u64,
+    address: mach_vm_address_t,
+}
+
+extern "C" {
+    fn __mach_stack_logging_start_reading(
+        task: task_t,
+        shared_memory_address: vm_address_t,
+        uses_lite_mode: *mut u32,
+    ) -> kern_return_t;
+
+    fn __mach_stack_logging_stop_reading(task: task_t) -> kern_return_t;
+
+    fn __mach_stack_logging_enumerate_records(
+        task: task_t,
+        address: mach_vm_address_t,
+        enumerator: extern "C" fn(mach_stack_logging_record_t, *mut c_void),
+        context: *mut c_void,
+    ) -> kern_return_t;
+
+    fn __mach_stack_logging_frames_for_uniqued_stack(
+        task: task_t,
+        stack_identifier: u64,
+        stack_frames_buffer: *mut mach_vm_address_t,
+        max_stack_frames: u32,
+        count: *mut u32,
+    ) -> kern_return_t;
+}
+
+/// A sampled allocation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Allocation {
+    /// The return addresses of the allocating call stack, with the leaf first.
+    pub frames: Vec<u64>,
+    /// The size of the allocation, in bytes.
+    pub size: u64,
+}
+
+/// Picks the allocations to report out of the full list of log records. The
+/// records before `seen_record_count` have been handled by a previous read.
+#[derive(Debug, Clone)]
+struct RecordFilter {
+    every_nth: u64,
+    seen_record_count: u64,
+    /// The number of allocations to skip before the next one is picked.
+    allocations_until_pick: u64,
+}
+
+impl RecordFilter {
+    fn new(every_nth: u32) -> Self {
+        Self {
+            every_nth: u64::from(every_nth.max(1)),
+            seen_record_count: 0,
+            allocations_until_pick: 0,
+        }
+    }
+
+    /// Call this once per enumeration, before the first record.
+    fn start_enumeration(&mut self) -> EnumerationState {
+        EnumerationState {
+            skip: self.seen_record_count,
+            picked: Vec::new(),
+        }
+    }
+
+    /// Adds the `(stack_identifier, size)` of the record to `state.picked` if
+    /// it's a new malloc allocation which should be reported.
+    fn process_record(
+        &mut self,
+        state: &mut EnumerationState,
+        type_flags: u32,
+        stack_identifier: u64,
+        size: u64,
+    ) {
+        if state.skip > 0 {
+            state.skip -= 1;
+            return;
+        }
+        self.seen_record_count += 1;
+        // VM allocations are the pages which malloc itself gets from the kernel,
+        // or allocations outside of malloc.
+        if type_flags & STACK_LOGGING_TYPE_ALLOC == 0
+            || type_flags & STACK_LOGGING_TYPE_VM_ALLOCATE != 0
+        {
+            return;
+        }
+        if self.allocations_until_pick == 0 {
+            state.picked.push((stack_identifier, size));
+            self.allocations_until_pick = self.every_nth;
+        }
+        self.allocations_until_pick -= 1;
+    }
+}
+
+struct EnumerationState {
+    skip: u64,
+    picked: Vec<(u64, u64)>,
+}
+
+struct EnumerationContext<'a> {
+    filter: &'a mut RecordFilter,
+    state: EnumerationState,
+}
+
+extern "C" fn enumerate_record(record: mach_stack_logging_record_t, context: *mut c_void) {
+    let context = unsafe { &mut *(context as *mut EnumerationContext) };
+    context.filter.process_record(
+        &mut context.state,
+        record.type_flags,
+        record.stack_identifier,
+        record.argument,
+    );
+}
+
+/// Reads new allocations from the allocation log of a task. The task must have
+/// been launched with `MallocStackLogging=1`.
+pub struct MallocStackReader {
+    task: task_t,
+    filter: RecordFilter,
+    stack_cache: HashMap<u64, Vec<u64>>,
+}
+
+impl MallocStackReader {
+    /// Start reading the allocation log of `task`. Only every `every_nth`
+    /// allocation is reported.
+    ///
+    /// Fails if the task doesn't log its allocations, or if it only keeps a
+    /// table of its live allocations ("lite" mode), which has no history.
+    pub fn start(task: task_t, every_nth: u32) -> Result<Self, KernelError> {
+        let mut uses_lite_mode = 0;
+        unsafe { __mach_stack_logging_start_reading(task, 0, &mut uses_lite_mode) }
+            .into_result()?;
+        if uses_lite_mode != 0 {
+            unsafe { __mach_stack_logging_stop_reading(task) };
+            return Err(KernelError::NotSupported);
+        }
+        Ok(Self {
+            task,
+            filter: RecordFilter::new(every_nth),
+            stack_cache: HashMap::new(),
+        })
+    }
+
+    /// The number of allocations which each reported allocation stands for.
+    pub fn every_nth(&self) -> u32 {
+        self.filter.every_nth as u32
+    }
+
+    /// Returns the sampled allocations which were made since the previous call.
+    pub fn read_new_allocations(&mut self) -> Result<Vec<Allocation>, KernelError> {
+        let mut context = EnumerationContext {
+            state: self.filter.start_enumeration(),
+            filter: &mut self.filter,
+        };
+        unsafe {
+            __mach_stack_logging_enumerate_records(
+                self.task,
+                0,
+                enumerate_record,
+                &mut context as *mut EnumerationContext as *mut c_void,
+            )
+        }
+        .into_result()?;
+
+        let picked = context.state.picked;
+        let mut allocations = Vec::with_capacity(picked.len());
+        for (stack_identifier, size) in picked {
+            let frames = match self.stack_cache.get(&stack_identifier) {
+                Some(frames) => frames.clone(),
+                None => {
+                    let frames = self.frames_for_stack(stack_identifier)?;
+                    self.stack_cache.insert(stack_identifier, frames.clone());
+                    frames
+                }
+            };
+            allocations.push(Allocation { frames, size });
+        }
+        Ok(allocations)
+    }
+
+    fn frames_for_stack(&self, stack_identifier: u64) -> Result<Vec<u64>, KernelError> {
+        let mut frames = vec![0; MAX_ALLOCATION_STACK_FRAMES as usize];
+        let mut count = 0;
+        unsafe {
+            __mach_stack_logging_frames_for_uniqued_stack(
+                self.task,
+                stack_identifier,
+                frames.as_mut_ptr(),
+                MAX_ALLOCATION_STACK_FRAMES,
+                &mut count,
+            )
+        }
+        .into_result()?;
+        frames.truncate(count as usize);
+        Ok(frames)
+    }
+}
+
+impl Drop for MallocStackReader {
+    fn drop(&mut self) {
+        unsafe { __mach_stack_logging_stop_reading(self.task) };
+    }
+}
+
+#[test]
+fn test_record_filter() {
+    let enumerate = |filter: &mut RecordFilter, records: &[(u32, u64)]| {
+        let mut state = filter.start_enumeration();
+        for (index, (type_flags, size)) in records.iter().enumerate() {
+            filter.process_record(&mut state, *type_flags, index as u64, *size);
+        }
+        state.picked
+    };
+    let free = 0;
+    let malloc = STACK_LOGGING_TYPE_ALLOC;
+    let vm_allocate = STACK_LOGGING_TYPE_ALLOC | STACK_LOGGING_TYPE_VM_ALLOCATE;
+
+    let mut filter = RecordFilter::new(2);
+    let records = vec![(malloc, 16), (free, 0), (vm_allocate, 4096), (malloc, 32)];
+    assert_eq!(enumerate(&mut filter, &records), vec![(0, 16)]);
+
+    // The next enumeration starts from the beginning of the log again, and only
+    // the new records count.
+    let mut records = records;
+    records.extend([(malloc, 48), (malloc, 64)]);
+    assert_eq!(enumerate(&mut filter, &records), vec![(4, 48)]);
+    assert_eq!(enumerate(&mut filter, &records), vec![]);
+
+    // Every allocation is reported with an interval of 0 or 1.
+    let mut filter = RecordFilter::new(0);
+    assert_eq!(
+        enumerate(&mut filter, &[(malloc, 16), (malloc, 32)]),
+        vec![(0, 16), (1, 32)]
+    );
+}
@@ -1,13 +1,21 @@
 #[allow(deref_nullptr)]
 mod dyld_bindings;
 
+mod clock;
 mod error;
+mod exception_port;
 pub mod kernel_error;
 mod mach_ipc;
+mod malloc_stack_logging;
 mod proc_maps;
 mod process_launcher;
+pub mod process_list;
 pub mod profiler;
+pub mod sample_once;
 mod sampler;
+mod stack_scan;
+mod system_info;
+mod task_port_receiver;
 mod task_profiler;
 pub mod thread_act;
 pub mod thread_info;
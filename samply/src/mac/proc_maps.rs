@@ -42,8 +42,10 @@ use mach::{structs::x86_thread_state64_t, thread_status::x86_THREAD_STATE64};
 
 use super::dyld_bindings::{self};
 use super::error::SamplingError;
-use super::kernel_error::{self, IntoResult, KernelError};
+use super::kernel_error::{self, retry_if_transient, IntoResult, KernelError};
+use super::stack_scan::{scan_stack, MAX_IMPLAUSIBLE_FRAME_COUNT};
 use super::task_profiler::UnwindSectionBytes;
+use crate::code_ranges::CodeRanges;
 
 pub const TASK_DYLD_INFO_COUNT: mach_msg_type_number_t = 5;
 
@@ -96,6 +98,12 @@ impl DyldInfoManager {
         }
     }
 
+    /// The libraries which were loaded at the last call to
+    /// [`DyldInfoManager::check_for_changes`], sorted by address.
+    pub fn current_libs(&self) -> &[DyldInfo] {
+        &self.saved_image_info
+    }
+
     pub fn unmap_memory(&mut self) {
         self.memory.clear();
     }
@@ -109,7 +117,7 @@ impl DyldInfoManager {
         {
             let image_infos: &dyld_all_image_infos =
                 unsafe { self.memory.get_type_ref_at_address(info_addr) }?;
-            // infoArrayChangeTimestamp is 10.12+. TODO: check version
+            // last_change_timestamp is only set if the structure has this field.
             if image_infos.infoArrayChangeTimestamp == last_change_timestamp {
                 return Ok(Vec::new());
             }
@@ -133,12 +141,16 @@ impl DyldInfoManager {
             ) = {
                 let image_infos: &dyld_all_image_infos =
                     unsafe { self.memory.get_type_ref_at_address(info_addr) }?;
+                // infoArrayChangeTimestamp and dyldPath were added in version 15
+                // (macOS 10.12). Older versions only have the image array, so
+                // we have to enumerate it every time, and dyld itself is missing.
+                let has_version_15_fields = image_infos.version >= 15;
                 (
                     image_infos.infoArray as usize as u64,
                     image_infos.infoArrayCount,
-                    image_infos.infoArrayChangeTimestamp, // 10.12+
+                    Some(image_infos.infoArrayChangeTimestamp).filter(|_| has_version_15_fields),
                     image_infos.dyldImageLoadAddress as usize as u64,
-                    image_infos.dyldPath as usize as u64, // 10.12+
+                    Some(image_infos.dyldPath as usize as u64).filter(|_| has_version_15_fields),
                 )
             };
 
@@ -164,7 +176,7 @@ impl DyldInfoManager {
                     left.base_avma.cmp(&right.base_avma)
                 });
 
-            self.last_change_timestamp = Some(info_array_change_timestamp);
+            self.last_change_timestamp = info_array_change_timestamp;
             self.saved_image_info = new_image_info;
 
             Ok(diff)
@@ -208,14 +220,17 @@ fn enumerate_dyld_images(
     info_array_addr: u64,
     info_array_count: u32,
     dyld_image_load_addr: u64,
-    dyld_image_path: u64,
+    dyld_image_path: Option<u64>,
 ) -> kernel_error::Result<Vec<DyldInfo>> {
     // Adapted from rbspy and from the Gecko profiler's shared-libraries-macos.cc.
-    let mut vec = vec![get_dyld_image_info(
-        memory,
-        dyld_image_load_addr,
-        dyld_image_path,
-    )?];
+    let mut vec = Vec::new();
+    if let Some(dyld_image_path) = dyld_image_path {
+        vec.push(get_dyld_image_info(
+            memory,
+            dyld_image_load_addr,
+            dyld_image_path,
+        )?);
+    }
 
     for image_index in 0..info_array_count {
         let (base_avma, image_file_path) = {
@@ -360,10 +375,82 @@ impl arm_thread_state64_t {
 #[cfg(target_arch = "aarch64")]
 pub static ARM_THREAD_STATE64: thread_state_flavor_t = 6;
 
+/// The full register state of a thread at the time of a sample. This is used
+/// for debugging unwinding problems, or for unwinding stacks with a different
+/// strategy after the fact.
+#[derive(Debug, Clone)]
+pub struct RegisterDump {
+    pub pc: u64,
+    pub sp: u64,
+    pub fp: u64,
+    /// The thread state as returned by `thread_get_state`.
+    pub raw_state: RawThreadState,
+}
+
+/// The native thread state struct of the architecture we're running on.
+#[derive(Debug, Clone, Copy)]
+pub enum RawThreadState {
+    #[cfg(target_arch = "x86_64")]
+    X86_64(x86_thread_state64_t),
+    #[cfg(target_arch = "aarch64")]
+    Aarch64(arm_thread_state64_t),
+}
+
+impl RawThreadState {
+    /// All general purpose registers, with their names.
+    pub fn registers(&self) -> Vec<(&'static str, u64)> {
+        match self {
+            #[cfg(target_arch = "x86_64")]
+            RawThreadState::X86_64(state) => vec![
+                ("rax", state.__rax),
+                ("rbx", state.__rbx),
+                ("rcx", state.__rcx),
+                ("rdx", state.__rdx),
+                ("rdi", state.__rdi),
+                ("rsi", state.__rsi),
+                ("rbp", state.__rbp),
+                ("rsp", state.__rsp),
+                ("r8", state.__r8),
+                ("r9", state.__r9),
+                ("r10", state.__r10),
+                ("r11", state.__r11),
+                ("r12", state.__r12),
+                ("r13", state.__r13),
+                ("r14", state.__r14),
+                ("r15", state.__r15),
+                ("rip", state.__rip),
+                ("rflags", state.__rflags),
+            ],
+            #[cfg(target_arch = "aarch64")]
+            RawThreadState::Aarch64(state) => {
+                const X_REGISTER_NAMES: [&str; 29] = [
+                    "x0", "x1", "x2", "x3", "x4", "x5", "x6", "x7", "x8", "x9", "x10", "x11",
+                    "x12", "x13", "x14", "x15", "x16", "x17", "x18", "x19", "x20", "x21", "x22",
+                    "x23", "x24", "x25", "x26", "x27", "x28",
+                ];
+                let mut registers: Vec<(&'static str, u64)> =
+                    X_REGISTER_NAMES.iter().copied().zip(state.__x).collect();
+                registers.extend([
+                    ("fp", state.__fp),
+                    ("lr", state.__lr),
+                    ("sp", state.__sp),
+                    ("pc", state.__pc),
+                    ("cpsr", u64::from(state.__cpsr)),
+                ]);
+                registers
+            }
+        }
+    }
+}
+
 #[cfg(target_arch = "x86_64")]
-fn get_unwinding_registers(
-    thread_act: mach_port_t,
-) -> kernel_error::Result<(u64, UnwindRegsX86_64)> {
+type ThreadState = x86_thread_state64_t;
+
+#[cfg(target_arch = "aarch64")]
+type ThreadState = arm_thread_state64_t;
+
+#[cfg(target_arch = "x86_64")]
+fn get_thread_state(thread_act: mach_port_t) -> kernel_error::Result<ThreadState> {
     let mut state: x86_thread_state64_t = unsafe { mem::zeroed() };
     let mut count = x86_thread_state64_t::count();
     unsafe {
@@ -375,16 +462,11 @@ fn get_unwinding_registers(
         )
     }
     .into_result()?;
-    Ok((
-        state.__rip,
-        UnwindRegsX86_64::new(state.__rip, state.__rsp, state.__rbp),
-    ))
+    Ok(state)
 }
 
 #[cfg(target_arch = "aarch64")]
-fn get_unwinding_registers(
-    thread_act: mach_port_t,
-) -> kernel_error::Result<(u64, UnwindRegsAarch64)> {
+fn get_thread_state(thread_act: mach_port_t) -> kernel_error::Result<ThreadState> {
     let mut state: arm_thread_state64_t = unsafe { mem::zeroed() };
     let mut count = arm_thread_state64_t::count();
     unsafe {
@@ -396,18 +478,51 @@ fn get_unwinding_registers(
         )
     }
     .into_result()?;
+    Ok(state)
+}
+
+#[cfg(target_arch = "x86_64")]
+fn get_unwinding_registers(state: &ThreadState) -> (u64, UnwindRegsX86_64) {
+    (
+        state.__rip,
+        UnwindRegsX86_64::new(state.__rip, state.__rsp, state.__rbp),
+    )
+}
+
+#[cfg(target_arch = "aarch64")]
+fn get_unwinding_registers(state: &ThreadState) -> (u64, UnwindRegsAarch64) {
     let mask = PtrAuthMask::new_24_40();
-    Ok((
+    (
         mask.strip_ptr_auth(state.__pc),
         UnwindRegsAarch64::new_with_ptr_auth_mask(mask, state.__lr, state.__sp, state.__fp),
-    ))
+    )
+}
+
+#[cfg(target_arch = "x86_64")]
+fn get_register_dump(state: &ThreadState) -> RegisterDump {
+    RegisterDump {
+        pc: state.__rip,
+        sp: state.__rsp,
+        fp: state.__rbp,
+        raw_state: RawThreadState::X86_64(*state),
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+fn get_register_dump(state: &ThreadState) -> RegisterDump {
+    RegisterDump {
+        pc: state.__pc,
+        sp: state.__sp,
+        fp: state.__fp,
+        raw_state: RawThreadState::Aarch64(*state),
+    }
 }
 
 fn with_suspended_thread<R>(
     thread_act: mach_port_t,
     f: impl FnOnce() -> R,
 ) -> kernel_error::Result<R> {
-    unsafe { thread_suspend(thread_act) }.into_result()?;
+    retry_if_transient(|| unsafe { thread_suspend(thread_act) }.into_result())?;
     let result = f();
     let _ = unsafe { thread_resume(thread_act) };
     Ok(result)
@@ -416,6 +531,7 @@ fn with_suspended_thread<R>(
 pub struct StackwalkerRef<'a> {
     unwinder: &'a framehop::UnwinderNative<UnwindSectionBytes, framehop::MayAllocateDuringUnwind>,
     cache: &'a mut framehop::CacheNative<UnwindSectionBytes, framehop::MayAllocateDuringUnwind>,
+    code_ranges: &'a CodeRanges,
 }
 
 impl<'a> StackwalkerRef<'a> {
@@ -425,28 +541,84 @@ impl<'a> StackwalkerRef<'a> {
             framehop::MayAllocateDuringUnwind,
         >,
         cache: &'a mut framehop::CacheNative<UnwindSectionBytes, framehop::MayAllocateDuringUnwind>,
+        code_ranges: &'a CodeRanges,
     ) -> Self {
-        Self { unwinder, cache }
+        Self {
+            unwinder,
+            cache,
+            code_ranges,
+        }
     }
 }
 
+/// The default for the maximum number of frames which a stack walk collects,
+/// see [`ThreadProfiler::set_max_frames`](super::thread_profiler::ThreadProfiler::set_max_frames).
+pub const DEFAULT_MAX_FRAMES: usize = 1024;
+
+/// The result of [`get_backtrace`], apart from the frames.
+pub struct Backtrace {
+    pub register_dump: Option<RegisterDump>,
+    /// Whether the stack walk stopped at a frame which couldn't be unwound, for
+    /// example because the stack memory at a corrupt frame pointer isn't mapped.
+    /// The frames up to that point are kept, but the frames closer to the root
+    /// are missing.
+    pub is_incomplete: bool,
+    /// The number of frames at the root end of the stack which were found by
+    /// scanning the stack rather than by unwinding. They may be wrong.
+    pub scanned_frame_count: usize,
+    /// Whether the stack walk stopped because it reached the maximum number of
+    /// frames. The innermost frames are kept, and the frames closer to the
+    /// root are missing.
+    pub is_depth_limited: bool,
+}
+
+/// Walks the stack of the thread and puts the frames into `frames`.
+///
+/// If `dump_registers` is true, the register state at the time of the stack walk
+/// is returned as well. If `stack_scan_words` is non-zero and the walk finds
+/// implausibly few frames, up to that many words of the stack are scanned for
+/// return addresses, see [`scan_stack`]. The walk stops after `max_frames`
+/// frames.
 pub fn get_backtrace(
     stackwalker: StackwalkerRef,
     memory: &mut ForeignMemory,
     thread_act: mach_port_t,
     frames: &mut Vec<FrameAddress>,
-) -> Result<(), SamplingError> {
+    dump_registers: bool,
+    stack_scan_words: usize,
+    max_frames: usize,
+) -> Result<Backtrace, SamplingError> {
     with_suspended_thread(thread_act, || {
-        let (pc, regs) = get_unwinding_registers(thread_act).map_err(|err| match err {
-            KernelError::InvalidArgument
-            | KernelError::MachSendInvalidDest
-            | KernelError::Terminated => {
-                SamplingError::ThreadTerminated("thread_get_state in get_unwinding_registers", err)
-            }
-            err => SamplingError::Ignorable("thread_get_state in get_unwinding_registers", err),
-        })?;
-        do_stackwalk(stackwalker, pc, regs, memory, frames);
-        Ok(())
+        let state =
+            retry_if_transient(|| get_thread_state(thread_act)).map_err(|err| match err {
+                KernelError::InvalidArgument
+                | KernelError::MachSendInvalidDest
+                | KernelError::Terminated => {
+                    SamplingError::ThreadTerminated("thread_get_state in get_thread_state", err)
+                }
+                err => SamplingError::Ignorable("thread_get_state in get_thread_state", err),
+            })?;
+        let (pc, regs) = get_unwinding_registers(&state);
+        let (walk_end, scanned_frame_count) = do_stackwalk(
+            stackwalker,
+            pc,
+            regs,
+            memory,
+            frames,
+            stack_scan_words,
+            max_frames,
+        );
+        let register_dump = if dump_registers {
+            Some(get_register_dump(&state))
+        } else {
+            None
+        };
+        Ok(Backtrace {
+            register_dump,
+            is_incomplete: walk_end == WalkEnd::Error,
+            scanned_frame_count,
+            is_depth_limited: walk_end == WalkEnd::DepthLimit,
+        })
     })
     .unwrap_or_else(|err| match err {
         KernelError::InvalidArgument
@@ -462,13 +634,29 @@ pub fn get_backtrace(
     })
 }
 
+/// How a stack walk ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum WalkEnd {
+    /// The walk reached the root of the stack.
+    Root,
+    /// A frame couldn't be unwound.
+    Error,
+    /// The walk reached the maximum number of frames.
+    DepthLimit,
+}
+
+/// Returns how the walk ended, and the number of frames which were found by
+/// scanning the stack. Failed stack reads end the walk; they don't fail the
+/// sample.
 fn do_stackwalk(
     stackwalker: StackwalkerRef,
     pc: u64,
     regs: UnwindRegsNative,
     memory: &mut ForeignMemory,
     frames: &mut Vec<FrameAddress>,
-) {
+    stack_scan_words: usize,
+    max_frames: usize,
+) -> (WalkEnd, usize) {
     let mut read_stack = |addr| {
         if addr % 8 != 0 {
             // Unaligned address
@@ -483,15 +671,42 @@ fn do_stackwalk(
     let mut iter = stackwalker
         .unwinder
         .iter_frames(pc, regs, stackwalker.cache, &mut read_stack);
-    while let Ok(Some(address)) = iter.next() {
-        frames.push(address);
+    let walk_end = collect_frames(|| iter.next(), frames, max_frames);
+
+    let scanned_frame_count =
+        if stack_scan_words != 0 && frames.len() <= MAX_IMPLAUSIBLE_FRAME_COUNT {
+            scan_stack(
+                regs.sp(),
+                stack_scan_words,
+                stackwalker.code_ranges,
+                &mut read_stack,
+                frames,
+            )
+        } else {
+            0
+        };
+
+    frames.reverse();
+    (walk_end, scanned_frame_count)
+}
 
-        if frames.len() >= 10000 {
-            break;
+/// Appends the frames which `next_frame` returns to `frames`, until the root is
+/// reached, an error occurs, or `frames` has `max_frames` frames. In the last
+/// case, the remaining frames aren't unwound at all, so that runaway recursion
+/// doesn't stall the sampler.
+fn collect_frames<E>(
+    mut next_frame: impl FnMut() -> Result<Option<FrameAddress>, E>,
+    frames: &mut Vec<FrameAddress>,
+    max_frames: usize,
+) -> WalkEnd {
+    loop {
+        match next_frame() {
+            Ok(Some(_)) if frames.len() >= max_frames => return WalkEnd::DepthLimit,
+            Ok(Some(address)) => frames.push(address),
+            Ok(None) => return WalkEnd::Root,
+            Err(_) => return WalkEnd::Error,
         }
     }
-
-    frames.reverse();
 }
 
 #[derive(Debug, Clone)]
@@ -775,3 +990,32 @@ where
     }
     modifications
 }
+
+#[test]
+fn test_collect_frames() {
+    let walk = |depth: u64, max_frames| {
+        let mut addresses = (0..depth).map(|i| FrameAddress::InstructionPointer(0x1000 + i));
+        let mut frames = Vec::new();
+        let walk_end = collect_frames(|| Ok::<_, ()>(addresses.next()), &mut frames, max_frames);
+        (walk_end, frames.len())
+    };
+    assert_eq!(walk(10, 1024), (WalkEnd::Root, 10));
+    assert_eq!(walk(1024, 1024), (WalkEnd::Root, 1024));
+    assert_eq!(walk(1025, 1024), (WalkEnd::DepthLimit, 1024));
+    assert_eq!(walk(100_000, 1024), (WalkEnd::DepthLimit, 1024));
+
+    let mut calls = 0;
+    let mut frames = Vec::new();
+    let walk_end = collect_frames(
+        || {
+            calls += 1;
+            match calls {
+                1 => Ok(Some(FrameAddress::InstructionPointer(0x1000))),
+                _ => Err(()),
+            }
+        },
+        &mut frames,
+        1024,
+    );
+    assert_eq!((walk_end, frames.len()), (WalkEnd::Error, 1));
+}
@@ -19,9 +19,13 @@ static PRELOAD_LIB_CONTENTS: &[u8] =
     include_bytes!("../../resources/libsamply_mac_preload.dylib.gz");
 
 impl TaskAccepter {
+    /// Launches `program`, which sends us its task port once it starts. With
+    /// `malloc_stack_logging`, the program logs its allocations, so that they
+    /// can be read with a [`MallocStackReader`](super::malloc_stack_logging::MallocStackReader).
     pub fn create_and_launch_root_task<I, S>(
         program: S,
         args: I,
+        malloc_stack_logging: bool,
     ) -> Result<(Self, Child), MachError>
     where
         I: IntoIterator<Item = S>,
@@ -56,7 +60,8 @@ impl TaskAccepter {
             .chain(std::iter::once((
                 "SAMPLY_BOOTSTRAP_SERVER_NAME".into(),
                 server_name.into(),
-            )));
+            )))
+            .chain(malloc_stack_logging.then(|| ("MallocStackLogging".into(), "1".into())));
 
         let root_child = Command::new(program)
             .args(args)
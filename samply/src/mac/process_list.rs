@@ -0,0 +1,140 @@
+use std::io;
+use std::path::Path;
+
+use thiserror::Error;
+
+#[derive(Debug, Clone)]
+pub struct RunningProcess {
+    pub pid: u32,
+    /// The path of the executable, or the short process name if the path
+    /// isn't accessible.
+    pub path: String,
+}
+
+impl RunningProcess {
+    fn name(&self) -> &str {
+        Path::new(&self.path)
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or(&self.path)
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ProcessNameError {
+    #[error("Could not list the running processes: {0}")]
+    CouldNotListProcesses(#[source] io::Error),
+
+    #[error("No running process is named {0:?}.")]
+    NotFound(String),
+
+    #[error(
+        "{} running processes are named {:?}, use --pid to pick one:{}",
+        .1.len(),
+        .0,
+        format_candidates(.1)
+    )]
+    Ambiguous(String, Vec<RunningProcess>),
+}
+
+fn format_candidates(candidates: &[RunningProcess]) -> String {
+    candidates
+        .iter()
+        .map(|process| format!("\n  {:>6}  {}", process.pid, process.path))
+        .collect()
+}
+
+/// Find the pid of the running process whose executable is called `name`.
+/// Fails if no process or more than one process has that name.
+pub fn pid_for_process_name(name: &str) -> Result<u32, ProcessNameError> {
+    let own_pid = std::process::id();
+    let mut candidates: Vec<RunningProcess> = running_processes()
+        .map_err(ProcessNameError::CouldNotListProcesses)?
+        .into_iter()
+        .filter(|process| process.pid != own_pid && process.name() == name)
+        .collect();
+    match candidates.len() {
+        0 => Err(ProcessNameError::NotFound(name.to_owned())),
+        1 => Ok(candidates[0].pid),
+        _ => {
+            candidates.sort_by_key(|process| process.pid);
+            Err(ProcessNameError::Ambiguous(name.to_owned(), candidates))
+        }
+    }
+}
+
+/// Resolve each name with [`pid_for_process_name`].
+pub fn pids_for_process_names(names: &[String]) -> Result<Vec<u32>, ProcessNameError> {
+    names
+        .iter()
+        .map(|name| pid_for_process_name(name))
+        .collect()
+}
+
+fn running_processes() -> io::Result<Vec<RunningProcess>> {
+    let pid_count = unsafe { libc::proc_listallpids(std::ptr::null_mut(), 0) };
+    if pid_count < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Leave some room for processes which are started in the meantime.
+    let mut pids: Vec<libc::c_int> = vec![0; pid_count as usize + 64];
+    let buffer_size = (pids.len() * std::mem::size_of::<libc::c_int>()) as libc::c_int;
+    let pid_count = unsafe { libc::proc_listallpids(pids.as_mut_ptr() as *mut _, buffer_size) };
+    if pid_count < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    pids.truncate(pid_count as usize);
+
+    Ok(pids
+        .into_iter()
+        .filter(|pid| *pid > 0)
+        .filter_map(|pid| {
+            let path = process_path(pid).or_else(|| process_name(pid))?;
+            Some(RunningProcess {
+                pid: pid as u32,
+                path,
+            })
+        })
+        .collect())
+}
+
+fn process_path(pid: libc::c_int) -> Option<String> {
+    let mut buffer = vec![0u8; libc::PROC_PIDPATHINFO_MAXSIZE as usize];
+    let len =
+        unsafe { libc::proc_pidpath(pid, buffer.as_mut_ptr() as *mut _, buffer.len() as u32) };
+    if len <= 0 {
+        return None;
+    }
+    buffer.truncate(len as usize);
+    Some(String::from_utf8_lossy(&buffer).into_owned())
+}
+
+/// The short process name, which the kernel truncates to 32 bytes.
+fn process_name(pid: libc::c_int) -> Option<String> {
+    let mut buffer = [0u8; 64];
+    let len = unsafe { libc::proc_name(pid, buffer.as_mut_ptr() as *mut _, buffer.len() as u32) };
+    if len <= 0 {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&buffer[..len as usize]).into_owned())
+}
+
+#[test]
+fn test_ambiguous_process_name_message() {
+    let candidates = vec![
+        RunningProcess {
+            pid: 123,
+            path: "/usr/local/bin/myserver".to_string(),
+        },
+        RunningProcess {
+            pid: 4567,
+            path: "/opt/myserver/myserver".to_string(),
+        },
+    ];
+    assert_eq!(candidates[0].name(), "myserver");
+    let err = ProcessNameError::Ambiguous("myserver".to_string(), candidates);
+    assert_eq!(
+        err.to_string(),
+        "2 running processes are named \"myserver\", use --pid to pick one:\n     123  /usr/local/bin/myserver\n    4567  /opt/myserver/myserver"
+    );
+}
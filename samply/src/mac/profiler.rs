@@ -1,47 +1,47 @@
-use crossbeam_channel::unbounded;
+use crossbeam_channel::{unbounded, Receiver};
 use fxprof_processed_profile::Profile;
-use serde_json::to_writer;
 
 use std::ffi::OsString;
 use std::fs::File;
-use std::io::BufWriter;
+use std::io::LineWriter;
 use std::path::Path;
 use std::process::ExitStatus;
 use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
 use std::thread;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime};
 
-use super::process_launcher::{MachError, TaskAccepter};
-use super::sampler::{Sampler, TaskInit};
+use super::error::SamplingError;
+use super::process_launcher::{mach_port_t, MachError, TaskAccepter};
+use super::sampler::{DumpTrigger, Sampler, TaskInit};
+use super::task_port_receiver::TaskPortReceiver;
+use super::task_profiler::attach_to_pid;
+use super::thread_profiler::{stack_log_callback, SampleCallback};
+use crate::profile_output::{save_profile, ProfileOutput};
+use crate::recording_options::RecordingOptions;
 use crate::server::{start_server_main, ServerProps};
 
 pub fn start_recording(
-    output_file: &Path,
+    output: &ProfileOutput,
     command_name: OsString,
     command_args: &[OsString],
-    time_limit: Option<Duration>,
-    interval: Duration,
+    options: &RecordingOptions,
     server_props: Option<ServerProps>,
 ) -> Result<ExitStatus, MachError> {
     let (saver_sender, saver_receiver) = unbounded();
-    let output_file = output_file.to_owned();
-    let saver_thread = thread::spawn(move || {
-        let profile: Profile = saver_receiver.recv().expect("saver couldn't recv");
-        let file = File::create(&output_file).unwrap();
-        let writer = BufWriter::new(file);
-        to_writer(writer, &profile).expect("Couldn't write JSON");
-
-        // Reuse the saver thread as the server thread.
-        if let Some(server_props) = server_props {
-            start_server_main(&output_file, server_props);
-        }
-    });
-
     let (task_sender, task_receiver) = unbounded();
     let command_name_copy = command_name.to_string_lossy().to_string();
+    let launch_time = SystemTime::now();
+    let mut sampler = create_sampler(
+        command_name_copy,
+        task_receiver,
+        options,
+        output,
+        launch_time,
+    );
+    // Allocations can only be recorded in processes which we launch ourselves.
+    sampler.set_allocation_sampling(options.allocation_sampling);
     let sampler_thread = thread::spawn(move || {
-        let sampler = Sampler::new(command_name_copy, task_receiver, interval, time_limit);
         let profile = sampler.run().expect("Sampler ran into an error");
         saver_sender.send(profile).expect("couldn't send profile");
     });
@@ -57,8 +57,25 @@ pub fn start_recording(
     )
     .expect("cannot register signal handler");
 
-    let (mut task_accepter, mut root_child) =
-        TaskAccepter::create_and_launch_root_task(&command_name, command_args)?;
+    let (mut task_accepter, mut root_child) = TaskAccepter::create_and_launch_root_task(
+        &command_name,
+        command_args,
+        options.allocation_sampling.is_some(),
+    )?;
+
+    // The output file name can depend on the pid, so we only know it once the
+    // root task has been launched.
+    let output_file = output.path_for_process(root_child.id(), launch_time);
+    let saver_thread = thread::spawn(move || {
+        let profile: Profile = saver_receiver.recv().expect("saver couldn't recv");
+        save_profile(&profile, &output_file).expect("Couldn't write JSON");
+        eprintln!("Saved profile to {}", output_file.display());
+
+        // Reuse the saver thread as the server thread.
+        if let Some(server_props) = server_props {
+            start_server_main(&output_file, server_props);
+        }
+    });
 
     let (accepter_sender, accepter_receiver) = unbounded();
     let accepter_thread = thread::spawn(move || loop {
@@ -72,6 +89,7 @@ pub fn start_recording(
                     start_time: Instant::now(),
                     task: accepted_task.take_task(),
                     pid: accepted_task.get_id(),
+                    is_attached: false,
                 });
                 if send_result.is_err() {
                     // The sampler has already shut down. This task arrived too late.
@@ -103,3 +121,177 @@ pub fn start_recording(
 
     Ok(exit_status)
 }
+
+/// Record the already running processes `pids` into one profile, until all of
+/// them have exited or until Ctrl+C is pressed. The processes are sampled
+/// together and share the same start time, so that they line up on the
+/// profile's timeline. Each one becomes a separate process in the profile.
+///
+/// Unlike with a launched command, processes which are spawned by the recorded
+/// processes are not profiled.
+pub fn start_recording_pids(
+    output: &ProfileOutput,
+    pids: &[u32],
+    options: &RecordingOptions,
+    server_props: Option<ServerProps>,
+) -> Result<(), SamplingError> {
+    let tasks = pids
+        .iter()
+        .map(|&pid| Ok((pid, attach_to_pid(pid)?)))
+        .collect::<Result<Vec<_>, SamplingError>>()?;
+    record_running_tasks(output, tasks, options, server_props)
+}
+
+/// Wait until another process sends a task port to the mach service
+/// `service_name`, and then record that process like [`start_recording_pids`].
+/// See [`TaskPortReceiver`] for the message format. This lets a privileged
+/// helper obtain the task port, so that samply itself doesn't need root
+/// privileges.
+pub fn start_recording_task_from_service(
+    output: &ProfileOutput,
+    service_name: &str,
+    options: &RecordingOptions,
+    server_props: Option<ServerProps>,
+) -> Result<(), SamplingError> {
+    let receive_error = |err| SamplingError::CouldNotReceiveTaskPort(service_name.to_owned(), err);
+    let mut receiver = TaskPortReceiver::register(service_name).map_err(receive_error)?;
+    eprintln!(
+        "Waiting for a task port on mach service {}...",
+        service_name
+    );
+    let task = loop {
+        match receiver.try_accept(Duration::from_secs(1)) {
+            Ok(task) => break task,
+            Err(MachError::RcvTimedOut) => {}
+            Err(err) => return Err(receive_error(err)),
+        }
+    };
+    // Unregister the service name.
+    drop(receiver);
+    record_running_tasks(output, vec![task], options, server_props)
+}
+
+/// Records the running processes `tasks`, given as `(pid, task)` pairs, for
+/// [`start_recording_pids`] and [`start_recording_task_from_service`]. The
+/// profile doesn't depend on how the task ports were obtained.
+fn record_running_tasks(
+    output: &ProfileOutput,
+    tasks: Vec<(u32, mach_port_t)>,
+    options: &RecordingOptions,
+    server_props: Option<ServerProps>,
+) -> Result<(), SamplingError> {
+    let (task_sender, task_receiver) = unbounded();
+    let start_time = Instant::now();
+    let launch_time = SystemTime::now();
+    let pids: Vec<u32> = tasks.iter().map(|(pid, _)| *pid).collect();
+    for (pid, task) in tasks {
+        task_sender
+            .send(TaskInit {
+                start_time,
+                task,
+                pid,
+                is_attached: true,
+            })
+            .expect("couldn't send task");
+    }
+    // No more tasks will arrive. Once all recorded processes are dead, the
+    // sampler stops.
+    drop(task_sender);
+
+    // The first Ctrl+C stops the recording. Once the recording has stopped,
+    // Ctrl+C terminates samply again, e.g. while the server is running.
+    let stop_flag = Arc::new(AtomicBool::new(false));
+    #[cfg(unix)]
+    {
+        signal_hook::flag::register_conditional_default(
+            signal_hook::consts::SIGINT,
+            stop_flag.clone(),
+        )
+        .expect("cannot register signal handler");
+        signal_hook::flag::register(signal_hook::consts::SIGINT, stop_flag.clone())
+            .expect("cannot register signal handler");
+    }
+
+    let pid_list: Vec<String> = pids.iter().map(|pid| pid.to_string()).collect();
+    let mut sampler = create_sampler(
+        format!("pid {}", pid_list.join(", ")),
+        task_receiver,
+        options,
+        output,
+        launch_time,
+    );
+    sampler.set_stop_flag(stop_flag.clone());
+    eprintln!("Recording, press Ctrl+C to stop.");
+    let profile = sampler.run();
+    stop_flag.store(true, std::sync::atomic::Ordering::SeqCst);
+    let profile = profile?;
+
+    let output_file = output.path_for_process(pids[0], launch_time);
+    save_profile(&profile, &output_file).expect("Couldn't write JSON");
+    eprintln!("Saved profile to {}", output_file.display());
+
+    if let Some(server_props) = server_props {
+        start_server_main(&output_file, server_props);
+    }
+    Ok(())
+}
+
+/// Creates the sampler for a recording with the given options. Allocation
+/// sampling is left to the caller, because it only works for launched processes.
+fn create_sampler(
+    command_name: String,
+    task_receiver: Receiver<TaskInit>,
+    options: &RecordingOptions,
+    output: &ProfileOutput,
+    launch_time: SystemTime,
+) -> Sampler {
+    let mut sampler = Sampler::new(command_name, task_receiver, options.interval);
+    sampler.set_time_limit(options.time_limit);
+    sampler.set_sample_limit(options.sample_limit);
+    sampler.set_idle_markers(options.idle_markers);
+    sampler.set_overhead_markers(options.overhead_markers);
+    sampler.set_exception_sampling(options.exception_sampling.clone());
+    sampler.set_thread_filter(options.thread_filter.clone());
+    sampler.set_ring_buffer_duration(options.ring_buffer);
+    if let Some(max_frames) = options.max_frames {
+        sampler.set_max_frames(max_frames);
+    }
+    sampler.set_stack_scan_words(options.stack_scan_words);
+    sampler.set_register_dump_path(options.register_dump_path.clone());
+    if let Some(on_sample) = options.live_stacks_path.as_deref().and_then(open_stack_log) {
+        sampler.set_sample_callback(on_sample);
+    }
+    if options.ring_buffer.is_some() {
+        sampler.set_dump_trigger(dump_trigger(output, launch_time));
+    }
+    sampler
+}
+
+/// Saves the ring buffer window of a `--ring-buffer` recording every time
+/// samply receives SIGUSR1.
+fn dump_trigger(output: &ProfileOutput, launch_time: SystemTime) -> DumpTrigger {
+    let flag = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(signal_hook::consts::SIGUSR1, flag.clone())
+        .expect("cannot register signal handler");
+    eprintln!(
+        "Keeping only the most recent samples. Run `kill -USR1 {}` to save them.",
+        std::process::id()
+    );
+    DumpTrigger {
+        flag,
+        output: output.clone(),
+        launch_time,
+    }
+}
+
+/// Creates the file for `--live-stacks`. The recording goes on without it if
+/// the file can't be created.
+fn open_stack_log(path: &Path) -> Option<SampleCallback> {
+    match File::create(path) {
+        Ok(file) => Some(stack_log_callback(LineWriter::new(file))),
+        Err(err) => {
+            eprintln!("Couldn't create {}: {}", path.display(), err);
+            None
+        }
+    }
+}
@@ -0,0 +1,395 @@
+use framehop::FrameAddress;
+use fxprof_processed_profile::{
+    CategoryColor, CategoryPairHandle, Profile, ReferenceTimestamp, SamplingInterval, Timestamp,
+};
+use wholesym::{
+    FrameDebugInfo, FramesLookupResult, MultiArchDisambiguator, SymbolManager, SymbolManagerConfig,
+    SymbolMap,
+};
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::sync::mpsc;
+use std::time::SystemTime;
+
+use super::error::SamplingError;
+use super::proc_maps::DyldInfo;
+use super::task_profiler::{attach_to_pid, TaskProfiler, UnwinderCache};
+use super::thread_profiler::SampleCallback;
+use crate::thread_filter::ThreadFilter;
+
+/// A thread of a process which was sampled with [`sample_once`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ThreadInfo {
+    pub tid: u32,
+    pub name: Option<String>,
+    pub is_main_thread: bool,
+}
+
+/// A symbolicated stack frame. If functions were inlined at the frame's
+/// address, there is one `Frame` per function, all with the same address.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    /// The instruction pointer for the innermost frame, and the return address
+    /// for all other frames.
+    pub address: u64,
+    /// The file name of the library which contains the address.
+    pub lib_name: Option<String>,
+    pub function: Option<String>,
+    pub file: Option<String>,
+    pub line: Option<u32>,
+    /// Whether this function was inlined into the function of the next frame.
+    pub is_inline: bool,
+}
+
+impl fmt::Display for ThreadInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Thread {}", self.tid)?;
+        if let Some(name) = &self.name {
+            write!(f, " \"{}\"", name)?;
+        }
+        if self.is_main_thread {
+            write!(f, " (main thread)")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:#x} {}",
+            self.address,
+            self.function.as_deref().unwrap_or("???")
+        )?;
+        if let Some(lib_name) = &self.lib_name {
+            write!(f, " ({})", lib_name)?;
+        }
+        match (&self.file, self.line) {
+            (Some(file), Some(line)) => write!(f, " {}:{}", file, line)?,
+            (Some(file), None) => write!(f, " {}", file)?,
+            _ => {}
+        }
+        if self.is_inline {
+            write!(f, " [inlined]")?;
+        }
+        Ok(())
+    }
+}
+
+/// Takes a single stack of every thread of the running process `pid` and
+/// symbolicates it with the libraries which are currently loaded, like
+/// `sample(1)` or `pstack`. The process is only attached to while the stacks
+/// are taken, not during symbolication.
+///
+/// The threads are ordered by tid, which puts the main thread first. Stacks are
+/// innermost frame first. Threads whose stack couldn't be walked have an empty
+/// stack. Like `samply record --pid`, this requires root privileges.
+pub fn sample_once(pid: u32) -> Result<Vec<(ThreadInfo, Vec<Frame>)>, SamplingError> {
+    let task = attach_to_pid(pid)?;
+
+    // The task profiler always records into a profile. We only need its stack
+    // walks, so this profile is thrown away.
+    let command_name = format!("pid {}", pid);
+    let mut profile = Profile::new(
+        &command_name,
+        ReferenceTimestamp::from_system_time(SystemTime::now()),
+        SamplingInterval::from_millis(1),
+    );
+    let default_category =
+        CategoryPairHandle::from(profile.add_category("Regular", CategoryColor::Blue));
+    let now = Timestamp::from_millis_since_reference(0.0);
+    let mut task_profiler = TaskProfiler::new(
+        task,
+        pid,
+        now,
+        &command_name,
+        &mut profile,
+        default_category,
+        ThreadFilter::default(),
+    )?;
+
+    let (stack_sender, stack_receiver) = mpsc::channel();
+    let mut on_sample: Option<SampleCallback> = Some(Box::new(move |tid, stack| {
+        let _ = stack_sender.send((tid, stack.to_vec()));
+    }));
+    let still_alive = task_profiler.sample(
+        now,
+        &|| now,
+        1,
+        &mut UnwinderCache::default(),
+        &mut on_sample,
+        &mut profile,
+    )?;
+    if !still_alive {
+        // The process exited while we were looking at it.
+        return Ok(Vec::new());
+    }
+
+    let main_thread = task_profiler.main_thread();
+    let mut threads: Vec<ThreadInfo> = task_profiler
+        .live_threads()
+        .map(|thread| ThreadInfo {
+            tid: thread.tid(),
+            name: thread.name().map(ToOwned::to_owned),
+            is_main_thread: Some(thread.profile_thread()) == main_thread,
+        })
+        .collect();
+    threads.sort_by_key(|thread| thread.tid);
+    let libs = task_profiler.loaded_libs().to_vec();
+
+    // Detach before symbolicating, which can take a while.
+    drop(task_profiler);
+    drop(on_sample);
+
+    let mut stacks: HashMap<u32, Vec<FrameAddress>> = stack_receiver.into_iter().collect();
+    let stacks = threads
+        .into_iter()
+        .map(|thread| {
+            let stack = stacks.remove(&thread.tid).unwrap_or_default();
+            (thread, stack)
+        })
+        .collect();
+    Ok(symbolicate_stacks(stacks, &libs))
+}
+
+/// Writes the result of [`sample_once`] as text, one indented frame per line,
+/// with a blank line between threads.
+pub fn write_stacks(w: &mut impl io::Write, stacks: &[(ThreadInfo, Vec<Frame>)]) -> io::Result<()> {
+    for (i, (thread, frames)) in stacks.iter().enumerate() {
+        if i != 0 {
+            writeln!(w)?;
+        }
+        writeln!(w, "{}", thread)?;
+        for frame in frames {
+            writeln!(w, "    {}", frame)?;
+        }
+    }
+    Ok(())
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn symbolicate_stacks(
+    stacks: Vec<(ThreadInfo, Vec<FrameAddress>)>,
+    libs: &[DyldInfo],
+) -> Vec<(ThreadInfo, Vec<Frame>)> {
+    let mut symbolicator = Symbolicator {
+        symbol_manager: SymbolManager::with_config(SymbolManagerConfig::default()),
+        symbol_maps: HashMap::new(),
+    };
+    let mut result = Vec::with_capacity(stacks.len());
+    for (thread, stack) in stacks {
+        let mut frames = Vec::new();
+        for address in stack {
+            symbolicator.append_frames(address, libs, &mut frames).await;
+        }
+        result.push((thread, frames));
+    }
+    result
+}
+
+struct Symbolicator {
+    symbol_manager: SymbolManager,
+    /// The symbol map of each library, by base address. `None` if the library's
+    /// symbols couldn't be loaded.
+    symbol_maps: HashMap<u64, Option<SymbolMap>>,
+}
+
+impl Symbolicator {
+    async fn append_frames(
+        &mut self,
+        address: FrameAddress,
+        libs: &[DyldInfo],
+        frames: &mut Vec<Frame>,
+    ) {
+        let lookup_avma = address.address_for_lookup();
+        let lib = libs
+            .iter()
+            .find(|lib| (lib.base_avma..lib.base_avma + lib.vmsize).contains(&lookup_avma));
+        let lib = match lib {
+            Some(lib) => lib,
+            None => {
+                frames.push(Frame {
+                    address: address.address(),
+                    lib_name: None,
+                    function: None,
+                    file: None,
+                    line: None,
+                    is_inline: false,
+                });
+                return;
+            }
+        };
+        let lib_name = Path::new(&lib.file)
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string());
+
+        let (symbol_name, debug_frames) = self.lookup(lib, lookup_avma - lib.base_avma).await;
+        match debug_frames {
+            Some(debug_frames) if !debug_frames.is_empty() => {
+                let inline_count = debug_frames.len() - 1;
+                for (i, debug_frame) in debug_frames.into_iter().enumerate() {
+                    let is_inline = i < inline_count;
+                    let function = match debug_frame.function {
+                        Some(function) => Some(function),
+                        None if !is_inline => symbol_name.clone(),
+                        None => None,
+                    };
+                    frames.push(Frame {
+                        address: address.address(),
+                        lib_name: lib_name.clone(),
+                        function,
+                        file: debug_frame.file_path.map(|path| path.display_path()),
+                        line: debug_frame.line_number,
+                        is_inline,
+                    });
+                }
+            }
+            _ => frames.push(Frame {
+                address: address.address(),
+                lib_name,
+                function: symbol_name,
+                file: None,
+                line: None,
+                is_inline: false,
+            }),
+        }
+    }
+
+    /// Returns the symbol name and the debug info frames for the address, which
+    /// is relative to the library's base address.
+    async fn lookup(
+        &mut self,
+        lib: &DyldInfo,
+        relative_address: u64,
+    ) -> (Option<String>, Option<Vec<FrameDebugInfo>>) {
+        if !self.symbol_maps.contains_key(&lib.base_avma) {
+            let disambiguator = match (lib.debug_id, lib.arch) {
+                (Some(debug_id), _) => Some(MultiArchDisambiguator::DebugId(debug_id)),
+                (None, Some(arch)) => Some(MultiArchDisambiguator::Arch(arch.to_owned())),
+                (None, None) => None,
+            };
+            let symbol_map = self
+                .symbol_manager
+                .load_symbol_map_for_binary_at_path(Path::new(&lib.file), disambiguator)
+                .await
+                .ok();
+            self.symbol_maps.insert(lib.base_avma, symbol_map);
+        }
+        let symbol_map = match &self.symbol_maps[&lib.base_avma] {
+            Some(symbol_map) => symbol_map,
+            None => return (None, None),
+        };
+        let address_info = match u32::try_from(relative_address)
+            .ok()
+            .and_then(|address| symbol_map.lookup(address))
+        {
+            Some(address_info) => address_info,
+            None => return (None, None),
+        };
+        let debug_frames = match address_info.frames {
+            FramesLookupResult::Available(frames) => Some(frames),
+            FramesLookupResult::External(external) => {
+                self.symbol_manager
+                    .lookup_external(&symbol_map.symbol_file_origin(), &external)
+                    .await
+            }
+            FramesLookupResult::Unavailable => None,
+        };
+        (Some(address_info.symbol.name), debug_frames)
+    }
+}
+
+#[test]
+fn test_write_stacks() {
+    let frame = |address, function: Option<&str>, line: Option<u32>, is_inline| Frame {
+        address,
+        lib_name: Some("example".to_string()),
+        function: function.map(ToOwned::to_owned),
+        file: line.map(|_| "example.c".to_string()),
+        line,
+        is_inline,
+    };
+    let stacks = vec![
+        (
+            ThreadInfo {
+                tid: 259,
+                name: None,
+                is_main_thread: true,
+            },
+            vec![
+                frame(0x1000, Some("square"), Some(5), true),
+                frame(0x1000, Some("sum_of_squares"), Some(11), false),
+                frame(0x1020, None, None, false),
+            ],
+        ),
+        (
+            ThreadInfo {
+                tid: 260,
+                name: Some("worker".to_string()),
+                is_main_thread: false,
+            },
+            vec![Frame {
+                address: 0x5000,
+                lib_name: None,
+                function: None,
+                file: None,
+                line: None,
+                is_inline: false,
+            }],
+        ),
+    ];
+    let mut output = Vec::new();
+    write_stacks(&mut output, &stacks).unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "Thread 259 (main thread)
+    0x1000 square (example) example.c:5 [inlined]
+    0x1000 sum_of_squares (example) example.c:11
+    0x1020 ??? (example)
+
+Thread 260 \"worker\"
+    0x5000 ???
+"
+    );
+}
+
+#[test]
+fn test_sample_once() {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    // Our own process can be sampled without root privileges.
+    let done = Arc::new(AtomicBool::new(false));
+    let (ready_sender, ready_receiver) = mpsc::channel();
+    let waiting_thread = {
+        let done = done.clone();
+        thread::Builder::new()
+            .name("waiting".to_string())
+            .spawn(move || {
+                ready_sender.send(()).unwrap();
+                while !done.load(Ordering::SeqCst) {
+                    thread::sleep(Duration::from_millis(1));
+                }
+            })
+            .unwrap()
+    };
+    ready_receiver.recv().unwrap();
+    let stacks = sample_once(std::process::id()).unwrap();
+    done.store(true, Ordering::SeqCst);
+    waiting_thread.join().unwrap();
+
+    assert!(stacks[0].0.is_main_thread);
+    let (_, frames) = stacks
+        .iter()
+        .find(|(thread, _)| thread.name.as_deref() == Some("waiting"))
+        .unwrap();
+    assert!(!frames.is_empty());
+    assert!(frames.iter().any(|frame| frame.lib_name.is_some()));
+}
@@ -1,23 +1,38 @@
 use crossbeam_channel::Receiver;
 use fxprof_processed_profile::{
-    CategoryColor, CategoryPairHandle, Profile, ReferenceTimestamp, Timestamp,
+    CategoryColor, CategoryPairHandle, MarkerDynamicField, MarkerFieldFormat, MarkerLocation,
+    MarkerSchema, MarkerSchemaField, MarkerTiming, Profile, ProfilerMarker, ReferenceTimestamp,
+    ThreadHandle, Timestamp,
 };
 use mach::port::mach_port_t;
 
+use std::fs::File;
+use std::io::{BufWriter, Write};
 use std::mem;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::thread;
-use std::time::SystemTime;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime};
 
+use super::clock::{Clock, RealClock};
 use super::error::SamplingError;
-use super::task_profiler::TaskProfiler;
+use super::proc_maps::DEFAULT_MAX_FRAMES;
+use super::system_info::add_system_info;
+use super::task_profiler::{TaskProfiler, UnwinderCache};
+use super::thread_profiler::SampleCallback;
+use crate::exception_sampling::ExceptionSampling;
+use crate::profile_output::{save_profile, ProfileOutput};
+use crate::thread_filter::ThreadFilter;
 
 #[derive(Debug, Clone)]
 pub struct TaskInit {
     pub start_time: Instant,
     pub task: mach_port_t,
     pub pid: u32,
+    /// Whether the task was already running when we attached to it, e.g. with
+    /// `--pid`. The start times of its existing threads are unknown then.
+    pub is_attached: bool,
 }
 
 pub struct Sampler {
@@ -25,15 +40,33 @@ pub struct Sampler {
     task_receiver: Receiver<TaskInit>,
     interval: Duration,
     time_limit: Option<Duration>,
+    sample_limit: Option<u64>,
+    idle_markers: bool,
+    stop_flag: Option<Arc<AtomicBool>>,
+    clock: Arc<dyn Clock>,
+    on_sample: Option<SampleCallback>,
+    thread_filter: ThreadFilter,
+    stack_scan_words: usize,
+    max_frames: usize,
+    overhead_markers: bool,
+    allocation_sampling: Option<u32>,
+    exception_sampling: ExceptionSampling,
+    ring_buffer_duration: Option<Duration>,
+    dump_trigger: Option<DumpTrigger>,
+    register_dump_path: Option<PathBuf>,
+}
+
+/// Saves the current window of a ring buffer recording while the recording
+/// goes on, every time `flag` is set, e.g. from a signal handler.
+pub struct DumpTrigger {
+    pub flag: Arc<AtomicBool>,
+    /// The profile is saved to the output file of the root task.
+    pub output: ProfileOutput,
+    pub launch_time: SystemTime,
 }
 
 impl Sampler {
-    pub fn new(
-        command: String,
-        task_receiver: Receiver<TaskInit>,
-        interval: Duration,
-        time_limit: Option<Duration>,
-    ) -> Self {
+    pub fn new(command: String, task_receiver: Receiver<TaskInit>, interval: Duration) -> Self {
         let command_name = Path::new(&command)
             .components()
             .next_back()
@@ -46,13 +79,128 @@ impl Sampler {
             command_name,
             task_receiver,
             interval,
-            time_limit,
+            time_limit: None,
+            sample_limit: None,
+            idle_markers: false,
+            stop_flag: None,
+            clock: Arc::new(RealClock),
+            on_sample: None,
+            thread_filter: ThreadFilter::default(),
+            stack_scan_words: 0,
+            max_frames: DEFAULT_MAX_FRAMES,
+            overhead_markers: false,
+            allocation_sampling: None,
+            exception_sampling: ExceptionSampling::default(),
+            ring_buffer_duration: None,
+            dump_trigger: None,
+            register_dump_path: None,
         }
     }
 
-    pub fn run(self) -> Result<Profile, SamplingError> {
-        let reference_instant = Instant::now();
-        let reference_system_time = SystemTime::now();
+    /// Stop sampling after `time_limit`, even if the tasks are still alive. No
+    /// limit by default.
+    pub fn set_time_limit(&mut self, time_limit: Option<Duration>) {
+        self.time_limit = time_limit;
+    }
+
+    /// Stop sampling once `sample_limit` thread samples have been taken, in
+    /// all tasks together. No limit by default.
+    pub fn set_sample_limit(&mut self, sample_limit: Option<u64>) {
+        self.sample_limit = sample_limit;
+    }
+
+    /// Add markers for the time that threads spend idle, in all tasks. See
+    /// [`TaskProfiler::set_idle_markers`]. Off by default.
+    pub fn set_idle_markers(&mut self, idle_markers: bool) {
+        self.idle_markers = idle_markers;
+    }
+
+    /// Stop sampling once `stop_flag` is set, even if the tasks are still alive.
+    pub fn set_stop_flag(&mut self, stop_flag: Arc<AtomicBool>) {
+        self.stop_flag = Some(stop_flag);
+    }
+
+    /// Only sample the threads which match `thread_filter`, in all tasks.
+    pub fn set_thread_filter(&mut self, thread_filter: ThreadFilter) {
+        self.thread_filter = thread_filter;
+    }
+
+    /// Scan up to `stack_scan_words` words of the stack for return addresses
+    /// when a stack walk is implausibly short, in all tasks. Off by default.
+    pub fn set_stack_scan_words(&mut self, stack_scan_words: usize) {
+        self.stack_scan_words = stack_scan_words;
+    }
+
+    /// Stop walking stacks after `max_frames` frames, in all tasks. See
+    /// [`ThreadProfiler::set_max_frames`](super::thread_profiler::ThreadProfiler::set_max_frames).
+    pub fn set_max_frames(&mut self, max_frames: usize) {
+        self.max_frames = max_frames;
+    }
+
+    /// Record how long each sampling tick takes, as "Sampling" markers on a
+    /// separate "samply" thread. This shows how much of the recorded time the
+    /// profiler itself spent reading the sampled processes. Off by default.
+    pub fn set_overhead_markers(&mut self, overhead_markers: bool) {
+        self.overhead_markers = overhead_markers;
+    }
+
+    /// Record every `every_nth` allocation of all tasks, see
+    /// [`TaskProfiler::set_allocation_sampling`]. Off by default. The tasks
+    /// must have been launched with `MallocStackLogging=1`.
+    pub fn set_allocation_sampling(&mut self, every_nth: Option<u32>) {
+        self.allocation_sampling = every_nth;
+    }
+
+    /// Capture a stack every time a thread raises one of the selected exceptions,
+    /// in all tasks, see [`TaskProfiler::set_exception_sampling`]. Only crashes
+    /// are captured by default.
+    pub fn set_exception_sampling(&mut self, exception_sampling: ExceptionSampling) {
+        self.exception_sampling = exception_sampling;
+    }
+
+    /// Only keep the samples and markers of the most recent `duration`, so that
+    /// a recording can run indefinitely with bounded memory. The current window
+    /// can be saved at any time with [`SamplingSession::dump`], for example once
+    /// a stall has been noticed. Off by default.
+    pub fn set_ring_buffer_duration(&mut self, duration: Option<Duration>) {
+        self.ring_buffer_duration = duration;
+    }
+
+    /// Dump the profile from [`Sampler::run`] whenever the trigger's flag is
+    /// set. See [`DumpTrigger`].
+    pub fn set_dump_trigger(&mut self, dump_trigger: DumpTrigger) {
+        self.dump_trigger = Some(dump_trigger);
+    }
+
+    /// Record the register state of every stack walk, in all tasks, and write
+    /// it to `path` as text when the recording ends. This is for debugging
+    /// wrong stacks. Off by default.
+    pub fn set_register_dump_path(&mut self, path: Option<PathBuf>) {
+        self.register_dump_path = path;
+    }
+
+    /// Take sample timestamps from `clock` instead of the real clock.
+    #[cfg(test)]
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    /// Observe the stacks while they're recorded, e.g. for a live view. See
+    /// [`SampleCallback`]. The profile is the same with or without a callback.
+    pub fn set_sample_callback(&mut self, on_sample: SampleCallback) {
+        self.on_sample = Some(on_sample);
+    }
+
+    /// Wait for the root task and start sampling. The returned session is
+    /// driven by calling [`SamplingSession::tick`] once per sampling interval.
+    ///
+    /// [`Sampler::run`] drives the session from the current thread. Callers who
+    /// want to use their own timer, for example an async interval, can drive it
+    /// themselves instead. Each tick makes blocking mach calls, so in an async
+    /// runtime it should run on a thread which is allowed to block.
+    pub fn start(self) -> Result<SamplingSession, SamplingError> {
+        let reference_instant = self.clock.now();
+        let reference_system_time = self.clock.system_time();
         let timestamp_maker = InstantTimestampMaker::new(reference_instant);
 
         let mut profile = Profile::new(
@@ -60,9 +208,18 @@ impl Sampler {
             ReferenceTimestamp::from_system_time(reference_system_time),
             self.interval.into(),
         );
+        add_system_info(&mut profile);
 
         let default_category =
             CategoryPairHandle::from(profile.add_category("Regular", CategoryColor::Blue));
+        let overhead_thread = if self.overhead_markers {
+            Some(add_overhead_thread(
+                &mut profile,
+                timestamp_maker.make_ts(reference_instant),
+            ))
+        } else {
+            None
+        };
 
         let root_task_init = match self.task_receiver.recv() {
             Ok(task_init) => task_init,
@@ -73,104 +230,81 @@ impl Sampler {
             }
         };
 
-        let root_task = TaskProfiler::new(
-            root_task_init.task,
-            root_task_init.pid,
-            timestamp_maker.make_ts(root_task_init.start_time),
-            &self.command_name,
-            &mut profile,
+        let mut session = SamplingSession {
+            command_name: self.command_name,
+            root_pid: root_task_init.pid,
+            task_receiver: self.task_receiver,
+            sample_limit: self.sample_limit,
+            idle_markers: self.idle_markers,
+            timestamp_maker,
+            profile,
             default_category,
-        )
-        .expect("couldn't create root TaskProfiler");
+            live_root_task: None,
+            live_other_tasks: Vec::new(),
+            dead_tasks: Vec::new(),
+            unwinder_cache: Default::default(),
+            throttle: SamplingThrottle::new(self.interval),
+            sample_times: SampleTimes::new(self.interval, self.time_limit, self.clock.now()),
+            sample_count: 0,
+            ring_buffer: self.ring_buffer_duration.map(RingBuffer::new),
+            clock: self.clock,
+            on_sample: self.on_sample,
+            thread_filter: self.thread_filter,
+            stack_scan_words: self.stack_scan_words,
+            max_frames: self.max_frames,
+            allocation_sampling: self.allocation_sampling,
+            exception_sampling: self.exception_sampling,
+            register_dump_path: self.register_dump_path,
+            overhead_thread,
+        };
+        let root_task = session
+            .create_task(&root_task_init)
+            .expect("couldn't create root TaskProfiler");
+        session.live_root_task = Some(root_task);
+        Ok(session)
+    }
 
-        let mut live_root_task = Some(root_task);
-        let mut live_other_tasks = Vec::new();
-        let mut dead_other_tasks = Vec::new();
-        let mut unwinder_cache = Default::default();
+    pub fn run(mut self) -> Result<Profile, SamplingError> {
+        let stop_flag = self.stop_flag.clone();
+        let dump_trigger = self.dump_trigger.take();
+        let mut session = self.start()?;
         let mut last_sleep_overshoot = Duration::from_nanos(0);
 
-        let sampling_start = Instant::now();
+        // Samples are taken on an absolute schedule, so that late wakeups don't
+        // accumulate into drift.
+        let mut next_sample_time = Instant::now();
 
         loop {
-            // Poll to see if there are any new tasks we should add. If no new tasks are available,
-            // this completes immediately.
-            while let Ok(task_init) = self.task_receiver.try_recv() {
-                let new_task = match TaskProfiler::new(
-                    task_init.task,
-                    task_init.pid,
-                    timestamp_maker.make_ts(task_init.start_time),
-                    &self.command_name,
-                    &mut profile,
-                    default_category,
-                ) {
-                    Ok(new_task) => new_task,
-                    Err(_) => {
-                        // The task is probably already dead again. We get here for tasks which are
-                        // very short-lived.
-                        continue;
-                    }
-                };
-
-                live_other_tasks.push(new_task);
-            }
-
-            let sample_instant = Instant::now();
-            if let Some(time_limit) = self.time_limit {
-                if sample_instant.duration_since(sampling_start) >= time_limit {
+            if let Some(stop_flag) = &stop_flag {
+                if stop_flag.load(Ordering::SeqCst) {
                     break;
                 }
             }
 
-            let sample_timestamp = timestamp_maker.make_ts(sample_instant);
-
-            if let Some(task) = &mut live_root_task {
-                let still_alive =
-                    task.sample(sample_timestamp, &mut unwinder_cache, &mut profile)?;
-                if !still_alive {
-                    task.notify_dead(sample_timestamp, &mut profile);
-                    live_root_task = None;
-                }
-            }
-
-            let mut other_tasks = Vec::with_capacity(live_other_tasks.capacity());
-            mem::swap(&mut live_other_tasks, &mut other_tasks);
-            for mut task in other_tasks.into_iter() {
-                let still_alive =
-                    task.sample(sample_timestamp, &mut unwinder_cache, &mut profile)?;
-                if still_alive {
-                    live_other_tasks.push(task);
-                } else {
-                    task.notify_dead(sample_timestamp, &mut profile);
-                    dead_other_tasks.push(task);
-                }
+            if !session.tick()? {
+                break;
             }
 
-            if live_root_task.is_none() && live_other_tasks.is_empty() {
-                // All tasks we know about are dead.
-                // Wait for a little more in case one of the just-ended tasks spawned a new task.
-                if let Ok(task_init) = self
-                    .task_receiver
-                    .recv_timeout(Duration::from_secs_f32(0.5))
-                {
-                    // Got one!
-                    let new_task = TaskProfiler::new(
-                        task_init.task,
-                        task_init.pid,
-                        timestamp_maker.make_ts(task_init.start_time),
-                        &self.command_name,
-                        &mut profile,
-                        default_category,
-                    )
-                    .expect("couldn't create TaskProfiler");
-                    live_other_tasks.push(new_task);
-                } else {
-                    println!("All tasks terminated.");
-                    break;
+            if let Some(dump_trigger) = &dump_trigger {
+                if dump_trigger.flag.swap(false, Ordering::SeqCst) {
+                    let path = dump_trigger
+                        .output
+                        .path_for_process(session.root_pid, dump_trigger.launch_time);
+                    match session.dump(&path) {
+                        Ok(()) => eprintln!("Saved profile to {}", path.display()),
+                        Err(err) => {
+                            eprintln!("Couldn't save profile to {}: {}", path.display(), err)
+                        }
+                    }
                 }
             }
 
-            let intended_wakeup_time = sample_instant + self.interval;
-            let indended_wait_time = intended_wakeup_time.saturating_duration_since(Instant::now());
+            // If we're running late, e.g. after a long stall, don't try to catch up
+            // with a burst of samples. The next sample's weight covers the time
+            // which was missed.
+            next_sample_time =
+                (next_sample_time + session.effective_interval()).max(Instant::now());
+            let indended_wait_time = next_sample_time.saturating_duration_since(Instant::now());
             let sleep_time = if indended_wait_time > last_sleep_overshoot {
                 indended_wait_time - last_sleep_overshoot
             } else {
@@ -179,10 +313,476 @@ impl Sampler {
             sleep_and_save_overshoot(sleep_time, &mut last_sleep_overshoot);
         }
 
-        Ok(profile)
+        Ok(session.finish())
+    }
+}
+
+/// The state of a running recording, see [`Sampler::start`].
+pub struct SamplingSession {
+    command_name: String,
+    /// The pid of the first task, which names the output file.
+    root_pid: u32,
+    task_receiver: Receiver<TaskInit>,
+    sample_limit: Option<u64>,
+    idle_markers: bool,
+    timestamp_maker: InstantTimestampMaker,
+    profile: Profile,
+    default_category: CategoryPairHandle,
+    live_root_task: Option<TaskProfiler>,
+    live_other_tasks: Vec<TaskProfiler>,
+    dead_tasks: Vec<TaskProfiler>,
+    unwinder_cache: UnwinderCache,
+    throttle: SamplingThrottle,
+    sample_times: SampleTimes,
+    /// The number of thread samples in all tasks.
+    sample_count: u64,
+    ring_buffer: Option<RingBuffer>,
+    clock: Arc<dyn Clock>,
+    on_sample: Option<SampleCallback>,
+    thread_filter: ThreadFilter,
+    stack_scan_words: usize,
+    max_frames: usize,
+    allocation_sampling: Option<u32>,
+    exception_sampling: ExceptionSampling,
+    register_dump_path: Option<PathBuf>,
+    /// The thread which gets the overhead markers, see
+    /// [`Sampler::set_overhead_markers`].
+    overhead_thread: Option<ThreadHandle>,
+}
+
+impl SamplingSession {
+    /// Take one sample of every thread of every live task, and pick up tasks
+    /// which were launched in the meantime. This blocks while the threads are
+    /// suspended and their stacks are read.
+    ///
+    /// Returns `Ok(false)` once the recording is over, because all tasks have
+    /// died or a time or sample limit was reached. Call [`SamplingSession::finish`]
+    /// afterwards, or at any time to stop the recording early.
+    pub fn tick(&mut self) -> Result<bool, SamplingError> {
+        // Poll to see if there are any new tasks we should add. If no new tasks are available,
+        // this completes immediately.
+        while let Ok(task_init) = self.task_receiver.try_recv() {
+            let new_task = match self.create_task(&task_init) {
+                Ok(new_task) => new_task,
+                Err(_) => {
+                    // The task is probably already dead again. We get here for tasks which are
+                    // very short-lived.
+                    continue;
+                }
+            };
+            self.live_other_tasks.push(new_task);
+        }
+
+        let sample_instant = self.clock.now();
+        if self.sample_times.time_limit_reached(sample_instant) {
+            self.profile.set_truncated(true);
+            return Ok(false);
+        }
+        if let Some(sample_limit) = self.sample_limit {
+            if self.sample_count >= sample_limit {
+                eprintln!(
+                    "Stopping the recording after reaching the limit of {} samples.",
+                    sample_limit
+                );
+                self.profile.set_truncated(true);
+                return Ok(false);
+            }
+        }
+
+        let timestamp_maker = self.timestamp_maker;
+        let sample_timestamp = timestamp_maker.make_ts(sample_instant);
+        let sample_weight = self.sample_times.record_sample(sample_instant);
+        let clock = &*self.clock;
+        let current_time = || timestamp_maker.make_ts(clock.now());
+        let mut sampled_thread_count = 0;
+
+        if let Some(task) = &mut self.live_root_task {
+            let still_alive = task.sample(
+                sample_timestamp,
+                &current_time,
+                sample_weight,
+                &mut self.unwinder_cache,
+                &mut self.on_sample,
+                &mut self.profile,
+            )?;
+            if still_alive {
+                sampled_thread_count += task.sampled_thread_count();
+            } else {
+                task.notify_dead(sample_timestamp, &mut self.profile);
+                self.dead_tasks.extend(self.live_root_task.take());
+            }
+        }
+
+        let mut other_tasks = Vec::with_capacity(self.live_other_tasks.capacity());
+        mem::swap(&mut self.live_other_tasks, &mut other_tasks);
+        for mut task in other_tasks.into_iter() {
+            let still_alive = task.sample(
+                sample_timestamp,
+                &current_time,
+                sample_weight,
+                &mut self.unwinder_cache,
+                &mut self.on_sample,
+                &mut self.profile,
+            )?;
+            if still_alive {
+                sampled_thread_count += task.sampled_thread_count();
+                self.live_other_tasks.push(task);
+            } else {
+                task.notify_dead(sample_timestamp, &mut self.profile);
+                self.dead_tasks.push(task);
+            }
+        }
+        self.sample_count += sampled_thread_count as u64;
+
+        if let Some(ring_buffer) = &mut self.ring_buffer {
+            if let Some(cutoff) = ring_buffer.discard_cutoff(sample_timestamp) {
+                self.profile.discard_before(cutoff);
+            }
+        }
+
+        if let Some(overhead_thread) = self.overhead_thread {
+            self.profile.add_marker(
+                overhead_thread,
+                "Sampling",
+                SamplingOverheadMarker {
+                    thread_count: sampled_thread_count,
+                },
+                MarkerTiming::Interval(sample_timestamp, current_time()),
+            );
+        }
+
+        if self.live_root_task.is_none() && self.live_other_tasks.is_empty() {
+            // All tasks we know about are dead.
+            // Wait for a little more in case one of the just-ended tasks spawned a new task.
+            if let Ok(task_init) = self
+                .task_receiver
+                .recv_timeout(Duration::from_secs_f32(0.5))
+            {
+                // Got one!
+                let new_task = self
+                    .create_task(&task_init)
+                    .expect("couldn't create TaskProfiler");
+                self.live_other_tasks.push(new_task);
+            } else {
+                println!("All tasks terminated.");
+                return Ok(false);
+            }
+        }
+
+        self.throttle
+            .record_sample_duration(self.clock.now().saturating_duration_since(sample_instant));
+        Ok(true)
+    }
+
+    /// Create the profiler for a new task and apply the recording's options to it.
+    fn create_task(&mut self, task_init: &TaskInit) -> Result<TaskProfiler, SamplingError> {
+        let mut task = TaskProfiler::new(
+            task_init.task,
+            task_init.pid,
+            self.timestamp_maker.make_ts(task_init.start_time),
+            &self.command_name,
+            &mut self.profile,
+            self.default_category,
+            self.thread_filter.clone(),
+        )?;
+        if task_init.is_attached {
+            task.mark_initial_threads_as_preexisting(&mut self.profile);
+        }
+        self.configure_task(&mut task);
+        Ok(task)
+    }
+
+    /// Apply the per-task options of the recording to `task`.
+    fn configure_task(&self, task: &mut TaskProfiler) {
+        task.set_idle_markers(self.idle_markers);
+        task.set_dump_registers(self.register_dump_path.is_some());
+        task.set_stack_scan_words(self.stack_scan_words);
+        task.set_max_frames(self.max_frames);
+        if let Some(every_nth) = self.allocation_sampling {
+            task.set_allocation_sampling(every_nth);
+        }
+        task.set_exception_sampling(&self.exception_sampling);
+    }
+
+    /// The time from the start of one tick to the start of the next. This is
+    /// the requested interval, unless sampling has been throttled because
+    /// ticks take too long.
+    pub fn effective_interval(&self) -> Duration {
+        self.throttle.effective_interval()
+    }
+
+    /// Save the profile recorded so far to `path`, while the recording goes
+    /// on. With a [ring buffer](Sampler::set_ring_buffer_duration), this is
+    /// the most recent window of the recording.
+    pub fn dump(&mut self, path: &Path) -> std::io::Result<()> {
+        self.discard_outside_window();
+        save_profile(&self.profile, path)
+    }
+
+    /// With a ring buffer, drops everything before the window which ends now.
+    fn discard_outside_window(&mut self) {
+        if let Some(ring_buffer) = &self.ring_buffer {
+            let now = self.timestamp_maker.make_ts(self.clock.now());
+            self.profile.discard_before(ring_buffer.window_start(now));
+        }
+    }
+
+    /// End the recording and return the profile. Tasks which are still
+    /// running are marked as ended now. With a ring buffer, the profile only
+    /// contains the most recent window.
+    pub fn finish(mut self) -> Profile {
+        self.discard_outside_window();
+        let end_timestamp = self.timestamp_maker.make_ts(self.clock.now());
+        for task in self
+            .live_root_task
+            .iter_mut()
+            .chain(&mut self.live_other_tasks)
+        {
+            task.notify_dead(end_timestamp, &mut self.profile);
+        }
+        if let Some(overhead_thread) = self.overhead_thread {
+            self.profile
+                .set_thread_end_time(overhead_thread, end_timestamp);
+        }
+
+        if let Some(max_interval) = self.throttle.max_throttled_interval() {
+            self.profile.set_throttled_interval(max_interval.into());
+        }
+
+        if let Some(path) = &self.register_dump_path {
+            match self.write_register_dumps(path) {
+                Ok(()) => eprintln!("Saved register dumps to {}", path.display()),
+                Err(err) => eprintln!(
+                    "Couldn't save register dumps to {}: {}",
+                    path.display(),
+                    err
+                ),
+            }
+        }
+
+        self.profile
+    }
+
+    fn write_register_dumps(&self, path: &Path) -> std::io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        for task in self
+            .live_root_task
+            .iter()
+            .chain(&self.live_other_tasks)
+            .chain(&self.dead_tasks)
+        {
+            task.write_register_dumps(&mut writer)?;
+        }
+        writer.flush()
+    }
+}
+
+/// Adds a "samply" process with a thread for the overhead markers. The thread
+/// gets the tid of the sampler thread.
+fn add_overhead_thread(profile: &mut Profile, start_time: Timestamp) -> ThreadHandle {
+    let process = profile.add_process("samply", std::process::id(), start_time);
+    let mut tid = 0;
+    unsafe { libc::pthread_threadid_np(libc::pthread_self(), &mut tid) };
+    let thread = profile.add_thread(process, tid as u32, start_time, true);
+    profile.set_thread_name(thread, "samply sampler");
+    thread
+}
+
+/// Decides when to drop the samples which have left the window of a ring buffer
+/// recording. Discarding moves all remaining samples, so it's only done each
+/// time the window has moved by an eighth of its duration. The profile holds
+/// at most 9/8 of the window in the meantime.
+#[derive(Debug, Clone)]
+struct RingBuffer {
+    duration_ns: u64,
+    next_discard_ns: u64,
+}
+
+impl RingBuffer {
+    fn new(duration: Duration) -> Self {
+        let duration_ns = duration.as_nanos() as u64;
+        Self {
+            duration_ns,
+            next_discard_ns: duration_ns + duration_ns / 8,
+        }
+    }
+
+    /// The start of the window which ends at `now`.
+    fn window_start(&self, now: Timestamp) -> Timestamp {
+        Timestamp::from_nanos_since_reference(
+            now.nanos_since_reference().saturating_sub(self.duration_ns),
+        )
+    }
+
+    /// Returns the time before which everything should be discarded, if it's
+    /// time to discard.
+    fn discard_cutoff(&mut self, now: Timestamp) -> Option<Timestamp> {
+        let now_ns = now.nanos_since_reference();
+        if now_ns < self.next_discard_ns {
+            return None;
+        }
+        self.next_discard_ns = now_ns + self.duration_ns / 8;
+        Some(self.window_start(now))
+    }
+}
+
+/// The time span of one sampling tick, from the start of the tick until the
+/// stacks of all threads have been read.
+#[derive(Debug, Clone)]
+struct SamplingOverheadMarker {
+    /// The number of threads which were sampled in this tick, in all tasks.
+    thread_count: usize,
+}
+
+impl ProfilerMarker for SamplingOverheadMarker {
+    const MARKER_TYPE_NAME: &'static str = "SamplingOverhead";
+
+    fn schema() -> MarkerSchema {
+        MarkerSchema {
+            type_name: Self::MARKER_TYPE_NAME,
+            locations: vec![MarkerLocation::MarkerChart, MarkerLocation::MarkerTable],
+            chart_label: None,
+            tooltip_label: Some("Sampled {marker.data.threadCount} threads"),
+            table_label: Some("{marker.data.threadCount} threads"),
+            fields: vec![MarkerSchemaField::Dynamic(MarkerDynamicField {
+                key: "threadCount",
+                label: "Sampled threads",
+                format: MarkerFieldFormat::Integer,
+                searchable: None,
+            })],
+        }
+    }
+
+    fn json_marker_data(&self) -> serde_json::Value {
+        serde_json::json!({ "type": Self::MARKER_TYPE_NAME, "threadCount": self.thread_count })
+    }
+}
+
+/// Lowers the sampling rate while taking a sample consistently takes longer
+/// than the sampling interval, for example because the sampled processes have
+/// a large number of threads. Otherwise the sampler would fall further and
+/// further behind, and the time between samples would silently be distorted.
+///
+/// The effective interval is always a power-of-two multiple of the requested
+/// interval.
+struct SamplingThrottle {
+    requested_interval: Duration,
+    multiplier: u32,
+    max_multiplier: u32,
+    consecutive_overruns: u32,
+    consecutive_fast_samples: u32,
+}
+
+impl SamplingThrottle {
+    /// The number of consecutive samples that need to take longer than the
+    /// effective interval before the interval is doubled.
+    const OVERRUNS_BEFORE_THROTTLING: u32 = 10;
+
+    /// The number of consecutive samples that need to take less than a quarter
+    /// of the effective interval before the interval is halved again.
+    const FAST_SAMPLES_BEFORE_UNTHROTTLING: u32 = 100;
+
+    /// The effective interval is never more than this many times the requested one.
+    const MAX_MULTIPLIER: u32 = 64;
+
+    fn new(requested_interval: Duration) -> Self {
+        Self {
+            requested_interval,
+            multiplier: 1,
+            max_multiplier: 1,
+            consecutive_overruns: 0,
+            consecutive_fast_samples: 0,
+        }
+    }
+
+    fn effective_interval(&self) -> Duration {
+        self.requested_interval * self.multiplier
+    }
+
+    /// The longest interval that was used, if sampling was ever throttled.
+    fn max_throttled_interval(&self) -> Option<Duration> {
+        if self.max_multiplier > 1 {
+            Some(self.requested_interval * self.max_multiplier)
+        } else {
+            None
+        }
+    }
+
+    /// Adjust the effective interval based on how long the most recent sample took.
+    fn record_sample_duration(&mut self, duration: Duration) {
+        let effective_interval = self.effective_interval();
+        if duration > effective_interval {
+            self.consecutive_fast_samples = 0;
+            self.consecutive_overruns += 1;
+            if self.consecutive_overruns >= Self::OVERRUNS_BEFORE_THROTTLING
+                && self.multiplier < Self::MAX_MULTIPLIER
+            {
+                self.multiplier *= 2;
+                self.max_multiplier = self.max_multiplier.max(self.multiplier);
+                self.consecutive_overruns = 0;
+            }
+        } else if duration < effective_interval / 4 && self.multiplier > 1 {
+            self.consecutive_overruns = 0;
+            self.consecutive_fast_samples += 1;
+            if self.consecutive_fast_samples >= Self::FAST_SAMPLES_BEFORE_UNTHROTTLING {
+                self.multiplier /= 2;
+                self.consecutive_fast_samples = 0;
+            }
+        } else {
+            self.consecutive_overruns = 0;
+            self.consecutive_fast_samples = 0;
+        }
+    }
+}
+
+/// When the samples of a session were taken, for the time limit and the
+/// sample weights.
+struct SampleTimes {
+    interval: Duration,
+    time_limit: Option<Duration>,
+    sampling_start: Instant,
+    previous_sample_instant: Option<Instant>,
+}
+
+impl SampleTimes {
+    fn new(interval: Duration, time_limit: Option<Duration>, sampling_start: Instant) -> Self {
+        Self {
+            interval,
+            time_limit,
+            sampling_start,
+            previous_sample_instant: None,
+        }
+    }
+
+    fn time_limit_reached(&self, now: Instant) -> bool {
+        match self.time_limit {
+            Some(time_limit) => now.saturating_duration_since(self.sampling_start) >= time_limit,
+            None => false,
+        }
+    }
+
+    /// Record a sample which is taken at `instant`, and return its weight.
+    fn record_sample(&mut self, instant: Instant) -> i32 {
+        let weight = match self.previous_sample_instant {
+            Some(previous) => {
+                sample_weight(instant.saturating_duration_since(previous), self.interval)
+            }
+            None => 1,
+        };
+        self.previous_sample_instant = Some(instant);
+        weight
     }
 }
 
+/// The weight of a sample which was taken `time_since_previous_sample` after the
+/// previous one: the number of requested intervals which have elapsed, rounded,
+/// and at least 1. Samples are usually spaced by one effective interval, but a
+/// sample after a stall stands for all the samples which couldn't be taken.
+fn sample_weight(time_since_previous_sample: Duration, requested_interval: Duration) -> i32 {
+    let intervals = time_since_previous_sample.as_secs_f64() / requested_interval.as_secs_f64();
+    intervals.round().max(1.0) as i32
+}
+
 fn sleep_and_save_overshoot(duration: Duration, overshoot: &mut Duration) {
     let before_sleep = Instant::now();
     thread::sleep(duration);
@@ -194,7 +794,7 @@ fn sleep_and_save_overshoot(duration: Duration, overshoot: &mut Duration) {
 }
 
 #[derive(Debug, Clone, Copy, PartialOrd, Ord, PartialEq, Eq, Hash)]
-struct InstantTimestampMaker {
+pub struct InstantTimestampMaker {
     reference_instant: Instant,
 }
 
@@ -215,3 +815,278 @@ impl InstantTimestampMaker {
         )
     }
 }
+
+#[test]
+fn test_sampling_throttle() {
+    let interval = Duration::from_millis(1);
+    let mut throttle = SamplingThrottle::new(interval);
+    assert_eq!(throttle.effective_interval(), interval);
+
+    // A few slow samples in a row don't throttle yet.
+    for _ in 0..SamplingThrottle::OVERRUNS_BEFORE_THROTTLING - 1 {
+        throttle.record_sample_duration(Duration::from_millis(3));
+    }
+    throttle.record_sample_duration(Duration::from_micros(500));
+    assert_eq!(throttle.effective_interval(), interval);
+    assert_eq!(throttle.max_throttled_interval(), None);
+
+    // Consistently slow samples double the interval until it fits.
+    for _ in 0..SamplingThrottle::OVERRUNS_BEFORE_THROTTLING * 2 {
+        throttle.record_sample_duration(Duration::from_millis(3));
+    }
+    assert_eq!(throttle.effective_interval(), Duration::from_millis(4));
+    for _ in 0..SamplingThrottle::OVERRUNS_BEFORE_THROTTLING * 2 {
+        throttle.record_sample_duration(Duration::from_millis(3));
+    }
+    assert_eq!(throttle.effective_interval(), Duration::from_millis(4));
+
+    // Once sampling is fast again, the interval goes back down.
+    for _ in 0..SamplingThrottle::FAST_SAMPLES_BEFORE_UNTHROTTLING * 2 {
+        throttle.record_sample_duration(Duration::from_micros(100));
+    }
+    assert_eq!(throttle.effective_interval(), interval);
+    assert_eq!(
+        throttle.max_throttled_interval(),
+        Some(Duration::from_millis(4))
+    );
+}
+
+#[test]
+fn test_sample_weight() {
+    let interval = Duration::from_millis(1);
+    assert_eq!(sample_weight(Duration::from_micros(1000), interval), 1);
+    // Jitter and early wakeups don't change the weight.
+    assert_eq!(sample_weight(Duration::from_micros(1400), interval), 1);
+    assert_eq!(sample_weight(Duration::from_micros(300), interval), 1);
+    // Throttled samples span several intervals.
+    assert_eq!(sample_weight(Duration::from_micros(4100), interval), 4);
+    // A long stall results in one sample with a large weight.
+    assert_eq!(sample_weight(Duration::from_millis(250), interval), 250);
+}
+
+#[test]
+fn test_sample_times_with_mock_clock() {
+    use super::clock::MockClock;
+
+    let clock = MockClock::new();
+    let timestamp_maker = InstantTimestampMaker::new(clock.now());
+    let mut sample_times = SampleTimes::new(
+        Duration::from_millis(1),
+        Some(Duration::from_millis(10)),
+        clock.now(),
+    );
+
+    let mut samples = Vec::new();
+    for step in [1, 1, 5, 2] {
+        clock.advance(Duration::from_millis(step));
+        let now = clock.now();
+        assert!(!sample_times.time_limit_reached(now));
+        samples.push((
+            timestamp_maker.make_ts(now),
+            sample_times.record_sample(now),
+        ));
+    }
+    assert_eq!(
+        samples,
+        vec![
+            (Timestamp::from_millis_since_reference(1.0), 1),
+            (Timestamp::from_millis_since_reference(2.0), 1),
+            (Timestamp::from_millis_since_reference(7.0), 5),
+            (Timestamp::from_millis_since_reference(9.0), 2),
+        ]
+    );
+
+    clock.advance(Duration::from_millis(1));
+    assert!(sample_times.time_limit_reached(clock.now()));
+}
+
+#[test]
+fn test_sample_timestamps_with_mock_clock() {
+    use super::clock::MockClock;
+
+    let done = Arc::new(AtomicBool::new(false));
+    let waiting_thread = {
+        let done = done.clone();
+        thread::Builder::new()
+            .name("waiting".to_string())
+            .spawn(move || {
+                while !done.load(Ordering::SeqCst) {
+                    thread::sleep(Duration::from_millis(1));
+                }
+            })
+            .unwrap()
+    };
+
+    let clock = MockClock::new();
+    let mut sampler = own_process_sampler();
+    sampler.set_clock(Arc::new(clock.clone()));
+    sampler.set_thread_filter(ThreadFilter {
+        names: vec!["waiting".to_string()],
+        tids: Vec::new(),
+        exclude_main_thread: true,
+    });
+    let mut session = sampler.start().unwrap();
+    for step in [0, 1, 5] {
+        clock.advance(Duration::from_millis(step));
+        assert!(session.tick().unwrap());
+    }
+    let profile = session.finish();
+    done.store(true, Ordering::SeqCst);
+    waiting_thread.join().unwrap();
+
+    // The samples are timestamped by the mock clock, however long the ticks
+    // actually took.
+    let json = serde_json::to_value(&profile).unwrap();
+    let thread = json["threads"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|thread| thread["name"] == "waiting")
+        .unwrap();
+    assert_eq!(
+        thread["samples"]["time"],
+        serde_json::json!([0.0, 1.0, 6.0])
+    );
+    assert_eq!(thread["samples"]["weight"], serde_json::json!([1, 1, 5]));
+}
+
+#[test]
+fn test_sampling_session_is_send() {
+    // Callers which drive the session themselves may move it to a blocking
+    // thread for each tick.
+    fn assert_send<T: Send>() {}
+    assert_send::<SamplingSession>();
+}
+
+#[test]
+fn test_ring_buffer() {
+    let ms = |millis| Timestamp::from_millis_since_reference(millis);
+    let mut ring_buffer = RingBuffer::new(Duration::from_millis(800));
+    assert_eq!(ring_buffer.window_start(ms(500.0)), ms(0.0));
+
+    // Nothing is discarded until the window has moved by an eighth.
+    assert_eq!(ring_buffer.discard_cutoff(ms(899.0)), None);
+    assert_eq!(ring_buffer.discard_cutoff(ms(900.0)), Some(ms(100.0)));
+    assert_eq!(ring_buffer.discard_cutoff(ms(950.0)), None);
+    assert_eq!(ring_buffer.discard_cutoff(ms(1000.0)), Some(ms(200.0)));
+
+    // After a long stall, the next sample discards right away.
+    assert_eq!(ring_buffer.discard_cutoff(ms(5000.0)), Some(ms(4200.0)));
+    assert_eq!(ring_buffer.window_start(ms(5010.0)), ms(4210.0));
+}
+
+/// A sampler whose root task is our own process, which stands in for the
+/// launched process.
+#[cfg(test)]
+fn own_process_sampler() -> Sampler {
+    use mach::port::MACH_PORT_NULL;
+    use mach::traps::{mach_task_self, task_for_pid};
+
+    let pid = std::process::id();
+    let mut task: mach_port_t = MACH_PORT_NULL;
+    unsafe { task_for_pid(mach_task_self(), pid as i32, &mut task) };
+    let (task_sender, task_receiver) = crossbeam_channel::unbounded();
+    task_sender
+        .send(TaskInit {
+            start_time: Instant::now(),
+            task,
+            pid,
+            is_attached: false,
+        })
+        .unwrap();
+    Sampler::new("test".to_string(), task_receiver, Duration::from_millis(1))
+}
+
+#[test]
+fn test_root_task_idle_markers() {
+    let mut sampler = own_process_sampler();
+    sampler.set_idle_markers(true);
+    let session = sampler.start().unwrap();
+    assert!(session.live_root_task.as_ref().unwrap().idle_markers());
+}
+
+#[test]
+fn test_deep_recursion_is_truncated() {
+    use std::sync::mpsc;
+
+    // A thread of our own process recurses deeply and then waits while it's
+    // sampled.
+    fn recurse(depth: usize, ready: &mpsc::Sender<()>, done: &AtomicBool) -> usize {
+        if depth == 0 {
+            ready.send(()).unwrap();
+            while !done.load(Ordering::SeqCst) {
+                thread::sleep(Duration::from_millis(1));
+            }
+            return 0;
+        }
+        std::hint::black_box(recurse(depth - 1, ready, done)) + 1
+    }
+    let done = Arc::new(AtomicBool::new(false));
+    let (ready_sender, ready_receiver) = mpsc::channel();
+    let recursing_thread = {
+        let done = done.clone();
+        thread::Builder::new()
+            .name("deep recursion".to_string())
+            .spawn(move || recurse(5000, &ready_sender, &done))
+            .unwrap()
+    };
+    ready_receiver.recv().unwrap();
+
+    let mut sampler = own_process_sampler();
+    sampler.set_max_frames(100);
+    sampler.set_thread_filter(ThreadFilter {
+        names: vec!["deep recursion".to_string()],
+        tids: Vec::new(),
+        exclude_main_thread: true,
+    });
+    let mut session = sampler.start().unwrap();
+    session.tick().unwrap();
+    let profile = session.finish();
+    done.store(true, Ordering::SeqCst);
+    recursing_thread.join().unwrap();
+
+    // The innermost 100 frames are kept, under a "(truncated stack)" root.
+    let json = serde_json::to_value(&profile).unwrap();
+    let thread = json["threads"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|thread| thread["name"] == "deep recursion")
+        .unwrap();
+    let func_name = |stack: usize| {
+        let frame = thread["stackTable"]["frame"][stack].as_u64().unwrap();
+        let func = thread["frameTable"]["func"][frame as usize]
+            .as_u64()
+            .unwrap();
+        let name = thread["funcTable"]["name"][func as usize].as_u64().unwrap();
+        thread["stringArray"][name as usize]
+            .as_str()
+            .unwrap()
+            .to_string()
+    };
+    let mut stack = thread["samples"]["stack"][0].as_u64().unwrap() as usize;
+    let mut frame_count = 0;
+    while func_name(stack) != "(truncated stack)" {
+        frame_count += 1;
+        stack = thread["stackTable"]["prefix"][stack].as_u64().unwrap() as usize;
+    }
+    assert_eq!(frame_count, 100);
+}
+
+#[test]
+fn test_register_dump_file() {
+    let dump_file = tempfile::NamedTempFile::new().unwrap();
+    let mut sampler = own_process_sampler();
+    sampler.set_register_dump_path(Some(dump_file.path().to_owned()));
+    let mut session = sampler.start().unwrap();
+    session.tick().unwrap();
+    session.finish();
+
+    // Every thread was sampled once, so there is at least one dump.
+    let dumps = std::fs::read_to_string(dump_file.path()).unwrap();
+    assert!(dumps.starts_with("tid "));
+    assert!(dumps.contains(" pc=0x"));
+    // The general purpose registers come from the raw thread state, e.g.
+    // "rsp" on x86_64 and "sp" on aarch64.
+    assert!(dumps.contains("sp = 0x"));
+}
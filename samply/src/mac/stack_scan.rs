@@ -0,0 +1,89 @@
+use framehop::FrameAddress;
+
+use crate::code_ranges::CodeRanges;
+
+/// Stack walks which yield at most this many frames are considered implausibly
+/// short, and are extended by scanning the stack if stack scanning is enabled.
+/// Every regular thread has at least a few frames from the thread start code.
+pub const MAX_IMPLAUSIBLE_FRAME_COUNT: usize = 2;
+
+/// Reads up to `max_words` stack words, starting at `sp`, and appends the ones
+/// which look like return addresses, i.e. which point into `code_ranges`, to
+/// `frames`. `frames` contains the result of the regular stack walk, innermost
+/// frame first; values which are already in it are skipped. The scan stops at
+/// the first word which can't be read.
+///
+/// Any pointer into code which is stored on the stack is found, e.g. a function
+/// pointer in a local variable, so the frames found this way are only guesses.
+/// Returns the number of frames which were appended.
+pub fn scan_stack(
+    sp: u64,
+    max_words: usize,
+    code_ranges: &CodeRanges,
+    mut read_stack: impl FnMut(u64) -> Result<u64, ()>,
+    frames: &mut Vec<FrameAddress>,
+) -> usize {
+    let walked_frame_count = frames.len();
+    for word_index in 0..max_words as u64 {
+        let value = match read_stack(sp + word_index * 8) {
+            Ok(value) => strip_code_pointer(value),
+            Err(()) => break,
+        };
+        if !code_ranges.contains(value) {
+            continue;
+        }
+        let frame = match FrameAddress::from_return_address(value) {
+            Some(frame) => frame,
+            None => continue,
+        };
+        if !frames.iter().any(|f| f.address() == frame.address()) {
+            frames.push(frame);
+        }
+    }
+    frames.len() - walked_frame_count
+}
+
+/// Return addresses on the stack can carry a pointer authentication hash in
+/// their high bits.
+#[cfg(target_arch = "aarch64")]
+fn strip_code_pointer(value: u64) -> u64 {
+    framehop::aarch64::PtrAuthMask::new_24_40().strip_ptr_auth(value)
+}
+
+#[cfg(target_arch = "x86_64")]
+fn strip_code_pointer(value: u64) -> u64 {
+    value
+}
+
+#[test]
+fn test_scan_stack() {
+    let mut code_ranges = CodeRanges::default();
+    code_ranges.add(0x1000..0x2000, None);
+    let stack: Vec<u64> = vec![0x7, 0x1010, 0x9999, 0x1020, 0x1010, 0x1030, 0x1040];
+    let read_stack = |address: u64| {
+        let index = ((address - 0x8000) / 8) as usize;
+        stack.get(index).copied().ok_or(())
+    };
+
+    // The leaf comes from the walk, and the walk already found 0x1020.
+    let mut frames = vec![
+        FrameAddress::InstructionPointer(0x1500),
+        FrameAddress::from_return_address(0x1020).unwrap(),
+    ];
+    let scanned_count = scan_stack(0x8000, 6, &code_ranges, read_stack, &mut frames);
+    assert_eq!(scanned_count, 2);
+    assert_eq!(
+        frames,
+        vec![
+            FrameAddress::InstructionPointer(0x1500),
+            FrameAddress::from_return_address(0x1020).unwrap(),
+            FrameAddress::from_return_address(0x1010).unwrap(),
+            FrameAddress::from_return_address(0x1030).unwrap(),
+        ]
+    );
+
+    // The scan stops at unreadable memory.
+    let mut frames = vec![FrameAddress::InstructionPointer(0x1500)];
+    let scanned_count = scan_stack(0x8000, 100, &code_ranges, read_stack, &mut frames);
+    assert_eq!(scanned_count, 4);
+}
@@ -0,0 +1,80 @@
+use std::convert::TryFrom;
+use std::ffi::CString;
+
+use fxprof_processed_profile::Profile;
+
+/// Record the macOS version, the CPU and the samply version in the profile's
+/// metadata. Values which can't be queried are left out.
+pub fn add_system_info(profile: &mut Profile) {
+    profile.set_platform("Macintosh");
+    if let Some(version) = sysctl_string("kern.osproductversion") {
+        profile.set_os_name(&format!("macOS {}", version));
+    }
+    if let Some(cpu_name) = sysctl_string("machdep.cpu.brand_string") {
+        profile.set_cpu_name(&cpu_name);
+    }
+    if let (Some(physical_cpus), Some(logical_cpus)) =
+        (sysctl_u32("hw.physicalcpu"), sysctl_u32("hw.logicalcpu"))
+    {
+        profile.set_cpu_counts(physical_cpus, logical_cpus);
+    }
+    profile.set_misc(&format!("samply {}", env!("CARGO_PKG_VERSION")));
+}
+
+fn sysctl_string(name: &str) -> Option<String> {
+    let name = CString::new(name).ok()?;
+    let mut len: libc::size_t = 0;
+    let ret = unsafe {
+        libc::sysctlbyname(
+            name.as_ptr(),
+            std::ptr::null_mut(),
+            &mut len,
+            std::ptr::null_mut(),
+            0,
+        )
+    };
+    if ret != 0 || len == 0 {
+        return None;
+    }
+    let mut buffer = vec![0u8; len];
+    let ret = unsafe {
+        libc::sysctlbyname(
+            name.as_ptr(),
+            buffer.as_mut_ptr() as *mut _,
+            &mut len,
+            std::ptr::null_mut(),
+            0,
+        )
+    };
+    if ret != 0 {
+        return None;
+    }
+    buffer.truncate(len);
+    // The value is nul-terminated.
+    let end = buffer.iter().position(|b| *b == 0).unwrap_or(buffer.len());
+    let value = String::from_utf8_lossy(&buffer[..end]).trim().to_string();
+    if value.is_empty() {
+        None
+    } else {
+        Some(value)
+    }
+}
+
+fn sysctl_u32(name: &str) -> Option<u32> {
+    let name = CString::new(name).ok()?;
+    let mut value: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>();
+    let ret = unsafe {
+        libc::sysctlbyname(
+            name.as_ptr(),
+            &mut value as *mut libc::c_int as *mut _,
+            &mut len,
+            std::ptr::null_mut(),
+            0,
+        )
+    };
+    if ret != 0 {
+        return None;
+    }
+    u32::try_from(value).ok()
+}
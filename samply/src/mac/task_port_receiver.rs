@@ -0,0 +1,65 @@
+use mach::mach_port::mach_port_deallocate;
+use mach::traps::mach_task_self;
+
+use std::convert::TryInto;
+use std::time::Duration;
+
+use super::mach_ipc::{mach_port_t, BlockingMode, MachError, OsIpcMultiShotServer};
+
+/// Receives the task port of a process from another process, over a mach
+/// service with a known name.
+///
+/// This lets a privileged helper call `task_for_pid` and hand the port to an
+/// unprivileged samply, which then never needs to call `task_for_pid` itself.
+/// The helper looks up the service with `bootstrap_look_up` and sends a message
+/// with the payload `b"My task"` followed by the pid as a little-endian `u32`,
+/// and with the task port as the message's only port. This is the message which
+/// libsamply_mac_preload sends for launched processes, minus the reply port.
+pub struct TaskPortReceiver {
+    server: OsIpcMultiShotServer,
+}
+
+impl TaskPortReceiver {
+    /// Registers `service_name` with the bootstrap server. Fails if the name is
+    /// already taken. The name is unregistered when the receiver is dropped.
+    pub fn register(service_name: &str) -> Result<Self, MachError> {
+        let server = OsIpcMultiShotServer::with_name(service_name)?;
+        Ok(Self { server })
+    }
+
+    /// Waits for a task port, and returns it together with the process's pid.
+    /// The caller owns the returned send right. Messages which don't follow the
+    /// format described on [`TaskPortReceiver`] are skipped, and the ports in
+    /// them are deallocated.
+    pub fn try_accept(&mut self, timeout: Duration) -> Result<(u32, mach_port_t), MachError> {
+        loop {
+            let (payload, mut channels, _) = self
+                .server
+                .accept(BlockingMode::BlockingWithTimeout(timeout))?;
+            let pid = match parse_task_message(&payload) {
+                Some(pid) if channels.len() == 1 => pid,
+                _ => {
+                    for channel in channels {
+                        unsafe { mach_port_deallocate(mach_task_self(), channel.into_port()) };
+                    }
+                    continue;
+                }
+            };
+            let task = channels.pop().unwrap().into_port();
+            return Ok((pid, task));
+        }
+    }
+}
+
+/// Returns the pid from a `b"My task"` message.
+fn parse_task_message(payload: &[u8]) -> Option<u32> {
+    let pid_bytes = payload.strip_prefix(b"My task")?;
+    Some(u32::from_le_bytes(pid_bytes.try_into().ok()?))
+}
+
+#[test]
+fn test_parse_task_message() {
+    assert_eq!(parse_task_message(b"My task\x39\x30\0\0"), Some(12345));
+    assert_eq!(parse_task_message(b"My task\x39\x30\0"), None);
+    assert_eq!(parse_task_message(b"My tusk\x39\x30\0\0"), None);
+}
@@ -1,33 +1,49 @@
+use clap::ValueEnum;
 use framehop::{
     CacheNative, MayAllocateDuringUnwind, Module, ModuleUnwindData, TextByteData, Unwinder,
     UnwinderNative,
 };
 use fxprof_processed_profile::debugid::DebugId;
 use fxprof_processed_profile::{
-    CategoryPairHandle, LibraryInfo, ProcessHandle, Profile, Timestamp,
+    CategoryPairHandle, Frame, LibraryInfo, MarkerDynamicField, MarkerFieldFormat, MarkerLocation,
+    MarkerSchema, MarkerSchemaField, MarkerTiming, ProcessHandle, Profile, ProfilerMarker,
+    ThreadHandle, Timestamp,
 };
+use mach::mach_port::mach_port_deallocate;
 use mach::mach_types::thread_act_port_array_t;
 use mach::mach_types::thread_act_t;
 use mach::message::mach_msg_type_number_t;
-use mach::port::mach_port_t;
+use mach::port::{mach_port_t, MACH_PORT_NULL};
 use mach::task::task_threads;
-use mach::traps::mach_task_self;
+use mach::traps::{mach_task_self, task_for_pid};
 use mach::vm::mach_vm_deallocate;
 use mach::vm_types::{mach_vm_address_t, mach_vm_size_t};
 use object::{CompressedFileRange, CompressionFormat, Object, ObjectSection};
 use samply_symbols::{object, DebugIdExt};
-use wholesym::samply_symbols;
+use wholesym::{samply_symbols, MultiArchDisambiguator};
 
 use std::collections::hash_map::Entry;
 use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::iter;
 use std::mem;
-use std::ops::Deref;
+use std::ops::{Deref, Range};
 use std::path::Path;
 
-use super::error::SamplingError;
-use super::kernel_error::{IntoResult, KernelError};
-use super::proc_maps::{DyldInfo, DyldInfoManager, Modification, StackwalkerRef, VmSubData};
-use super::thread_profiler::{get_thread_id, ThreadProfiler};
+use super::error::{AttachError, SamplingError};
+use super::exception_port::{ExceptionPort, PendingException};
+use super::kernel_error::{retry_if_transient, IntoResult, KernelError};
+use super::malloc_stack_logging::MallocStackReader;
+use super::proc_maps::{
+    DyldInfo, DyldInfoManager, Modification, StackwalkerRef, VmSubData, DEFAULT_MAX_FRAMES,
+};
+use super::thread_profiler::{
+    get_thread_cpu_time_since_thread_start, get_thread_id, get_thread_name, SampleCallback,
+    ThreadProfiler,
+};
+use crate::code_ranges::{executable_ranges_for_lib, CodeRanges};
+use crate::exception_sampling::ExceptionSampling;
+use crate::thread_filter::ThreadFilter;
 
 pub enum UnwindSectionBytes {
     Remapped(VmSubData),
@@ -74,6 +90,9 @@ impl Deref for MmapSubData {
 
 pub type UnwinderCache = CacheNative<UnwindSectionBytes, MayAllocateDuringUnwind>;
 
+/// See [`TaskProfiler::record_allocations`].
+const ALLOCATION_READ_INTERVAL_NS: u64 = 100_000_000;
+
 pub struct TaskProfiler {
     task: mach_port_t,
     pid: u32,
@@ -84,11 +103,48 @@ pub struct TaskProfiler {
     command_name: String,
     profile_process: ProcessHandle,
     ignored_errors: Vec<SamplingError>,
+    /// The number of times that reading the list of loaded libraries failed.
+    lib_change_error_count: u64,
+    /// Whether the list of loaded libraries has been read successfully. The
+    /// libraries in the first list were loaded before we started observing the
+    /// task, so they don't get "Load library" markers.
+    has_read_lib_list: bool,
+    /// The number of libraries which were added to the profile.
+    lib_count: u64,
     unwinder: UnwinderNative<UnwindSectionBytes, MayAllocateDuringUnwind>,
     default_category: CategoryPairHandle,
+    main_thread: Option<ThreadHandle>,
+    idle_markers: bool,
+    previous_sample_time: Option<Timestamp>,
+    /// The time at which the thread list was last read. Threads which weren't
+    /// in it were created afterwards.
+    thread_list_time: Timestamp,
+    idle_start_time: Option<Timestamp>,
+    dump_registers: bool,
+    stack_scan_words: usize,
+    max_frames: usize,
+    code_ranges: CodeRanges,
+    exception_port: Option<ExceptionPort>,
+    timer_samples: bool,
+    /// The number of threads whose stack was read in the last sample.
+    sampled_thread_count: usize,
+    thread_filter: ThreadFilter,
+    malloc_stack_reader: Option<MallocStackReader>,
+    last_allocation_read_time: Option<Timestamp>,
 }
 
 impl TaskProfiler {
+    /// The `TaskProfiler` takes over the send right to `task`, and deallocates it
+    /// once the task is dead or the `TaskProfiler` is dropped. If creating the
+    /// `TaskProfiler` fails, the send right is deallocated right away.
+    ///
+    /// `task` can come from [`attach_to_pid`], from the preload library of a
+    /// launched process, or from another process which sent it over mach IPC,
+    /// e.g. through a [`TaskPortReceiver`](super::task_port_receiver::TaskPortReceiver).
+    /// The profiler works the same in all cases.
+    ///
+    /// Only the threads which match `thread_filter` are sampled.
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         task: mach_port_t,
         pid: u32,
@@ -96,21 +152,79 @@ impl TaskProfiler {
         command_name: &str,
         profile: &mut Profile,
         default_category: CategoryPairHandle,
+        thread_filter: ThreadFilter,
     ) -> Result<Self, SamplingError> {
-        let thread_acts = get_thread_list(task)?;
+        let threads = match get_thread_list(task) {
+            Ok(threads) => threads,
+            Err(err) => {
+                unsafe { mach_port_deallocate(mach_task_self(), task) };
+                return Err(err);
+            }
+        };
+        let threads = threads
+            .into_iter()
+            .filter_map(|thread_port| {
+                let (tid, _is_libdispatch_thread) = get_thread_id(thread_port.name()).ok()?;
+                Some((thread_port.into_raw(), tid))
+            })
+            .collect();
+        let mut task_profiler = Self::from_parts(
+            task,
+            pid,
+            start_time,
+            command_name,
+            threads,
+            profile,
+            default_category,
+            thread_filter,
+        );
+        // Without an exception port we simply won't get a crash stack.
+        task_profiler.exception_port = ExceptionPort::install(task, &[]).ok();
+        Ok(task_profiler)
+    }
+
+    /// Create a `TaskProfiler` for the given `(thread_act, tid)` pairs, without
+    /// querying the task for its threads and without installing an exception port.
+    ///
+    /// [`TaskProfiler::new`] calls this with the task's current threads. Tests can
+    /// call it with made-up threads and then feed library changes to
+    /// [`TaskProfiler::apply_lib_changes`], without needing a live task.
+    ///
+    /// Threads which don't match `thread_filter` are left out, and their send
+    /// rights are deallocated.
+    #[allow(clippy::too_many_arguments)]
+    pub fn from_parts(
+        task: mach_port_t,
+        pid: u32,
+        start_time: Timestamp,
+        command_name: &str,
+        threads: Vec<(thread_act_t, u32)>,
+        profile: &mut Profile,
+        default_category: CategoryPairHandle,
+        thread_filter: ThreadFilter,
+    ) -> Self {
         let profile_process = profile.add_process(command_name, pid, start_time);
         let mut live_threads = HashMap::new();
-        for (i, thread_act) in thread_acts.into_iter().enumerate() {
-            // Assume that the first thread is the main thread. This seems to hold true in practice.
-            let is_main = i == 0;
-            if let Ok((tid, _is_libdispatch_thread)) = get_thread_id(thread_act) {
-                let profile_thread = profile.add_thread(profile_process, tid, start_time, is_main);
-                let thread =
-                    ThreadProfiler::new(task, tid, profile_thread, thread_act, default_category);
-                live_threads.insert(thread_act, thread);
+        // Thread IDs are assigned in increasing order across the whole system, so
+        // the main thread, which is created first, has the lowest one. The order
+        // in which the task lists its threads is not reliable.
+        let main_tid = threads.iter().map(|(_, tid)| *tid).min();
+        let mut main_thread = None;
+        for (thread_act, tid) in threads {
+            let is_main = Some(tid) == main_tid;
+            if !thread_matches_filter(&thread_filter, thread_act, tid, is_main) {
+                unsafe { mach_port_deallocate(mach_task_self(), thread_act) };
+                continue;
+            }
+            let profile_thread = profile.add_thread(profile_process, tid, start_time, is_main);
+            if is_main {
+                main_thread = Some(profile_thread);
             }
+            let thread =
+                ThreadProfiler::new(task, tid, profile_thread, thread_act, default_category);
+            live_threads.insert(thread_act, thread);
         }
-        Ok(TaskProfiler {
+        TaskProfiler {
             task,
             pid,
             live_threads,
@@ -120,18 +234,193 @@ impl TaskProfiler {
             profile_process,
             executable_lib: None,
             ignored_errors: Vec::new(),
+            lib_change_error_count: 0,
+            has_read_lib_list: false,
+            lib_count: 0,
             unwinder: UnwinderNative::new(),
             default_category,
-        })
+            main_thread,
+            idle_markers: false,
+            previous_sample_time: None,
+            thread_list_time: start_time,
+            idle_start_time: None,
+            dump_registers: false,
+            stack_scan_words: 0,
+            max_frames: DEFAULT_MAX_FRAMES,
+            code_ranges: CodeRanges::default(),
+            exception_port: None,
+            timer_samples: true,
+            sampled_thread_count: 0,
+            thread_filter,
+            malloc_stack_reader: None,
+            last_allocation_read_time: None,
+        }
+    }
+
+    /// Marks the threads which the task had when this profiler was created as
+    /// having unknown start times. This is for tasks which were already running
+    /// when we attached to them. The first threads of a launched task start
+    /// together with the task.
+    pub fn mark_initial_threads_as_preexisting(&self, profile: &mut Profile) {
+        for thread in self.live_threads.values() {
+            profile.set_thread_start_time_known(thread.profile_thread(), false);
+        }
+    }
+
+    /// Whether to record the full register state of every stack walk, for all
+    /// current and future threads of the task. Off by default. The recorded state
+    /// can be retrieved with [`TaskProfiler::write_register_dumps`].
+    pub fn set_dump_registers(&mut self, dump_registers: bool) {
+        self.dump_registers = dump_registers;
+        for thread in self.live_threads.values_mut() {
+            thread.set_dump_registers(dump_registers);
+        }
+    }
+
+    /// Writes the register state which was recorded while register dumping was
+    /// enabled, as text, grouped by thread.
+    pub fn write_register_dumps(&self, w: &mut impl std::io::Write) -> std::io::Result<()> {
+        for thread in self.live_threads.values().chain(&self.dead_threads) {
+            for (timestamp, dump) in thread.register_dumps() {
+                writeln!(
+                    w,
+                    "tid {} at {:?}: pc=0x{:x} sp=0x{:x} fp=0x{:x}",
+                    thread.tid(),
+                    timestamp,
+                    dump.pc,
+                    dump.sp,
+                    dump.fp
+                )?;
+                for (name, value) in dump.raw_state.registers() {
+                    writeln!(w, "    {name:>6} = 0x{value:016x}")?;
+                }
+            }
+        }
+        Ok(())
     }
 
+    /// Enables stack scanning for all current and future threads of the task, see
+    /// [`ThreadProfiler::set_stack_scan_words`]. Off by default.
+    pub fn set_stack_scan_words(&mut self, stack_scan_words: usize) {
+        if stack_scan_words != 0 && self.stack_scan_words == 0 {
+            // Narrow down the code ranges of the libraries which are already
+            // loaded to their executable sections.
+            for lib in self.lib_info_manager.current_libs() {
+                self.code_ranges.remove(lib.base_avma);
+                self.code_ranges.add(
+                    lib.base_avma..(lib.base_avma + lib.vmsize),
+                    executable_ranges_for_dyld_lib(lib),
+                );
+            }
+        }
+        self.stack_scan_words = stack_scan_words;
+        for thread in self.live_threads.values_mut() {
+            thread.set_stack_scan_words(stack_scan_words);
+        }
+    }
+
+    /// Limits the stack depth of all current and future threads of the task,
+    /// see [`ThreadProfiler::set_max_frames`].
+    pub fn set_max_frames(&mut self, max_frames: usize) {
+        self.max_frames = max_frames;
+        for thread in self.live_threads.values_mut() {
+            thread.set_max_frames(max_frames);
+        }
+    }
+
+    /// Whether to add "Idle" markers to the main thread for the time spans
+    /// during which none of the task's threads were running. Off by default.
+    pub fn set_idle_markers(&mut self, idle_markers: bool) {
+        self.idle_markers = idle_markers;
+    }
+
+    #[cfg(test)]
+    pub fn idle_markers(&self) -> bool {
+        self.idle_markers
+    }
+
+    /// Capture a stack every time a thread of the task raises one of the
+    /// exceptions of `exception_sampling`, and, if it asks for it, stop taking
+    /// timer samples. The captured stacks are marked with the exception. Only
+    /// crashes are captured by default.
+    ///
+    /// The exceptions are picked up at the next sample, and the raising thread
+    /// stays blocked until then. The exception is then passed on unchanged, so
+    /// the process handles it as if it hadn't been captured, e.g. with its own
+    /// signal handler. Exceptions for which the task has its own Mach exception
+    /// handler, e.g. a crash reporter, are left to that handler and not captured.
+    pub fn set_exception_sampling(&mut self, exception_sampling: &ExceptionSampling) {
+        self.timer_samples = exception_sampling.timer_samples();
+        if exception_sampling.is_empty() || self.task == MACH_PORT_NULL {
+            return;
+        }
+        // Uninstall the crash-only port first, so that EXC_CRASH is free again
+        // for the new port.
+        self.exception_port = None;
+        match ExceptionPort::install(self.task, &exception_sampling.kinds) {
+            Ok(exception_port) => {
+                let skipped_kinds: Vec<String> = exception_sampling
+                    .kinds
+                    .iter()
+                    .filter(|kind| !exception_port.handles(**kind))
+                    .filter_map(|kind| kind.to_possible_value())
+                    .map(|value| value.get_name().to_owned())
+                    .collect();
+                if !skipped_kinds.is_empty() {
+                    eprintln!(
+                        "Warning: Process \"{}\" [pid: {}] handles these exceptions itself, so they are not captured: {}",
+                        self.command_name,
+                        self.pid,
+                        skipped_kinds.join(", ")
+                    );
+                }
+                self.exception_port = Some(exception_port);
+            }
+            Err(err) => eprintln!(
+                "Warning: Could not capture the exceptions of process \"{}\" [pid: {}]: {}",
+                self.command_name, self.pid, err
+            ),
+        }
+    }
+
+    /// Record every `every_nth` allocation of the task, as allocation samples
+    /// on the main thread which are weighted by the allocation size times
+    /// `every_nth`. Off by default.
+    ///
+    /// This only works if the task was launched with `MallocStackLogging=1`;
+    /// otherwise a warning is printed and no allocations are recorded. The
+    /// allocation log doesn't say which thread allocated, which is why all
+    /// allocations end up on the main thread.
+    pub fn set_allocation_sampling(&mut self, every_nth: u32) {
+        match MallocStackReader::start(self.task, every_nth) {
+            Ok(reader) => self.malloc_stack_reader = Some(reader),
+            Err(err) => eprintln!(
+                "Warning: Could not read the allocations of process \"{}\" [pid: {}]: {}",
+                self.command_name, self.pid, err
+            ),
+        }
+    }
+
+    /// Sample all threads of the task. `now` is the time of this sampling tick.
+    /// Each thread's sample gets the time which `current_time` returns just
+    /// before its stack is read. `weight` is passed on to [`ThreadProfiler::sample`].
     pub fn sample(
         &mut self,
         now: Timestamp,
+        current_time: &dyn Fn() -> Timestamp,
+        weight: i32,
         unwinder_cache: &mut UnwinderCache,
+        on_sample: &mut Option<SampleCallback>,
         profile: &mut Profile,
     ) -> Result<bool, SamplingError> {
-        let result = self.sample_impl(now, unwinder_cache, profile);
+        let result = self.sample_impl(
+            now,
+            current_time,
+            weight,
+            unwinder_cache,
+            on_sample,
+            profile,
+        );
         match result {
             Ok(()) => Ok(true),
             Err(SamplingError::ProcessTerminated(_, _)) => Ok(false),
@@ -156,32 +445,200 @@ impl TaskProfiler {
     fn sample_impl(
         &mut self,
         now: Timestamp,
+        current_time: &dyn Fn() -> Timestamp,
+        weight: i32,
         unwinder_cache: &mut UnwinderCache,
+        on_sample: &mut Option<SampleCallback>,
         profile: &mut Profile,
     ) -> Result<(), SamplingError> {
-        // First, check for any newly-loaded libraries.
-        let changes = self
-            .lib_info_manager
-            .check_for_changes()
-            .unwrap_or_else(|_| Vec::new());
+        // First, check for any newly-loaded libraries. Failing to do so doesn't
+        // stop the sampling, but the failure is reported, because it means that
+        // frames in the missing libraries can't be symbolicated.
+        let changes = match self.lib_info_manager.check_for_changes() {
+            Ok(changes) => {
+                if self.has_read_lib_list {
+                    self.add_lib_change_markers(&changes, now, profile);
+                }
+                self.has_read_lib_list = true;
+                changes
+            }
+            Err(err) => {
+                self.report_lib_change_error(err, now, profile);
+                Vec::new()
+            }
+        };
+        self.apply_lib_changes(changes, profile);
+
+        // If the process is crashing, the crashing thread is blocked until the
+        // exception is dropped at the end of this function. Until then we can
+        // still grab its stack. The same goes for threads which raised one of
+        // the exceptions selected for stack captures. Each thread can have at
+        // most one pending exception.
+        let pending_exceptions: Vec<PendingException> = match &self.exception_port {
+            Some(exception_port) => iter::from_fn(|| exception_port.try_receive()).collect(),
+            None => Vec::new(),
+        };
+        self.sampled_thread_count = 0;
+
+        // Enumerate threads.
+        let thread_list_time = current_time();
+        let thread_ports = get_thread_list(self.task)?;
+        let previously_live_threads: HashSet<_> = self.live_threads.keys().cloned().collect();
+        let mut now_live_threads = HashSet::new();
+        for thread_port in thread_ports {
+            let thread_act = thread_port.name();
+            let mut entry = self.live_threads.entry(thread_act);
+            let thread = match entry {
+                // The ThreadProfiler already holds a send right to this thread,
+                // so thread_port is dropped at the end of this iteration.
+                Entry::Occupied(ref mut entry) => entry.get_mut(),
+                Entry::Vacant(entry) => {
+                    if let Ok((tid, _is_libdispatch_thread)) = get_thread_id(thread_act) {
+                        // Threads which don't match the filter are checked again
+                        // at every sample, because they can be renamed.
+                        if !thread_matches_filter(&self.thread_filter, thread_act, tid, false) {
+                            continue;
+                        }
+                        // The thread was created since the previous thread list.
+                        // This is how the threads which a launched process creates
+                        // right at startup are picked up.
+                        let cpu_time_us = get_thread_cpu_time_since_thread_start(thread_act)
+                            .map_or(0, |(user_time_us, system_time_us)| {
+                                user_time_us + system_time_us
+                            });
+                        let start_time = estimate_thread_start_time(
+                            self.thread_list_time,
+                            current_time(),
+                            cpu_time_us,
+                        );
+                        let profile_thread =
+                            profile.add_thread(self.profile_process, tid, start_time, false);
+                        let mut thread = ThreadProfiler::new(
+                            self.task,
+                            tid,
+                            profile_thread,
+                            thread_port.into_raw(),
+                            self.default_category,
+                        );
+                        thread.set_dump_registers(self.dump_registers);
+                        thread.set_stack_scan_words(self.stack_scan_words);
+                        thread.set_max_frames(self.max_frames);
+                        entry.insert(thread)
+                    } else {
+                        continue;
+                    }
+                }
+            };
+            // Grab a sample from the thread. Its timestamp is taken now rather than
+            // at the start of the tick, so that the time spent checking for library
+            // changes and sampling the other threads doesn't shift it.
+            let sample_time = current_time();
+            let stackwalker =
+                StackwalkerRef::new(&self.unwinder, unwinder_cache, &self.code_ranges);
+            let pending_exception = pending_exceptions
+                .iter()
+                .find(|exception| exception.thread_act() == thread_act);
+            match pending_exception {
+                Some(exception) if exception.is_crash() => {
+                    let crash_info = exception.crash_info();
+                    eprintln!(
+                        "Process \"{}\" [pid: {}] crashed with {} ({}) on thread {}.",
+                        self.command_name,
+                        self.pid,
+                        crash_info.exception_name(),
+                        crash_info.signal_name(),
+                        thread.tid()
+                    );
+                    thread.sample_crash(
+                        stackwalker,
+                        sample_time,
+                        on_sample,
+                        profile,
+                        &crash_info,
+                    )?;
+                    self.sampled_thread_count += 1;
+                    now_live_threads.insert(thread_act);
+                }
+                Some(exception) => {
+                    thread.sample_exception(
+                        stackwalker,
+                        sample_time,
+                        on_sample,
+                        profile,
+                        &exception.crash_info(),
+                    )?;
+                    self.sampled_thread_count += 1;
+                    now_live_threads.insert(thread_act);
+                }
+                None if !self.timer_samples => {
+                    // The thread is still in the task's thread list, so it's alive.
+                    now_live_threads.insert(thread_act);
+                }
+                None => {
+                    let still_alive =
+                        thread.sample(stackwalker, sample_time, weight, on_sample, profile)?;
+                    self.sampled_thread_count += 1;
+                    if still_alive {
+                        now_live_threads.insert(thread_act);
+                    }
+                }
+            }
+        }
+        let dead_threads = previously_live_threads.difference(&now_live_threads);
+        for thread_act in dead_threads {
+            let mut thread = self.live_threads.remove(thread_act).unwrap();
+            thread.notify_dead(now, profile);
+            self.dead_threads.push(thread);
+        }
+
+        self.thread_list_time = thread_list_time;
+
+        if self.idle_markers {
+            self.update_idle_state(profile);
+        }
+        if self.malloc_stack_reader.is_some() {
+            self.record_allocations(now, profile);
+        }
+        self.previous_sample_time = Some(now);
+        Ok(())
+    }
+
+    /// Adds loaded libraries to the unwinder and to the profile, and removes
+    /// unloaded ones from the profile. The first executable which is loaded
+    /// determines the process name.
+    pub fn apply_lib_changes(
+        &mut self,
+        changes: Vec<Modification<DyldInfo>>,
+        profile: &mut Profile,
+    ) {
         for change in changes {
             match change {
                 Modification::Added(mut lib) => {
                     self.add_lib_to_unwinder_and_ensure_debug_id(&mut lib);
+                    // Reading the executable sections from disk is only worth it
+                    // for stack scanning.
+                    let executable_ranges = if self.stack_scan_words != 0 {
+                        executable_ranges_for_dyld_lib(&lib)
+                    } else {
+                        None
+                    };
+                    self.code_ranges.add(
+                        lib.base_avma..(lib.base_avma + lib.vmsize),
+                        executable_ranges,
+                    );
                     let path = Path::new(&lib.file);
                     if self.executable_lib.is_none() && lib.is_executable {
                         self.executable_lib = Some(lib.clone());
-                        self.command_name = path
-                            .components()
-                            .next_back()
-                            .unwrap()
-                            .as_os_str()
-                            .to_string_lossy()
-                            .to_string();
-                        profile.set_process_name(self.profile_process, &self.command_name);
+                        // dyld can report an empty path, e.g. for some daemons. Keep the
+                        // command name in that case rather than showing a blank process name.
+                        if let Some(name) = path.file_name() {
+                            self.command_name = name.to_string_lossy().to_string();
+                            profile.set_process_name(self.profile_process, &self.command_name);
+                        }
                     }
 
                     if let Some(name) = path.file_name() {
+                        self.lib_count += 1;
                         let name = name.to_string_lossy();
                         let path = path.to_string_lossy();
                         profile.add_lib(
@@ -202,50 +659,137 @@ impl TaskProfiler {
                     }
                 }
                 Modification::Removed(lib) => {
+                    self.code_ranges.remove(lib.base_avma);
                     profile.unload_lib(self.profile_process, lib.base_avma);
                 }
             }
         }
+    }
 
-        // Enumerate threads.
-        let thread_acts = get_thread_list(self.task)?;
-        let previously_live_threads: HashSet<_> = self.live_threads.keys().cloned().collect();
-        let mut now_live_threads = HashSet::new();
-        for thread_act in thread_acts {
-            let mut entry = self.live_threads.entry(thread_act);
-            let thread = match entry {
-                Entry::Occupied(ref mut entry) => entry.get_mut(),
-                Entry::Vacant(entry) => {
-                    if let Ok((tid, _is_libdispatch_thread)) = get_thread_id(thread_act) {
-                        let profile_thread =
-                            profile.add_thread(self.profile_process, tid, now, false);
-                        let thread = ThreadProfiler::new(
-                            self.task,
-                            tid,
-                            profile_thread,
-                            thread_act,
-                            self.default_category,
-                        );
-                        entry.insert(thread)
-                    } else {
-                        continue;
-                    }
-                }
+    /// Adds a "Load library" or "Unload library" marker to the main thread for
+    /// each change, at the time at which the change was noticed. The actual
+    /// load or unload happened at some point since the previous sample.
+    fn add_lib_change_markers(
+        &self,
+        changes: &[Modification<DyldInfo>],
+        now: Timestamp,
+        profile: &mut Profile,
+    ) {
+        let main_thread = match self.main_thread {
+            Some(main_thread) => main_thread,
+            None => return,
+        };
+        for change in changes {
+            let (name, lib) = match change {
+                Modification::Added(lib) => ("Load library", lib),
+                Modification::Removed(lib) => ("Unload library", lib),
             };
-            // Grab a sample from the thread.
-            let stackwalker = StackwalkerRef::new(&self.unwinder, unwinder_cache);
-            let still_alive = thread.sample(stackwalker, now, profile)?;
-            if still_alive {
-                now_live_threads.insert(thread_act);
+            profile.add_marker(
+                main_thread,
+                name,
+                LibraryChangeMarker {
+                    path: lib.file.clone(),
+                },
+                MarkerTiming::Instant(now),
+            );
+        }
+    }
+
+    /// Prints the first error, and adds a marker for it to the main thread, so
+    /// that it's visible in the profile why libraries may be missing. Later
+    /// errors are only counted; the count is printed when the task dies.
+    fn report_lib_change_error(&mut self, err: KernelError, now: Timestamp, profile: &mut Profile) {
+        self.lib_change_error_count += 1;
+        if self.lib_change_error_count > 1 {
+            return;
+        }
+        eprintln!(
+            "Warning: Could not read the list of loaded libraries of process \"{}\" [pid: {}]: {}",
+            self.command_name, self.pid, err
+        );
+        if let Some(main_thread) = self.main_thread {
+            profile.add_marker(
+                main_thread,
+                "Library list error",
+                LibraryListErrorMarker(err.to_string()),
+                MarkerTiming::Instant(now),
+            );
+        }
+    }
+
+    /// Keeps track of stretches of time during which no thread was running, and
+    /// adds an "Idle" marker for each such stretch once it has ended.
+    fn update_idle_state(&mut self, profile: &mut Profile) {
+        let previous_sample_time = match self.previous_sample_time {
+            Some(previous_sample_time) => previous_sample_time,
+            None => return,
+        };
+        let is_idle = self
+            .live_threads
+            .values()
+            .all(ThreadProfiler::last_sample_was_idle);
+        if is_idle {
+            // No thread has used any CPU time since the previous sample.
+            self.idle_start_time.get_or_insert(previous_sample_time);
+        } else if let Some(idle_start_time) = self.idle_start_time.take() {
+            self.add_idle_marker(idle_start_time, previous_sample_time, profile);
+        }
+    }
+
+    /// Adds the allocations which were made since the previous read, at most
+    /// once per [`ALLOCATION_READ_INTERVAL_NS`], because every read goes through
+    /// the task's entire allocation log.
+    fn record_allocations(&mut self, now: Timestamp, profile: &mut Profile) {
+        if let Some(last_read_time) = self.last_allocation_read_time {
+            let elapsed = now
+                .nanos_since_reference()
+                .saturating_sub(last_read_time.nanos_since_reference());
+            if elapsed < ALLOCATION_READ_INTERVAL_NS {
+                return;
             }
         }
-        let dead_threads = previously_live_threads.difference(&now_live_threads);
-        for thread_act in dead_threads {
-            let mut thread = self.live_threads.remove(thread_act).unwrap();
-            thread.notify_dead(now, profile);
-            self.dead_threads.push(thread);
+        self.last_allocation_read_time = Some(now);
+        let reader = match &mut self.malloc_stack_reader {
+            Some(reader) => reader,
+            None => return,
+        };
+        let allocations = match reader.read_new_allocations() {
+            Ok(allocations) => allocations,
+            Err(err) => {
+                eprintln!(
+                    "Warning: Could not read the allocations of process \"{}\" [pid: {}], no further allocations will be recorded: {}",
+                    self.command_name, self.pid, err
+                );
+                self.malloc_stack_reader = None;
+                return;
+            }
+        };
+        let main_thread = match self.main_thread {
+            Some(main_thread) => main_thread,
+            None => return,
+        };
+        let every_nth = u64::from(reader.every_nth());
+        let category = self.default_category;
+        for allocation in allocations {
+            let frames = allocation
+                .frames
+                .iter()
+                .rev()
+                .map(|address| (Frame::ReturnAddress(*address), category));
+            let size = i64::try_from(allocation.size.saturating_mul(every_nth)).unwrap_or(i64::MAX);
+            profile.add_allocation_sample(main_thread, now, frames, size);
+        }
+    }
+
+    fn add_idle_marker(&self, start: Timestamp, end: Timestamp, profile: &mut Profile) {
+        if let Some(main_thread) = self.main_thread {
+            profile.add_marker(
+                main_thread,
+                "Idle",
+                IdleMarker,
+                MarkerTiming::Interval(start, end),
+            );
         }
-        Ok(())
     }
 
     fn add_lib_to_unwinder_and_ensure_debug_id(&mut self, lib: &mut DyldInfo) {
@@ -320,14 +864,231 @@ impl TaskProfiler {
         self.unwinder.add_module(module);
     }
 
+    /// The number of threads whose stack was read in the last sample. Unless
+    /// timer samples are off, see [`TaskProfiler::set_exception_sampling`], this
+    /// is the number of threads which were alive at the last sample.
+    pub fn sampled_thread_count(&self) -> usize {
+        self.sampled_thread_count
+    }
+
+    /// The threads which were alive at the last sample, in no particular order.
+    pub fn live_threads(&self) -> impl Iterator<Item = &ThreadProfiler> {
+        self.live_threads.values()
+    }
+
+    /// The main thread, unless it was excluded by the thread filter.
+    pub fn main_thread(&self) -> Option<ThreadHandle> {
+        self.main_thread
+    }
+
+    /// The libraries which were loaded at the last sample, sorted by address.
+    pub fn loaded_libs(&self) -> &[DyldInfo] {
+        self.lib_info_manager.current_libs()
+    }
+
     pub fn notify_dead(&mut self, end_time: Timestamp, profile: &mut Profile) {
+        if let Some(idle_start_time) = self.idle_start_time.take() {
+            self.add_idle_marker(idle_start_time, end_time, profile);
+        }
         for (_, mut thread) in self.live_threads.drain() {
             thread.notify_dead(end_time, profile);
             self.dead_threads.push(thread);
         }
         profile.set_process_end_time(self.profile_process, end_time);
         self.lib_info_manager.unmap_memory();
+        self.release_task_port();
+        if self.lib_change_error_count > 0 {
+            eprintln!(
+                "Warning: Reading the list of loaded libraries of process \"{}\" [pid: {}] failed {} times. {} libraries were detected.",
+                self.command_name, self.pid, self.lib_change_error_count, self.lib_count
+            );
+        }
     }
+
+    /// Dead tasks stay around until the profile is finished, but their port
+    /// isn't needed anymore. The exception port is uninstalled and the
+    /// allocation log reader is stopped first, because both need the task port.
+    fn release_task_port(&mut self) {
+        self.exception_port = None;
+        self.malloc_stack_reader = None;
+        if self.task != MACH_PORT_NULL {
+            unsafe { mach_port_deallocate(mach_task_self(), self.task) };
+            self.task = MACH_PORT_NULL;
+        }
+    }
+}
+
+impl Drop for TaskProfiler {
+    fn drop(&mut self) {
+        self.release_task_port();
+    }
+}
+
+/// Gets the task port of the running process `pid`. The caller owns the
+/// returned send right. samply's own pid and pid 0 are refused, see
+/// [`AttachError::check_pid`].
+pub fn attach_to_pid(pid: u32) -> Result<mach_port_t, AttachError> {
+    AttachError::check_pid(pid)?;
+    let mut task: mach_port_t = MACH_PORT_NULL;
+    unsafe { task_for_pid(mach_task_self(), pid as i32, &mut task) }
+        .into_result()
+        .map_err(|err| AttachError::from_task_for_pid_error(pid, err))?;
+    Ok(task)
+}
+
+/// Estimates when a thread was created which wasn't in the thread list at
+/// `thread_list_time`, but is at `now`. The thread can't be younger than the
+/// CPU time it has used. Threads which have been running since they were
+/// created, e.g. the worker threads that a runtime starts at startup, get
+/// their actual start time.
+fn estimate_thread_start_time(
+    thread_list_time: Timestamp,
+    now: Timestamp,
+    cpu_time_us: u64,
+) -> Timestamp {
+    let now_ns = now.nanos_since_reference();
+    let earliest_ns = thread_list_time.nanos_since_reference().min(now_ns);
+    let start_ns = now_ns.saturating_sub(cpu_time_us.saturating_mul(1000));
+    Timestamp::from_nanos_since_reference(start_ns.max(earliest_ns))
+}
+
+#[test]
+fn test_estimate_thread_start_time() {
+    let us = |us: u64| Timestamp::from_nanos_since_reference(us * 1000);
+    let ms = |ms: u64| us(ms * 1000);
+    // A thread which was busy since it was created.
+    assert_eq!(estimate_thread_start_time(ms(10), ms(11), 400), us(10_600));
+    // An idle thread could have been created at any time since the thread list.
+    assert_eq!(estimate_thread_start_time(ms(10), ms(11), 0), ms(11));
+    // The CPU time of several cores can add up to more than the elapsed time.
+    assert_eq!(estimate_thread_start_time(ms(10), ms(11), 5000), ms(10));
+}
+
+/// Whether the thread matches `thread_filter`. The thread's name is only looked
+/// up if the filter needs it.
+fn thread_matches_filter(
+    thread_filter: &ThreadFilter,
+    thread_act: thread_act_t,
+    tid: u32,
+    is_main_thread: bool,
+) -> bool {
+    let name = if thread_filter.needs_names() {
+        get_thread_name(thread_act).ok().flatten()
+    } else {
+        None
+    };
+    thread_filter.matches(tid, name.as_deref(), is_main_thread)
+}
+
+/// Marks the first time that the list of loaded libraries couldn't be read.
+#[derive(Debug, Clone)]
+struct LibraryListErrorMarker(String);
+
+impl ProfilerMarker for LibraryListErrorMarker {
+    const MARKER_TYPE_NAME: &'static str = "LibraryListError";
+
+    fn schema() -> MarkerSchema {
+        MarkerSchema {
+            type_name: Self::MARKER_TYPE_NAME,
+            locations: vec![MarkerLocation::MarkerChart, MarkerLocation::MarkerTable],
+            chart_label: None,
+            tooltip_label: Some("Could not read the list of loaded libraries: {marker.data.error}"),
+            table_label: Some("{marker.data.error}"),
+            fields: vec![MarkerSchemaField::Dynamic(MarkerDynamicField {
+                key: "error",
+                label: "Error",
+                format: MarkerFieldFormat::String,
+                searchable: None,
+            })],
+        }
+    }
+
+    fn json_marker_data(&self) -> serde_json::Value {
+        serde_json::json!({ "type": Self::MARKER_TYPE_NAME, "error": self.0 })
+    }
+}
+
+/// Marks the time at which a library was found to be loaded or unloaded.
+#[derive(Debug, Clone)]
+struct LibraryChangeMarker {
+    path: String,
+}
+
+impl ProfilerMarker for LibraryChangeMarker {
+    const MARKER_TYPE_NAME: &'static str = "LibraryChange";
+
+    fn schema() -> MarkerSchema {
+        MarkerSchema {
+            type_name: Self::MARKER_TYPE_NAME,
+            locations: vec![MarkerLocation::MarkerChart, MarkerLocation::MarkerTable],
+            chart_label: Some("{marker.data.name}"),
+            tooltip_label: Some("{marker.name}: {marker.data.name}"),
+            table_label: Some("{marker.name}: {marker.data.path}"),
+            fields: vec![
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "name",
+                    label: "Library",
+                    format: MarkerFieldFormat::String,
+                    searchable: Some(true),
+                }),
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "path",
+                    label: "Path",
+                    format: MarkerFieldFormat::FilePath,
+                    searchable: Some(true),
+                }),
+            ],
+        }
+    }
+
+    fn json_marker_data(&self) -> serde_json::Value {
+        let name = Path::new(&self.path)
+            .file_name()
+            .map(|name| name.to_string_lossy());
+        serde_json::json!({
+            "type": Self::MARKER_TYPE_NAME,
+            "name": name,
+            "path": self.path,
+        })
+    }
+}
+
+/// A marker for a time span during which no thread of the task was running.
+#[derive(Debug, Clone)]
+struct IdleMarker;
+
+impl ProfilerMarker for IdleMarker {
+    const MARKER_TYPE_NAME: &'static str = "Idle";
+
+    fn schema() -> MarkerSchema {
+        MarkerSchema {
+            type_name: Self::MARKER_TYPE_NAME,
+            locations: vec![
+                MarkerLocation::MarkerChart,
+                MarkerLocation::MarkerTable,
+                MarkerLocation::TimelineOverview,
+            ],
+            chart_label: None,
+            tooltip_label: Some("No thread was running"),
+            table_label: None,
+            fields: vec![],
+        }
+    }
+
+    fn json_marker_data(&self) -> serde_json::Value {
+        serde_json::json!({ "type": Self::MARKER_TYPE_NAME })
+    }
+}
+
+/// The executable sections of `lib`, read from its file or from the dyld shared
+/// cache, see [`executable_ranges_for_lib`].
+fn executable_ranges_for_dyld_lib(lib: &DyldInfo) -> Option<Vec<Range<u32>>> {
+    let disambiguator = match (lib.debug_id, lib.arch) {
+        (Some(debug_id), _) => Some(MultiArchDisambiguator::DebugId(debug_id)),
+        (None, Some(arch)) => Some(MultiArchDisambiguator::Arch(arch.to_owned())),
+        (None, None) => None,
+    };
+    executable_ranges_for_lib(Path::new(&lib.file), disambiguator)
 }
 
 fn get_debug_frame(file_path: &str) -> Option<UnwindSectionBytes> {
@@ -383,32 +1144,433 @@ fn get_debug_frame(file_path: &str) -> Option<UnwindSectionBytes> {
     }
 }
 
-fn get_thread_list(task: mach_port_t) -> Result<Vec<thread_act_t>, SamplingError> {
-    let mut thread_list: thread_act_port_array_t = std::ptr::null_mut();
-    let mut thread_count: mach_msg_type_number_t = Default::default();
-    unsafe { task_threads(task, &mut thread_list, &mut thread_count) }
+/// A send right to a thread port, as returned by `task_threads`. Every call to
+/// `task_threads` adds a reference to the right, even for threads we already
+/// know about, so it needs to be deallocated unless it's handed off with
+/// [`ThreadPort::into_raw`].
+struct ThreadPort(thread_act_t);
+
+impl ThreadPort {
+    fn name(&self) -> thread_act_t {
+        self.0
+    }
+
+    fn into_raw(self) -> thread_act_t {
+        let thread_act = self.0;
+        mem::forget(self);
+        thread_act
+    }
+}
+
+impl Drop for ThreadPort {
+    fn drop(&mut self) {
+        unsafe { mach_port_deallocate(mach_task_self(), self.0) };
+    }
+}
+
+/// The array which `task_threads` allocates in our address space.
+struct ThreadPortArray {
+    thread_list: thread_act_port_array_t,
+    thread_count: mach_msg_type_number_t,
+}
+
+impl ThreadPortArray {
+    /// Takes over the send rights in the array.
+    fn into_thread_ports(self) -> Vec<ThreadPort> {
+        if self.thread_list.is_null() {
+            return Vec::new();
+        }
+        unsafe { std::slice::from_raw_parts(self.thread_list, self.thread_count as usize) }
+            .iter()
+            .map(|thread_act| ThreadPort(*thread_act))
+            .collect()
+    }
+}
+
+impl Drop for ThreadPortArray {
+    fn drop(&mut self) {
+        if self.thread_list.is_null() {
+            return;
+        }
+        let _ = unsafe {
+            mach_vm_deallocate(
+                mach_task_self(),
+                self.thread_list as usize as mach_vm_address_t,
+                (self.thread_count as usize * mem::size_of::<thread_act_t>()) as mach_vm_size_t,
+            )
+        };
+    }
+}
+
+fn get_thread_list(task: mach_port_t) -> Result<Vec<ThreadPort>, SamplingError> {
+    let mut array = ThreadPortArray {
+        thread_list: std::ptr::null_mut(),
+        thread_count: Default::default(),
+    };
+    retry_if_transient(|| {
+        unsafe { task_threads(task, &mut array.thread_list, &mut array.thread_count) }.into_result()
+    })
+    .map_err(|err| match err {
+        KernelError::InvalidArgument
+        | KernelError::MachSendInvalidDest
+        | KernelError::Terminated => {
+            SamplingError::ProcessTerminated("task_threads in get_thread_list", err)
+        }
+        err => SamplingError::Ignorable("task_threads in get_thread_list", err),
+    })?;
+
+    Ok(array.into_thread_ports())
+}
+
+#[test]
+fn test_preexisting_threads() {
+    use fxprof_processed_profile::{CategoryColor, ReferenceTimestamp, SamplingInterval};
+
+    let start_time_known = |profile: &Profile| -> Vec<serde_json::Value> {
+        let json = serde_json::to_value(profile).unwrap();
+        json["threads"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|thread| thread["startTimeKnown"].clone())
+            .collect()
+    };
+    let mut profile = Profile::new(
+        "test",
+        ReferenceTimestamp::from_millis_since_unix_epoch(0.0),
+        SamplingInterval::from_millis(1),
+    );
+    let default_category =
+        CategoryPairHandle::from(profile.add_category("Regular", CategoryColor::Blue));
+    let pid = std::process::id();
+    let mut task: mach_port_t = MACH_PORT_NULL;
+    unsafe { task_for_pid(mach_task_self(), pid as i32, &mut task) }
         .into_result()
-        .map_err(|err| match err {
-            KernelError::InvalidArgument
-            | KernelError::MachSendInvalidDest
-            | KernelError::Terminated => {
-                SamplingError::ProcessTerminated("task_threads in get_thread_list", err)
-            }
-            err => SamplingError::Ignorable("task_threads in get_thread_list", err),
-        })?;
+        .unwrap();
+    let task_profiler = TaskProfiler::new(
+        task,
+        pid,
+        Timestamp::from_millis_since_reference(0.0),
+        "test",
+        &mut profile,
+        default_category,
+        ThreadFilter::default(),
+    )
+    .unwrap();
+
+    // Like in a launched task, the threads started with the task.
+    let before = start_time_known(&profile);
+    assert!(!before.is_empty());
+    assert!(before.iter().all(|known| known.is_null()));
+
+    // In a task which we attached to, they were already running.
+    task_profiler.mark_initial_threads_as_preexisting(&mut profile);
+    let after = start_time_known(&profile);
+    assert!(after.iter().all(|known| *known == serde_json::json!(false)));
+}
+
+#[test]
+fn test_task_port_is_released() {
+    use fxprof_processed_profile::{CategoryColor, ReferenceTimestamp, SamplingInterval};
+    use mach::kern_return::kern_return_t;
+    use mach::port::{mach_port_name_t, mach_port_right_t, MACH_PORT_RIGHT_SEND};
+
+    extern "C" {
+        fn mach_port_get_refs(
+            task: mach_port_t,
+            name: mach_port_name_t,
+            right: mach_port_right_t,
+            refs: *mut u32,
+        ) -> kern_return_t;
+    }
+    let send_refs = |task: mach_port_t| {
+        let mut refs = 0;
+        unsafe { mach_port_get_refs(mach_task_self(), task, MACH_PORT_RIGHT_SEND, &mut refs) }
+            .into_result()
+            .unwrap();
+        refs
+    };
 
-    let thread_acts =
-        unsafe { std::slice::from_raw_parts(thread_list, thread_count as usize) }.to_owned();
+    let mut profile = Profile::new(
+        "test",
+        ReferenceTimestamp::from_millis_since_unix_epoch(0.0),
+        SamplingInterval::from_millis(1),
+    );
+    let default_category =
+        CategoryPairHandle::from(profile.add_category("Regular", CategoryColor::Blue));
+    let start_time = Timestamp::from_millis_since_reference(0.0);
+    let pid = std::process::id();
 
-    unsafe {
-        mach_vm_deallocate(
-            mach_task_self(),
-            thread_list as usize as mach_vm_address_t,
-            (thread_count as usize * mem::size_of::<thread_act_t>()) as mach_vm_size_t,
-        )
+    // Every task_for_pid call adds a reference to the send right for our own task.
+    let refs_before = send_refs(unsafe { mach_task_self() });
+    for i in 0..100 {
+        let mut task: mach_port_t = MACH_PORT_NULL;
+        unsafe { task_for_pid(mach_task_self(), pid as i32, &mut task) }
+            .into_result()
+            .unwrap();
+        let mut task_profiler = TaskProfiler::from_parts(
+            task,
+            pid,
+            start_time,
+            "test",
+            vec![],
+            &mut profile,
+            default_category,
+            ThreadFilter::default(),
+        );
+        if i % 2 == 0 {
+            task_profiler.notify_dead(start_time, &mut profile);
+        }
     }
-    .into_result()
-    .map_err(|err| SamplingError::Fatal("mach_vm_deallocate in get_thread_list", err))?;
+    assert_eq!(send_refs(unsafe { mach_task_self() }), refs_before);
+}
+
+#[test]
+fn test_threads_created_after_first_sample() {
+    use fxprof_processed_profile::{CategoryColor, ReferenceTimestamp, SamplingInterval};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Barrier};
+    use std::time::Instant;
 
-    Ok(thread_acts)
+    const WORKER_COUNT: usize = 8;
+
+    let mut profile = Profile::new(
+        "test",
+        ReferenceTimestamp::from_millis_since_unix_epoch(0.0),
+        SamplingInterval::from_millis(1),
+    );
+    let default_category =
+        CategoryPairHandle::from(profile.add_category("Regular", CategoryColor::Blue));
+    let reference = Instant::now();
+    let current_time =
+        || Timestamp::from_nanos_since_reference(reference.elapsed().as_nanos() as u64);
+    let pid = std::process::id();
+    let mut task: mach_port_t = MACH_PORT_NULL;
+    unsafe { task_for_pid(mach_task_self(), pid as i32, &mut task) }
+        .into_result()
+        .unwrap();
+    // Only the workers are sampled, so that the test thread never suspends itself.
+    let thread_filter = ThreadFilter {
+        names: vec!["early-worker".to_string()],
+        tids: Vec::new(),
+        exclude_main_thread: true,
+    };
+    let mut task_profiler = TaskProfiler::from_parts(
+        task,
+        pid,
+        current_time(),
+        "test",
+        vec![],
+        &mut profile,
+        default_category,
+        thread_filter,
+    );
+    let mut unwinder_cache = UnwinderCache::default();
+    let mut sample = |task_profiler: &mut TaskProfiler, profile: &mut Profile| {
+        let now = current_time();
+        assert!(task_profiler
+            .sample(
+                now,
+                &current_time,
+                1,
+                &mut unwinder_cache,
+                &mut None,
+                profile
+            )
+            .unwrap());
+        now
+    };
+
+    // Like a launched process, whose runtime creates its threads right after
+    // the first sample.
+    sample(&mut task_profiler, &mut profile);
+    assert_eq!(task_profiler.live_threads().count(), 0);
+    let spawn_time = current_time();
+    let stop = Arc::new(AtomicBool::new(false));
+    let running = Arc::new(Barrier::new(WORKER_COUNT + 1));
+    let workers: Vec<_> = (0..WORKER_COUNT)
+        .map(|i| {
+            let stop = stop.clone();
+            let running = running.clone();
+            std::thread::Builder::new()
+                .name(format!("early-worker-{}", i))
+                .spawn(move || {
+                    running.wait();
+                    while !stop.load(Ordering::Relaxed) {
+                        std::hint::spin_loop();
+                    }
+                })
+                .unwrap()
+        })
+        .collect();
+    running.wait();
+    let second_sample_time = sample(&mut task_profiler, &mut profile);
+    let worker_count = task_profiler.live_threads().count();
+    stop.store(true, Ordering::Relaxed);
+    for worker in workers {
+        worker.join().unwrap();
+    }
+    assert_eq!(worker_count, WORKER_COUNT);
+    task_profiler.notify_dead(current_time(), &mut profile);
+
+    let json = serde_json::to_value(&profile).unwrap();
+    let threads = json["threads"].as_array().unwrap();
+    assert_eq!(threads.len(), WORKER_COUNT);
+    let to_ms = |time: Timestamp| time.nanos_since_reference() as f64 / 1_000_000.0;
+    for thread in threads {
+        let register_time = thread["registerTime"].as_f64().unwrap();
+        assert!(register_time >= to_ms(spawn_time), "{}", register_time);
+        assert!(
+            register_time <= to_ms(second_sample_time),
+            "{}",
+            register_time
+        );
+        assert_eq!(thread["startTimeKnown"], serde_json::Value::Null);
+    }
+}
+
+#[cfg(test)]
+fn dyld_info(file: &str, base_avma: u64, is_executable: bool) -> DyldInfo {
+    use super::proc_maps::UnwindSectionInfo;
+
+    DyldInfo {
+        is_executable,
+        file: file.to_string(),
+        base_avma,
+        vmsize: 0x1000,
+        svma_info: framehop::ModuleSvmaInfo {
+            base_svma: 0,
+            text: None,
+            text_env: None,
+            stubs: None,
+            stub_helper: None,
+            eh_frame: None,
+            eh_frame_hdr: None,
+            got: None,
+        },
+        debug_id: Some(DebugId::nil()),
+        arch: Some("arm64"),
+        unwind_sections: UnwindSectionInfo {
+            unwind_info_section: None,
+            eh_frame_section: None,
+            text_segment: None,
+        },
+    }
+}
+
+#[test]
+fn test_apply_lib_changes_without_task() {
+    use fxprof_processed_profile::{CategoryColor, ReferenceTimestamp, SamplingInterval};
+
+    let mut profile = Profile::new(
+        "test",
+        ReferenceTimestamp::from_millis_since_unix_epoch(0.0),
+        SamplingInterval::from_millis(1),
+    );
+    let default_category =
+        CategoryPairHandle::from(profile.add_category("Regular", CategoryColor::Blue));
+    let start_time = Timestamp::from_millis_since_reference(0.0);
+    let mut task_profiler = TaskProfiler::from_parts(
+        mach::port::MACH_PORT_NULL,
+        123,
+        start_time,
+        "launcher",
+        vec![(2, 1002), (1, 1001)],
+        &mut profile,
+        default_category,
+        ThreadFilter::default(),
+    );
+    task_profiler.apply_lib_changes(
+        vec![
+            Modification::Added(dyld_info("/nonexistent/bin/myapp", 0x10000, true)),
+            Modification::Added(dyld_info("/nonexistent/lib/libfoo.dylib", 0x20000, false)),
+            Modification::Added(dyld_info("/nonexistent/bin/other", 0x30000, true)),
+        ],
+        &mut profile,
+    );
+
+    let json = serde_json::to_value(&profile).unwrap();
+    let lib_names: Vec<_> = json["libs"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|lib| lib["name"].as_str().unwrap())
+        .collect();
+    assert_eq!(lib_names, ["myapp", "libfoo.dylib", "other"]);
+    // Only the first executable determines the process name.
+    assert_eq!(json["threads"][0]["processName"], "myapp");
+    // The thread with the lowest tid is the main thread, regardless of the
+    // order in which the threads were passed.
+    assert_eq!(json["threads"][0]["tid"], 1001);
+    assert_eq!(json["threads"][0]["isMainThread"], true);
+    assert_eq!(json["threads"][1]["tid"], 1002);
+    assert_eq!(json["threads"][1]["isMainThread"], false);
+}
+
+#[test]
+fn test_lib_change_markers() {
+    use fxprof_processed_profile::{CategoryColor, ReferenceTimestamp, SamplingInterval};
+
+    let mut profile = Profile::new(
+        "test",
+        ReferenceTimestamp::from_millis_since_unix_epoch(0.0),
+        SamplingInterval::from_millis(1),
+    );
+    let default_category =
+        CategoryPairHandle::from(profile.add_category("Regular", CategoryColor::Blue));
+    let task_profiler = TaskProfiler::from_parts(
+        mach::port::MACH_PORT_NULL,
+        123,
+        Timestamp::from_millis_since_reference(0.0),
+        "myapp",
+        vec![(1, 1001)],
+        &mut profile,
+        default_category,
+        ThreadFilter::default(),
+    );
+    task_profiler.add_lib_change_markers(
+        &[Modification::Added(dyld_info(
+            "/nonexistent/lib/libplugin.dylib",
+            0x20000,
+            false,
+        ))],
+        Timestamp::from_millis_since_reference(5.0),
+        &mut profile,
+    );
+    task_profiler.add_lib_change_markers(
+        &[Modification::Removed(dyld_info(
+            "/nonexistent/lib/libplugin.dylib",
+            0x20000,
+            false,
+        ))],
+        Timestamp::from_millis_since_reference(8.0),
+        &mut profile,
+    );
+
+    let json = serde_json::to_value(&profile).unwrap();
+    let thread = &json["threads"][0];
+    let string_array = thread["stringArray"].as_array().unwrap();
+    let marker_names: Vec<_> = thread["markers"]["name"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|index| {
+            string_array[index.as_u64().unwrap() as usize]
+                .as_str()
+                .unwrap()
+        })
+        .collect();
+    assert_eq!(marker_names, ["Load library", "Unload library"]);
+    assert_eq!(
+        thread["markers"]["startTime"],
+        serde_json::json!([5.0, 8.0])
+    );
+    assert_eq!(
+        thread["markers"]["data"][0],
+        serde_json::json!({
+            "type": "LibraryChange",
+            "name": "libplugin.dylib",
+            "path": "/nonexistent/lib/libplugin.dylib",
+        })
+    );
 }
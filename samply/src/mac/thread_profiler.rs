@@ -1,15 +1,24 @@
 use framehop::FrameAddress;
 use fxprof_processed_profile::{
-    CategoryPairHandle, CpuDelta, Frame, Profile, StringHandle, ThreadHandle, Timestamp,
+    CategoryPairHandle, CpuDelta, Frame, MarkerDynamicField, MarkerFieldFormat, MarkerLocation,
+    MarkerSchema, MarkerSchemaField, MarkerTiming, Profile, ProfilerMarker, StringHandle,
+    ThreadHandle, Timestamp,
 };
+use mach::mach_port::mach_port_deallocate;
 use mach::mach_types::thread_act_t;
-use mach::port::mach_port_t;
+use mach::port::{mach_port_t, MACH_PORT_NULL};
+use mach::traps::mach_task_self;
 
+use std::io::Write;
 use std::mem;
+use std::panic::{self, AssertUnwindSafe};
 
 use super::error::SamplingError;
-use super::kernel_error::{self, IntoResult, KernelError};
-use super::proc_maps::{get_backtrace, ForeignMemory, StackwalkerRef};
+use super::exception_port::CrashInfo;
+use super::kernel_error::{self, retry_if_transient, IntoResult, KernelError};
+use super::proc_maps::{
+    get_backtrace, ForeignMemory, RegisterDump, StackwalkerRef, DEFAULT_MAX_FRAMES,
+};
 use super::thread_act::thread_info;
 use super::thread_info::time_value;
 use super::thread_info::{
@@ -18,6 +27,32 @@ use super::thread_info::{
     THREAD_EXTENDED_INFO_COUNT, THREAD_IDENTIFIER_INFO, THREAD_IDENTIFIER_INFO_COUNT,
 };
 
+/// Called for every sample with a fresh stack walk, with the thread's tid and
+/// its stack, innermost frame first. The addresses are not symbolicated. The
+/// callback runs on the sampling thread, so it should return quickly, for
+/// example by sending the stack into a channel.
+pub type SampleCallback = Box<dyn FnMut(u32, &[FrameAddress]) + Send>;
+
+/// A [`SampleCallback`] which writes every stack to `w` as one line, with the
+/// tid followed by the addresses, innermost frame first. Writing stops after
+/// the first error.
+pub fn stack_log_callback(mut w: impl Write + Send + 'static) -> SampleCallback {
+    let mut failed = false;
+    Box::new(move |tid, stack| {
+        if failed {
+            return;
+        }
+        let mut line = tid.to_string();
+        for frame in stack {
+            line.push_str(&format!(" 0x{:x}", frame.address()));
+        }
+        if let Err(err) = writeln!(w, "{}", line) {
+            eprintln!("Couldn't write to the stack log: {}", err);
+            failed = true;
+        }
+    })
+}
+
 pub struct ThreadProfiler {
     thread_act: thread_act_t,
     name: Option<String>,
@@ -27,11 +62,20 @@ pub struct ThreadProfiler {
     tick_count: usize,
     stack_memory: ForeignMemory,
     previous_sample_cpu_time_us: u64,
+    previous_sample_system_time_us: u64,
     ignored_errors: Vec<SamplingError>,
     default_category: CategoryPairHandle,
+    truncated_stack_count: u64,
+    last_sample_was_idle: bool,
+    dump_registers: bool,
+    register_dumps: Vec<(Timestamp, RegisterDump)>,
+    stack_scan_words: usize,
+    max_frames: usize,
 }
 
 impl ThreadProfiler {
+    /// The `ThreadProfiler` takes over the send right to `thread_act`, and
+    /// deallocates it once the thread is dead or the `ThreadProfiler` is dropped.
     pub fn new(
         task: mach_port_t,
         tid: u32,
@@ -48,18 +92,29 @@ impl ThreadProfiler {
             tick_count: 0,
             stack_memory: ForeignMemory::new(task),
             previous_sample_cpu_time_us: 0,
+            previous_sample_system_time_us: 0,
             ignored_errors: Vec::new(),
             default_category,
+            truncated_stack_count: 0,
+            last_sample_was_idle: false,
+            dump_registers: false,
+            register_dumps: Vec::new(),
+            stack_scan_words: 0,
+            max_frames: DEFAULT_MAX_FRAMES,
         }
     }
 
+    /// Take a sample. `weight` is the number of sampling intervals which this
+    /// sample stands for; it is larger than 1 while the sampler is throttled.
     pub fn sample(
         &mut self,
         stackwalker: StackwalkerRef,
         now: Timestamp,
+        weight: i32,
+        on_sample: &mut Option<SampleCallback>,
         profile: &mut Profile,
     ) -> Result<bool, SamplingError> {
-        let result = self.sample_impl(stackwalker, now, profile);
+        let result = self.sample_impl(stackwalker, now, weight, on_sample, profile);
         match result {
             Ok(()) => Ok(true),
             Err(SamplingError::ThreadTerminated(_, _)) => Ok(false),
@@ -86,37 +141,73 @@ impl ThreadProfiler {
         &mut self,
         stackwalker: StackwalkerRef,
         now: Timestamp,
+        weight: i32,
+        on_sample: &mut Option<SampleCallback>,
         profile: &mut Profile,
     ) -> Result<(), SamplingError> {
         self.tick_count += 1;
 
-        if self.name.is_none() && self.tick_count % 10 == 1 {
-            self.name = get_thread_name(self.thread_act)?;
-            if let Some(name) = &self.name {
-                profile.set_thread_name(self.profile_thread, name);
+        // Threads can be renamed at any time, e.g. by thread pools which name
+        // their workers after the work they pick up, so keep checking the name.
+        // The profile gets the most recent non-empty name.
+        if self.tick_count % 10 == 1 {
+            if let Some(name) = get_thread_name(self.thread_act)? {
+                if self.name.as_ref() != Some(&name) {
+                    profile.set_thread_name(self.profile_thread, &name);
+                    self.name = Some(name);
+                }
             }
         }
 
-        let cpu_time_us = get_thread_cpu_time_since_thread_start(self.thread_act)?;
-        let cpu_time_us = cpu_time_us.0 + cpu_time_us.1;
+        let (user_time_us, system_time_us) =
+            get_thread_cpu_time_since_thread_start(self.thread_act)?;
+        let cpu_time_us = user_time_us + system_time_us;
         let cpu_delta_us = cpu_time_us - self.previous_sample_cpu_time_us;
+        let system_delta_us = system_time_us.saturating_sub(self.previous_sample_system_time_us);
         let cpu_delta = CpuDelta::from_micros(cpu_delta_us);
+        self.last_sample_was_idle = cpu_delta.is_zero();
 
-        if !cpu_delta.is_zero() || self.tick_count == 0 {
+        // The first sample always walks the stack, because there is no previous
+        // stack to repeat.
+        if !cpu_delta.is_zero() || self.tick_count == 1 {
             self.stack_scratch_space.clear();
-            get_backtrace(
+            let backtrace = get_backtrace(
                 stackwalker,
                 &mut self.stack_memory,
                 self.thread_act,
                 &mut self.stack_scratch_space,
+                self.dump_registers,
+                self.stack_scan_words,
+                self.max_frames,
             )?;
+            if let Some(register_dump) = backtrace.register_dump {
+                self.register_dumps.push((now, register_dump));
+            }
 
+            let in_kernel = was_mostly_in_kernel(cpu_delta_us, system_delta_us);
+            let kernel_root = kernel_root(profile, in_kernel, self.default_category);
+            let root = stack_root(
+                profile,
+                backtrace.is_incomplete,
+                backtrace.is_depth_limited,
+                backtrace.scanned_frame_count,
+                self.default_category,
+            );
             let frames = StackDepthLimitingFrameIter::new(
                 profile,
                 &self.stack_scratch_space,
                 self.default_category,
             );
-            profile.add_sample(self.profile_thread, now, frames, cpu_delta, 1);
+            if frames.is_truncated() || backtrace.is_depth_limited {
+                self.truncated_stack_count += 1;
+                profile.set_thread_truncated_stack_count(
+                    self.profile_thread,
+                    self.truncated_stack_count,
+                );
+            }
+            let frames = kernel_root.into_iter().chain(root).chain(frames);
+            profile.add_sample(self.profile_thread, now, frames, cpu_delta, weight);
+            call_sample_callback(on_sample, self.tid, &self.stack_scratch_space);
         } else {
             // No CPU time elapsed since just before the last time we grabbed a stack.
             // Assume that the thread has done literally zero work and could not have changed
@@ -133,17 +224,271 @@ impl ThreadProfiler {
             //     - query cpu time, notice it is still the same as A
             //     - add_sample_same_stack with stack from previous sample
             //
-            profile.add_sample_same_stack_zero_cpu(self.profile_thread, now, 1);
+            profile.add_sample_same_stack_zero_cpu(self.profile_thread, now, weight);
         }
 
         self.previous_sample_cpu_time_us = cpu_time_us;
+        self.previous_sample_system_time_us = system_time_us;
+
+        Ok(())
+    }
+
+    /// Grab one final stack from this thread, which is blocked in the delivery of
+    /// a crash exception, and mark it as the crash point.
+    pub fn sample_crash(
+        &mut self,
+        stackwalker: StackwalkerRef,
+        now: Timestamp,
+        on_sample: &mut Option<SampleCallback>,
+        profile: &mut Profile,
+        crash_info: &CrashInfo,
+    ) -> Result<(), SamplingError> {
+        self.add_exception_sample(stackwalker, now, on_sample, profile)?;
+        profile.add_marker(
+            self.profile_thread,
+            "Crash",
+            CrashMarker(*crash_info),
+            MarkerTiming::Instant(now),
+        );
+        Ok(())
+    }
+
+    /// Grab the stack of this thread, which is blocked in the delivery of one of
+    /// the exceptions that were selected for stack captures, and mark it with
+    /// the exception. The thread continues once the exception has been passed on.
+    pub fn sample_exception(
+        &mut self,
+        stackwalker: StackwalkerRef,
+        now: Timestamp,
+        on_sample: &mut Option<SampleCallback>,
+        profile: &mut Profile,
+        exception_info: &CrashInfo,
+    ) -> Result<(), SamplingError> {
+        self.add_exception_sample(stackwalker, now, on_sample, profile)?;
+        profile.add_marker(
+            self.profile_thread,
+            exception_info.exception_name(),
+            ExceptionMarker(*exception_info),
+            MarkerTiming::Instant(now),
+        );
+        Ok(())
+    }
+
+    /// Adds a sample with the stack of this thread, which is blocked in the
+    /// delivery of an exception. The sample doesn't stand for any CPU time.
+    fn add_exception_sample(
+        &mut self,
+        stackwalker: StackwalkerRef,
+        now: Timestamp,
+        on_sample: &mut Option<SampleCallback>,
+        profile: &mut Profile,
+    ) -> Result<(), SamplingError> {
+        self.stack_scratch_space.clear();
+        let backtrace = get_backtrace(
+            stackwalker,
+            &mut self.stack_memory,
+            self.thread_act,
+            &mut self.stack_scratch_space,
+            self.dump_registers,
+            self.stack_scan_words,
+            self.max_frames,
+        )?;
+        if let Some(register_dump) = backtrace.register_dump {
+            self.register_dumps.push((now, register_dump));
+        }
 
+        let root = stack_root(
+            profile,
+            backtrace.is_incomplete,
+            backtrace.is_depth_limited,
+            backtrace.scanned_frame_count,
+            self.default_category,
+        );
+        let frames = StackDepthLimitingFrameIter::new(
+            profile,
+            &self.stack_scratch_space,
+            self.default_category,
+        );
+        let frames = root.into_iter().chain(frames);
+        profile.add_sample(self.profile_thread, now, frames, CpuDelta::ZERO, 1);
+        call_sample_callback(on_sample, self.tid, &self.stack_scratch_space);
         Ok(())
     }
 
+    /// Whether to record the full register state for every sample with a stack
+    /// walk. This is only meant for debugging unwinding problems, and is off by
+    /// default because of the amount of data it collects.
+    pub fn set_dump_registers(&mut self, dump_registers: bool) {
+        self.dump_registers = dump_registers;
+    }
+
+    /// When a stack walk finds no more than a leaf frame and its caller, e.g. in
+    /// code without frame pointers or unwind info, scan up to `stack_scan_words`
+    /// words of the stack for return addresses into loaded libraries. The frames
+    /// found this way are put under a "(scanned stack)" root. 0 disables
+    /// scanning, which is the default.
+    pub fn set_stack_scan_words(&mut self, stack_scan_words: usize) {
+        self.stack_scan_words = stack_scan_words;
+    }
+
+    /// Stop walking the stack after `max_frames` frames. Deeper stacks, e.g.
+    /// from runaway recursion, keep their innermost frames and are put under a
+    /// "(truncated stack)" root, and they are counted as truncated stacks of
+    /// the thread. Defaults to [`DEFAULT_MAX_FRAMES`].
+    pub fn set_max_frames(&mut self, max_frames: usize) {
+        self.max_frames = max_frames;
+    }
+
+    /// The register state recorded for each sample while register dumping was
+    /// enabled, see [`ThreadProfiler::set_dump_registers`].
+    pub fn register_dumps(&self) -> &[(Timestamp, RegisterDump)] {
+        &self.register_dumps
+    }
+
+    /// Whether the thread used no CPU time between the previous sample and the
+    /// most recent sample.
+    pub fn last_sample_was_idle(&self) -> bool {
+        self.last_sample_was_idle
+    }
+
+    /// The most recent non-empty name of the thread, if it has been looked up yet.
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    pub fn tid(&self) -> u32 {
+        self.tid
+    }
+
+    pub fn profile_thread(&self) -> ThreadHandle {
+        self.profile_thread
+    }
+
     pub fn notify_dead(&mut self, end_time: Timestamp, profile: &mut Profile) {
         profile.set_thread_end_time(self.profile_thread, end_time);
         self.stack_memory.clear();
+        self.release_thread_port();
+    }
+
+    /// Dead threads stay around until the profile is finished, but their port
+    /// isn't needed anymore.
+    fn release_thread_port(&mut self) {
+        if self.thread_act != MACH_PORT_NULL {
+            unsafe { mach_port_deallocate(mach_task_self(), self.thread_act) };
+            self.thread_act = MACH_PORT_NULL;
+        }
+    }
+}
+
+impl Drop for ThreadProfiler {
+    fn drop(&mut self) {
+        self.release_thread_port();
+    }
+}
+
+/// Marks the sample which was taken from a thread while it was crashing.
+#[derive(Debug, Clone)]
+struct CrashMarker(CrashInfo);
+
+impl ProfilerMarker for CrashMarker {
+    const MARKER_TYPE_NAME: &'static str = "Crash";
+
+    fn schema() -> MarkerSchema {
+        MarkerSchema {
+            type_name: Self::MARKER_TYPE_NAME,
+            locations: vec![
+                MarkerLocation::MarkerChart,
+                MarkerLocation::MarkerTable,
+                MarkerLocation::TimelineOverview,
+            ],
+            chart_label: Some("{marker.data.exception}"),
+            tooltip_label: Some("Crash: {marker.data.exception} ({marker.data.signal})"),
+            table_label: Some("{marker.data.exception} ({marker.data.signal})"),
+            fields: vec![
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "exception",
+                    label: "Exception",
+                    format: MarkerFieldFormat::String,
+                    searchable: Some(true),
+                }),
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "signal",
+                    label: "Signal",
+                    format: MarkerFieldFormat::String,
+                    searchable: Some(true),
+                }),
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "code",
+                    label: "Code",
+                    format: MarkerFieldFormat::String,
+                    searchable: None,
+                }),
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "subcode",
+                    label: "Subcode",
+                    format: MarkerFieldFormat::String,
+                    searchable: None,
+                }),
+            ],
+        }
+    }
+
+    fn json_marker_data(&self) -> serde_json::Value {
+        serde_json::json!({
+            "type": Self::MARKER_TYPE_NAME,
+            "exception": self.0.exception_name(),
+            "signal": self.0.signal_name(),
+            "code": format!("0x{:x}", self.0.code),
+            "subcode": format!("0x{:x}", self.0.subcode),
+        })
+    }
+}
+
+/// Marks a sample which was taken from a thread while it was blocked in one of
+/// the exceptions that were selected for stack captures.
+#[derive(Debug, Clone)]
+struct ExceptionMarker(CrashInfo);
+
+impl ProfilerMarker for ExceptionMarker {
+    const MARKER_TYPE_NAME: &'static str = "Exception";
+
+    fn schema() -> MarkerSchema {
+        MarkerSchema {
+            type_name: Self::MARKER_TYPE_NAME,
+            locations: vec![MarkerLocation::MarkerChart, MarkerLocation::MarkerTable],
+            chart_label: Some("{marker.data.exception}"),
+            tooltip_label: Some("{marker.data.exception} ({marker.data.code})"),
+            table_label: Some("{marker.data.exception} ({marker.data.code})"),
+            fields: vec![
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "exception",
+                    label: "Exception",
+                    format: MarkerFieldFormat::String,
+                    searchable: Some(true),
+                }),
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "code",
+                    label: "Code",
+                    format: MarkerFieldFormat::String,
+                    searchable: None,
+                }),
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "subcode",
+                    label: "Subcode",
+                    format: MarkerFieldFormat::String,
+                    searchable: None,
+                }),
+            ],
+        }
+    }
+
+    fn json_marker_data(&self) -> serde_json::Value {
+        serde_json::json!({
+            "type": Self::MARKER_TYPE_NAME,
+            "exception": self.0.exception_name(),
+            "code": format!("0x{:x}", self.0.code),
+            "subcode": format!("0x{:x}", self.0.subcode),
+        })
     }
 }
 
@@ -179,6 +524,132 @@ fn test_should_elide_frames() {
     assert_eq!(should_elide_frames::<100>(450), Some((100, 300)));
 }
 
+/// The root frame for a stack whose walk stopped early, see
+/// [`Backtrace::is_incomplete`](super::proc_maps::Backtrace::is_incomplete). It
+/// groups such samples in the call tree, instead of letting whichever frame the
+/// walk stopped at look like a root function.
+fn incomplete_stack_root(
+    profile: &mut Profile,
+    is_incomplete: bool,
+    category: CategoryPairHandle,
+) -> Option<(Frame, CategoryPairHandle)> {
+    if !is_incomplete {
+        return None;
+    }
+    let label = profile.intern_string("(incomplete stack)");
+    Some((Frame::Label(label), category))
+}
+
+/// The root frame for a stack which was extended by scanning the stack, which
+/// marks the frames between this root and the first unwound frame as uncertain.
+/// For a stack whose walk reached the frame limit, a "(truncated stack)" root,
+/// because its root end is missing. Otherwise, the [`incomplete_stack_root`],
+/// if the walk stopped early.
+fn stack_root(
+    profile: &mut Profile,
+    is_incomplete: bool,
+    is_depth_limited: bool,
+    scanned_frame_count: usize,
+    category: CategoryPairHandle,
+) -> Option<(Frame, CategoryPairHandle)> {
+    if is_depth_limited {
+        let label = profile.intern_string("(truncated stack)");
+        return Some((Frame::Label(label), category));
+    }
+    if scanned_frame_count == 0 {
+        return incomplete_stack_root(profile, is_incomplete, category);
+    }
+    let label = profile.intern_string("(scanned stack)");
+    Some((Frame::Label(label), category))
+}
+
+/// A panic in the callback is not allowed to stop the recording. The callback is
+/// dropped instead, so that it isn't called in a broken state again.
+fn call_sample_callback(on_sample: &mut Option<SampleCallback>, tid: u32, stack: &[FrameAddress]) {
+    if let Some(callback) = on_sample {
+        if panic::catch_unwind(AssertUnwindSafe(|| callback(tid, stack))).is_err() {
+            eprintln!("The sample callback panicked, it won't be called again.");
+            *on_sample = None;
+        }
+    }
+}
+
+#[test]
+fn test_call_sample_callback() {
+    use std::sync::{Arc, Mutex};
+
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let seen_clone = seen.clone();
+    let mut on_sample: Option<SampleCallback> = Some(Box::new(move |tid, stack| {
+        seen_clone.lock().unwrap().push((tid, stack.len()));
+        assert!(tid != 2, "callback failure");
+    }));
+    let stack = [
+        FrameAddress::InstructionPointer(0x1000),
+        FrameAddress::from_return_address(0x2000).unwrap(),
+    ];
+    call_sample_callback(&mut on_sample, 1, &stack);
+    assert!(on_sample.is_some());
+
+    // A panicking callback is dropped, and sampling goes on.
+    call_sample_callback(&mut on_sample, 2, &stack);
+    assert!(on_sample.is_none());
+    call_sample_callback(&mut on_sample, 3, &stack);
+    assert_eq!(*seen.lock().unwrap(), vec![(1, 2), (2, 2)]);
+}
+
+#[test]
+fn test_stack_log_callback() {
+    let log_file = tempfile::NamedTempFile::new().unwrap();
+    let mut on_sample = stack_log_callback(log_file.reopen().unwrap());
+    on_sample(
+        5,
+        &[
+            FrameAddress::InstructionPointer(0x1000),
+            FrameAddress::from_return_address(0x2004).unwrap(),
+        ],
+    );
+    on_sample(6, &[]);
+    drop(on_sample);
+    assert_eq!(
+        std::fs::read_to_string(log_file.path()).unwrap(),
+        "5 0x1000 0x2004\n6\n"
+    );
+}
+
+/// Whether a thread spent most of the CPU time since the previous sample in
+/// the kernel, e.g. in system calls or page faults. This is only a coarse
+/// indicator: the user-space stack of such a sample usually ends in a system
+/// call wrapper, but the thread may just as well have been in user space at
+/// the moment it was sampled.
+fn was_mostly_in_kernel(cpu_delta_us: u64, system_delta_us: u64) -> bool {
+    cpu_delta_us != 0 && system_delta_us * 2 > cpu_delta_us
+}
+
+#[test]
+fn test_was_mostly_in_kernel() {
+    assert!(!was_mostly_in_kernel(0, 0));
+    assert!(!was_mostly_in_kernel(1000, 0));
+    assert!(!was_mostly_in_kernel(1000, 500));
+    assert!(was_mostly_in_kernel(1000, 501));
+    assert!(was_mostly_in_kernel(1000, 1000));
+}
+
+/// The root frame for samples which were mostly spent in the kernel, see
+/// [`was_mostly_in_kernel`]. It separates syscall-bound from CPU-bound work
+/// in the call tree.
+fn kernel_root(
+    profile: &mut Profile,
+    in_kernel: bool,
+    category: CategoryPairHandle,
+) -> Option<(Frame, CategoryPairHandle)> {
+    if !in_kernel {
+        return None;
+    }
+    let label = profile.intern_string("[kernel]");
+    Some((Frame::Label(label), category))
+}
+
 struct StackDepthLimitingFrameIter<'a> {
     frames: &'a [FrameAddress],
     category: CategoryPairHandle,
@@ -234,6 +705,14 @@ impl<'a> StackDepthLimitingFrameIter<'a> {
             state,
         }
     }
+
+    /// Whether part of the stack is elided.
+    pub fn is_truncated(&self) -> bool {
+        matches!(
+            self.state,
+            StackDepthLimitingFrameIterState::BeforeElidedPiece { .. }
+        )
+    }
 }
 
 impl<'a> Iterator for StackDepthLimitingFrameIter<'a> {
@@ -307,19 +786,21 @@ pub fn get_thread_id(thread_act: thread_act_t) -> kernel_error::Result<(u32, boo
     Ok((identifier_info_data.thread_id as u32, is_libdispatch_thread))
 }
 
-fn get_thread_name(thread_act: thread_act_t) -> Result<Option<String>, SamplingError> {
+pub fn get_thread_name(thread_act: thread_act_t) -> Result<Option<String>, SamplingError> {
     // Get the thread name.
     let mut extended_info_data: thread_extended_info_data_t = unsafe { mem::zeroed() };
     let mut count = THREAD_EXTENDED_INFO_COUNT;
-    unsafe {
-        thread_info(
-            thread_act,
-            THREAD_EXTENDED_INFO,
-            &mut extended_info_data as *mut _ as thread_info_t,
-            &mut count,
-        )
-    }
-    .into_result()
+    retry_if_transient(|| {
+        unsafe {
+            thread_info(
+                thread_act,
+                THREAD_EXTENDED_INFO,
+                &mut extended_info_data as *mut _ as thread_info_t,
+                &mut count,
+            )
+        }
+        .into_result()
+    })
     .map_err(|err| match err {
         KernelError::InvalidArgument
         | KernelError::MachSendInvalidDest
@@ -341,21 +822,24 @@ fn get_thread_name(thread_act: thread_act_t) -> Result<Option<String>, SamplingE
     Ok(if name.is_empty() { None } else { Some(name) })
 }
 
-// (user time, system time) in microseconds
-fn get_thread_cpu_time_since_thread_start(
+/// Returns the user and system CPU time, in microseconds, which the thread has
+/// used since it was created.
+pub fn get_thread_cpu_time_since_thread_start(
     thread_act: thread_act_t,
 ) -> Result<(u64, u64), SamplingError> {
     let mut basic_info_data: thread_basic_info_data_t = unsafe { mem::zeroed() };
     let mut count = THREAD_BASIC_INFO_COUNT;
-    unsafe {
-        thread_info(
-            thread_act,
-            THREAD_BASIC_INFO,
-            &mut basic_info_data as *mut _ as thread_info_t,
-            &mut count,
-        )
-    }
-    .into_result()
+    retry_if_transient(|| {
+        unsafe {
+            thread_info(
+                thread_act,
+                THREAD_BASIC_INFO,
+                &mut basic_info_data as *mut _ as thread_info_t,
+                &mut count,
+            )
+        }
+        .into_result()
+    })
     .map_err(|err| match err {
         KernelError::InvalidArgument
         | KernelError::MachSendInvalidDest
@@ -4,9 +4,22 @@ mod mac;
 #[cfg(target_os = "linux")]
 mod linux;
 
+#[cfg(target_os = "windows")]
+mod windows;
+
+#[cfg(any(target_os = "macos", target_os = "windows"))]
+mod code_ranges;
+#[cfg(any(target_os = "macos", target_os = "linux", target_os = "windows"))]
+mod exception_sampling;
 mod import;
 mod linux_shared;
+#[cfg(any(target_os = "macos", target_os = "linux", target_os = "windows"))]
+mod profile_output;
+#[cfg(any(target_os = "macos", target_os = "linux", target_os = "windows"))]
+mod recording_options;
 mod server;
+#[cfg(any(target_os = "macos", target_os = "linux", target_os = "windows"))]
+mod thread_filter;
 
 use clap::{Args, Parser, Subcommand};
 use tempfile::NamedTempFile;
@@ -23,6 +36,8 @@ pub use mac::{kernel_error, thread_act, thread_info};
 use linux::profiler;
 #[cfg(target_os = "macos")]
 use mac::profiler;
+#[cfg(target_os = "windows")]
+use windows::profiler;
 
 use server::{start_server_main, PortSelection, ServerProps};
 
@@ -32,7 +47,7 @@ use server::{start_server_main, PortSelection, ServerProps};
     about = r#"
 samply is a sampling CPU profiler.
 Run a command, record a CPU profile of its execution, and open the profiler UI.
-Recording is currently supported on Linux and macOS.
+Recording is currently supported on Linux, macOS and Windows.
 On other platforms, samply can only load existing profiles.
 
 EXAMPLES:
@@ -54,9 +69,13 @@ enum Action {
     /// Load a profile from a file and display it.
     Load(LoadArgs),
 
-    #[cfg(any(target_os = "macos", target_os = "linux"))]
+    #[cfg(any(target_os = "macos", target_os = "linux", target_os = "windows"))]
     /// Record a profile and display it.
-    Record(RecordArgs),
+    Record(Box<RecordArgs>),
+
+    #[cfg(target_os = "macos")]
+    /// Print the current stack of every thread of a running process, like sample(1).
+    Stacks(StacksArgs),
 }
 
 #[derive(Debug, Args)]
@@ -68,7 +87,14 @@ struct LoadArgs {
     server_args: ServerArgs,
 }
 
-#[cfg(any(target_os = "macos", target_os = "linux"))]
+#[cfg(target_os = "macos")]
+#[derive(Debug, Args)]
+struct StacksArgs {
+    /// The pid of the process. This requires root privileges, like `samply record --pid`.
+    pid: u32,
+}
+
+#[cfg(any(target_os = "macos", target_os = "linux", target_os = "windows"))]
 #[derive(Debug, Args)]
 struct RecordArgs {
     /// Do not run a local server after recording.
@@ -83,15 +109,146 @@ struct RecordArgs {
     #[arg(short, long)]
     duration: Option<f64>,
 
+    /// Stop recording after this many samples, counted across all threads
+    /// and processes. Only supported on macOS.
+    #[arg(long)]
+    max_samples: Option<u64>,
+
+    /// Only keep the samples of the most recent number of seconds, so that the
+    /// recording can run for as long as needed with bounded memory, e.g. until
+    /// an intermittent stall has happened. The most recent seconds are saved
+    /// when the recording ends, and whenever samply receives SIGUSR1. Only
+    /// supported on macOS.
+    #[arg(long, value_name = "SECONDS")]
+    ring_buffer: Option<f64>,
+
+    /// Stop walking a stack after this many frames, e.g. for runaway recursion.
+    /// Deeper stacks keep their innermost frames and are put under a
+    /// "(truncated stack)" root. The default is 1024. Only supported on macOS.
+    #[arg(long, value_name = "N")]
+    max_frames: Option<usize>,
+
+    /// When a stack walk finds no more than a leaf frame and its caller, e.g. in
+    /// code without frame pointers or unwind information, scan up to this many
+    /// words of the stack for return addresses into loaded libraries. The frames
+    /// found this way are put under a "(scanned stack)" root, since they may be
+    /// wrong. Off by default. Only supported on macOS.
+    #[arg(long, value_name = "WORDS", default_value = "0")]
+    stack_scan_words: usize,
+
+    /// Write the register state of every stack walk to this file when the
+    /// recording ends, for debugging wrong stacks. Only supported on macOS.
+    #[arg(long, value_name = "FILE")]
+    dump_registers: Option<PathBuf>,
+
+    /// While recording, write every freshly walked stack to this file as one
+    /// line with the tid and the unsymbolicated addresses, innermost frame
+    /// first, e.g. to watch a recording with `tail -f`. Only supported on
+    /// macOS.
+    #[arg(long, value_name = "FILE")]
+    live_stacks: Option<PathBuf>,
+
     /// Output filename.
     #[arg(short, long, default_value = "profile.json")]
     output: PathBuf,
 
+    /// Save the profile into this directory instead, with a file name generated
+    /// from --name-template. The directory is created if it doesn't exist.
+    #[arg(long, conflicts_with = "output")]
+    output_dir: Option<PathBuf>,
+
+    /// The file name template used with --output-dir. `{pid}` is replaced with
+    /// the pid of the launched process and `{date}` with the UTC launch time.
+    /// Profiles are gzip-compressed if the name ends in `.gz`.
+    #[arg(long, requires = "output_dir", default_value = profile_output::DEFAULT_NAME_TEMPLATE)]
+    name_template: String,
+
+    /// Add "Idle" markers to the main thread for the time spans during which
+    /// no thread of the process was running. Only supported on macOS.
+    #[arg(long)]
+    idle_markers: bool,
+
+    /// Add "Sampling" markers to a separate "samply" thread for the time that
+    /// each sample took, to show how much time the profiler itself spends
+    /// reading the recorded processes. Only supported on macOS.
+    #[arg(long)]
+    overhead_markers: bool,
+
+    /// Also record the allocations of the launched command, weighted by their
+    /// size, so that the call tree can show where memory was allocated. The
+    /// command is launched with MallocStackLogging=1, which slows it down. Only
+    /// supported on macOS.
+    #[arg(long, conflicts_with_all = ["pids", "process_names", "task_port_service"])]
+    allocations: bool,
+
+    /// With --allocations, only record every Nth allocation, which then counts
+    /// N times.
+    #[arg(long, value_name = "N", requires = "allocations", default_value = "1")]
+    allocation_interval: u32,
+
+    /// Also capture a stack every time a recorded thread raises one of these
+    /// exceptions, e.g. a trap instruction or an exceeded resource limit, and
+    /// mark it with the exception. The exception is then passed on to the
+    /// process unchanged. Crashes are always captured. Exceptions which the
+    /// process handles itself, e.g. with a crash reporter, are not captured.
+    /// Only supported on macOS.
+    #[arg(long, value_name = "KIND", value_enum, value_delimiter = ',')]
+    exception_samples: Vec<exception_sampling::ExceptionKind>,
+
+    /// Only capture the stacks of --exception-samples, and don't sample the
+    /// threads on a timer.
+    #[arg(long, requires = "exception_samples")]
+    exception_samples_only: bool,
+
+    /// Only record the threads whose name contains this string. Can be given
+    /// multiple times. The main thread is always recorded, unless
+    /// --exclude-main-thread is given. Only supported on macOS.
+    #[arg(long = "thread-name", value_name = "NAME")]
+    thread_names: Vec<String>,
+
+    /// Only record the thread with this thread id, in addition to the threads
+    /// selected by --thread-name. Can be given multiple times. Only supported
+    /// on macOS.
+    #[arg(long = "tid", value_name = "TID")]
+    tids: Vec<u32>,
+
+    /// Don't record the main thread if it isn't selected by --thread-name or
+    /// --tid.
+    #[arg(long)]
+    exclude_main_thread: bool,
+
     #[command(flatten)]
     server_args: ServerArgs,
 
+    /// Record the already running process with this pid instead of launching a
+    /// command. Can be given multiple times to record several processes into
+    /// one profile. Requires root privileges. Only supported on macOS.
+    #[arg(long = "pid", value_name = "PID", conflicts_with = "command")]
+    pids: Vec<u32>,
+
+    /// Record the already running process whose executable has this name, like
+    /// --pid. Fails if no process or more than one process has this name; in the
+    /// latter case, the matching processes are listed. Only supported on macOS.
+    #[arg(long = "process-name", value_name = "NAME", conflicts_with = "command")]
+    process_names: Vec<String>,
+
+    /// Register a mach service with this name, wait until another process sends
+    /// the task port of a running process to it, and record that process. This
+    /// lets a privileged helper obtain the task port, so that samply doesn't
+    /// need root privileges. Only supported on macOS.
+    #[arg(
+        long,
+        value_name = "NAME",
+        conflicts_with_all = ["command", "pids", "process_names"]
+    )]
+    task_port_service: Option<String>,
+
     /// Profile the execution of this command.
-    #[arg(required = true, allow_hyphen_values = true, trailing_var_arg = true)]
+    #[arg(
+        required_unless_present_any = ["pids", "process_names", "task_port_service"],
+        allow_hyphen_values = true,
+        trailing_var_arg = true
+    )]
     command: Vec<std::ffi::OsString>,
 }
 
@@ -129,8 +286,23 @@ fn main() {
             start_server_main(filename, load_args.server_args.server_props());
         }
 
-        #[cfg(any(target_os = "macos", target_os = "linux"))]
+        #[cfg(target_os = "macos")]
+        Action::Stacks(stacks_args) => {
+            use mac::sample_once::{sample_once, write_stacks};
+
+            let stacks = match sample_once(stacks_args.pid) {
+                Ok(stacks) => stacks,
+                Err(err) => {
+                    eprintln!("Error: {}", err);
+                    std::process::exit(1);
+                }
+            };
+            write_stacks(&mut std::io::stdout().lock(), &stacks).expect("couldn't write stacks");
+        }
+
+        #[cfg(any(target_os = "macos", target_os = "linux", target_os = "windows"))]
         Action::Record(record_args) => {
+            use profile_output::ProfileOutput;
             use std::time::Duration;
 
             let server_props = if record_args.save_only {
@@ -140,6 +312,7 @@ fn main() {
             };
 
             let time_limit = record_args.duration.map(Duration::from_secs_f64);
+            let ring_buffer = record_args.ring_buffer.map(Duration::from_secs_f64);
             if record_args.rate <= 0.0 {
                 eprintln!(
                     "Error: sampling rate must be greater than zero, got {}",
@@ -148,12 +321,83 @@ fn main() {
                 std::process::exit(1);
             }
             let interval = Duration::from_secs_f64(1.0 / record_args.rate);
+            let allocation_sampling = record_args
+                .allocations
+                .then_some(record_args.allocation_interval);
+            let output = match record_args.output_dir {
+                Some(dir) => ProfileOutput::Directory {
+                    dir,
+                    name_template: record_args.name_template,
+                },
+                None => ProfileOutput::File(record_args.output),
+            };
+            let options = recording_options::RecordingOptions {
+                time_limit,
+                sample_limit: record_args.max_samples,
+                ring_buffer,
+                max_frames: record_args.max_frames,
+                stack_scan_words: record_args.stack_scan_words,
+                register_dump_path: record_args.dump_registers,
+                live_stacks_path: record_args.live_stacks,
+                interval,
+                idle_markers: record_args.idle_markers,
+                overhead_markers: record_args.overhead_markers,
+                allocation_sampling,
+                exception_sampling: exception_sampling::ExceptionSampling {
+                    kinds: record_args.exception_samples,
+                    exceptions_only: record_args.exception_samples_only,
+                },
+                thread_filter: thread_filter::ThreadFilter {
+                    names: record_args.thread_names,
+                    tids: record_args.tids,
+                    exclude_main_thread: record_args.exclude_main_thread,
+                },
+            };
+            if !record_args.pids.is_empty()
+                || !record_args.process_names.is_empty()
+                || record_args.task_port_service.is_some()
+            {
+                #[cfg(target_os = "macos")]
+                let result = if let Some(service_name) = &record_args.task_port_service {
+                    profiler::start_recording_task_from_service(
+                        &output,
+                        service_name,
+                        &options,
+                        server_props,
+                    )
+                    .map_err(|err| err.to_string())
+                } else {
+                    match mac::process_list::pids_for_process_names(&record_args.process_names) {
+                        Ok(named_pids) => {
+                            let mut pids = record_args.pids;
+                            pids.extend(named_pids);
+                            profiler::start_recording_pids(&output, &pids, &options, server_props)
+                                .map_err(|err| err.to_string())
+                        }
+                        Err(err) => Err(err.to_string()),
+                    }
+                };
+                #[cfg(target_os = "linux")]
+                let result: Result<(), String> = Err(
+                    "--pid, --process-name and --task-port-service are not supported on Linux yet."
+                        .to_string(),
+                );
+                #[cfg(target_os = "windows")]
+                let result: Result<(), String> = Err(
+                    "--pid, --process-name and --task-port-service are not supported on Windows yet."
+                        .to_string(),
+                );
+                if let Err(err) = result {
+                    eprintln!("Error: {}", err);
+                    std::process::exit(1);
+                }
+                return;
+            }
             let exit_status = match profiler::start_recording(
-                &record_args.output,
+                &output,
                 record_args.command[0].clone(),
                 &record_args.command[1..],
-                time_limit,
-                interval,
+                &options,
                 server_props,
             ) {
                 Ok(exit_status) => exit_status,
@@ -221,4 +465,49 @@ fn verify_cli() {
         matches!(opt.action, Action::Record(record_args) if record_args.command == ["rustup"] && record_args.server_args.no_open),
         "Arguments which come before the command name should be treated as samply arguments."
     );
+
+    #[cfg(any(target_os = "macos", target_os = "linux", target_os = "windows"))]
+    {
+        let opt = Opt::parse_from(["samply", "record", "--output-dir", "profiles", "rustup"]);
+        assert!(
+            matches!(opt.action, Action::Record(record_args) if record_args.output_dir.as_deref() == Some(Path::new("profiles")) && record_args.name_template == profile_output::DEFAULT_NAME_TEMPLATE)
+        );
+
+        assert!(Opt::try_parse_from([
+            "samply",
+            "record",
+            "-o",
+            "out.json",
+            "--output-dir",
+            "profiles",
+            "rustup"
+        ])
+        .is_err());
+        assert!(Opt::try_parse_from([
+            "samply",
+            "record",
+            "--name-template",
+            "{pid}.json",
+            "rustup"
+        ])
+        .is_err());
+
+        let opt = Opt::parse_from([
+            "samply",
+            "record",
+            "--task-port-service",
+            "org.example.samply-helper",
+        ]);
+        assert!(
+            matches!(opt.action, Action::Record(record_args) if record_args.task_port_service.as_deref() == Some("org.example.samply-helper") && record_args.command.is_empty())
+        );
+        assert!(Opt::try_parse_from([
+            "samply",
+            "record",
+            "--task-port-service",
+            "org.example.samply-helper",
+            "rustup"
+        ])
+        .is_err());
+    }
 }
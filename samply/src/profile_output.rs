@@ -0,0 +1,117 @@
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use fxprof_processed_profile::Profile;
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The default template for [`ProfileOutput::Directory`].
+pub const DEFAULT_NAME_TEMPLATE: &str = "profile-{pid}-{date}.json.gz";
+
+/// Where a recorded profile should be saved.
+#[derive(Debug, Clone)]
+pub enum ProfileOutput {
+    /// Save the profile to this file.
+    File(PathBuf),
+    /// Save the profile into this directory, with a file name generated from
+    /// the template. The template can contain the placeholders `{pid}`, for the
+    /// pid of the launched process, and `{date}`, for the UTC time at which the
+    /// process was launched, formatted as `YYYYMMDD-HHMMSS`.
+    Directory { dir: PathBuf, name_template: String },
+}
+
+impl ProfileOutput {
+    /// Returns the path of the profile file for the process with the given pid,
+    /// which was launched at `launch_time`.
+    pub fn path_for_process(&self, pid: u32, launch_time: SystemTime) -> PathBuf {
+        match self {
+            ProfileOutput::File(path) => path.clone(),
+            ProfileOutput::Directory { dir, name_template } => {
+                let name = name_template
+                    .replace("{pid}", &pid.to_string())
+                    .replace("{date}", &format_utc_date(launch_time));
+                dir.join(name)
+            }
+        }
+    }
+}
+
+/// Writes the profile as JSON. If the path ends in `.gz`, the JSON is gzip-compressed.
+pub fn save_profile(profile: &Profile, path: &Path) -> std::io::Result<()> {
+    if let Some(dir) = path.parent() {
+        if !dir.as_os_str().is_empty() {
+            std::fs::create_dir_all(dir)?;
+        }
+    }
+    let writer = BufWriter::new(File::create(path)?);
+    if path.extension() == Some("gz".as_ref()) {
+        let mut encoder = GzEncoder::new(writer, Compression::default());
+        serde_json::to_writer(&mut encoder, profile)?;
+        encoder.finish()?.flush()
+    } else {
+        let mut writer = writer;
+        serde_json::to_writer(&mut writer, profile)?;
+        writer.flush()
+    }
+}
+
+/// Formats the time as `YYYYMMDD-HHMMSS`, in UTC.
+fn format_utc_date(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs());
+    let (days, secs_of_day) = (secs / 86400, secs % 86400);
+    let (year, month, day) = civil_from_days(days as i64);
+    format!(
+        "{:04}{:02}{:02}-{:02}{:02}{:02}",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60
+    )
+}
+
+/// Converts days since 1970-01-01 into a (year, month, day) date in the
+/// proleptic Gregorian calendar. This is Howard Hinnant's `civil_from_days`.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[test]
+fn test_path_for_process() {
+    use std::time::Duration;
+
+    // 2022-11-06T01:30:32Z
+    let launch_time = UNIX_EPOCH + Duration::from_secs(1667698232);
+    let output = ProfileOutput::Directory {
+        dir: PathBuf::from("profiles"),
+        name_template: DEFAULT_NAME_TEMPLATE.to_string(),
+    };
+    assert_eq!(
+        output.path_for_process(1234, launch_time),
+        Path::new("profiles/profile-1234-20221106-013032.json.gz")
+    );
+    assert_eq!(
+        ProfileOutput::File(PathBuf::from("out.json")).path_for_process(1234, launch_time),
+        Path::new("out.json")
+    );
+    assert_eq!(format_utc_date(UNIX_EPOCH), "19700101-000000");
+    assert_eq!(
+        format_utc_date(UNIX_EPOCH + Duration::from_secs(951782400)),
+        "20000229-000000"
+    );
+}
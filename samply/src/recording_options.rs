@@ -0,0 +1,93 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::exception_sampling::ExceptionSampling;
+use crate::thread_filter::ThreadFilter;
+
+/// What `samply record` records, independent of which processes are recorded
+/// and where the profile goes. Built once from the command line arguments and
+/// passed to the platform's profiler.
+#[derive(Debug, Clone, Default)]
+pub struct RecordingOptions {
+    /// Stop recording after this long.
+    pub time_limit: Option<Duration>,
+    /// Stop recording after this many samples.
+    pub sample_limit: Option<u64>,
+    /// Only keep the samples of the most recent `ring_buffer` duration.
+    pub ring_buffer: Option<Duration>,
+    /// Stop walking stacks after this many frames.
+    pub max_frames: Option<usize>,
+    /// Scan up to this many stack words when a stack walk is implausibly short.
+    pub stack_scan_words: usize,
+    /// Write the register state of every stack walk to this file.
+    pub register_dump_path: Option<PathBuf>,
+    /// Write the stacks to this file while they're recorded.
+    pub live_stacks_path: Option<PathBuf>,
+    /// The time between two samples.
+    pub interval: Duration,
+    pub idle_markers: bool,
+    pub overhead_markers: bool,
+    /// Record every nth allocation of the launched processes.
+    pub allocation_sampling: Option<u32>,
+    pub exception_sampling: ExceptionSampling,
+    pub thread_filter: ThreadFilter,
+}
+
+impl RecordingOptions {
+    /// Prints a warning for every option which is set, but which the profiler
+    /// for `platform`, e.g. "Linux", doesn't support yet.
+    #[cfg_attr(target_os = "macos", allow(dead_code))]
+    pub fn warn_unsupported(&self, platform: &str) {
+        for option in self.unsupported_options() {
+            eprintln!("Warning: {option} is not supported on {platform} yet and will be ignored.");
+        }
+    }
+
+    /// The command line flags of the options which are set, other than the
+    /// duration and the sampling rate.
+    #[cfg_attr(target_os = "macos", allow(dead_code))]
+    fn unsupported_options(&self) -> Vec<&'static str> {
+        let options = [
+            ("--max-samples", self.sample_limit.is_some()),
+            ("--ring-buffer", self.ring_buffer.is_some()),
+            ("--max-frames", self.max_frames.is_some()),
+            ("--stack-scan-words", self.stack_scan_words != 0),
+            ("--dump-registers", self.register_dump_path.is_some()),
+            ("--live-stacks", self.live_stacks_path.is_some()),
+            ("--idle-markers", self.idle_markers),
+            ("--overhead-markers", self.overhead_markers),
+            ("--allocations", self.allocation_sampling.is_some()),
+            ("--exception-samples", !self.exception_sampling.is_empty()),
+            ("--thread-name", !self.thread_filter.names.is_empty()),
+            ("--tid", !self.thread_filter.tids.is_empty()),
+        ];
+        options
+            .iter()
+            .filter_map(|&(option, is_set)| is_set.then_some(option))
+            .collect()
+    }
+}
+
+#[test]
+fn test_unsupported_options() {
+    let options = RecordingOptions {
+        time_limit: Some(Duration::from_secs(5)),
+        interval: Duration::from_millis(1),
+        ..Default::default()
+    };
+    assert!(options.unsupported_options().is_empty());
+
+    let options = RecordingOptions {
+        stack_scan_words: 64,
+        idle_markers: true,
+        thread_filter: ThreadFilter {
+            tids: vec![42],
+            ..Default::default()
+        },
+        ..options
+    };
+    assert_eq!(
+        options.unsupported_options(),
+        vec!["--stack-scan-words", "--idle-markers", "--tid"]
+    );
+}
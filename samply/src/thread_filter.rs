@@ -0,0 +1,64 @@
+// Only the macOS sampler applies the filter so far.
+#![cfg_attr(not(target_os = "macos"), allow(dead_code))]
+
+/// Restricts a recording to some of the threads of the profiled processes,
+/// e.g. to the worker pool of a server with hundreds of threads. Threads which
+/// don't match are neither sampled nor added to the profile.
+#[derive(Debug, Clone, Default)]
+pub struct ThreadFilter {
+    /// Threads whose name contains one of these strings are recorded. Names are
+    /// checked again while a thread doesn't match, so a thread which is renamed
+    /// later is recorded from then on.
+    pub names: Vec<String>,
+    /// Threads with one of these thread ids are recorded.
+    pub tids: Vec<u32>,
+    /// The main thread is recorded even if it doesn't match, unless this is set.
+    pub exclude_main_thread: bool,
+}
+
+impl ThreadFilter {
+    /// Whether all threads are recorded.
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty() && self.tids.is_empty()
+    }
+
+    /// Whether [`ThreadFilter::matches`] needs the thread's name.
+    pub fn needs_names(&self) -> bool {
+        !self.names.is_empty()
+    }
+
+    /// Whether to record the thread with the given tid. `name` is `None` if the
+    /// thread doesn't have a name, or if the name wasn't looked up because
+    /// [`ThreadFilter::needs_names`] is false.
+    pub fn matches(&self, tid: u32, name: Option<&str>, is_main_thread: bool) -> bool {
+        if self.is_empty() || (is_main_thread && !self.exclude_main_thread) {
+            return true;
+        }
+        let name_matches = |name: &str| self.names.iter().any(|part| name.contains(part.as_str()));
+        self.tids.contains(&tid) || name.is_some_and(name_matches)
+    }
+}
+
+#[test]
+fn test_thread_filter() {
+    let all = ThreadFilter::default();
+    assert!(all.matches(5, None, false));
+
+    let workers = ThreadFilter {
+        names: vec!["worker".to_string()],
+        tids: vec![42],
+        exclude_main_thread: false,
+    };
+    assert!(workers.matches(5, Some("tokio-worker-3"), false));
+    assert!(workers.matches(42, None, false));
+    assert!(!workers.matches(5, Some("io"), false));
+    assert!(!workers.matches(5, None, false));
+    assert!(workers.matches(1, Some("main"), true));
+
+    let only_workers = ThreadFilter {
+        exclude_main_thread: true,
+        ..workers
+    };
+    assert!(!only_workers.matches(1, Some("main"), true));
+    assert!(only_workers.matches(1, Some("worker"), true));
+}
@@ -0,0 +1,15 @@
+use thiserror::Error;
+
+use std::io;
+
+#[derive(Debug, Error)]
+pub enum SamplingError {
+    #[error("Could not launch the process: {0}")]
+    CouldNotLaunchProcess(io::Error),
+
+    #[error("Could not open process {0} for sampling: {1}")]
+    CouldNotAttach(u32, io::Error),
+
+    #[error("Could not enumerate the threads of process {0}: {1}")]
+    ThreadEnumerationFailed(u32, io::Error),
+}
@@ -0,0 +1,59 @@
+use std::ffi::OsString;
+use std::process::{Command, ExitStatus};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::SystemTime;
+
+use super::error::SamplingError;
+use super::sampler::ProcessSampler;
+use crate::profile_output::{save_profile, ProfileOutput};
+use crate::recording_options::RecordingOptions;
+use crate::server::{start_server_main, ServerProps};
+
+pub fn start_recording(
+    output: &ProfileOutput,
+    command_name: OsString,
+    command_args: &[OsString],
+    options: &RecordingOptions,
+    server_props: Option<ServerProps>,
+) -> Result<ExitStatus, SamplingError> {
+    options.warn_unsupported("Windows");
+
+    let launch_time = SystemTime::now();
+    let mut root_child = Command::new(&command_name)
+        .args(command_args)
+        .spawn()
+        .map_err(SamplingError::CouldNotLaunchProcess)?;
+    let pid = root_child.id();
+
+    // Only the launched process is sampled, not the processes it launches.
+    let (time_limit, interval) = (options.time_limit, options.interval);
+    let stop_flag = Arc::new(AtomicBool::new(false));
+    let sampler = match ProcessSampler::new(&command_name.to_string_lossy(), pid, interval) {
+        Ok(sampler) => sampler,
+        Err(err) => {
+            let _ = root_child.kill();
+            return Err(err);
+        }
+    };
+    let sampler_stop_flag = stop_flag.clone();
+    let sampler_thread =
+        thread::spawn(move || sampler.run(interval, time_limit, &sampler_stop_flag));
+
+    let exit_status = root_child.wait().expect("couldn't wait for child");
+    stop_flag.store(true, Ordering::SeqCst);
+    let profile = sampler_thread
+        .join()
+        .expect("couldn't join sampler thread")?;
+
+    let output_file = output.path_for_process(pid, launch_time);
+    save_profile(&profile, &output_file).expect("Couldn't write JSON");
+    eprintln!("Saved profile to {}", output_file.display());
+
+    if let Some(server_props) = server_props {
+        start_server_main(&output_file, server_props);
+    }
+
+    Ok(exit_status)
+}
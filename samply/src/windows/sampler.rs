@@ -0,0 +1,654 @@
+use fxprof_processed_profile::debugid::{CodeId, DebugId};
+use fxprof_processed_profile::{
+    CategoryColor, CategoryPairHandle, CpuDelta, Frame, LibraryInfo, ProcessHandle, Profile,
+    ReferenceTimestamp, ThreadHandle, Timestamp,
+};
+use object::read::pe::{ImageNtHeaders, ImageOptionalHeader, PeFile, PeFile32, PeFile64};
+use object::{LittleEndian, Object};
+use samply_symbols::{debug_id_for_object, object};
+use wholesym::samply_symbols;
+use winapi::shared::minwindef::{FALSE, FILETIME, LPCVOID, LPVOID};
+use winapi::um::handleapi::{CloseHandle, INVALID_HANDLE_VALUE};
+use winapi::um::memoryapi::ReadProcessMemory;
+use winapi::um::processthreadsapi::{
+    GetThreadContext, GetThreadTimes, OpenProcess, OpenThread, ResumeThread, SuspendThread,
+};
+use winapi::um::tlhelp32::{
+    CreateToolhelp32Snapshot, Module32FirstW, Module32NextW, Thread32First, Thread32Next,
+    MODULEENTRY32W, TH32CS_SNAPMODULE, TH32CS_SNAPMODULE32, TH32CS_SNAPTHREAD, THREADENTRY32,
+};
+use winapi::um::winnt::{
+    CONTEXT, CONTEXT_CONTROL, CONTEXT_INTEGER, HANDLE, PROCESS_QUERY_LIMITED_INFORMATION,
+    PROCESS_VM_READ, THREAD_GET_CONTEXT, THREAD_QUERY_LIMITED_INFORMATION, THREAD_SUSPEND_RESUME,
+};
+
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io;
+use std::mem;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use super::error::SamplingError;
+use crate::code_ranges::{executable_ranges_for_lib, CodeRanges};
+
+/// Frame pointer walks stop after this many frames, in case of a cycle in
+/// corrupted stack memory.
+const MAX_STACK_DEPTH: usize = 10000;
+
+/// Reading the module list is much slower than sampling a thread, so it's only
+/// done for every this many samples.
+const MODULE_CHECK_INTERVAL: u64 = 10;
+
+/// Samples all threads of one process by suspending each thread, reading its
+/// registers with `GetThreadContext` and walking the frame pointer chain with
+/// `ReadProcessMemory`.
+///
+/// Only frame pointers are used for unwinding, so stacks through code which was
+/// compiled without them, which includes most of the Windows system libraries,
+/// are cut short. The walk also stops at return addresses which aren't in an
+/// executable section of a loaded module.
+pub struct ProcessSampler {
+    pid: u32,
+    process: OwnedHandle,
+    profile: Profile,
+    profile_process: ProcessHandle,
+    default_category: CategoryPairHandle,
+    reference_instant: Instant,
+    live_threads: HashMap<u32, ThreadSampler>,
+    /// The base addresses of the modules which were added to the profile.
+    modules: HashSet<u64>,
+    code_ranges: CodeRanges,
+    sample_count: u64,
+    stack_scratch_space: Vec<Frame>,
+}
+
+impl ProcessSampler {
+    pub fn new(command_name: &str, pid: u32, interval: Duration) -> Result<Self, SamplingError> {
+        let process = OwnedHandle::new(unsafe {
+            OpenProcess(
+                PROCESS_QUERY_LIMITED_INFORMATION | PROCESS_VM_READ,
+                FALSE,
+                pid,
+            )
+        })
+        .ok_or_else(|| SamplingError::CouldNotAttach(pid, io::Error::last_os_error()))?;
+
+        let reference_instant = Instant::now();
+        let mut profile = Profile::new(
+            command_name,
+            ReferenceTimestamp::from_system_time(SystemTime::now()),
+            interval.into(),
+        );
+        profile.set_platform("Windows");
+        let default_category =
+            CategoryPairHandle::from(profile.add_category("Regular", CategoryColor::Blue));
+        let profile_process =
+            profile.add_process(command_name, pid, Timestamp::from_nanos_since_reference(0));
+
+        Ok(Self {
+            pid,
+            process,
+            profile,
+            profile_process,
+            default_category,
+            reference_instant,
+            live_threads: HashMap::new(),
+            modules: HashSet::new(),
+            code_ranges: CodeRanges::default(),
+            sample_count: 0,
+            stack_scratch_space: Vec::new(),
+        })
+    }
+
+    /// Sample the process once per `interval` until `stop_flag` is set, the
+    /// time limit is reached or all of its threads have exited.
+    pub fn run(
+        mut self,
+        interval: Duration,
+        time_limit: Option<Duration>,
+        stop_flag: &AtomicBool,
+    ) -> Result<Profile, SamplingError> {
+        while !stop_flag.load(Ordering::SeqCst) {
+            let sample_start = Instant::now();
+            if let Some(time_limit) = time_limit {
+                if sample_start.duration_since(self.reference_instant) >= time_limit {
+                    self.profile.set_truncated(true);
+                    break;
+                }
+            }
+            if !self.sample()? {
+                break;
+            }
+            if let Some(remaining) = interval.checked_sub(sample_start.elapsed()) {
+                thread::sleep(remaining);
+            }
+        }
+        Ok(self.finish())
+    }
+
+    /// Take one sample of every thread. Returns `Ok(false)` once the process has
+    /// no threads left.
+    fn sample(&mut self) -> Result<bool, SamplingError> {
+        if self.sample_count.is_multiple_of(MODULE_CHECK_INTERVAL) {
+            self.update_modules();
+        }
+        self.sample_count += 1;
+
+        let tids = get_thread_ids(self.pid)
+            .map_err(|err| SamplingError::ThreadEnumerationFailed(self.pid, err))?;
+        if tids.is_empty() {
+            return Ok(false);
+        }
+
+        let now = make_timestamp(self.reference_instant, Instant::now());
+        let is_first_sample = self.sample_count == 1;
+        for (index, &tid) in tids.iter().enumerate() {
+            if self.live_threads.contains_key(&tid) {
+                continue;
+            }
+            // The thread list is in creation order, so the first thread which is
+            // there when we attach is the main thread.
+            let is_main = is_first_sample && index == 0;
+            let thread = match OwnedHandle::new(unsafe {
+                OpenThread(
+                    THREAD_SUSPEND_RESUME | THREAD_GET_CONTEXT | THREAD_QUERY_LIMITED_INFORMATION,
+                    FALSE,
+                    tid,
+                )
+            }) {
+                Some(thread) => thread,
+                // The thread has probably exited already.
+                None => continue,
+            };
+            let profile_thread = self
+                .profile
+                .add_thread(self.profile_process, tid, now, is_main);
+            if is_first_sample {
+                // This thread existed before we started observing the process, so
+                // we don't know when it was actually created.
+                self.profile
+                    .set_thread_start_time_known(profile_thread, false);
+            }
+            self.live_threads
+                .insert(tid, ThreadSampler::new(thread, profile_thread));
+        }
+
+        let live_tids: HashSet<u32> = tids.into_iter().collect();
+        let dead_tids: Vec<u32> = self
+            .live_threads
+            .keys()
+            .filter(|tid| !live_tids.contains(tid))
+            .copied()
+            .collect();
+        for tid in dead_tids {
+            let thread = self.live_threads.remove(&tid).unwrap();
+            self.profile.set_thread_end_time(thread.profile_thread, now);
+        }
+
+        for thread in self.live_threads.values_mut() {
+            // Take each thread's timestamp when its stack is read, so that the
+            // time spent on the other threads doesn't shift it.
+            let sample_time = make_timestamp(self.reference_instant, Instant::now());
+            thread.sample(
+                self.process.0,
+                &self.code_ranges,
+                sample_time,
+                &mut self.stack_scratch_space,
+                self.default_category,
+                &mut self.profile,
+            );
+        }
+        Ok(true)
+    }
+
+    /// Adds newly loaded modules to the profile, and removes unloaded ones.
+    fn update_modules(&mut self) {
+        let modules = match get_modules(self.pid) {
+            Ok(modules) => modules,
+            // The module list can't be read while the process is starting up or
+            // exiting. Try again next time.
+            Err(_) => return,
+        };
+        let loaded_bases: HashSet<u64> = modules.iter().map(|module| module.base).collect();
+        for module in modules {
+            if self.modules.insert(module.base) {
+                self.code_ranges.add(
+                    module.base..(module.base + module.size),
+                    executable_ranges_for_lib(Path::new(&module.path), None),
+                );
+                self.profile.add_lib(
+                    self.profile_process,
+                    library_info(&module.path, module.base, module.size),
+                );
+            }
+        }
+        let unloaded_bases: Vec<u64> = self.modules.difference(&loaded_bases).copied().collect();
+        for base in unloaded_bases {
+            self.modules.remove(&base);
+            self.code_ranges.remove(base);
+            self.profile.unload_lib(self.profile_process, base);
+        }
+    }
+
+    fn finish(mut self) -> Profile {
+        let end_time = make_timestamp(self.reference_instant, Instant::now());
+        for (_, thread) in self.live_threads.drain() {
+            self.profile
+                .set_thread_end_time(thread.profile_thread, end_time);
+        }
+        self.profile
+            .set_process_end_time(self.profile_process, end_time);
+        self.profile
+    }
+}
+
+struct ThreadSampler {
+    thread: OwnedHandle,
+    profile_thread: ThreadHandle,
+    tick_count: usize,
+    /// In units of 100 nanoseconds.
+    previous_sample_cpu_time: u64,
+}
+
+impl ThreadSampler {
+    fn new(thread: OwnedHandle, profile_thread: ThreadHandle) -> Self {
+        Self {
+            thread,
+            profile_thread,
+            tick_count: 0,
+            previous_sample_cpu_time: 0,
+        }
+    }
+
+    fn sample(
+        &mut self,
+        process: HANDLE,
+        code_ranges: &CodeRanges,
+        now: Timestamp,
+        frames: &mut Vec<Frame>,
+        category: CategoryPairHandle,
+        profile: &mut Profile,
+    ) {
+        self.tick_count += 1;
+        let cpu_time = get_thread_cpu_time(self.thread.0).unwrap_or(self.previous_sample_cpu_time);
+        let cpu_delta_100ns = cpu_time.saturating_sub(self.previous_sample_cpu_time);
+        self.previous_sample_cpu_time = cpu_time;
+        let cpu_delta = CpuDelta::from_nanos(cpu_delta_100ns * 100);
+
+        if cpu_delta.is_zero() && self.tick_count > 1 {
+            // The thread hasn't run since the last sample, so its stack can't
+            // have changed. This avoids suspending idle threads.
+            profile.add_sample_same_stack_zero_cpu(self.profile_thread, now, 1);
+            return;
+        }
+
+        frames.clear();
+        if !get_backtrace(process, self.thread.0, code_ranges, frames) {
+            // The thread is probably exiting.
+            return;
+        }
+        // The walk goes from the leaf to the root, the profile wants the root first.
+        let frames = frames.iter().rev().map(|frame| (frame.clone(), category));
+        profile.add_sample(self.profile_thread, now, frames, cpu_delta, 1);
+    }
+}
+
+/// A handle which is closed when it's dropped.
+struct OwnedHandle(HANDLE);
+
+impl OwnedHandle {
+    /// Returns `None` for the values which the Windows APIs return on failure.
+    fn new(handle: HANDLE) -> Option<Self> {
+        if handle.is_null() || handle == INVALID_HANDLE_VALUE {
+            None
+        } else {
+            Some(Self(handle))
+        }
+    }
+}
+
+// Handles can be used from any thread.
+unsafe impl Send for OwnedHandle {}
+
+impl Drop for OwnedHandle {
+    fn drop(&mut self) {
+        unsafe { CloseHandle(self.0) };
+    }
+}
+
+/// `CONTEXT` needs to be 16-byte aligned, which winapi's definition doesn't
+/// ensure.
+#[repr(C, align(16))]
+struct AlignedContext(CONTEXT);
+
+/// Suspends the thread, walks its stack and resumes it. The frames are pushed
+/// to `frames`, leaf first. Returns false if the thread couldn't be suspended
+/// or its registers couldn't be read.
+fn get_backtrace(
+    process: HANDLE,
+    thread: HANDLE,
+    code_ranges: &CodeRanges,
+    frames: &mut Vec<Frame>,
+) -> bool {
+    let mut context: AlignedContext = unsafe { mem::zeroed() };
+    context.0.ContextFlags = CONTEXT_CONTROL | CONTEXT_INTEGER;
+    if unsafe { SuspendThread(thread) } == u32::MAX {
+        return false;
+    }
+    // SuspendThread returns before the thread is actually suspended, but
+    // GetThreadContext waits for the suspension to complete.
+    let got_context = unsafe { GetThreadContext(thread, &mut context.0) } != FALSE;
+    if got_context {
+        let (pc, sp, fp) = get_unwinding_registers(&context.0);
+        walk_frame_pointers(
+            pc,
+            sp,
+            fp,
+            code_ranges,
+            |address| read_u64_pair(process, address),
+            frames,
+        );
+    }
+    unsafe { ResumeThread(thread) };
+    got_context
+}
+
+/// Returns (pc, sp, fp).
+#[cfg(target_arch = "x86_64")]
+fn get_unwinding_registers(context: &CONTEXT) -> (u64, u64, u64) {
+    (context.Rip, context.Rsp, context.Rbp)
+}
+
+/// Returns (pc, sp, fp).
+#[cfg(target_arch = "aarch64")]
+fn get_unwinding_registers(context: &CONTEXT) -> (u64, u64, u64) {
+    (context.Pc, context.Sp, unsafe { context.u.s() }.Fp)
+}
+
+/// Follows the chain of saved frame pointers. Every frame record consists of
+/// the caller's frame pointer followed by the return address. The walk stops at
+/// the first return address which isn't in `code_ranges`, since it can only
+/// come from a broken chain.
+fn walk_frame_pointers(
+    pc: u64,
+    sp: u64,
+    mut fp: u64,
+    code_ranges: &CodeRanges,
+    mut read_frame_record: impl FnMut(u64) -> Option<[u64; 2]>,
+    frames: &mut Vec<Frame>,
+) {
+    frames.push(Frame::InstructionPointer(pc));
+    while frames.len() < MAX_STACK_DEPTH {
+        // Frame records are on the stack, i.e. above the stack pointer, and aligned.
+        if fp < sp || !fp.is_multiple_of(8) {
+            break;
+        }
+        let [caller_fp, return_address] = match read_frame_record(fp) {
+            Some(record) => record,
+            None => break,
+        };
+        if !code_ranges.contains(return_address) {
+            break;
+        }
+        frames.push(Frame::ReturnAddress(return_address));
+        // The stack grows downwards, so the caller's frame record is at a
+        // higher address. Anything else means that the chain is broken.
+        if caller_fp <= fp {
+            break;
+        }
+        fp = caller_fp;
+    }
+}
+
+fn read_u64_pair(process: HANDLE, address: u64) -> Option<[u64; 2]> {
+    let mut buffer = [0u64; 2];
+    let mut bytes_read = 0;
+    let ok = unsafe {
+        ReadProcessMemory(
+            process,
+            address as usize as LPCVOID,
+            buffer.as_mut_ptr() as LPVOID,
+            mem::size_of_val(&buffer),
+            &mut bytes_read,
+        )
+    };
+    if ok == FALSE || bytes_read != mem::size_of_val(&buffer) {
+        return None;
+    }
+    Some(buffer)
+}
+
+/// Returns the sum of user and kernel time, in units of 100 nanoseconds.
+fn get_thread_cpu_time(thread: HANDLE) -> Option<u64> {
+    let mut creation_time: FILETIME = unsafe { mem::zeroed() };
+    let mut exit_time: FILETIME = unsafe { mem::zeroed() };
+    let mut kernel_time: FILETIME = unsafe { mem::zeroed() };
+    let mut user_time: FILETIME = unsafe { mem::zeroed() };
+    let ok = unsafe {
+        GetThreadTimes(
+            thread,
+            &mut creation_time,
+            &mut exit_time,
+            &mut kernel_time,
+            &mut user_time,
+        )
+    };
+    if ok == FALSE {
+        return None;
+    }
+    Some(filetime_to_u64(&kernel_time) + filetime_to_u64(&user_time))
+}
+
+fn filetime_to_u64(filetime: &FILETIME) -> u64 {
+    (u64::from(filetime.dwHighDateTime) << 32) | u64::from(filetime.dwLowDateTime)
+}
+
+/// Returns the ids of the threads of the process `pid`, in creation order.
+fn get_thread_ids(pid: u32) -> io::Result<Vec<u32>> {
+    let snapshot = OwnedHandle::new(unsafe { CreateToolhelp32Snapshot(TH32CS_SNAPTHREAD, 0) })
+        .ok_or_else(io::Error::last_os_error)?;
+    let mut entry: THREADENTRY32 = unsafe { mem::zeroed() };
+    entry.dwSize = mem::size_of::<THREADENTRY32>() as u32;
+    let mut tids = Vec::new();
+    let mut has_entry = unsafe { Thread32First(snapshot.0, &mut entry) } != FALSE;
+    while has_entry {
+        if entry.th32OwnerProcessID == pid {
+            tids.push(entry.th32ThreadID);
+        }
+        has_entry = unsafe { Thread32Next(snapshot.0, &mut entry) } != FALSE;
+    }
+    Ok(tids)
+}
+
+struct Module {
+    base: u64,
+    size: u64,
+    path: String,
+}
+
+fn get_modules(pid: u32) -> io::Result<Vec<Module>> {
+    let snapshot = OwnedHandle::new(unsafe {
+        CreateToolhelp32Snapshot(TH32CS_SNAPMODULE | TH32CS_SNAPMODULE32, pid)
+    })
+    .ok_or_else(io::Error::last_os_error)?;
+    let mut entry: MODULEENTRY32W = unsafe { mem::zeroed() };
+    entry.dwSize = mem::size_of::<MODULEENTRY32W>() as u32;
+    let mut modules = Vec::new();
+    let mut has_entry = unsafe { Module32FirstW(snapshot.0, &mut entry) } != FALSE;
+    while has_entry {
+        modules.push(Module {
+            base: entry.modBaseAddr as u64,
+            size: u64::from(entry.modBaseSize),
+            path: wide_string_to_string(&entry.szExePath),
+        });
+        has_entry = unsafe { Module32NextW(snapshot.0, &mut entry) } != FALSE;
+    }
+    Ok(modules)
+}
+
+/// Converts a nul-terminated UTF-16 string.
+fn wide_string_to_string(wide: &[u16]) -> String {
+    let len = wide.iter().position(|c| *c == 0).unwrap_or(wide.len());
+    String::from_utf16_lossy(&wide[..len])
+}
+
+fn library_info(path: &str, base_avma: u64, size: u64) -> LibraryInfo {
+    let name = match Path::new(path).file_name() {
+        Some(name) => name.to_string_lossy().to_string(),
+        None => path.to_string(),
+    };
+    // Symbolication needs the PDB's debug ID, and the PDB path and name.
+    let (debug_id, code_id, debug_path) = match read_pe_info(path) {
+        Some(info) => (info.debug_id, info.code_id, info.pdb_path),
+        None => (DebugId::nil(), None, None),
+    };
+    let debug_path = debug_path.unwrap_or_else(|| path.to_string());
+    let debug_name = match debug_path.rsplit_once(['/', '\\']) {
+        Some((_dir, file_name)) => file_name.to_string(),
+        None => debug_path.clone(),
+    };
+    LibraryInfo {
+        base_avma,
+        avma_range: base_avma..(base_avma + size),
+        name,
+        debug_name,
+        path: path.to_string(),
+        debug_path,
+        debug_id,
+        code_id,
+        arch: None,
+        symbol_table: None,
+    }
+}
+
+struct PeInfo {
+    debug_id: DebugId,
+    code_id: Option<CodeId>,
+    pdb_path: Option<String>,
+}
+
+fn read_pe_info(path: &str) -> Option<PeInfo> {
+    let file = File::open(path).ok()?;
+    let mmap = unsafe { memmap2::Mmap::map(&file) }.ok()?;
+    let data = &mmap[..];
+    let object = object::File::parse(data).ok()?;
+    let debug_id = debug_id_for_object(&object)?;
+    let pdb_path = match object.pdb_info() {
+        Ok(Some(pdb_info)) => std::str::from_utf8(pdb_info.path()).ok().map(str::to_owned),
+        _ => None,
+    };
+    let code_id = if let Ok(pe) = PeFile64::parse(data) {
+        Some(pe_code_id(&pe))
+    } else if let Ok(pe) = PeFile32::parse(data) {
+        Some(pe_code_id(&pe))
+    } else {
+        None
+    };
+    Some(PeInfo {
+        debug_id,
+        code_id,
+        pdb_path,
+    })
+}
+
+/// The code ID which symbol servers use for PE binaries: the timestamp and the
+/// image size.
+fn pe_code_id<Pe: ImageNtHeaders>(pe: &PeFile<'_, Pe>) -> CodeId {
+    let header = pe.nt_headers();
+    let timestamp = header.file_header().time_date_stamp.get(LittleEndian);
+    let image_size = header.optional_header().size_of_image();
+    CodeId::new(format!("{:08X}{:x}", timestamp, image_size))
+}
+
+fn make_timestamp(reference_instant: Instant, instant: Instant) -> Timestamp {
+    let nanos = instant
+        .saturating_duration_since(reference_instant)
+        .as_nanos();
+    Timestamp::from_nanos_since_reference(nanos as u64)
+}
+
+#[test]
+fn test_walk_frame_pointers() {
+    // Frame records at 0x1000 -> 0x1040 -> 0x1080, which ends the chain.
+    let records: HashMap<u64, [u64; 2]> = vec![
+        (0x1000, [0x1040, 0x7000]),
+        (0x1040, [0x1080, 0x7100]),
+        (0x1080, [0, 0]),
+    ]
+    .into_iter()
+    .collect();
+    let mut code_ranges = CodeRanges::default();
+    code_ranges.add(0x6000..0x8000, None);
+    let mut frames = Vec::new();
+    walk_frame_pointers(
+        0x6000,
+        0xff0,
+        0x1000,
+        &code_ranges,
+        |address| records.get(&address).copied(),
+        &mut frames,
+    );
+    assert_eq!(
+        frames,
+        vec![
+            Frame::InstructionPointer(0x6000),
+            Frame::ReturnAddress(0x7000),
+            Frame::ReturnAddress(0x7100),
+        ]
+    );
+
+    // A frame pointer below the stack pointer isn't followed.
+    let mut frames = Vec::new();
+    walk_frame_pointers(
+        0x6000,
+        0x2000,
+        0x1000,
+        &code_ranges,
+        |address| records.get(&address).copied(),
+        &mut frames,
+    );
+    assert_eq!(frames, vec![Frame::InstructionPointer(0x6000)]);
+
+    // A return address outside of any module ends the walk.
+    let mut code_ranges = CodeRanges::default();
+    code_ranges.add(0x6000..0x7080, None);
+    let mut frames = Vec::new();
+    walk_frame_pointers(
+        0x6000,
+        0xff0,
+        0x1000,
+        &code_ranges,
+        |address| records.get(&address).copied(),
+        &mut frames,
+    );
+    assert_eq!(
+        frames,
+        vec![
+            Frame::InstructionPointer(0x6000),
+            Frame::ReturnAddress(0x7000),
+        ]
+    );
+
+    // A return address in a data section of the module ends the walk.
+    let mut code_ranges = CodeRanges::default();
+    code_ranges.add(0x6000..0x8000, Some(vec![0x0..0x1080, 0x1800..0x2000]));
+    let mut frames = Vec::new();
+    walk_frame_pointers(
+        0x6000,
+        0xff0,
+        0x1000,
+        &code_ranges,
+        |address| records.get(&address).copied(),
+        &mut frames,
+    );
+    assert_eq!(
+        frames,
+        vec![
+            Frame::InstructionPointer(0x6000),
+            Frame::ReturnAddress(0x7000),
+        ]
+    );
+}
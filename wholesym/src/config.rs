@@ -17,6 +17,7 @@ pub struct SymbolManagerConfig {
     pub(crate) use_debuginfod: bool,
     pub(crate) debuginfod_cache_dir_if_not_installed: Option<PathBuf>,
     pub(crate) debuginfod_servers: Vec<(String, PathBuf)>,
+    pub(crate) symbolication_concurrency: usize,
 }
 
 impl SymbolManagerConfig {
@@ -135,6 +136,14 @@ impl SymbolManagerConfig {
         self
     }
 
+    /// The maximum number of libraries which are symbolicated concurrently in
+    /// [`SymbolManager::query_json_api`](crate::SymbolManager::query_json_api)
+    /// requests. Defaults to 1. The value is capped at the number of available CPU cores.
+    pub fn symbolication_concurrency(mut self, concurrency: usize) -> Self {
+        self.symbolication_concurrency = concurrency;
+        self
+    }
+
     /// Add a server to search for ELF debuginfo and executable files, along with a local cache directory.
     /// These servers are consulted independently of `use_debuginfod`.
     ///
@@ -1,7 +1,10 @@
 use std::path::{Path, PathBuf};
 
+use samply_api::samply_symbols::FileAndPathHelperResult;
 use symsrv::{memmap2, FileContents};
 
+use crate::download::download_to_cache;
+
 pub struct DebuginfodSymbolCache(DebuginfodSymbolCacheInner);
 
 enum DebuginfodSymbolCacheInner {
@@ -133,33 +136,9 @@ impl ManualDebuginfodSymbolCache {
         file_type: &str,
         server_base_url: &str,
         cache_dir: &Path,
-    ) -> Result<FileContents, Box<dyn std::error::Error>> {
+    ) -> FileAndPathHelperResult<FileContents> {
         let url = format!("{server_base_url}/buildid/{buildid}/{file_type}");
-        if self.verbose {
-            eprintln!("Downloading {}...", url);
-        }
-        let sym_file_response = reqwest::get(&url).await?.error_for_status()?;
-        let mut stream = sym_file_response.bytes_stream();
         let dest_path = cache_dir.join(buildid).join(file_type);
-        if let Some(dir) = dest_path.parent() {
-            tokio::fs::create_dir_all(dir).await?;
-        }
-        if self.verbose {
-            eprintln!("Saving bytes to {:?}.", dest_path);
-        }
-        let file = tokio::fs::File::create(&dest_path).await?;
-        let mut writer = tokio::io::BufWriter::new(file);
-        use futures_util::StreamExt;
-        while let Some(item) = stream.next().await {
-            tokio::io::copy(&mut item?.as_ref(), &mut writer).await?;
-        }
-        drop(writer);
-        if self.verbose {
-            eprintln!("Opening file {:?}", dest_path.to_string_lossy());
-        }
-        let file = std::fs::File::open(&dest_path)?;
-        Ok(FileContents::Mmap(unsafe {
-            memmap2::MmapOptions::new().map(&file)?
-        }))
+        download_to_cache(&url, &dest_path, self.verbose).await
     }
 }
@@ -0,0 +1,127 @@
+use fs2::FileExt;
+use futures_util::StreamExt;
+use samply_api::samply_symbols::FileAndPathHelperResult;
+use symsrv::{memmap2, FileContents};
+use tokio::io::AsyncWriteExt;
+
+use std::ffi::OsString;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// How long to wait before checking again whether another process is done
+/// downloading the file that we want.
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Downloads `url` to `dest_path` in a cache directory, and returns the
+/// downloaded file.
+///
+/// The cache directory can be shared between several processes. The file is
+/// downloaded to a temporary file next to `dest_path` and then renamed, so
+/// `dest_path` never refers to a partial download. An advisory lock on
+/// `<dest_path>.lock` makes concurrent fetchers of the same file wait for the
+/// first download instead of downloading the file again.
+pub async fn download_to_cache(
+    url: &str,
+    dest_path: &Path,
+    verbose: bool,
+) -> FileAndPathHelperResult<FileContents> {
+    if let Some(dir) = dest_path.parent() {
+        tokio::fs::create_dir_all(dir).await?;
+    }
+    let _lock = lock_cache_entry(dest_path).await?;
+
+    // The file may have been downloaded while we were waiting for the lock.
+    if let Ok(file) = File::open(dest_path) {
+        return Ok(mmap_file(&file)?);
+    }
+
+    if verbose {
+        eprintln!("Downloading {}...", url);
+    }
+    let response = reqwest::get(url).await?.error_for_status()?;
+    let mut stream = response.bytes_stream();
+    // Only the holder of the lock writes to this file, so the name doesn't
+    // need to be unique. A file left behind by a crashed download is
+    // overwritten.
+    let partial_path = path_with_suffix(dest_path, ".part");
+    if verbose {
+        eprintln!("Saving bytes to {:?}.", dest_path);
+    }
+    let file = tokio::fs::File::create(&partial_path).await?;
+    let mut writer = tokio::io::BufWriter::new(file);
+    while let Some(item) = stream.next().await {
+        writer.write_all(&item?).await?;
+    }
+    writer.flush().await?;
+    drop(writer);
+    tokio::fs::rename(&partial_path, dest_path).await?;
+
+    if verbose {
+        eprintln!("Opening file {:?}", dest_path.to_string_lossy());
+    }
+    let file = File::open(dest_path)?;
+    Ok(mmap_file(&file)?)
+}
+
+/// Waits until this process holds the lock for `dest_path`. The lock is
+/// released when the returned file is dropped. The lock file itself is never
+/// deleted, because deleting it would let two processes lock different files
+/// for the same entry.
+async fn lock_cache_entry(dest_path: &Path) -> io::Result<File> {
+    let lock_file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path_with_suffix(dest_path, ".lock"))?;
+    loop {
+        match lock_file.try_lock_exclusive() {
+            Ok(()) => return Ok(lock_file),
+            Err(err) if err.kind() == fs2::lock_contended_error().kind() => {
+                tokio::time::sleep(LOCK_POLL_INTERVAL).await;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+fn mmap_file(file: &File) -> io::Result<FileContents> {
+    Ok(FileContents::Mmap(unsafe {
+        memmap2::MmapOptions::new().map(file)?
+    }))
+}
+
+fn path_with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut s = OsString::from(path);
+    s.push(suffix);
+    PathBuf::from(s)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_path_with_suffix() {
+        assert_eq!(
+            path_with_suffix(Path::new("/cache/abc/debuginfo"), ".lock"),
+            Path::new("/cache/abc/debuginfo.lock")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_lock_cache_entry_waits_for_release() {
+        let dir = tempfile::tempdir().unwrap();
+        let dest_path = dir.path().join("file.sym");
+        let first = lock_cache_entry(&dest_path).await.unwrap();
+        let second =
+            tokio::time::timeout(Duration::from_millis(300), lock_cache_entry(&dest_path)).await;
+        assert!(second.is_err(), "the entry should still be locked");
+        drop(first);
+        let second = tokio::time::timeout(Duration::from_secs(5), lock_cache_entry(&dest_path))
+            .await
+            .expect("the lock should be available after the first holder is dropped");
+        assert!(second.is_ok());
+    }
+}
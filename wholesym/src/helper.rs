@@ -15,7 +15,9 @@ use std::{
     sync::{Arc, Mutex},
 };
 
-use crate::{config::SymbolManagerConfig, debuginfod::DebuginfodSymbolCache};
+use crate::{
+    config::SymbolManagerConfig, debuginfod::DebuginfodSymbolCache, download::download_to_cache,
+};
 
 #[derive(Debug, Clone)]
 pub enum WholesymFileLocation {
@@ -51,7 +53,34 @@ impl FileLocation for WholesymFileLocation {
     fn location_for_pdb_from_binary(&self, pdb_path_in_binary: &str) -> Option<Self> {
         // We only respect absolute paths to PDB files if those paths were found in a local binary.
         match self {
-            Self::LocalFile(_) => Some(Self::LocalFile(pdb_path_in_binary.into())),
+            Self::LocalFile(binary_path) => {
+                let pdb_path = Path::new(pdb_path_in_binary);
+                if !pdb_path.exists() {
+                    // The binary was probably built on a different machine. Look for the
+                    // PDB next to the binary. The path is usually a Windows path, so
+                    // split at both kinds of separators.
+                    let pdb_name = pdb_path_in_binary.rsplit(['/', '\\']).next()?;
+                    if let Some(dir) = binary_path.parent() {
+                        let sibling_path = dir.join(pdb_name);
+                        if sibling_path.exists() {
+                            return Some(Self::LocalFile(sibling_path));
+                        }
+                    }
+                }
+                Some(Self::LocalFile(pdb_path.into()))
+            }
+            _ => None,
+        }
+    }
+
+    fn location_for_dwp(&self) -> Option<Self> {
+        // DWARF packages are only looked for next to local binaries.
+        match self {
+            Self::LocalFile(binary_path) => {
+                let mut filename = binary_path.file_name()?.to_owned();
+                filename.push(".dwp");
+                Some(Self::LocalFile(binary_path.with_file_name(filename)))
+            }
             _ => None,
         }
     }
@@ -184,6 +213,10 @@ impl Helper {
         }
     }
 
+    pub fn config(&self) -> &SymbolManagerConfig {
+        &self.config
+    }
+
     pub fn add_known_lib(&self, lib_info: LibraryInfo) {
         let mut known_libs = self.known_libs.lock().unwrap();
         let lib_info = Arc::new(lib_info);
@@ -278,32 +311,8 @@ impl Helper {
         cache_dir: &Path,
     ) -> FileAndPathHelperResult<FileContents> {
         let url = format!("{}/{}", server_base_url, rel_path);
-        if self.config.verbose {
-            eprintln!("Downloading {}...", url);
-        }
-        let sym_file_response = reqwest::get(&url).await?.error_for_status()?;
-        let mut stream = sym_file_response.bytes_stream();
         let dest_path = cache_dir.join(rel_path);
-        if let Some(dir) = dest_path.parent() {
-            tokio::fs::create_dir_all(dir).await?;
-        }
-        if self.config.verbose {
-            eprintln!("Saving bytes to {:?}.", dest_path);
-        }
-        let file = tokio::fs::File::create(&dest_path).await?;
-        let mut writer = tokio::io::BufWriter::new(file);
-        use futures_util::StreamExt;
-        while let Some(item) = stream.next().await {
-            tokio::io::copy(&mut item?.as_ref(), &mut writer).await?;
-        }
-        drop(writer);
-        if self.config.verbose {
-            eprintln!("Opening file {:?}", dest_path.to_string_lossy());
-        }
-        let file = File::open(&dest_path)?;
-        Ok(FileContents::Mmap(unsafe {
-            memmap2::MmapOptions::new().map(&file)?
-        }))
+        download_to_cache(&url, &dest_path, self.config.verbose).await
     }
 
     fn fill_in_library_info_details(&self, info: &mut LibraryInfo) {
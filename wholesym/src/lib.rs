@@ -2,6 +2,7 @@ pub use debugid;
 
 mod config;
 mod debuginfod;
+mod download;
 mod helper;
 mod moria_mac;
 #[cfg(target_os = "macos")]
@@ -12,7 +13,8 @@ pub use config::SymbolManagerConfig;
 pub use samply_api::samply_symbols;
 pub use samply_api::samply_symbols::{
     AddressInfo, CodeId, ElfBuildId, Error, ExternalFileAddressInFileRef, ExternalFileAddressRef,
-    ExternalFileRef, ExternalFileSymbolMap, FrameDebugInfo, FramesLookupResult, LibraryInfo,
-    MappedPath, MultiArchDisambiguator, PeCodeId, SourceFilePath, SymbolInfo,
+    ExternalFileRef, ExternalFileSymbolMap, FrameDebugInfo, FramesLookupResult, FunctionNameMatch,
+    LibraryInfo, MappedPath, MultiArchDisambiguator, PathRewriteRule, PeCodeId, SourceFilePath,
+    SymbolInfo, UnnamedInlineFramePolicy,
 };
 pub use symbol_manager::{SymbolFileOrigin, SymbolManager, SymbolMap};
@@ -117,12 +117,15 @@ where
     Ok(unsafe { U::wrap_under_get_rule(U::Ref::from_void_ptr(t)) })
 }
 
-/// Attempt to locate the Mach-O file inside a dSYM matching `uuid` using spotlight.
+/// Attempt to locate the dSYM bundle matching `uuid` using spotlight. If there
+/// are multiple matching bundles, for example because a build was copied, the
+/// most recently modified one is returned.
 fn spotlight_locate_dsym_bundle(uuid: Uuid) -> Result<String, Error> {
     let uuid = uuid.hyphenated().to_string().to_uppercase();
     let query_string = format!("com_apple_xcode_dsym_uuids == {}", uuid);
     let query = MDQuery::create(&query_string)?;
     let count = query.execute()?;
+    let mut bundles = Vec::new();
     for i in 0..count {
         let item = unsafe { MDQueryGetResultAtIndex(ctref(&query), i) as MDItemRef };
         let attr = unsafe { CFString::wrap_under_get_rule(kMDItemPath) };
@@ -132,10 +135,18 @@ fn spotlight_locate_dsym_bundle(uuid: Uuid) -> Result<String, Error> {
         }
         let cf_attr = unsafe { CFType::wrap_under_get_rule(cf_attr) };
         if let Ok(path) = cast::<CFType, CFString>(&cf_attr) {
-            return Ok(path.to_string());
+            let path = path.to_string();
+            let modified = std::fs::metadata(&path)
+                .and_then(|metadata| metadata.modified())
+                .ok();
+            bundles.push((modified, path));
         }
     }
-    Err("dSYM not found")
+    bundles
+        .into_iter()
+        .max_by_key(|(modified, _)| *modified)
+        .map(|(_, path)| path)
+        .ok_or("dSYM not found")
 }
 
 /// Get the path to the Mach-O file containing DWARF debug info inside `bundle`.
@@ -1,11 +1,14 @@
 use std::borrow::Cow;
+use std::collections::HashMap;
+use std::ops::Range;
 use std::path::Path;
 use std::{future::Future, pin::Pin};
 
 use debugid::DebugId;
 use samply_api::samply_symbols::{
     self, AddressInfo, Error, ExternalFileAddressRef, ExternalFileRef, ExternalFileSymbolMap,
-    FrameDebugInfo, LibraryInfo, MultiArchDisambiguator,
+    FrameDebugInfo, FunctionNameMatch, LibraryInfo, MultiArchDisambiguator, PathRewriteRule,
+    UnnamedInlineFramePolicy,
 };
 use samply_api::Api;
 use yoke::{Yoke, Yokeable};
@@ -26,6 +29,14 @@ impl SymbolMap {
         self.0.lookup(address)
     }
 
+    /// Look up symbol information for the return address of a caller frame.
+    /// This looks up `return_address - 1` so that the result describes the call
+    /// instruction rather than the instruction after it.
+    /// See [`samply_symbols::SymbolMap::lookup_return_address`].
+    pub fn lookup_return_address(&self, return_address: u32) -> Option<AddressInfo> {
+        self.0.lookup_return_address(return_address)
+    }
+
     /// Returns an abstract "origin token" which needs to be passed to [`SymbolManager::lookup_external`]
     /// when resolving [`FramesLookupResult::External`](crate::FramesLookupResult::External) addresses.
     ///
@@ -46,6 +57,47 @@ impl SymbolMap {
         self.0.symbol_count()
     }
 
+    /// Whether function names returned by [`SymbolMap::lookup`] should be simplified
+    /// for readability. See [`samply_symbols::SymbolMap::set_simplify_names`].
+    pub fn set_simplify_names(&mut self, simplify_names: bool) {
+        self.0.set_simplify_names(simplify_names);
+    }
+
+    /// Supply authoritative lookup results for specific relative addresses, which
+    /// take precedence over the symbol information from the file.
+    /// See [`samply_symbols::SymbolMap::set_address_overrides`].
+    pub fn set_address_overrides(&mut self, overrides: HashMap<u32, AddressInfo>) {
+        self.0.set_address_overrides(overrides);
+    }
+
+    /// Choose how [`SymbolMap::lookup`] reports inline frames without a function name.
+    /// See [`samply_symbols::SymbolMap::set_unnamed_inline_frame_policy`].
+    pub fn set_unnamed_inline_frame_policy(&mut self, policy: UnnamedInlineFramePolicy) {
+        self.0.set_unnamed_inline_frame_policy(policy);
+    }
+
+    /// Limit the number of inline frames which [`SymbolMap::lookup`] returns per address.
+    /// See [`samply_symbols::SymbolMap::set_max_inline_depth`].
+    pub fn set_max_inline_depth(&mut self, max_inline_depth: Option<usize>) {
+        self.0.set_max_inline_depth(max_inline_depth);
+    }
+
+    /// Rewrite the prefixes of the source file paths which [`SymbolMap::lookup`] returns.
+    /// See [`samply_symbols::SymbolMap::set_path_rewrite_rules`].
+    pub fn set_path_rewrite_rules(&mut self, rules: Vec<PathRewriteRule>) {
+        self.0.set_path_rewrite_rules(rules);
+    }
+
+    /// Find the relative address ranges of all functions whose name matches `name`.
+    /// See [`samply_symbols::SymbolMap::find_function_ranges`].
+    pub fn find_function_ranges(
+        &self,
+        name: &str,
+        match_mode: FunctionNameMatch,
+    ) -> Vec<Range<u32>> {
+        self.0.find_function_ranges(name, match_mode)
+    }
+
     /// Iterate over all symbols in this `SymbolMap`.
     ///
     /// This iterator yields the relative address and the name of each symbol.
@@ -107,6 +159,28 @@ impl SymbolManager {
         path: &Path,
         disambiguator: Option<MultiArchDisambiguator>,
     ) -> Result<LibraryInfo, Error> {
+        let binary = Self::load_binary_at_path(path, disambiguator).await?;
+        Ok(binary.library_info())
+    }
+
+    /// Computes the ranges of relative addresses which are covered by executable
+    /// sections of the given binary, sorted by start address. See
+    /// [`BinaryImage::executable_ranges`](samply_symbols::BinaryImage::executable_ranges).
+    ///
+    /// Unwinders can check candidate return addresses against these ranges with
+    /// [`is_executable_address`](samply_symbols::is_executable_address).
+    pub async fn executable_ranges_for_binary_at_path(
+        path: &Path,
+        disambiguator: Option<MultiArchDisambiguator>,
+    ) -> Result<Vec<Range<u32>>, Error> {
+        let binary = Self::load_binary_at_path(path, disambiguator).await?;
+        Ok(binary.executable_ranges())
+    }
+
+    async fn load_binary_at_path(
+        path: &Path,
+        disambiguator: Option<MultiArchDisambiguator>,
+    ) -> Result<samply_symbols::BinaryImage<symsrv::FileContents>, Error> {
         let might_be_in_dyld_shared_cache =
             path.starts_with("/usr/") || path.starts_with("/System/");
 
@@ -124,18 +198,17 @@ impl SymbolManager {
                 disambiguator.clone(),
             )
             .await;
-        let binary = match binary_res {
-            Ok(binary) => binary,
+        match binary_res {
+            Ok(binary) => Ok(binary),
             Err(Error::HelperErrorDuringOpenFile(_, _)) if might_be_in_dyld_shared_cache => {
                 // The file at the given path could not be opened, so it probably doesn't exist.
                 // Check the dyld cache.
                 symbol_manager
                     .load_binary_for_dyld_cache_image(&path.to_string_lossy(), disambiguator)
-                    .await?
+                    .await
             }
-            Err(e) => return Err(e),
-        };
-        Ok(binary.library_info())
+            Err(e) => Err(e),
+        }
     }
 
     /// Tell the `SymbolManager` about a known library. This allows it to find
@@ -333,7 +406,8 @@ impl<'h> SymbolManagerTrait for SymbolManagerWrapper<'h> {
         path: &'a str,
         request_json: &'a str,
     ) -> Pin<Box<dyn Future<Output = String> + 'a + Send>> {
-        let api = Api::new(&self.0);
+        let concurrency = self.0.helper().config().symbolication_concurrency;
+        let api = Api::new(&self.0).with_symbolication_concurrency(concurrency);
         let f = api.query_api(path, request_json);
         Box::pin(f)
     }
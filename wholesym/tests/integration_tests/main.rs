@@ -32,6 +32,25 @@ fn dll() {
     assert_eq!(info.arch, None);
 }
 
+#[test]
+fn pdb_next_to_binary() {
+    // The PDB path in mozglue.dll points to the build machine. The PDB should
+    // be found next to the DLL instead, so that lookups have file and line
+    // information, which the DLL's export table doesn't have.
+    let dll_path = fixtures_dir().join("win64-ci").join("mozglue.dll");
+    let symbol_manager = wholesym::SymbolManager::with_config(Default::default());
+    let symbol_map = futures::executor::block_on(
+        symbol_manager.load_symbol_map_for_binary_at_path(&dll_path, None),
+    )
+    .unwrap();
+    let (address, _) = symbol_map.iter_symbols().next().unwrap();
+    let info = symbol_map.lookup(address).unwrap();
+    match info.frames {
+        FramesLookupResult::Available(frames) => assert!(frames[0].file_path.is_some()),
+        _ => panic!("expected frames from the PDB"),
+    }
+}
+
 #[test]
 fn exe() {
     // Compute the LibraryInfo for firefox.exe.